@@ -0,0 +1,1362 @@
+use crate::cancel::cancelled;
+use crate::copy::{io_error, open_error, skip_existing};
+use crate::logfile::FAILURE;
+use crate::options::{CopyOptions, Overwrite};
+use crate::partial::{CopyFailure, PartialCopyError};
+use crate::pipeline::{Queue, BUFFERS_PER_THREAD};
+use crate::pool::{take_buffers, PooledBuffer};
+use crate::progress::Progress;
+use crate::tree::warn_skipped;
+use crate::verify::read_full;
+use crate::walker::{walk_tree, TreeEntry};
+use crate::Error;
+use flate2::read::MultiGzDecoder;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tar archives are made of blocks of this size.
+const BLOCK: usize = 512;
+
+/// Archives are padded to a whole record of 20 blocks, as tar pads them.
+const RECORD: u64 = 20 * BLOCK as u64;
+
+const ZEROS: [u8; BLOCK] = [0; BLOCK];
+
+/// An entry of an archive being written.
+struct Member {
+    path: PathBuf,
+    /// The path in the archive, with `/` between its components and after
+    /// those of directories.
+    name: Vec<u8>,
+    metadata: fs::Metadata,
+    /// Where the symbolic link points, when it is one.
+    link: Option<Vec<u8>>,
+}
+
+/// Write `sources` to `writer` as a tar archive, returning the number of
+/// bytes of file data archived.
+///
+/// Each source is archived under its own name, or its entries under
+/// theirs when it has none such as `.`, and directories are walked as for
+/// [`copy_tree`](crate::copy_tree), with [`CopyOptions::filter`],
+/// [`CopyOptions::symlinks`] and the other options that choose the entries.
+/// Up to `options.threads` reader threads read the files a buffer at a time
+/// ahead of where the archive has got to, so that trees of many small files
+/// are read with several reads in flight and the archive is written out in
+/// order as they come in. Entries other than files, directories and
+/// symbolic links are left out, and files that change size while they are
+/// read are cut or padded with zeros to the size they had.
+///
+/// Names too long for a ustar header, sizes of 8Gb and over and large
+/// owner ids are stored in PAX headers, which any modern tar reads.
+pub fn write_archive<P: AsRef<Path>, W: Write>(
+    sources: &[P],
+    mut writer: W,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let mut failures = Vec::new();
+    let mut members = Vec::new();
+    for src in sources {
+        add_members(src.as_ref(), &mut members, &mut failures, options)?;
+    }
+    let buffer_size = options.buffer_size.max(BLOCK);
+    // Every buffer of file data to read, by member, offset and length.
+    let mut pieces = Vec::new();
+    let mut size = 0;
+    for (index, member) in members.iter().enumerate() {
+        let len = file_size(member);
+        let mut offset = 0;
+        while offset < len {
+            let piece = (len - offset).min(buffer_size as u64);
+            pieces.push((index, offset, piece as usize));
+            offset += piece;
+        }
+        size += len;
+    }
+    let readers = options.threads.max(1).min(pieces.len().max(1));
+    let free = Queue::new(take_buffers(
+        options.buffer_pool.as_ref(),
+        buffer_size,
+        (readers + 1) * BUFFERS_PER_THREAD,
+        1,
+    ));
+    // Pieces read, by their number, until they are written.
+    type Read = (io::Result<usize>, PooledBuffer);
+    let ready: Mutex<BTreeMap<usize, Read>> = Mutex::default();
+    let arrived = Condvar::new();
+    let next_read = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let copied = Arc::new(AtomicU64::new(0));
+
+    let progress = Progress::new(size, options);
+    progress.follow(Arc::clone(&copied));
+    let written = progress.show(|| {
+        thread::scope(|scope| {
+            for _ in 0..readers {
+                scope.spawn(|| {
+                    // The file read last, which the next pieces are likely of.
+                    let mut open: Option<(usize, File)> = None;
+                    while let Some(mut buffer) = free.pop() {
+                        let index = next_read.fetch_add(1, Ordering::SeqCst);
+                        let Some(&(member, offset, len)) = pieces.get(index) else {
+                            break;
+                        };
+                        if stop.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        if open.as_ref().is_none_or(|(open, _)| *open != member) {
+                            open = File::open(&members[member].path)
+                                .ok()
+                                .map(|file| (member, file));
+                        }
+                        let read = match &open {
+                            Some((_, file)) => read_full(file, &mut buffer[..len], offset),
+                            None => File::open(&members[member].path).map(|_| 0),
+                        };
+                        ready.lock().unwrap().insert(index, (read, buffer));
+                        arrived.notify_all();
+                    }
+                });
+            }
+            let mut write = || -> Result<u64, Error> {
+                let mut archived = 0;
+                let mut piece = 0;
+                for member in &members {
+                    if options.is_cancelled() {
+                        break;
+                    }
+                    let size = file_size(member);
+                    let header = headers(member, size);
+                    writer
+                        .write_all(&header)
+                        .map_err(|e| format!("Failed to write the archive: {}", e))?;
+                    archived += header.len() as u64;
+                    let (mut failed, mut short) = (None, false);
+                    let mut offset = 0;
+                    while offset < size {
+                        let mut pending = ready.lock().unwrap();
+                        let (read, mut buffer) = loop {
+                            if let Some(read) = pending.remove(&piece) {
+                                break read;
+                            }
+                            pending = arrived.wait(pending).unwrap();
+                        };
+                        drop(pending);
+                        let len = pieces[piece].2;
+                        match read {
+                            Ok(bytes_read) if bytes_read < len => {
+                                buffer[bytes_read..len].fill(0);
+                                short = true;
+                            }
+                            Ok(_) => {}
+                            Err(e) if options.continue_on_error => {
+                                buffer[..len].fill(0);
+                                failed.get_or_insert(io_error("read", &member.path, offset, e));
+                            }
+                            Err(e) => return Err(io_error("read", &member.path, offset, e)),
+                        }
+                        if let Some(bwlimit) = options.bwlimit.as_ref() {
+                            bwlimit.take(len);
+                        }
+                        writer
+                            .write_all(&buffer[..len])
+                            .map_err(|e| format!("Failed to write the archive: {}", e))?;
+                        free.push(buffer);
+                        offset += len as u64;
+                        piece += 1;
+                        copied.fetch_add(len as u64, Ordering::SeqCst);
+                    }
+                    let padding = padding(size);
+                    writer
+                        .write_all(&ZEROS[..padding])
+                        .map_err(|e| format!("Failed to write the archive: {}", e))?;
+                    archived += size + padding as u64;
+                    if let Some(e) = failed {
+                        log::error!(target: FAILURE, "{}", e);
+                        failures.push(CopyFailure {
+                            path: member.path.clone(),
+                            reason: e.to_string(),
+                        });
+                        if let Some(stats) = &options.stats {
+                            stats.failed();
+                        }
+                        continue;
+                    }
+                    if short {
+                        log::warn!(
+                            "'{}' got shorter while it was archived, it is padded with zeros",
+                            member.path.display()
+                        );
+                    }
+                    if member.metadata.is_file() && member.link.is_none() {
+                        log::info!("Archived '{}', {} bytes", member.path.display(), size);
+                        if let Some(stats) = &options.stats {
+                            stats.copied(size);
+                        }
+                    }
+                    if options.verbosity > 1 {
+                        progress.message(&format!(" Archived '{}'", member.path.display()));
+                    }
+                }
+                if !options.is_cancelled() {
+                    // Two blocks of zeros end the archive.
+                    archived += 2 * BLOCK as u64;
+                    let end = 2 * BLOCK + (archived.next_multiple_of(RECORD) - archived) as usize;
+                    writer
+                        .write_all(&vec![0; end])
+                        .and_then(|_| writer.flush())
+                        .map_err(|e| format!("Failed to write the archive: {}", e))?;
+                }
+                Ok(copied.load(Ordering::SeqCst))
+            };
+            let written = write();
+            // Readers waiting for a buffer are let go.
+            stop.store(true, Ordering::SeqCst);
+            free.close();
+            written
+        })
+    })?;
+    if options.is_cancelled() {
+        return Err(cancelled(format!(
+            "Archive interrupted after {} of {} bytes",
+            written, size
+        )));
+    }
+    if !failures.is_empty() {
+        return Err(Box::new(PartialCopyError::new(failures, None)));
+    }
+    Ok(written)
+}
+
+/// Add the entries of `src` to `members`.
+fn add_members(
+    src: &Path,
+    members: &mut Vec<Member>,
+    failures: &mut Vec<CopyFailure>,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    let base = match src.file_name() {
+        Some(_) => src.parent().unwrap_or(Path::new("")),
+        None => src,
+    };
+    let metadata = fs::metadata(src).map_err(|e| open_error(src, e))?;
+    let entries = if metadata.is_dir() {
+        walk_tree(src, options)
+    } else {
+        Box::new(std::iter::once(Ok(TreeEntry {
+            path: src.to_path_buf(),
+            depth: 0,
+            metadata,
+        })))
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err((path, e)) if options.continue_on_error => {
+                log::error!(target: FAILURE, "Failed to archive '{}': {}", path.display(), e);
+                failures.push(CopyFailure {
+                    path,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+            Err((_, e)) => return Err(e),
+        };
+        let mut name = archive_name(entry.path.strip_prefix(base).unwrap_or(&entry.path));
+        if name.is_empty() {
+            // The top of a source archived from inside.
+            continue;
+        }
+        let file_type = entry.metadata.file_type();
+        let link = if file_type.is_symlink() {
+            let target = fs::read_link(&entry.path)
+                .map_err(|e| format!("Failed to read link '{}': {}", entry.path.display(), e))?;
+            Some(os_bytes(target.as_os_str()))
+        } else if file_type.is_dir() {
+            name.push(b'/');
+            None
+        } else if file_type.is_file() {
+            None
+        } else {
+            if options.progress {
+                warn_skipped(
+                    &entry.path,
+                    "archives only hold files, directories and links",
+                );
+            }
+            continue;
+        };
+        members.push(Member {
+            path: entry.path,
+            name,
+            metadata: entry.metadata,
+            link,
+        });
+    }
+    Ok(())
+}
+
+/// The bytes of data `member` has in the archive.
+fn file_size(member: &Member) -> u64 {
+    match member.link {
+        None if member.metadata.is_file() => member.metadata.len(),
+        _ => 0,
+    }
+}
+
+/// Zeros that pad `size` bytes of data to a whole block.
+fn padding(size: u64) -> usize {
+    (size.next_multiple_of(BLOCK as u64) - size) as usize
+}
+
+/// `relative` as a name in an archive.
+fn archive_name(relative: &Path) -> Vec<u8> {
+    let mut name = Vec::new();
+    for component in relative.components() {
+        if let Component::Normal(part) = component {
+            if !name.is_empty() {
+                name.push(b'/');
+            }
+            name.extend_from_slice(&os_bytes(part));
+        }
+    }
+    name
+}
+
+#[cfg(unix)]
+fn os_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_vec()
+}
+
+/// Windows names are stored as UTF-8, with `/` between components.
+#[cfg(not(unix))]
+fn os_bytes(s: &OsStr) -> Vec<u8> {
+    s.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+#[cfg(unix)]
+fn os_string(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// The mode, owner and group of the entry `metadata` is of.
+#[cfg(unix)]
+fn ownership(metadata: &fs::Metadata) -> (u64, u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (
+        (metadata.mode() & 0o7777) as u64,
+        metadata.uid() as u64,
+        metadata.gid() as u64,
+    )
+}
+
+/// Windows has no modes or ids, only read-only files.
+#[cfg(not(unix))]
+fn ownership(metadata: &fs::Metadata) -> (u64, u64, u64) {
+    let mode = match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    };
+    (mode, 0, 0)
+}
+
+/// The ustar header of `member`, after a PAX header for what does not fit
+/// in it.
+fn headers(member: &Member, size: u64) -> Vec<u8> {
+    let (mode, uid, gid) = ownership(&member.metadata);
+    let mtime = member
+        .metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs());
+    let mut pax = Vec::new();
+    let mut header = [0; BLOCK];
+    match split_name(&member.name) {
+        Some((prefix, name)) => {
+            header[..name.len()].copy_from_slice(name);
+            header[345..345 + prefix.len()].copy_from_slice(prefix);
+        }
+        None => {
+            pax_record(&mut pax, "path", &member.name);
+            header[..100].copy_from_slice(&member.name[..100]);
+        }
+    }
+    if let Some(link) = &member.link {
+        if link.len() > 100 {
+            pax_record(&mut pax, "linkpath", link);
+        }
+        let len = link.len().min(100);
+        header[157..157 + len].copy_from_slice(&link[..len]);
+    }
+    number(&mut header[100..108], mode);
+    for (field, key, value) in [(108..116, "uid", uid), (116..124, "gid", gid)] {
+        if !number(&mut header[field], value) {
+            pax_record(&mut pax, key, value.to_string().as_bytes());
+        }
+    }
+    if !number(&mut header[124..136], size) {
+        pax_record(&mut pax, "size", size.to_string().as_bytes());
+    }
+    number(&mut header[136..148], mtime.min(0o777_7777_7777));
+    header[156] = if member.link.is_some() {
+        b'2'
+    } else if member.metadata.is_dir() {
+        b'5'
+    } else {
+        b'0'
+    };
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    checksum(&mut header);
+    let mut blocks = Vec::with_capacity(BLOCK);
+    if !pax.is_empty() {
+        let mut pax_header = [0; BLOCK];
+        pax_header[..14].copy_from_slice(b"././@PaxHeader");
+        number(&mut pax_header[100..108], 0o644);
+        number(&mut pax_header[124..136], pax.len() as u64);
+        number(&mut pax_header[136..148], mtime.min(0o777_7777_7777));
+        pax_header[156] = b'x';
+        pax_header[257..265].copy_from_slice(b"ustar\x0000");
+        checksum(&mut pax_header);
+        blocks.extend_from_slice(&pax_header);
+        blocks.extend_from_slice(&pax);
+        blocks.extend_from_slice(&ZEROS[..padding(pax.len() as u64)]);
+    }
+    blocks.extend_from_slice(&header);
+    blocks
+}
+
+/// `name` split into the prefix and name fields of a ustar header, if it
+/// fits in them.
+fn split_name(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    if name.len() <= 100 {
+        return Some((&[], name));
+    }
+    // Directories keep their trailing / in the name field.
+    let end = name.len() - usize::from(name.ends_with(b"/"));
+    (1..end.min(156))
+        .rev()
+        .filter(|&at| name[at] == b'/')
+        .map(|at| (&name[..at], &name[at + 1..]))
+        .find(|(_, rest)| !rest.is_empty() && rest.len() <= 100)
+}
+
+/// Write `value` in octal to the header `field`, ending it with a NUL,
+/// unless it is too large for it.
+fn number(field: &mut [u8], value: u64) -> bool {
+    let digits = field.len() - 1;
+    if value >= 1 << (3 * digits) {
+        return false;
+    }
+    field.copy_from_slice(format!("{:0width$o}\0", value, width = digits).as_bytes());
+    true
+}
+
+/// Fill in the checksum of `header`.
+fn checksum(header: &mut [u8; BLOCK]) {
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+}
+
+/// Add the record of `key` and `value` to the data of a PAX header.
+fn pax_record(pax: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // The length at the start counts its own digits too.
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + rest.to_string().len();
+    len = rest + len.to_string().len();
+    pax.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    pax.extend_from_slice(value);
+    pax.push(b'\n');
+}
+
+/// A header read from an archive.
+struct Header {
+    name: Vec<u8>,
+    link: Vec<u8>,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+    kind: u8,
+}
+
+impl Header {
+    fn parse(block: &[u8; BLOCK]) -> Result<Self, Error> {
+        let mut unsigned = block.to_owned();
+        unsigned[148..156].fill(b' ');
+        let sum: u64 = unsigned.iter().map(|&byte| byte as u64).sum();
+        let signed: i64 = unsigned.iter().map(|&byte| byte as i8 as i64).sum();
+        let stored = parse_number(&block[148..156]);
+        if stored != Some(sum) && stored.map(|stored| stored as i64) != Some(signed) {
+            return Err(
+                "Not a tar archive, or a damaged one: a header has the wrong checksum".into(),
+            );
+        }
+        let mut name = field(&block[..100]).to_vec();
+        // GNU archives use the prefix field for other things.
+        if &block[257..263] == b"ustar\0" && block[345] != 0 {
+            let mut prefixed = field(&block[345..500]).to_vec();
+            prefixed.push(b'/');
+            prefixed.extend_from_slice(&name);
+            name = prefixed;
+        }
+        let invalid = || "Not a tar archive, or a damaged one: a header has an invalid number";
+        Ok(Header {
+            name,
+            link: field(&block[157..257]).to_vec(),
+            size: parse_number(&block[124..136]).ok_or_else(invalid)?,
+            mode: parse_number(&block[100..108]).unwrap_or(0o644) as u32,
+            mtime: parse_number(&block[136..148]).unwrap_or(0),
+            kind: block[156],
+        })
+    }
+}
+
+/// The bytes of a header field up to the NUL that ends it.
+fn field(bytes: &[u8]) -> &[u8] {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// A number in a header field, in octal or, when its first bit is set, in
+/// the base-256 of GNU tar.
+fn parse_number(bytes: &[u8]) -> Option<u64> {
+    if bytes.first().is_some_and(|&byte| byte & 0x80 != 0) {
+        return bytes[1..]
+            .iter()
+            .try_fold((bytes[0] & 0x7f) as u64, |value, &byte| {
+                value.checked_mul(256).map(|value| value | byte as u64)
+            });
+    }
+    let digits = String::from_utf8_lossy(field(bytes));
+    let digits = digits.trim_matches(' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// A file of an archive read into a buffer, for a writer thread to create.
+struct Job {
+    path: PathBuf,
+    len: usize,
+    buffer: PooledBuffer,
+    mode: u32,
+    mtime: u64,
+}
+
+/// Extract the tar archive `reader` gives, plain or gzip-compressed, into
+/// the directory `dest`, returning the number of bytes of file data
+/// extracted.
+///
+/// Files that fit in a buffer are handed to up to `options.threads` writer
+/// threads as they are read, so archives of many small files are not held
+/// up by creating them one after the other, and larger ones are written as
+/// they are read. Modes and modification times are restored, directories'
+/// once everything in them is extracted. Symbolic and hard links are
+/// recreated, and entries with `..` in their names or that would be
+/// extracted through a link in the archive are refused. Existing files are
+/// replaced, unless [`CopyOptions::overwrite`] is
+/// [`Overwrite::Never`] or [`Overwrite::Update`] and says otherwise.
+pub fn extract_archive<R: Read, Q: AsRef<Path>>(
+    reader: R,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let dest = dest.as_ref();
+    let buffer_size = options.buffer_size.max(BLOCK);
+    let mut reader = BufReader::with_capacity(buffer_size, reader);
+    let gzip = reader
+        .fill_buf()
+        .map_err(|e| format!("Failed to read the archive: {}", e))?
+        .starts_with(&[0x1f, 0x8b]);
+    let mut reader: Box<dyn Read> = if gzip {
+        Box::new(MultiGzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    create_dir_all(dest).map_err(|e| io_error("create", dest, 0, e))?;
+    let writers = options.threads.max(1);
+    let free = Queue::new(take_buffers(
+        options.buffer_pool.as_ref(),
+        buffer_size,
+        (writers + 1) * BUFFERS_PER_THREAD,
+        1,
+    ));
+    let jobs: Queue<Job> = Queue::new([]);
+    let copied = Arc::new(AtomicU64::new(0));
+    let failures: Mutex<Vec<CopyFailure>> = Mutex::default();
+    let failure: Mutex<Option<Error>> = Mutex::default();
+
+    let progress = Progress::unbounded(options);
+    progress.follow(Arc::clone(&copied));
+    let mut extract = Extract {
+        dest,
+        options,
+        buffer_size,
+        free: &free,
+        jobs: &jobs,
+        copied: &copied,
+        dirs: Vec::new(),
+        known: HashSet::new(),
+        hard_links: Vec::new(),
+        links: false,
+    };
+    let result = progress.show(|| {
+        thread::scope(|scope| {
+            for _ in 0..writers {
+                scope.spawn(|| {
+                    while let Some(job) = jobs.pop() {
+                        let result = write_job(&job, options);
+                        free.push(job.buffer);
+                        match result {
+                            Ok(()) => {
+                                copied.fetch_add(job.len as u64, Ordering::SeqCst);
+                            }
+                            Err(e) => extract_failed(&job.path, e, &failures, &failure, options),
+                        }
+                    }
+                });
+            }
+            let result = extract.run(&mut reader, &failures, &failure);
+            jobs.close();
+            result
+        })
+    });
+    result?;
+    if let Some(e) = failure.into_inner().unwrap() {
+        return Err(e);
+    }
+    let mut failures = failures.into_inner().unwrap();
+    for (target, path) in &extract.hard_links {
+        // Links extracted after the entry are checked for again.
+        let relative = path.strip_prefix(dest).unwrap_or(path);
+        let result = match link_ancestor(dest, target).or_else(|| link_ancestor(dest, relative)) {
+            Some(link) => Err(format!(
+                "Refusing to link '{}' through the link '{}'",
+                path.display(),
+                link.display()
+            )),
+            None => {
+                let target = dest.join(target);
+                let _ = fs::remove_file(path);
+                fs::hard_link(&target, path).map_err(|e| {
+                    format!(
+                        "Failed to link '{}' to '{}': {}",
+                        path.display(),
+                        target.display(),
+                        e
+                    )
+                })
+            }
+        };
+        if let Err(e) = result {
+            if !options.continue_on_error {
+                return Err(e.into());
+            }
+            failures.push(CopyFailure {
+                path: path.clone(),
+                reason: e,
+            });
+        }
+    }
+    // Deepest first, so the times are not changed by what goes in them.
+    for (path, mode, mtime) in extract.dirs.iter().rev() {
+        if let Err(e) = restore_dir(path, *mode, *mtime) {
+            log::warn!(
+                "Failed to set the mode and time of '{}': {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    if options.is_cancelled() {
+        return Err(cancelled(format!(
+            "Extraction interrupted after {} bytes",
+            copied.load(Ordering::SeqCst)
+        )));
+    }
+    if !failures.is_empty() {
+        return Err(Box::new(PartialCopyError::new(failures, None)));
+    }
+    Ok(copied.load(Ordering::SeqCst))
+}
+
+/// Note down the failure to extract `path`, with
+/// [`CopyOptions::continue_on_error`], or stop the extraction.
+fn extract_failed(
+    path: &Path,
+    e: Error,
+    failures: &Mutex<Vec<CopyFailure>>,
+    failure: &Mutex<Option<Error>>,
+    options: &CopyOptions,
+) {
+    log::error!(target: FAILURE, "Failed to extract '{}': {}", path.display(), e);
+    if let Some(stats) = &options.stats {
+        stats.failed();
+    }
+    if options.continue_on_error {
+        failures.lock().unwrap().push(CopyFailure {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        });
+    } else {
+        failure.lock().unwrap().get_or_insert(e);
+    }
+}
+
+/// Create the file of `job`.
+fn write_job(job: &Job, options: &CopyOptions) -> Result<(), Error> {
+    let mut file = create_file(&job.path)?;
+    if let Some(bwlimit) = options.bwlimit.as_ref() {
+        bwlimit.take(job.len);
+    }
+    file.write_all(&job.buffer[..job.len])
+        .map_err(|e| io_error("write", &job.path, 0, e))?;
+    restore_file(&file, job.mode, job.mtime)
+        .map_err(|e| io_error("set the mode of", &job.path, 0, e))?;
+    if let Some(stats) = &options.stats {
+        stats.copied(job.len as u64);
+    }
+    Ok(())
+}
+
+/// Create the extracted file `path`, first removing what is there if it is
+/// not a regular file, such as a link a later entry of the same name made
+/// while the file waited for a writer. One made in between fails the open
+/// rather than being followed.
+fn create_file(path: &Path) -> Result<File, Error> {
+    if path
+        .symlink_metadata()
+        .is_ok_and(|metadata| !metadata.is_file() && !metadata.is_dir())
+    {
+        fs::remove_file(path).map_err(|e| io_error("remove", path, 0, e))?;
+    }
+    let mut open_options = OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        open_options.custom_flags(nix::libc::O_NOFOLLOW);
+    }
+    open_options
+        .open(path)
+        .map_err(|e| io_error("create", path, 0, e))
+}
+
+/// The first directory on the way from `dest` to `relative` in it that is
+/// a symbolic link.
+fn link_ancestor(dest: &Path, relative: &Path) -> Option<PathBuf> {
+    let mut ancestor = dest.to_path_buf();
+    for component in relative.parent().into_iter().flat_map(Path::components) {
+        ancestor.push(component);
+        if is_link(&ancestor) {
+            return Some(ancestor);
+        }
+    }
+    None
+}
+
+fn is_link(path: &Path) -> bool {
+    path.symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+fn mtime_of(mtime: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(mtime)
+}
+
+/// Give the extracted `file` its `mode` and `mtime`.
+fn restore_file(file: &File, mode: u32, mtime: u64) -> io::Result<()> {
+    file.set_modified(mtime_of(mtime))?;
+    file.set_permissions(permissions(mode, file.metadata()?.permissions()))
+}
+
+#[cfg(unix)]
+fn restore_dir(path: &Path, mode: u32, mtime: u64) -> io::Result<()> {
+    File::open(path)?.set_modified(mtime_of(mtime))?;
+    fs::set_permissions(path, permissions(mode, fs::metadata(path)?.permissions()))
+}
+
+/// Directories can't be opened to set their times on Windows.
+#[cfg(not(unix))]
+fn restore_dir(_path: &Path, _mode: u32, _mtime: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn permissions(mode: u32, _permissions: fs::Permissions) -> fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+    fs::Permissions::from_mode(mode & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn permissions(mode: u32, mut permissions: fs::Permissions) -> fs::Permissions {
+    permissions.set_readonly(mode & 0o200 == 0);
+    permissions
+}
+
+/// The reading side of an extraction.
+struct Extract<'a> {
+    dest: &'a Path,
+    options: &'a CopyOptions,
+    buffer_size: usize,
+    free: &'a Queue<PooledBuffer>,
+    jobs: &'a Queue<Job>,
+    copied: &'a AtomicU64,
+    /// Directories extracted, with their mode and time to set at the end.
+    dirs: Vec<(PathBuf, u32, u64)>,
+    /// Directories known to exist.
+    known: HashSet<PathBuf>,
+    /// Hard links to make at the end, once the files they link to are, by
+    /// the target relative to `dest` and the path of the link.
+    hard_links: Vec<(PathBuf, PathBuf)>,
+    /// Whether symbolic links have been extracted, which later entries
+    /// must not be extracted through.
+    links: bool,
+}
+
+impl Extract<'_> {
+    fn run(
+        &mut self,
+        reader: &mut dyn Read,
+        failures: &Mutex<Vec<CopyFailure>>,
+        failure: &Mutex<Option<Error>>,
+    ) -> Result<(), Error> {
+        let (mut long_name, mut long_link) = (None, None);
+        let (mut pax_name, mut pax_link, mut pax_size) = (None, None, None);
+        let mut block = [0; BLOCK];
+        loop {
+            if self.options.is_cancelled() || failure.lock().unwrap().is_some() {
+                return Ok(());
+            }
+            if !read_block(reader, &mut block)? {
+                return Ok(());
+            }
+            if block == ZEROS {
+                return Ok(());
+            }
+            let mut header = Header::parse(&block)?;
+            match header.kind {
+                b'x' => {
+                    let data = read_data(reader, header.size)?;
+                    for (key, value) in pax_records(&data) {
+                        match key {
+                            b"path" => pax_name = Some(value.to_vec()),
+                            b"linkpath" => pax_link = Some(value.to_vec()),
+                            b"size" => {
+                                pax_size = String::from_utf8_lossy(value).parse::<u64>().ok()
+                            }
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                b'L' => {
+                    long_name = Some(field(&read_data(reader, header.size)?).to_vec());
+                    continue;
+                }
+                b'K' => {
+                    long_link = Some(field(&read_data(reader, header.size)?).to_vec());
+                    continue;
+                }
+                b'g' => {
+                    skip_data(reader, header.size)?;
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(name) = pax_name.take().or(long_name.take()) {
+                header.name = name;
+            }
+            if let Some(link) = pax_link.take().or(long_link.take()) {
+                header.link = link;
+            }
+            if let Some(size) = pax_size.take() {
+                header.size = size;
+            }
+            let name = String::from_utf8_lossy(&header.name).into_owned();
+            let path = match self.destination(&header.name) {
+                Ok(Some(path)) => path,
+                Ok(None) => {
+                    skip_data(reader, header.size)?;
+                    continue;
+                }
+                Err(e) => {
+                    skip_data(reader, header.size)?;
+                    extract_failed(Path::new(&name), e, failures, failure, self.options);
+                    continue;
+                }
+            };
+            // Errors from the archive itself end the extraction, those of
+            // the entry only skip it.
+            let result = match header.kind {
+                b'0' | 0 | b'7' => self.file(reader, &header, &path)?,
+                b'5' => self.dir(&header, &path),
+                b'2' => self.symlink(&header, &path),
+                b'1' => self.hard_link(&header, &path),
+                kind => {
+                    skip_data(reader, header.size)?;
+                    if self.options.progress {
+                        warn_skipped(
+                            &path,
+                            &format!("entries of type '{}' are not extracted", kind as char),
+                        );
+                    }
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                extract_failed(&path, e, failures, failure, self.options);
+            }
+        }
+    }
+
+    /// Where the entry `name` goes, or `None` for the top of the archive.
+    fn destination(&mut self, name: &[u8]) -> Result<Option<PathBuf>, Error> {
+        let relative = safe_path(name).ok_or_else(|| {
+            format!(
+                "Refusing to extract '{}', it would go outside '{}'",
+                String::from_utf8_lossy(name),
+                self.dest.display()
+            )
+        })?;
+        if relative.as_os_str().is_empty() {
+            return Ok(None);
+        }
+        if let Some(link) = self
+            .links
+            .then(|| link_ancestor(self.dest, &relative))
+            .flatten()
+        {
+            return Err(format!(
+                "Refusing to extract '{}' through the link '{}'",
+                relative.display(),
+                link.display()
+            )
+            .into());
+        }
+        Ok(Some(self.dest.join(relative)))
+    }
+
+    /// Create the directory `path` is in, unless it is known to exist.
+    fn make_parent(&mut self, path: &Path) -> Result<(), Error> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        if !self.known.contains(parent) {
+            create_dir_all(parent).map_err(|e| io_error("create", parent, 0, e))?;
+            self.known.insert(parent.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is to be left alone under [`CopyOptions::overwrite`].
+    fn keep(&self, path: &Path, header: &Header) -> bool {
+        let Ok(existing) = path.symlink_metadata() else {
+            return false;
+        };
+        let reason = match self.options.overwrite {
+            Overwrite::Never => "it already exists",
+            Overwrite::Update
+                if existing
+                    .modified()
+                    .is_ok_and(|modified| modified >= mtime_of(header.mtime)) =>
+            {
+                "it is not older than the archived file"
+            }
+            _ => return false,
+        };
+        skip_existing(path, reason, self.options);
+        true
+    }
+
+    /// Extract a file, returning the error of creating it, if any. Errors
+    /// reading the archive are returned as such.
+    fn file(
+        &mut self,
+        reader: &mut dyn Read,
+        header: &Header,
+        path: &Path,
+    ) -> Result<Result<(), Error>, Error> {
+        if self.keep(path, header) {
+            skip_data(reader, header.size)?;
+            return Ok(Ok(()));
+        }
+        if let Err(e) = self.make_parent(path) {
+            skip_data(reader, header.size)?;
+            return Ok(Err(e));
+        }
+        if self.options.verbosity > 1 {
+            eprintln!(" Extract '{}'", path.display());
+        }
+        let Some(mut buffer) = self.free.pop() else {
+            return Ok(Ok(()));
+        };
+        if header.size <= self.buffer_size as u64 {
+            let len = header.size as usize;
+            read_archive(reader, &mut buffer[..len])?;
+            skip(reader, padding(header.size) as u64)?;
+            self.jobs.push(Job {
+                path: path.to_path_buf(),
+                len,
+                buffer,
+                mode: header.mode,
+                mtime: header.mtime,
+            });
+            return Ok(Ok(()));
+        }
+        // Larger files are written as they are read, which keeps the
+        // archive moving without holding them whole.
+        let mut file = create_file(path);
+        let mut left = header.size;
+        while left > 0 {
+            let len = left.min(buffer.len() as u64) as usize;
+            read_archive(reader, &mut buffer[..len])?;
+            if let Ok(out) = &mut file {
+                if let Some(bwlimit) = self.options.bwlimit.as_ref() {
+                    bwlimit.take(len);
+                }
+                let offset = header.size - left;
+                if let Err(e) = out.write_all(&buffer[..len]) {
+                    file = Err(io_error("write", path, offset, e));
+                }
+            }
+            self.copied.fetch_add(len as u64, Ordering::SeqCst);
+            left -= len as u64;
+        }
+        self.free.push(buffer);
+        skip(reader, padding(header.size) as u64)?;
+        Ok(file.and_then(|file| {
+            restore_file(&file, header.mode, header.mtime)
+                .map_err(|e| io_error("set the mode of", path, 0, e))?;
+            if let Some(stats) = &self.options.stats {
+                stats.copied(header.size);
+            }
+            Ok(())
+        }))
+    }
+
+    fn dir(&mut self, header: &Header, path: &Path) -> Result<(), Error> {
+        if !path.is_dir() {
+            create_dir_all(path).map_err(|e| io_error("create", path, 0, e))?;
+            if let Some(stats) = &self.options.stats {
+                stats.dir_created();
+            }
+        }
+        self.known.insert(path.to_path_buf());
+        self.dirs
+            .push((path.to_path_buf(), header.mode, header.mtime));
+        Ok(())
+    }
+
+    fn symlink(&mut self, header: &Header, path: &Path) -> Result<(), Error> {
+        if self.keep(path, header) {
+            return Ok(());
+        }
+        self.make_parent(path)?;
+        if path
+            .symlink_metadata()
+            .is_ok_and(|metadata| !metadata.is_dir())
+        {
+            fs::remove_file(path).map_err(|e| io_error("remove", path, 0, e))?;
+        }
+        let target = os_string(&header.link);
+        make_symlink(&target, path).map_err(|e| {
+            format!(
+                "Failed to link '{}' -> '{}': {}",
+                path.display(),
+                target.display(),
+                e
+            )
+        })?;
+        self.links = true;
+        Ok(())
+    }
+
+    fn hard_link(&mut self, header: &Header, path: &Path) -> Result<(), Error> {
+        let target = match safe_path(&header.link) {
+            Some(target) if !target.as_os_str().is_empty() => target,
+            _ => {
+                return Err(format!(
+                    "Refusing to link to '{}', it is outside '{}'",
+                    String::from_utf8_lossy(&header.link),
+                    self.dest.display()
+                )
+                .into())
+            }
+        };
+        if let Some(link) = self
+            .links
+            .then(|| link_ancestor(self.dest, &target))
+            .flatten()
+        {
+            return Err(format!(
+                "Refusing to link to '{}' through the link '{}'",
+                target.display(),
+                link.display()
+            )
+            .into());
+        }
+        self.make_parent(path)?;
+        self.hard_links.push((target, path.to_path_buf()));
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn make_symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
+
+/// The name of an archive entry as a path relative to where it is
+/// extracted, with any `/` it starts with taken off, or `None` when it
+/// would go outside.
+fn safe_path(name: &[u8]) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for part in name.split(|&byte| byte == b'/') {
+        match part {
+            b"" | b"." => {}
+            b".." => return None,
+            part => path.push(os_string(part)),
+        }
+    }
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then_some(path)
+}
+
+/// Read a whole block, returning `false` when the archive ends before it.
+fn read_block(reader: &mut dyn Read, block: &mut [u8; BLOCK]) -> Result<bool, Error> {
+    let mut filled = 0;
+    while filled < BLOCK {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err("The archive ends in the middle of a header".into()),
+            Ok(bytes_read) => filled += bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(format!("Failed to read the archive: {}", e).into()),
+        }
+    }
+    Ok(true)
+}
+
+fn read_archive(reader: &mut dyn Read, buffer: &mut [u8]) -> Result<(), Error> {
+    reader.read_exact(buffer).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            "The archive ends in the middle of a file".into()
+        } else {
+            format!("Failed to read the archive: {}", e).into()
+        }
+    })
+}
+
+/// The `size` bytes of data after a header, without their padding.
+fn read_data(reader: &mut dyn Read, size: u64) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0; size as usize];
+    read_archive(reader, &mut data)?;
+    skip(reader, padding(size) as u64)?;
+    Ok(data)
+}
+
+/// Skip `size` bytes of data and the padding after them.
+fn skip_data(reader: &mut dyn Read, size: u64) -> Result<(), Error> {
+    skip(reader, size.next_multiple_of(BLOCK as u64))
+}
+
+fn skip(reader: &mut dyn Read, size: u64) -> Result<(), Error> {
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())
+        .map_err(|e| format!("Failed to read the archive: {}", e))?;
+    if skipped < size {
+        return Err("The archive ends in the middle of a file".into());
+    }
+    Ok(())
+}
+
+/// The keys and values of the records of a PAX header.
+fn pax_records(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut records = Vec::new();
+    while let Some(space) = data.iter().position(|&byte| byte == b' ') {
+        let Some(len) = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space + 1 && len <= data.len())
+        else {
+            break;
+        };
+        let record = &data[space + 1..len - 1];
+        if let Some(equals) = record.iter().position(|&byte| byte == b'=') {
+            records.push((&record[..equals], &record[equals + 1..]));
+        }
+        data = &data[len..];
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    /// A ustar entry of `kind` for `name`, with `data` after it.
+    fn entry(name: &str, kind: u8, link: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = [0; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        number(&mut header[100..108], 0o644);
+        number(&mut header[124..136], data.len() as u64);
+        number(&mut header[136..148], 0);
+        header[156] = kind;
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        checksum(&mut header);
+        let mut blocks = header.to_vec();
+        blocks.extend_from_slice(data);
+        blocks.extend_from_slice(&ZEROS[..padding(data.len() as u64)]);
+        blocks
+    }
+
+    fn archive(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = entries.concat();
+        archive.extend_from_slice(&[0; 2 * BLOCK]);
+        archive
+    }
+
+    #[test]
+    fn safe_path_refuses_parents() {
+        assert_eq!(safe_path(b"/a/./b/"), Some(PathBuf::from("a/b")));
+        assert_eq!(safe_path(b"./"), Some(PathBuf::new()));
+        assert_eq!(safe_path(b"a/../../b"), None);
+        assert_eq!(safe_path(b".."), None);
+    }
+
+    #[test]
+    fn numbers_in_octal_and_base_256() {
+        assert_eq!(parse_number(b"0000644\0"), Some(0o644));
+        assert_eq!(parse_number(b"  755 \0"), Some(0o755));
+        assert_eq!(parse_number(b"\0\0\0"), Some(0));
+        assert_eq!(parse_number(b"0009\0"), None);
+        let mut big = [0; 12];
+        big[0] = 0x80;
+        big[7..].copy_from_slice(&[0x02, 0, 0, 0, 0]);
+        assert_eq!(parse_number(&big), Some(8 << 30));
+        let mut field = [0; 12];
+        assert!(number(&mut field, (8 << 30) - 1));
+        assert!(!number(&mut field, 8 << 30));
+    }
+
+    #[test]
+    fn pax_records_round_trip() {
+        let mut pax = Vec::new();
+        pax_record(&mut pax, "path", &[b'a'; 95]);
+        pax_record(&mut pax, "size", b"9");
+        assert_eq!(
+            pax_records(&pax),
+            [(&b"path"[..], &[b'a'; 95][..]), (&b"size"[..], &b"9"[..])]
+        );
+        assert!(pax_records(b"99 path=cut short\n").is_empty());
+    }
+
+    #[test]
+    fn header_checksum_is_checked() {
+        let mut block: [u8; BLOCK] = entry("file", b'0', "", b"")[..BLOCK].try_into().unwrap();
+        let header = Header::parse(&block).unwrap();
+        assert_eq!(header.name, b"file");
+        assert_eq!(header.mode, 0o644);
+        block[0] = b'g';
+        assert!(Header::parse(&block).is_err());
+    }
+
+    #[test]
+    fn archives_what_it_extracts() {
+        let dir = scratch("archive-round-trip");
+        fs::create_dir_all(dir.join("src/sub")).unwrap();
+        fs::write(dir.join("src/small"), b"small").unwrap();
+        let large: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        fs::write(dir.join("src/sub/large"), &large).unwrap();
+        let long = "n".repeat(150);
+        fs::write(dir.join("src/sub").join(&long), b"").unwrap();
+        let options = CopyOptions::new().threads(2).buffer_size(64 * 1024);
+        let mut tar = Vec::new();
+        write_archive(&[dir.join("src")], &mut tar, &options).unwrap();
+        extract_archive(&tar[..], dir.join("out"), &options).unwrap();
+        assert_eq!(fs::read(dir.join("out/src/small")).unwrap(), b"small");
+        assert_eq!(fs::read(dir.join("out/src/sub/large")).unwrap(), large);
+        assert!(dir.join("out/src/sub").join(&long).is_file());
+    }
+
+    #[test]
+    fn refuses_entries_outside() {
+        let dir = scratch("archive-outside");
+        let tar = archive(&[entry("../escaped", b'0', "", b"data")]);
+        assert!(extract_archive(&tar[..], dir.join("out"), &CopyOptions::new()).is_err());
+        assert!(!dir.join("escaped").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn queued_file_is_not_written_through_a_later_link() {
+        let dir = scratch("archive-queued-link");
+        fs::write(dir.join("victim"), b"original").unwrap();
+        // Enough files ahead of it that the one writer is still busy when
+        // the link is made.
+        let mut entries: Vec<_> = (0..400)
+            .map(|i| entry(&format!("pad{}", i), b'0', "", b"padding"))
+            .collect();
+        entries.push(entry("x", b'0', "", b"archived"));
+        entries.push(entry("x", b'2', dir.join("victim").to_str().unwrap(), b""));
+        let tar = archive(&entries);
+        let _ = extract_archive(&tar[..], dir.join("out"), &CopyOptions::new().threads(1));
+        assert_eq!(fs::read(dir.join("victim")).unwrap(), b"original");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_are_not_made_through_links() {
+        let dir = scratch("archive-hard-link");
+        fs::write(dir.join("victim"), b"original").unwrap();
+        let outside = dir.to_str().unwrap();
+        for (name, entries) in [
+            (
+                "link-first",
+                [
+                    entry("l", b'2', outside, b""),
+                    entry("h", b'1', "l/victim", b""),
+                ],
+            ),
+            (
+                "link-after",
+                [
+                    entry("h", b'1', "l/victim", b""),
+                    entry("l", b'2', outside, b""),
+                ],
+            ),
+        ] {
+            let out = dir.join(name);
+            assert!(extract_archive(&archive(&entries)[..], &out, &CopyOptions::new()).is_err());
+            assert!(!out.join("h").exists());
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use crate::device::device_size;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Storage that data can be copied from and to in chunks, such as a local
+/// file or a block device.
+///
+/// Reads and writes are made at a given offset, which lets every copy
+/// thread share one backend without a common position getting in the way.
+/// [`copy_backend`](crate::copy_backend) copies between any two backends
+/// with the thread pool [`copy_file`](crate::copy_file) uses.
+pub trait StorageBackend: Send + Sync {
+    /// Read into `buffer` from `offset`, returning how many bytes were
+    /// read. Reads come up short only at the end of the data.
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Write `data` at `offset`, returning how many bytes were written.
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<usize>;
+
+    /// The size of the data.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Whether there is no data at all.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Make the storage `len` bytes long before a copy writes to it, or
+    /// trim it back after one. Storage of a fixed size can leave this be.
+    fn allocate(&self, len: u64) -> io::Result<()>;
+
+    /// The local file behind this backend, which opens up the kernel copy
+    /// offloads, page cache hints and the io_uring engine.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+
+    /// Write all of `data` at `offset`, carrying on after short writes. On
+    /// failure returns the offset that could not be written.
+    fn write_all_at(&self, mut data: &[u8], mut offset: u64) -> Result<(), (u64, io::Error)> {
+        while !data.is_empty() {
+            match self.write_at(data, offset) {
+                Ok(0) => return Err((offset, io::ErrorKind::WriteZero.into())),
+                Ok(written) => {
+                    data = &data[written..];
+                    offset += written as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err((offset, e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for File {
+    #[cfg(unix)]
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buffer, offset)
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(self, data, offset)
+    }
+
+    // These move the file position as well, which nothing here relies on.
+    #[cfg(windows)]
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buffer, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_write(self, data, offset)
+    }
+
+    /// Block devices have no length in their metadata, it is asked of the
+    /// device instead.
+    fn len(&self) -> io::Result<u64> {
+        let metadata = self.metadata()?;
+        if metadata.is_file() {
+            return Ok(metadata.len());
+        }
+        device_size(self)
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        if self.metadata()?.is_file() {
+            self.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// The `len` bytes of a file or device from `start`, as storage of its own,
+/// for copies from or to an offset in it. Reads stop at the end of the
+/// region, though they fill the whole buffer from the file when they can,
+/// so direct reads keep their aligned length.
+pub(crate) struct Region {
+    file: File,
+    start: u64,
+    len: u64,
+}
+
+impl Region {
+    pub(crate) fn new(file: File, start: u64, len: u64) -> Self {
+        Region { file, start, len }
+    }
+}
+
+impl StorageBackend for Region {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let left = self.len.saturating_sub(offset);
+        if left == 0 {
+            return Ok(0);
+        }
+        let read = self.file.read_at(buffer, self.start + offset)?;
+        Ok(read.min(left as usize))
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<usize> {
+        self.file.write_at(data, self.start + offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    /// Files end with the region, whatever is before it is kept.
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        self.file.allocate(self.start + len)
+    }
+
+    // The file is not handed out, as its offsets are not the region's.
+}
+
+/// Several files written as one, each write going to all of them at once
+/// with a thread for every file but the last. Reads come from the first.
+pub(crate) struct Fanout {
+    pub(crate) files: Vec<(PathBuf, File)>,
+}
+
+impl Fanout {
+    /// `error` from writing the file at `path`, naming it.
+    fn failed(path: &Path, error: io::Error) -> io::Error {
+        io::Error::new(error.kind(), format!("'{}': {}", path.display(), error))
+    }
+}
+
+impl StorageBackend for Fanout {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.files[0].1.read_at(buffer, offset)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<usize> {
+        let (last, others) = self.files.split_last().unwrap();
+        thread::scope(|scope| {
+            let writes: Vec<_> = others
+                .iter()
+                .map(|(path, file)| {
+                    scope.spawn(move || {
+                        file.write_all_at(data, offset)
+                            .map_err(|(_, e)| Self::failed(path, e))
+                    })
+                })
+                .collect();
+            let written = last
+                .1
+                .write_all_at(data, offset)
+                .map_err(|(_, e)| Self::failed(&last.0, e));
+            for write in writes {
+                write.join().unwrap()?;
+            }
+            written.map(|_| data.len())
+        })
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.files[0].1.len()
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        for (path, file) in &self.files {
+            file.allocate(len).map_err(|e| Self::failed(path, e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use crate::options::CopyOptions;
+use crate::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where `dest` is moved before it is replaced or deleted, with
+/// [`CopyOptions::backup`] or [`CopyOptions::backup_dir`].
+pub(crate) fn backup_path(dest: &Path, options: &CopyOptions) -> Option<PathBuf> {
+    let suffix = match (&options.backup, &options.backup_dir) {
+        (None, None) => return None,
+        (Some(suffix), _) => suffix.as_str(),
+        (None, Some(_)) => "",
+    };
+    let mut path = match &options.backup_dir {
+        Some(dir) => {
+            // Destinations keep their place in the tree being copied to.
+            let relative = options
+                .backup_root
+                .as_ref()
+                .and_then(|root| dest.strip_prefix(root).ok())
+                .filter(|relative| !relative.as_os_str().is_empty())
+                .or_else(|| dest.file_name().map(Path::new))?;
+            dir.join(relative).into_os_string()
+        }
+        None => dest.as_os_str().to_owned(),
+    };
+    path.push(suffix);
+    Some(PathBuf::from(path))
+}
+
+/// Whether `path` is a backup made by an earlier copy, which is not to be
+/// deleted as extraneous.
+pub(crate) fn is_backup(path: &Path, options: &CopyOptions) -> bool {
+    match (&options.backup_dir, &options.backup) {
+        (Some(dir), _) => path.starts_with(dir),
+        (None, Some(suffix)) if !suffix.is_empty() => path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().ends_with(suffix.as_str())),
+        _ => false,
+    }
+}
+
+/// Move `dest` to `backup`, replacing an older backup there. With `keep`
+/// the backup is a hard link to `dest`, or a copy of it, and `dest` stays
+/// in place for an atomic copy to replace.
+pub(crate) fn back_up(dest: &Path, backup: &Path, keep: bool) -> Result<(), Error> {
+    let failed = |e: io::Error| {
+        format!(
+            "Failed to back up '{}' to '{}': {}",
+            dest.display(),
+            backup.display(),
+            e
+        )
+    };
+    if let Some(parent) = backup.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(failed)?;
+    }
+    if keep {
+        if backup.symlink_metadata().is_ok() {
+            fs::remove_file(backup).map_err(failed)?;
+        }
+        return match fs::hard_link(dest, backup) {
+            Ok(()) => Ok(()),
+            Err(_) => fs::copy(dest, backup)
+                .map(|_| ())
+                .map_err(|e| failed(e).into()),
+        };
+    }
+    match fs::rename(dest, backup) {
+        Ok(()) => Ok(()),
+        // The backup directory can be on another filesystem.
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => fs::copy(dest, backup)
+            .and_then(|_| fs::remove_file(dest))
+            .map_err(|e| failed(e).into()),
+        Err(e) => Err(failed(e).into()),
+    }
+}
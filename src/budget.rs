@@ -0,0 +1,36 @@
+use std::sync::{Condvar, Mutex};
+
+/// A fixed number of threads shared between everything copying at once.
+///
+/// Each file being copied takes some of the budget for its copy threads and
+/// gives it back when it is done, so copying several files at the same time
+/// never runs more threads in total than the user asked for.
+pub(crate) struct ThreadBudget {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ThreadBudget {
+    pub(crate) fn new(threads: usize) -> Self {
+        ThreadBudget {
+            available: Mutex::new(threads),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until at least one thread is free and take up to `wanted`.
+    pub(crate) fn acquire(&self, wanted: usize) -> usize {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        let taken = wanted.clamp(1, *available);
+        *available -= taken;
+        taken
+    }
+
+    pub(crate) fn release(&self, threads: usize) {
+        *self.available.lock().unwrap() += threads;
+        self.released.notify_all();
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::ops::Range;
+
+// These are only hints, so the kernel is free to ignore them and failures
+// are ignored too. Other platforms than Linux go without.
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+    use nix::libc;
+    use std::fs::File;
+    use std::ops::Range;
+    use std::os::unix::io::AsRawFd;
+
+    pub(super) fn advise_sequential(file: &File) {
+        let advice = PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL;
+        let _ = posix_fadvise(file.as_raw_fd(), 0, 0, advice);
+    }
+
+    pub(super) fn will_need(file: &File, range: &Range<u64>) {
+        advise(file, range, PosixFadviseAdvice::POSIX_FADV_WILLNEED);
+    }
+
+    pub(super) fn drop_cached(infile: &File, outfile: &File, range: &Range<u64>) {
+        advise(infile, range, PosixFadviseAdvice::POSIX_FADV_DONTNEED);
+        finish_writeback(outfile, range);
+    }
+
+    pub(super) fn start_writeback(outfile: &File, range: &Range<u64>) {
+        sync_range(outfile, range, libc::SYNC_FILE_RANGE_WRITE);
+    }
+
+    pub(super) fn finish_writeback(outfile: &File, range: &Range<u64>) {
+        let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+            | libc::SYNC_FILE_RANGE_WRITE
+            | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+        sync_range(outfile, range, flags);
+        advise(outfile, range, PosixFadviseAdvice::POSIX_FADV_DONTNEED);
+    }
+
+    fn sync_range(file: &File, range: &Range<u64>, flags: libc::c_uint) {
+        let len = (range.end - range.start) as i64;
+        unsafe { libc::sync_file_range(file.as_raw_fd(), range.start as i64, len, flags) };
+    }
+
+    fn advise(file: &File, range: &Range<u64>, advice: PosixFadviseAdvice) {
+        let len = (range.end - range.start) as i64;
+        let _ = posix_fadvise(file.as_raw_fd(), range.start as i64, len, advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::fs::File;
+    use std::ops::Range;
+
+    pub(super) fn advise_sequential(_file: &File) {}
+
+    pub(super) fn will_need(_file: &File, _range: &Range<u64>) {}
+
+    pub(super) fn drop_cached(_infile: &File, _outfile: &File, _range: &Range<u64>) {}
+
+    pub(super) fn start_writeback(_outfile: &File, _range: &Range<u64>) {}
+
+    pub(super) fn finish_writeback(_outfile: &File, _range: &Range<u64>) {}
+}
+
+/// Tell the kernel `file` is read front to back, so it reads further ahead.
+pub(crate) fn advise_sequential(file: &File) {
+    imp::advise_sequential(file)
+}
+
+/// Ask for `range` of `file` to be read in ahead of the thread getting to it.
+pub(crate) fn will_need(file: &File, range: &Range<u64>) {
+    imp::will_need(file, range)
+}
+
+/// Drop `range` of both files from the page cache once it has been copied.
+/// The destination's pages are written out first, as dirty pages would
+/// stay cached.
+pub(crate) fn drop_cached(infile: &File, outfile: &File, range: &Range<u64>) {
+    imp::drop_cached(infile, outfile, range)
+}
+
+/// Start writing out `range` of the destination without waiting for it.
+pub(crate) fn start_writeback(outfile: &File, range: &Range<u64>) {
+    imp::start_writeback(outfile, range)
+}
+
+/// Wait for `range` of the destination to be written out, then drop it from
+/// the page cache.
+pub(crate) fn finish_writeback(outfile: &File, range: &Range<u64>) {
+    imp::finish_writeback(outfile, range)
+}
@@ -0,0 +1,22 @@
+use crate::Error;
+use std::fmt;
+
+/// Returned when a copy is stopped with [`CopyOptions::cancel`](crate::CopyOptions::cancel)
+/// before it finished. The message says what was copied and what became of
+/// the partial destination.
+#[derive(Debug)]
+pub struct CancelledError {
+    message: String,
+}
+
+impl fmt::Display for CancelledError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CancelledError {}
+
+pub(crate) fn cancelled(message: String) -> Error {
+    Box::new(CancelledError { message })
+}
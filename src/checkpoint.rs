@@ -0,0 +1,239 @@
+use crate::backend::StorageBackend;
+use crate::hash::HashAlgorithm;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+const HEADER: &str = "rpcp-checkpoint 1";
+
+/// Sidecar file next to a destination listing the byte ranges that have
+/// been copied, so an interrupted copy can carry on where it stopped.
+///
+/// One line is appended per completed chunk: `start end xxh3`. The digest
+/// lets a later run check the range really made it to the destination.
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Checkpoint {
+    fn path_for(dest: &Path) -> PathBuf {
+        let mut name = dest.as_os_str().to_owned();
+        name.push(".rpcp-checkpoint");
+        PathBuf::from(name)
+    }
+
+    /// Whether `dest` has a checkpoint, left by a copy to it that can be
+    /// resumed.
+    pub(crate) fn exists_for(dest: &Path) -> bool {
+        Self::path_for(dest).exists()
+    }
+
+    fn load_records(dest: &Path, source: &fs::Metadata) -> io::Result<Vec<(Range<u64>, String)>> {
+        let file = match File::open(Self::path_for(dest)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let outfile = match File::open(dest) {
+            Ok(outfile) => outfile,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER)
+            || lines.next().transpose()? != Some(source_line(source))
+        {
+            // Written by another version or for a different source.
+            return Ok(Vec::new());
+        }
+
+        let mut records = Vec::new();
+        for line in lines {
+            // A torn last line from a killed run is simply ignored.
+            let line = line?;
+            let mut fields = line.split(' ');
+            let (Some(start), Some(end), Some(digest)) = (
+                fields.next().and_then(|f| f.parse().ok()),
+                fields.next().and_then(|f| f.parse().ok()),
+                fields.next(),
+            ) else {
+                continue;
+            };
+            let range: Range<u64> = start..end;
+            if range.end <= source.len() && range_digest(&outfile, &range)? == digest {
+                records.push((range, digest.to_string()));
+            }
+        }
+        Ok(records)
+    }
+
+    /// Start a checkpoint for `dest`, keeping the records of an earlier run
+    /// for the same source that still match their digests. Also returns the
+    /// ranges covered by those records, which do not need copying again.
+    pub(crate) fn create(
+        dest: &Path,
+        source: &fs::Metadata,
+    ) -> io::Result<(Checkpoint, Vec<Range<u64>>)> {
+        let records = Self::load_records(dest, source)?;
+        let done = records.iter().map(|(range, _)| range.clone()).collect();
+        let path = Self::path_for(dest);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "{}", source_line(source))?;
+        for (range, digest) in records {
+            writeln!(file, "{} {} {}", range.start, range.end, digest)?;
+        }
+        let checkpoint = Checkpoint {
+            path,
+            file: Mutex::new(file),
+        };
+        Ok((checkpoint, done))
+    }
+
+    /// Record that `range` has been written with the given xxh3 digest.
+    pub(crate) fn record(&self, range: &Range<u64>, digest: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{} {} {}", range.start, range.end, digest)
+    }
+
+    /// Remove the checkpoint once the copy is complete.
+    pub(crate) fn remove(self) -> io::Result<()> {
+        fs::remove_file(self.path)
+    }
+}
+
+/// Identify the source by size and modification time, so a checkpoint is
+/// not applied to a source that changed since.
+fn source_line(source: &fs::Metadata) -> String {
+    let mtime = source
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("source {} {}", source.len(), mtime)
+}
+
+fn range_digest(file: &File, range: &Range<u64>) -> io::Result<String> {
+    let mut hasher = HashAlgorithm::Xxh3.hasher();
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut pos = range.start;
+    while pos < range.end {
+        let want = buffer.len().min((range.end - pos) as usize);
+        let bytes_read = file.read_at(&mut buffer[..want], pos)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        pos += bytes_read as u64;
+    }
+    Ok(hasher.finish())
+}
+
+/// The parts of `range` not covered by any of the `done` ranges.
+pub(crate) fn missing_ranges(range: &Range<u64>, done: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut done: Vec<_> = done
+        .iter()
+        .filter(|r| r.start < range.end && r.end > range.start)
+        .collect();
+    done.sort_by_key(|r| r.start);
+
+    let mut missing = Vec::new();
+    let mut pos = range.start;
+    for done_range in done {
+        if done_range.start > pos {
+            missing.push(pos..done_range.start);
+        }
+        pos = pos.max(done_range.end);
+    }
+    if pos < range.end {
+        missing.push(pos..range.end);
+    }
+    missing
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    #[test]
+    fn finds_missing_ranges() {
+        assert_eq!(missing_ranges(&(0..100), &[]), [0..100]);
+        assert_eq!(
+            missing_ranges(&(0..100), &[60..80, 10..20, 15..30]),
+            [0..10, 30..60, 80..100]
+        );
+        assert_eq!(missing_ranges(&(40..60), &[0..50, 55..200]), [50..55]);
+        assert!(missing_ranges(&(40..60), &[0..100]).is_empty());
+        assert_eq!(missing_ranges(&(40..60), &[0..40, 60..80]), [40..60]);
+    }
+
+    #[test]
+    fn resumes_ranges_that_made_it() {
+        let dir = scratch("checkpoint");
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 199) as u8).collect();
+        fs::write(&src, &data).unwrap();
+        fs::write(&dest, &data[..2000]).unwrap();
+        let source = fs::metadata(&src).unwrap();
+        let digest = |range: Range<u64>| {
+            let mut hasher = HashAlgorithm::Xxh3.hasher();
+            hasher.update(&data[range.start as usize..range.end as usize]);
+            hasher.finish()
+        };
+
+        let (checkpoint, done) = Checkpoint::create(&dest, &source).unwrap();
+        assert!(done.is_empty());
+        assert!(Checkpoint::exists_for(&dest));
+        checkpoint.record(&(0..1000), &digest(0..1000)).unwrap();
+        checkpoint
+            .record(&(1000..2000), "0123456789abcdef")
+            .unwrap();
+        checkpoint
+            .record(&(2000..3000), &digest(2000..3000))
+            .unwrap();
+        drop(checkpoint);
+        let path = dir.join("dest.rpcp-checkpoint");
+        let mut text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with(&format!("{}\n{}\n0 1000 ", HEADER, source_line(&source))));
+        // As a run killed while writing a line leaves it.
+        text.push_str("3000 40");
+        fs::write(&path, text).unwrap();
+
+        // Only the range whose digest matches what is in dest is kept: the
+        // second was recorded wrong and the third never got written.
+        let (checkpoint, done) = Checkpoint::create(&dest, &source).unwrap();
+        assert_eq!(done, [0..1000]);
+        checkpoint.remove().unwrap();
+        assert!(!Checkpoint::exists_for(&dest));
+    }
+
+    #[test]
+    fn forgets_checkpoints_of_other_sources() {
+        let dir = scratch("checkpoint-source");
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        fs::write(&src, [1; 100]).unwrap();
+        fs::write(&dest, [1; 100]).unwrap();
+        let source = fs::metadata(&src).unwrap();
+        let mut hasher = HashAlgorithm::Xxh3.hasher();
+        hasher.update(&[1; 100]);
+        let (checkpoint, _) = Checkpoint::create(&dest, &source).unwrap();
+        checkpoint.record(&(0..100), &hasher.finish()).unwrap();
+        drop(checkpoint);
+        assert_eq!(Checkpoint::create(&dest, &source).unwrap().1, [0..100]);
+
+        fs::write(&src, [1; 101]).unwrap();
+        let changed = fs::metadata(&src).unwrap();
+        assert!(Checkpoint::create(&dest, &changed).unwrap().1.is_empty());
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Bool(bool),
+    /// A number, kept as written.
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) | Value::String(n) => f.write_str(n),
+            Value::Array(values) => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+        }
+    }
+}
+
+/// The options set by a config file, `~/.config/rpcp/config.toml` unless
+/// another is given, in the order they are written:
+///
+/// ```toml
+/// threads = 16
+/// exclude = ["*.tmp", ".cache/"]
+///
+/// [profile.nfs]
+/// threads = 64
+/// buffer-size = "64K"
+/// ```
+///
+/// Keys are the long names of the options of a copy. Only the part of TOML
+/// needed for that is read: strings, numbers, booleans and arrays of them,
+/// and tables named `profile.NAME`.
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    defaults: Vec<(String, Value)>,
+    profiles: HashMap<String, Vec<(String, Value)>>,
+}
+
+impl Config {
+    /// Read the config file at `path`.
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read config file '{}': {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}:{}", path.display(), e))
+    }
+
+    /// The options of `profile`, or the defaults without one, with the
+    /// values of the profile replacing the defaults they share a key with.
+    pub(crate) fn options(&self, profile: Option<&str>) -> Result<Vec<(String, Value)>, String> {
+        let mut options = self.defaults.clone();
+        let Some(name) = profile else {
+            return Ok(options);
+        };
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| format!("there is no profile '{}' in the config file", name))?;
+        for (key, value) in profile {
+            match options.iter_mut().find(|(k, _)| k == key) {
+                Some(option) => option.1 = value.clone(),
+                None => options.push((key.clone(), value.clone())),
+            }
+        }
+        Ok(options)
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+            line: 1,
+        };
+        parser
+            .document()
+            .map_err(|e| format!("{}: {}", parser.line, e))
+    }
+}
+
+/// Where the config file is looked for when none is given.
+pub(crate) fn default_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    };
+    dir.map(|dir| dir.join("rpcp").join("config.toml"))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// The line `pos` is on, for errors.
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    /// Skip spaces and tabs, and with `newlines` line ends and comments too.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                '\n' if newlines => {}
+                '#' if newlines => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.next();
+                    }
+                    continue;
+                }
+                _ => break,
+            }
+            self.next();
+        }
+    }
+
+    /// The end of a line, after an optional comment.
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_space(false);
+        match self.peek() {
+            None | Some('\n') | Some('#') => Ok(()),
+            Some(c) => Err(format!("unexpected '{}' after the value", c)),
+        }
+    }
+
+    fn document(&mut self) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut table: Option<String> = None;
+        loop {
+            self.skip_space(true);
+            let Some(c) = self.peek() else {
+                return Ok(config);
+            };
+            if c == '[' {
+                self.next();
+                let mut name = String::new();
+                while let Some(c) = self.next().filter(|&c| c != ']' && c != '\n') {
+                    name.push(c);
+                }
+                let name = name.trim();
+                let profile = name.strip_prefix("profile.").map(|p| p.trim_matches('"'));
+                match profile.filter(|p| !p.is_empty()) {
+                    Some(profile) => {
+                        config.profiles.entry(profile.to_string()).or_default();
+                        table = Some(profile.to_string());
+                    }
+                    None => {
+                        return Err(format!("unknown table '{}', expected [profile.NAME]", name))
+                    }
+                }
+                self.end_of_line()?;
+                continue;
+            }
+            let key = self.key()?;
+            self.skip_space(false);
+            if self.next() != Some('=') {
+                return Err(format!("expected '=' after '{}'", key));
+            }
+            self.skip_space(false);
+            let value = self.value()?;
+            self.end_of_line()?;
+            let options = match &table {
+                Some(profile) => config.profiles.get_mut(profile).unwrap(),
+                None => &mut config.defaults,
+            };
+            if options.iter().any(|(k, _)| *k == key) {
+                return Err(format!("'{}' is set twice", key));
+            }
+            options.push((key, value));
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        if let Some(quote @ ('"' | '\'')) = self.peek() {
+            self.next();
+            return self.string(quote);
+        }
+        let mut key = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        {
+            key.push(c);
+            self.next();
+        }
+        if key.is_empty() {
+            return Err("expected a key".to_string());
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(quote @ ('"' | '\'')) => {
+                self.next();
+                self.string(quote).map(Value::String)
+            }
+            Some('[') => {
+                self.next();
+                let mut values = Vec::new();
+                loop {
+                    self.skip_space(true);
+                    if self.peek() == Some(']') {
+                        self.next();
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.skip_space(true);
+                    match self.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Value::Array(values)),
+                        _ => return Err("expected ',' or ']' in the array".to_string()),
+                    }
+                }
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = self
+                    .peek()
+                    .filter(|c| !c.is_whitespace() && !matches!(c, ',' | ']' | '#'))
+                {
+                    word.push(c);
+                    self.next();
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    number
+                        if number
+                            .starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+') =>
+                    {
+                        Ok(Value::Number(number.replace('_', "")))
+                    }
+                    "" => Err("expected a value".to_string()),
+                    word => Err(format!("'{}' is not a value, strings need quotes", word)),
+                }
+            }
+        }
+    }
+
+    /// The rest of a string opened with `quote`. Escapes are only read in
+    /// basic strings, the ones in double quotes.
+    fn string(&mut self, quote: char) -> Result<String, String> {
+        let mut string = String::new();
+        loop {
+            let Some(c) = self.peek().filter(|&c| c != '\n') else {
+                return Err("the string is not closed".to_string());
+            };
+            self.next();
+            match c {
+                c if c == quote => return Ok(string),
+                '\\' if quote == '"' => {
+                    let escaped = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| self.next()).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| format!("invalid escape '\\u{}'", hex))?
+                        }
+                        c => return Err(format!("invalid escape '\\{}'", c.unwrap_or(' '))),
+                    };
+                    string.push(escaped);
+                }
+                c => string.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(n: &str) -> Value {
+        Value::Number(n.to_string())
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn reads_defaults_and_profiles() {
+        let config = Config::parse(
+            "# defaults\n\
+             threads = 16\n\
+             exclude = [\"*.tmp\", '.cache/',]  # trailing comma\n\
+             verify = true\n\
+             \n\
+             [profile.nfs]\n\
+             threads = 1_024\n\
+             buffer-size = \"64K\"\n\
+             [profile.\"quoted\"]\n\
+             \"dry-run\" = false\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.options(None).unwrap(),
+            [
+                ("threads".to_string(), number("16")),
+                (
+                    "exclude".to_string(),
+                    Value::Array(vec![string("*.tmp"), string(".cache/")])
+                ),
+                ("verify".to_string(), Value::Bool(true)),
+            ]
+        );
+        // A profile replaces the defaults it shares keys with, in place.
+        assert_eq!(
+            config.options(Some("nfs")).unwrap()[0],
+            ("threads".to_string(), number("1024"))
+        );
+        assert_eq!(
+            config.options(Some("nfs")).unwrap()[3],
+            ("buffer-size".to_string(), string("64K"))
+        );
+        assert_eq!(
+            config.options(Some("quoted")).unwrap()[3],
+            ("dry-run".to_string(), Value::Bool(false))
+        );
+        assert!(config.options(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn reads_strings() {
+        let config = Config::parse(
+            "a = \"tab\\tquote\\\" back\\\\ \\u00e9\"\n\
+             b = 'C:\\raw\\n'\n\
+             c = [\n  \"multi\",\n  # comment\n  \"line\"\n]\n",
+        )
+        .unwrap();
+        let options = config.options(None).unwrap();
+        assert_eq!(options[0].1, string("tab\tquote\" back\\ é"));
+        assert_eq!(options[1].1, string("C:\\raw\\n"));
+        assert_eq!(
+            options[2].1,
+            Value::Array(vec![string("multi"), string("line")])
+        );
+        assert_eq!(options[2].1.to_string(), "[multi, line]");
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        for (text, error) in [
+            ("threads = 1\nthreads = 2\n", "2: 'threads' is set twice"),
+            ("\n\nthreads 16\n", "3: expected '=' after 'threads'"),
+            (
+                "name = bare\n",
+                "1: 'bare' is not a value, strings need quotes",
+            ),
+            ("a = \"open\n", "1: the string is not closed"),
+            ("a = 1 2\n", "1: unexpected '2' after the value"),
+            (
+                "[other]\n",
+                "1: unknown table 'other', expected [profile.NAME]",
+            ),
+            ("a = [1 2]\n", "1: expected ',' or ']' in the array"),
+            ("a = \"\\q\"\n", "1: invalid escape '\\q'"),
+            ("= 1\n", "1: expected a key"),
+        ] {
+            assert_eq!(Config::parse(text).unwrap_err(), error, "{:?}", text);
+        }
+    }
+}
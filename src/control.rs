@@ -0,0 +1,245 @@
+use crate::limit::RateLimiter;
+use crate::schedule::BwSchedule;
+use crate::stats::CopyStats;
+use crate::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A handle on running copies to pause, resume and throttle them, handed
+/// to them with [`CopyOptions::control`](crate::CopyOptions::control).
+///
+/// Copies are held at their next write while paused, so data already read
+/// is written once they resume. Copies that are cancelled while paused stop
+/// once they are resumed.
+#[derive(Debug, Clone)]
+pub struct Control {
+    pub(crate) limiter: Arc<RateLimiter>,
+}
+
+impl Control {
+    /// A handle on copies running without a limit.
+    pub fn new() -> Self {
+        Control {
+            limiter: Arc::new(RateLimiter::new(0)),
+        }
+    }
+
+    pub fn pause(&self) {
+        self.limiter.pause();
+    }
+
+    pub fn resume(&self) {
+        self.limiter.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.limiter.is_paused()
+    }
+
+    /// Limit the combined write throughput of the copies to
+    /// `bytes_per_second` from now on, or lift the limit with `None`.
+    pub fn set_bwlimit(&self, bytes_per_second: Option<u64>) {
+        self.limiter.set_rate(bytes_per_second.unwrap_or(0));
+    }
+
+    /// Limit the copies by the time of day as `schedule` says, or go back
+    /// to the limit set with [`set_bwlimit`](Self::set_bwlimit) with `None`.
+    pub fn set_bwlimit_schedule(&self, schedule: Option<BwSchedule>) {
+        self.limiter.set_schedule(schedule);
+    }
+
+    /// The limit the copies are held to right now.
+    pub fn bwlimit(&self) -> Option<u64> {
+        Some(self.limiter.rate()).filter(|&rate| rate > 0)
+    }
+
+    /// Bytes the copies have written so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.limiter.taken()
+    }
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The control socket of a copy, which is removed when this is dropped.
+#[derive(Debug)]
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listen on the Unix socket `path` for commands to `control`, one per
+/// line, each answered with a line:
+///
+/// - `pause` and `resume` hold and release the copies, answering `ok`.
+/// - `set-bwlimit RATE` limits them to RATE bytes per second, a size such
+///   as `50M`, or lifts the limit with `0` or `off`, answering `ok`.
+/// - `status` answers with a JSON object of whether the copies are
+///   `running` or `paused`, the bytes written, the limit and, with `stats`,
+///   the current rate, the files copied, skipped and failed, the retries
+///   and the verification failures.
+///
+/// Commands that can't be carried out are answered with `error: ` and
+/// why. The socket is served from a thread of its own for as long as the
+/// returned [`ControlSocket`] is kept.
+pub fn serve_control(
+    path: &Path,
+    control: Control,
+    stats: Option<Arc<CopyStats>>,
+) -> Result<ControlSocket, Error> {
+    imp::serve_control(path, control, stats)
+}
+
+/// The answer to the control command `line`.
+#[cfg(unix)]
+fn answer(line: &str, control: &Control, stats: Option<&CopyStats>) -> String {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("pause"), None, _) => {
+            control.pause();
+            log::info!("Paused from the control socket");
+            "ok".to_string()
+        }
+        (Some("resume"), None, _) => {
+            control.resume();
+            log::info!("Resumed from the control socket");
+            "ok".to_string()
+        }
+        (Some("set-bwlimit"), Some(rate), None) => {
+            let rate = match rate {
+                "off" | "none" => Ok(0),
+                rate => crate::progress::parse_size(rate),
+            };
+            match rate {
+                Ok(rate) => {
+                    control.set_bwlimit(Some(rate));
+                    log::info!("Bandwidth limit set to {} from the control socket", rate);
+                    "ok".to_string()
+                }
+                Err(e) => format!("error: {}", e),
+            }
+        }
+        (Some("status"), None, _) => {
+            let bwlimit = control
+                .bwlimit()
+                .map_or("null".to_string(), |rate| rate.to_string());
+            let files = stats.map_or(String::new(), |stats| {
+                format!(
+                    ",\"bytes_per_second\":{},\"files_copied\":{},\"files_skipped\":{},\"files_failed\":{},\"retries\":{},\"verification_failures\":{}",
+                    stats.current_rate(),
+                    stats.files_copied(),
+                    stats.files_skipped(),
+                    stats.files_failed(),
+                    stats.retries(),
+                    stats.verification_failures()
+                )
+            });
+            format!(
+                "{{\"state\":\"{}\",\"bytes\":{},\"bwlimit\":{}{}}}",
+                if control.is_paused() {
+                    "paused"
+                } else {
+                    "running"
+                },
+                control.bytes_written(),
+                bwlimit,
+                files
+            )
+        }
+        _ => format!(
+            "error: unknown command '{}', expected pause, resume, status or set-bwlimit RATE",
+            line.trim()
+        ),
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{answer, Control, ControlSocket};
+    use crate::stats::CopyStats;
+    use crate::Error;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    pub(super) fn serve_control(
+        path: &Path,
+        control: Control,
+        stats: Option<Arc<CopyStats>>,
+    ) -> Result<ControlSocket, Error> {
+        if path.exists() {
+            // A socket nothing listens on is left over from a copy that quit.
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!(
+                    "Control socket '{}' is in use by another copy",
+                    path.display()
+                )
+                .into());
+            }
+            std::fs::remove_file(path)
+                .map_err(|e| format!("Failed to remove '{}': {}", path.display(), e))?;
+        }
+        let listener = UnixListener::bind(path).map_err(|e| {
+            format!(
+                "Failed to listen on control socket '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (control, stats) = (control.clone(), stats.clone());
+                thread::spawn(move || serve(stream, &control, stats.as_deref()));
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Answer the commands sent on `stream` until it is closed.
+    fn serve(stream: UnixStream, control: &Control, stats: Option<&CopyStats>) {
+        let Ok(mut writer) = stream.try_clone() else {
+            return;
+        };
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if writeln!(writer, "{}", answer(&line, control, stats)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::{Control, ControlSocket};
+    use crate::stats::CopyStats;
+    use crate::Error;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    pub(super) fn serve_control(
+        _path: &Path,
+        _control: Control,
+        _stats: Option<Arc<CopyStats>>,
+    ) -> Result<ControlSocket, Error> {
+        Err("Control sockets are only supported on Unix".into())
+    }
+}
@@ -0,0 +1,1985 @@
+use crate::backend::{Fanout, Region, StorageBackend};
+use crate::backup::{back_up, backup_path};
+use crate::cache::{advise_sequential, drop_cached, finish_writeback, start_writeback, will_need};
+use crate::cancel::{cancelled, CancelledError};
+use crate::checkpoint::{missing_ranges, Checkpoint};
+use crate::delta::delta_copy;
+use crate::device::{device_size, is_block_device};
+use crate::direct::{align_ranges, align_up, ALIGNMENT};
+use crate::encrypt::encrypt_file;
+use crate::follow::follow_growth;
+use crate::fsync::{sync_dir, sync_file};
+use crate::hash::HashAlgorithm;
+use crate::limit::RateLimiter;
+use crate::logfile::FAILURE;
+use crate::manifest::FileDigest;
+use crate::metadata::preserve_metadata;
+#[cfg(unix)]
+use crate::mmap;
+use crate::numa::Placement;
+use crate::offload::{offload_copy, same_filesystem};
+use crate::options::{CopyOptions, Engine, Fsync, Overwrite};
+use crate::pipeline::Pipeline;
+use crate::pool::{take_buffers, PooledBuffer};
+use crate::progress::{human_size, Event, Progress};
+use crate::prompt::confirm_overwrite;
+use crate::scale::Scaler;
+use crate::space::{check_space, needed_space};
+use crate::sparse::{data_ranges, intersect, is_zero, may_have_holes, preallocate, Sparse};
+#[cfg(target_os = "linux")]
+use crate::splice::Splicer;
+use crate::split::split_file;
+use crate::stats::CopyStats;
+#[cfg(feature = "io-uring")]
+use crate::uring::Ring;
+#[cfg(target_os = "linux")]
+use crate::vectored::{self, Vectored};
+use crate::verify::{verified_event, verify_chunks, verify_copy, ChunkDigest, VerifyError};
+use crate::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(windows)]
+use std::os::windows::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Files smaller than this are always copied with a single thread.
+pub(crate) const SMALL_FILE_SIZE: u64 = 1024 * 1024; // 1Mb
+
+/// Copy a single file from `src` to `dest` using `options.threads` threads,
+/// returning the number of bytes copied.
+///
+/// The destination is created, or truncated if it already exists and
+/// [`CopyOptions::overwrite`] allows it. Destinations left alone count as
+/// 0 bytes copied. When
+/// verification is enabled each chunk is hashed as it is copied,
+/// and only the destination is read back to check the copy, so the source
+/// is read once. Mismatches are reported as a [`VerifyError`](crate::VerifyError).
+///
+/// Within one filesystem the copy is left to the kernel with a reflink or
+/// `copy_file_range` where possible, before falling back to the threads.
+///
+/// Holes in sparse sources are recreated in the destination as set with
+/// [`CopyOptions::sparse`].
+///
+/// Permissions, times and ownership are carried over as selected with
+/// [`CopyOptions::preserve`], [`CopyOptions::owner`] and
+/// [`CopyOptions::group`].
+///
+/// With [`CopyOptions::resume`] progress is recorded in a
+/// `<dest>.rpcp-checkpoint` file, and ranges recorded by an interrupted run
+/// are not copied again.
+///
+/// When [`CopyOptions::cancel`] is set part way the copy stops and fails
+/// with a [`CancelledError`](crate::CancelledError). The partial destination
+/// is removed, or kept as set with [`CopyOptions::keep_partial`].
+///
+/// An existing `dest` is replaced by copying to a temporary file next to it
+/// and renaming that over it, or rewritten where it is with
+/// [`CopyOptions::inplace`]. With [`CopyOptions::atomic`] a new `dest` is
+/// written to a temporary file too, which is renamed once copied, verified
+/// and given its metadata.
+///
+/// With [`CopyOptions::backup`] or [`CopyOptions::backup_dir`] an existing
+/// destination is moved aside before it is replaced.
+///
+/// With [`CopyOptions::fsync`] the destination and its directory are
+/// flushed to the device before the copy returns.
+///
+/// With [`CopyOptions::remove_source`] `src` is removed once it has been
+/// copied and verified.
+///
+/// Either side can be a block device, which is read or written in place
+/// like `dd`, with every byte written. [`CopyOptions::skip`],
+/// [`CopyOptions::seek`] and [`CopyOptions::count`] copy part of the source
+/// to an offset in the destination.
+///
+/// With [`CopyOptions::split`] files larger than the part size are copied
+/// to parts with names ending in `.part0000`, `.part0001` and so on, and
+/// others to `dest` as they are.
+///
+/// With [`CopyOptions::dry_run`] nothing is written, the copy that would be
+/// made is printed to stdout instead.
+pub fn copy_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    if let Some(part_size) = options.split {
+        let size = fs::metadata(src).map_err(|e| open_error(src, e))?.len();
+        let size = size
+            .saturating_sub(options.skip)
+            .min(options.count.unwrap_or(u64::MAX));
+        if size > part_size {
+            return split_file(src, dest, size, part_size, options);
+        }
+    }
+    check_in_place(dest, options)?;
+    if let Some(manifest) = &options.manifest {
+        manifest.add(src, dest, options);
+    }
+    if options.overwrite != Overwrite::Always {
+        let source = fs::metadata(src).map_err(|e| open_error(src, e))?;
+        if let Some(reason) = keep_existing(&source, dest, options) {
+            skip_existing(dest, reason, options);
+            return Ok(0);
+        }
+    }
+    if options.dry_run {
+        let size = fs::metadata(src).map_err(|e| open_error(src, e))?.len();
+        let size = size
+            .saturating_sub(options.skip)
+            .min(options.count.unwrap_or(u64::MAX));
+        let action = if options.remove_source {
+            "move"
+        } else if dest.exists() {
+            "overwrite"
+        } else {
+            "copy"
+        };
+        println!(
+            "would {} '{}' -> '{}' ({} bytes)",
+            action,
+            src.display(),
+            dest.display(),
+            size
+        );
+        if let Some(backup) = backup_path(dest, options).filter(|_| dest.exists()) {
+            println!(
+                "would back up '{}' to '{}'",
+                dest.display(),
+                backup.display()
+            );
+        }
+        return Ok(size);
+    }
+    let to_device = fs::metadata(dest).is_ok_and(|metadata| is_block_device(&metadata));
+    if options.space_check && !to_device {
+        let source = fs::metadata(src).map_err(|e| open_error(src, e))?;
+        check_space(dest, needed_space(&source, dest, options))?;
+    }
+    // A destination with a checkpoint is a copy of the source to resume.
+    let resuming = options.resume && Checkpoint::exists_for(dest);
+    if let Some(backup) = backup_path(dest, options).filter(|_| !resuming) {
+        if dest.symlink_metadata().is_ok() {
+            if options.verbosity > 1 {
+                eprintln!(" Back up '{}' to '{}'", dest.display(), backup.display());
+            }
+            back_up(dest, &backup, options.atomic)?;
+        }
+    }
+    let start = Instant::now();
+    log::info!("Copy '{}' -> '{}'", src.display(), dest.display());
+    if options.json {
+        Event::new("file_started")
+            .path("source", src)
+            .path("path", dest)
+            .emit();
+    }
+    let size = match copy_into_place(src, dest, options) {
+        Ok(size) => size,
+        Err(e) => {
+            if e.is::<CancelledError>() {
+                log::warn!(target: FAILURE, "{}", e);
+            } else {
+                log::error!(
+                    target: FAILURE,
+                    "Failed to copy '{}' -> '{}': {}",
+                    src.display(),
+                    dest.display(),
+                    e
+                );
+            }
+            if options.json && e.is::<VerifyError>() {
+                verified_event(dest, Some(&e.to_string()));
+            }
+            if let Some(stats) = options.stats.as_ref().filter(|_| !e.is::<CancelledError>()) {
+                stats.failed();
+                if e.is::<VerifyError>() {
+                    stats.verify_failed(1);
+                }
+            }
+            return Err(e);
+        }
+    };
+    if let Some(stats) = &options.stats {
+        stats.copied(size);
+    }
+    log::info!(
+        "Copied '{}' -> '{}', {} bytes in {:.3}s{}",
+        src.display(),
+        dest.display(),
+        size,
+        start.elapsed().as_secs_f64(),
+        if options.verify { ", verified" } else { "" }
+    );
+    if options.verbosity > 0 {
+        eprintln!("{}", copied_message(src, dest, size, start.elapsed()));
+    }
+    if options.json {
+        Event::new("file_completed")
+            .path("path", dest)
+            .value("bytes", size)
+            .emit();
+        if options.verify {
+            verified_event(dest, None);
+        }
+    }
+    if options.remove_source {
+        fs::remove_file(src)
+            .map_err(|e| format!("Failed to remove source '{}': {}", src.display(), e))?;
+    }
+    Ok(size)
+}
+
+/// Whether files of a batch under [`CopyOptions::batch`] can be copied
+/// with [`copy_small_file`], which has none of the options that need the
+/// chunks of [`copy_file`].
+pub(crate) fn can_batch(options: &CopyOptions) -> bool {
+    options.batch > 1
+        && !options.verify
+        && !options.resume
+        && options.follow.is_none()
+        && options.skip == 0
+        && options.seek == 0
+        && options.count.is_none()
+        && !options.direct
+        && !options.drop_cache
+        && !options.atomic
+        && options.part_suffix.is_none()
+        && options.backup.is_none()
+        && options.backup_dir.is_none()
+        && options.sparse != Sparse::Always
+        && options.encrypt.is_none()
+        && options.split.is_none()
+        && !options.delta
+}
+
+/// Copy the small file `src` to `dest` by reading it whole into `buffer`
+/// and writing it out at once, which saves most of the calls [`copy_file`]
+/// makes for each file. Sources with holes, those replacing an existing
+/// `dest` through a temporary file, and those that fail with
+/// [`CopyOptions::retries`] set, are copied with [`copy_file`] instead.
+pub(crate) fn copy_small_file(
+    src: &Path,
+    dest: &Path,
+    buffer: &mut Vec<u8>,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let start = Instant::now();
+    let size = match copy_whole(src, dest, buffer, options) {
+        Ok(Some(size)) => size,
+        Ok(None) => return copy_file(src, dest, options),
+        Err(e) if options.retries > 0 => {
+            log::warn!("Failed to copy '{}', trying again: {}", src.display(), e);
+            if let Some(stats) = &options.stats {
+                stats.retried();
+            }
+            return copy_file(src, dest, options);
+        }
+        Err(e) => {
+            log::error!(
+                target: FAILURE,
+                "Failed to copy '{}' -> '{}': {}",
+                src.display(),
+                dest.display(),
+                e
+            );
+            if let Some(stats) = &options.stats {
+                stats.failed();
+            }
+            return Err(e);
+        }
+    };
+    if let Some(stats) = &options.stats {
+        stats.copied(size);
+    }
+    log::info!(
+        "Copied '{}' -> '{}', {} bytes in {:.3}s",
+        src.display(),
+        dest.display(),
+        size,
+        start.elapsed().as_secs_f64()
+    );
+    if options.json {
+        Event::new("file_completed")
+            .path("path", dest)
+            .value("bytes", size)
+            .emit();
+    }
+    if options.remove_source {
+        fs::remove_file(src)
+            .map_err(|e| format!("Failed to remove source '{}': {}", src.display(), e))?;
+    }
+    Ok(size)
+}
+
+/// Read `src` into `buffer` and write it to `dest`, returning its size, or
+/// `None` when it is not a regular file or has holes to keep.
+fn copy_whole(
+    src: &Path,
+    dest: &Path,
+    buffer: &mut Vec<u8>,
+    options: &CopyOptions,
+) -> Result<Option<u64>, Error> {
+    let mut infile = File::open(src).map_err(|e| open_error(src, e))?;
+    let source = infile.metadata()?;
+    if !source.is_file()
+        || (options.sparse != Sparse::Never && may_have_holes(&source))
+        || !updates_in_place(dest, options)
+    {
+        return Ok(None);
+    }
+    log::info!("Copy '{}' -> '{}'", src.display(), dest.display());
+    if options.json {
+        Event::new("file_started")
+            .path("source", src)
+            .path("path", dest)
+            .emit();
+    }
+    buffer.clear();
+    io::Read::read_to_end(&mut infile, buffer)
+        .map_err(|e| format!("Failed to read '{}': {}", src.display(), e))?;
+    if let Some(limiter) = &options.bwlimit {
+        limiter.take(buffer.len());
+    }
+    let mut outfile = File::create(dest)
+        .map_err(|e| format!("Failed to create output file '{}': {:?}", dest.display(), e))?;
+    io::Write::write_all(&mut outfile, buffer)
+        .map_err(|e| format!("Failed to write '{}': {}", dest.display(), e))?;
+    drop(outfile);
+    check_source_change(src, &source, &infile.metadata()?, options)?;
+    if let Some(manifest) = &options.manifest {
+        manifest.hashed(dest, buffer);
+    }
+    preserve_metadata(src, &source, dest, options)?;
+    sync_file(dest, options.fsync)?;
+    if options.fsync != Fsync::Off {
+        sync_dir(dest.parent().unwrap_or(Path::new("")))?;
+    }
+    Ok(Some(buffer.len() as u64))
+}
+
+/// Fail copies that need a file to be replaced or tracked when they are to
+/// a block device, which is written in place, or to part of a file.
+fn check_in_place(dest: &Path, options: &CopyOptions) -> Result<(), Error> {
+    let offsets = options.skip > 0 || options.seek > 0 || options.count.is_some();
+    if options.resume && offsets {
+        return Err("Copies of part of a file can't be resumed".into());
+    }
+    if options.follow.is_some() && offsets {
+        return Err("Copies of part of a file can't follow it as it grows".into());
+    }
+    if options.part_suffix.is_some() && options.seek > 0 {
+        return Err("Copies to an offset in a file are written in place".into());
+    }
+    if !fs::metadata(dest).is_ok_and(|metadata| is_block_device(&metadata)) {
+        return Ok(());
+    }
+    if options.atomic || options.part_suffix.is_some() {
+        return Err(format!(
+            "Block device '{}' can't be replaced atomically, it is written in place",
+            dest.display()
+        )
+        .into());
+    }
+    if backup_path(dest, options).is_some() {
+        return Err(format!("Block device '{}' can't be backed up", dest.display()).into());
+    }
+    if options.resume {
+        return Err(format!(
+            "Copies to block device '{}' can't be resumed",
+            dest.display()
+        )
+        .into());
+    }
+    if options.follow.is_some() {
+        return Err(format!(
+            "Block device '{}' can't grow with a followed file",
+            dest.display()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The line printed for a copied file at [`CopyOptions::verbosity`] 1.
+pub(crate) fn copied_message(src: &Path, dest: &Path, size: u64, elapsed: Duration) -> String {
+    format!(
+        " Copied '{}' -> '{}', {} in {:.1?}",
+        src.display(),
+        dest.display(),
+        human_size(size),
+        elapsed
+    )
+}
+
+/// Copy `src` to `dest`, or through a temporary file renamed over `dest`
+/// with [`CopyOptions::atomic`] or [`CopyOptions::part_suffix`], and flush the directory it is in with
+/// [`CopyOptions::fsync`].
+fn copy_into_place(src: &Path, dest: &Path, options: &CopyOptions) -> Result<u64, Error> {
+    let (size, digest) = copy_or_rename(src, dest, options)?;
+    if options.fsync != Fsync::Off {
+        sync_dir(dest.parent().unwrap_or(Path::new("")))?;
+    }
+    if let (Some(manifest), Some(digest)) = (&options.manifest, digest) {
+        manifest.taken(dest, digest);
+    }
+    Ok(size)
+}
+
+fn copy_or_rename(
+    src: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<(u64, Option<String>), Error> {
+    let temp = match &options.part_suffix {
+        Some(suffix) => {
+            let mut temp = dest.as_os_str().to_owned();
+            temp.push(suffix);
+            PathBuf::from(temp)
+        }
+        None if options.atomic => temp_path(dest, options.resume),
+        None if !updates_in_place(dest, options) => temp_path(dest, false),
+        None => return copy_verified(src, dest, options),
+    };
+    let replaced = replaced_file(dest, options);
+    match copy_verified(src, &temp, options) {
+        Ok(copied) => {
+            move_into_place(&temp, dest, replaced.as_ref())?;
+            Ok(copied)
+        }
+        Err(mut e) => {
+            // A resumable copy keeps its temporary file for the next run.
+            if !options.resume && fs::remove_file(&temp).is_ok() {
+                if let Some(e) = e.downcast_mut::<VerifyError>() {
+                    e.removed = true;
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// The metadata of the file at `dest` a copy through a temporary file
+/// replaces, whose permissions it keeps unless [`CopyOptions::preserve`]
+/// gives it those of the source.
+fn replaced_file(dest: &Path, options: &CopyOptions) -> Option<fs::Metadata> {
+    fs::metadata(dest)
+        .ok()
+        .filter(|existing| existing.is_file() && !options.preserve)
+}
+
+/// Rename the finished temporary file `temp` over `dest`, with the
+/// permissions of the `replaced` file, as it would have in place.
+fn move_into_place(temp: &Path, dest: &Path, replaced: Option<&fs::Metadata>) -> Result<(), Error> {
+    if let Some(existing) = replaced {
+        fs::set_permissions(temp, existing.permissions())
+            .map_err(|e| format!("Failed to set permissions of '{}': {}", temp.display(), e))?;
+    }
+    fs::rename(temp, dest).map_err(|e| {
+        format!(
+            "Failed to move '{}' into place at '{}': {}",
+            temp.display(),
+            dest.display(),
+            e
+        )
+    })?;
+    Ok(())
+}
+
+/// Count and report `dest` as left alone for `reason`.
+pub(crate) fn skip_existing(dest: &Path, reason: &str, options: &CopyOptions) {
+    log::info!("Skipped '{}', {}", dest.display(), reason);
+    itemize("skipped", dest, Some(reason), options);
+    if let Some(stats) = &options.stats {
+        stats.skipped();
+    }
+    if options.json {
+        Event::new("file_skipped")
+            .path("path", dest)
+            .string("reason", reason)
+            .emit();
+    }
+    if options.dry_run {
+        println!("would skip '{}', {}", dest.display(), reason);
+    } else if options.progress {
+        eprintln!("Skipping '{}', {}", dest.display(), reason);
+    }
+}
+
+/// Print the line of [`CopyOptions::itemize`] for `dest`: what was done to
+/// it and why, if there is more to say.
+pub(crate) fn itemize(action: &str, dest: &Path, reason: Option<&str>, options: &CopyOptions) {
+    if !options.itemize {
+        return;
+    }
+    match reason {
+        Some(reason) => println!("{:<9} {} ({})", action, dest.display(), reason),
+        None => println!("{:<9} {}", action, dest.display()),
+    }
+}
+
+/// Why `dest` is to be left alone under [`CopyOptions::overwrite`], if it
+/// exists and is. `source` is the metadata of what would replace it.
+pub(crate) fn keep_existing(
+    source: &fs::Metadata,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Option<&'static str> {
+    let existing = dest.symlink_metadata().ok()?;
+    match options.overwrite {
+        Overwrite::Always => None,
+        Overwrite::Never => Some("it already exists"),
+        Overwrite::Update => {
+            let newer = match (source.modified(), existing.modified()) {
+                (Ok(source), Ok(existing)) => source > existing,
+                _ => true,
+            };
+            (!newer).then_some("it is not older than the source")
+        }
+        Overwrite::Ask if options.dry_run => None,
+        Overwrite::Ask => {
+            (!confirm_overwrite(&dest.display())).then_some("it was not to be overwritten")
+        }
+    }
+}
+
+/// Copy `src` to `dest`, then verify it, carry over the metadata and flush
+/// it with [`CopyOptions::fsync`]. Returns the bytes copied and the digest
+/// taken for [`CopyOptions::manifest`], if it could be.
+fn copy_verified(
+    src: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<(u64, Option<String>), Error> {
+    if let Some(recipients) = &options.encrypt {
+        let (size, source, digest) = encrypt_file(src, dest, recipients, options)?;
+        let after = fs::metadata(src).map_err(|e| open_error(src, e))?;
+        check_source_change(src, &source, &after, options)?;
+        preserve_metadata(src, &source, dest, options)?;
+        sync_file(dest, options.fsync)?;
+        return Ok((size, digest));
+    }
+    if options.delta && fs::metadata(dest).is_ok_and(|metadata| metadata.is_file()) {
+        let (written, source) = delta_copy(src, dest, options)?;
+        let after = fs::metadata(src).map_err(|e| open_error(src, e))?;
+        check_source_change(src, &source, &after, options)?;
+        preserve_metadata(src, &source, dest, options)?;
+        sync_file(dest, options.fsync)?;
+        return Ok((written, None));
+    }
+    let algorithm = options
+        .verify
+        .then(|| options.verify_hash.unwrap_or(HashAlgorithm::Xxh3));
+    let mut copied = copy_file_data(src, dest, options, algorithm)?;
+    if let Some(quiet) = options.follow {
+        let (size, appended) = follow_growth(src, dest, copied.size, quiet, algorithm, options)?;
+        copied.size = size;
+        copied.digest = None;
+        if let Some(chunk_digests) = copied.chunk_digests.as_mut() {
+            chunk_digests.extend(appended);
+        }
+        // The times to carry over are those of the last write.
+        copied.source = fs::metadata(src).map_err(|e| open_error(src, e))?;
+    }
+    // Digests taken as the data was read match a torn copy just as well.
+    let after = fs::metadata(src).map_err(|e| open_error(src, e))?;
+    check_source_change(src, &copied.source, &after, options)?;
+    match (algorithm, copied.chunk_digests) {
+        (Some(algorithm), Some(chunk_digests)) => {
+            // Only what was written is compared, devices go on after it.
+            let file = File::open(dest)?;
+            let written: Box<dyn StorageBackend> =
+                if options.seek > 0 || is_block_device(&file.metadata()?) {
+                    Box::new(Region::new(file, options.seek, copied.size))
+                } else {
+                    Box::new(file)
+                };
+            verify_chunks(
+                dest,
+                &*written,
+                copied.size,
+                &chunk_digests,
+                algorithm,
+                options,
+            )?;
+        }
+        // Resumed or offloaded ranges were never read from the source in
+        // this run.
+        (Some(_), None) => {
+            verify_copy(src, dest, options)?;
+        }
+        (None, _) => {}
+    }
+    // The permissions of a device are not the data's to set.
+    if !fs::metadata(dest).is_ok_and(|metadata| is_block_device(&metadata)) {
+        preserve_metadata(src, &copied.source, dest, options)?;
+    }
+    sync_file(dest, options.fsync)?;
+    Ok((copied.size, copied.digest))
+}
+
+/// Warn that `src` changed between `before` and `after` its copy, if it
+/// did, or fail with [`CopyOptions::fail_on_change`].
+fn check_source_change(
+    src: &Path,
+    before: &fs::Metadata,
+    after: &fs::Metadata,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    let Some(change) = source_change(before, after) else {
+        return Ok(());
+    };
+    if options.fail_on_change {
+        return Err(format!("'{}' {} while it was copied", src.display(), change).into());
+    }
+    log::warn!(
+        "'{}' {} while it was copied, the copy may mix old and new data",
+        src.display(),
+        change
+    );
+    Ok(())
+}
+
+/// How the source changed between `before` and `after` its copy, if it did.
+/// The size of a device is not in its metadata, and only its time can tell.
+fn source_change(before: &fs::Metadata, after: &fs::Metadata) -> Option<String> {
+    if before.len() != after.len() {
+        return Some(format!(
+            "changed size from {} to {} bytes",
+            before.len(),
+            after.len()
+        ));
+    }
+    match (before.modified(), after.modified()) {
+        (Ok(before), Ok(after)) if before != after => Some("was modified".to_string()),
+        _ => None,
+    }
+}
+
+/// Whether a copy to `dest` writes it where it is, without a temporary file
+/// renamed over it: when it is not an existing regular file, and for updates
+/// with [`CopyOptions::inplace`] or the options that need it.
+pub(crate) fn updates_in_place(dest: &Path, options: &CopyOptions) -> bool {
+    if options.atomic || options.part_suffix.is_some() {
+        return false;
+    }
+    options.inplace
+        || options.delta
+        || options.resume
+        || options.seek > 0
+        || !dest
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_file())
+}
+
+/// The temporary file an atomic copy to `dest` is written to, next to it so
+/// the rename stays within one filesystem. Names end in a random suffix so
+/// no two copies share one, except for resumable copies which need to find
+/// theirs again.
+fn temp_path(dest: &Path, resume: bool) -> PathBuf {
+    let mut temp = dest.as_os_str().to_owned();
+    temp.push(".rpcp-tmp");
+    if !resume {
+        let mut unique = [0; 8];
+        // Without randomness the process and a count keep copies apart.
+        if getrandom::fill(&mut unique).is_err() {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let count = NEXT.fetch_add(1, Ordering::SeqCst) as u64;
+            unique = ((std::process::id() as u64) << 32 | count).to_be_bytes();
+        }
+        temp.push(format!(".{:016x}", u64::from_be_bytes(unique)));
+    }
+    PathBuf::from(temp)
+}
+
+struct CopiedFile {
+    /// Metadata of the source, taken before it was read.
+    source: fs::Metadata,
+    /// Bytes copied, which is the size of the source unless only part of it
+    /// was.
+    size: u64,
+    /// Digest of every chunk as it was read from the source, when requested.
+    /// `None` when part of the file was resumed from a checkpoint.
+    chunk_digests: Option<Vec<ChunkDigest>>,
+    /// Digest of the whole file for [`CopyOptions::manifest`], when all of
+    /// it was read in the copy.
+    digest: Option<String>,
+}
+
+/// Copy the file data, hashing every chunk with `algorithm` when it is set.
+fn copy_file_data(
+    infile_path: &Path,
+    outfile_path: &Path,
+    options: &CopyOptions,
+    algorithm: Option<HashAlgorithm>,
+) -> Result<CopiedFile, Error> {
+    let mut num_threads = options.threads as u64;
+    let infile = open_options(options)?
+        .read(true)
+        .open(infile_path)
+        .map_err(|e| open_error(infile_path, e))?;
+    let metadata = infile.metadata()?;
+    let source_device = is_block_device(&metadata);
+    let source_size = StorageBackend::len(&infile)?;
+    if options.skip > source_size {
+        return Err(format!(
+            "Can't skip {} bytes of '{}', it is {} bytes",
+            options.skip,
+            infile_path.display(),
+            source_size
+        )
+        .into());
+    }
+    let infile_size = (source_size - options.skip).min(options.count.unwrap_or(u64::MAX));
+    let offsets = options.skip > 0 || options.seek > 0;
+    if offsets && !matches!(options.engine, Engine::Pread | Engine::Pipeline) {
+        return Err(format!(
+            "The {} engine can't copy from or to an offset, use pread or pipeline",
+            options.engine
+        )
+        .into());
+    }
+    if options.direct
+        && [options.skip, options.seek, options.count.unwrap_or(0)]
+            .iter()
+            .any(|bytes| !bytes.is_multiple_of(ALIGNMENT as u64))
+    {
+        return Err("Direct IO needs the offsets and count to be whole 4Kb blocks".into());
+    }
+
+    if infile_size < SMALL_FILE_SIZE {
+        if options.progress {
+            eprintln!("Small file. Copy with one thread");
+        }
+        num_threads = 1
+    };
+    let (checkpoint, done) = if options.resume {
+        let (checkpoint, done) = Checkpoint::create(outfile_path, &metadata)?;
+        (Some(checkpoint), done)
+    } else {
+        (None, Vec::new())
+    };
+    let resumed_bytes: u64 = done.iter().map(|r| r.end - r.start).sum();
+
+    let outfile = open_options(options)?
+        .write(true)
+        .create(true)
+        // The data before the offset it is written at is kept.
+        .truncate(resumed_bytes == 0 && options.seek == 0)
+        .open(outfile_path)
+        .map_err(|e| {
+            format!(
+                "Failed to create output file '{}': {:?}",
+                outfile_path.display(),
+                e
+            )
+        })?;
+    let dest_device = is_block_device(&outfile.metadata()?);
+    if dest_device {
+        let room = device_size(&outfile)?;
+        if options.seek + infile_size > room {
+            return Err(format!(
+                "'{}' is {} bytes, too small for {} bytes at offset {}",
+                outfile_path.display(),
+                room,
+                infile_size,
+                options.seek
+            )
+            .into());
+        }
+        if options.direct && !infile_size.is_multiple_of(ALIGNMENT as u64) {
+            return Err(format!(
+                "Direct IO writes whole 4Kb blocks, and would write past the {} bytes copied to '{}'",
+                infile_size,
+                outfile_path.display()
+            )
+            .into());
+        }
+    } else {
+        outfile.set_len(options.seek + infile_size)?;
+    }
+    // Whatever was on a device, or after the offset in a file, is still
+    // there, so every byte is written over it.
+    let every_byte;
+    let options = if (dest_device || options.seek > 0) && options.sparse != Sparse::Never {
+        every_byte = options.clone().sparse(Sparse::Never);
+        &every_byte
+    } else {
+        options
+    };
+
+    // Holes in the source are left as holes in the destination. set_len
+    // above has already made the whole destination one big hole.
+    let mut to_copy = missing_ranges(&(0..infile_size), &done);
+    if options.sparse != Sparse::Never && !offsets && !source_device {
+        to_copy = intersect(&to_copy, &data_ranges(&infile, infile_size)?);
+    }
+    let buffer_size = if options.direct {
+        // Direct IO only moves whole blocks.
+        to_copy = align_ranges(&to_copy);
+        align_up(options.buffer_size as u64) as usize
+    } else {
+        options.buffer_size
+    };
+    let copy_bytes: u64 = to_copy
+        .iter()
+        .map(|r| r.end.min(infile_size) - r.start)
+        .sum();
+    let skip_zeros = options.sparse == Sparse::Always;
+
+    // Within one filesystem the kernel can copy the data itself, unless it
+    // has to pass through here to be throttled, aligned or checked for zeros,
+    // or read for digests of part of the source to verify.
+    let offload = options.sparse == Sparse::Auto
+        && !options.direct
+        && options.bwlimit.is_none()
+        && !offsets
+        && (options.count.is_none() || !options.verify)
+        && !source_device
+        && !dest_device;
+    if offload && same_filesystem(&metadata, &outfile.metadata()?) {
+        let offloaded = offload_copy(
+            &infile,
+            &outfile,
+            (infile_path, outfile_path),
+            &to_copy,
+            resumed_bytes == 0,
+        )
+        .map_err(|e| {
+            format!(
+                "Failed to copy '{}' to '{}': {}",
+                infile_path.display(),
+                outfile_path.display(),
+                e
+            )
+        })?;
+        if offloaded {
+            log::debug!("Copied '{}' within the filesystem", infile_path.display());
+            if options.progress {
+                eprintln!(" Copied {} within the filesystem", infile_path.display());
+            }
+            if let Some(checkpoint) = checkpoint {
+                checkpoint.remove()?;
+            }
+            return Ok(CopiedFile {
+                source: metadata,
+                size: infile_size,
+                chunk_digests: None,
+                digest: None,
+            });
+        }
+    }
+
+    // Blocks of zeros are meant to end up as holes, so nothing is allocated
+    // for them ahead of time.
+    if options.preallocate && !skip_zeros && !dest_device {
+        preallocate(&outfile, &to_copy).map_err(|e| {
+            format!(
+                "Failed to allocate space for '{}': {}",
+                outfile_path.display(),
+                e
+            )
+        })?;
+    }
+
+    // Direct IO does not go through the page cache at all.
+    if !options.direct {
+        advise_sequential(&infile);
+    }
+
+    if options.progress {
+        eprintln!(" Copy {}", infile_path.display());
+        if resumed_bytes > 0 {
+            eprintln!("Resuming, {} bytes already copied", resumed_bytes);
+        }
+    }
+
+    let (infile, outfile): (Arc<dyn StorageBackend>, Arc<dyn StorageBackend>) = if offsets {
+        (
+            Arc::new(Region::new(infile, options.skip, infile_size)),
+            Arc::new(Region::new(outfile, options.seek, infile_size)),
+        )
+    } else {
+        (Arc::new(infile), Arc::new(outfile))
+    };
+    let io = ChunkIo::new(infile, infile_path, outfile, outfile_path, options);
+    let mut chunk_size = chunk_size(infile_size, num_threads, buffer_size);
+    // The digest of a whole file is of data read in this run.
+    let file_digest = (options.manifest.as_ref())
+        .filter(|_| resumed_bytes == 0 && !offsets && !dest_device)
+        .map(|manifest| Arc::new(FileDigest::new(manifest.algorithm())));
+    if file_digest.is_some() && num_threads > 1 {
+        // Threads wait their turn to hash a chunk, which is then only as
+        // long as the reads of the chunks before it.
+        chunk_size = buffer_size as u64;
+    }
+    if options.direct {
+        chunk_size = align_up(chunk_size);
+    }
+    let plan = ChunkPlan {
+        chunks: chunks(&to_copy, chunk_size),
+        size: infile_size,
+        done: infile_size - copy_bytes,
+        threads: num_threads,
+        buffer_size,
+    };
+    log::debug!(
+        "Copy {} bytes of '{}' in {} chunks with the {} engine, {} threads and {} byte buffers",
+        copy_bytes,
+        infile_path.display(),
+        plan.chunks.len(),
+        options.engine,
+        plan.threads,
+        plan.buffer_size
+    );
+    let checkpoint = checkpoint.map(Arc::new);
+    let copied = copy_chunks(
+        &io,
+        plan,
+        checkpoint.clone(),
+        algorithm,
+        file_digest.clone(),
+        options,
+    )?;
+    let (copied_bytes, mut chunk_digests) = copied;
+    if copied_bytes < infile_size && options.is_cancelled() {
+        drop(io);
+        let resumable = checkpoint.is_some();
+        return Err(interrupted(
+            outfile_path,
+            copied_bytes,
+            Some(infile_size),
+            resumable,
+            options,
+        ));
+    }
+    if options.direct {
+        // The last block was written whole.
+        io.outfile.allocate(infile_size)?;
+    }
+
+    if let Some(checkpoint) = checkpoint {
+        // Every thread has finished with it by now.
+        if let Ok(checkpoint) = Arc::try_unwrap(checkpoint) {
+            checkpoint.remove()?;
+        }
+    }
+    chunk_digests.sort_by_key(|chunk| chunk.range.start);
+    let chunk_digests = (resumed_bytes == 0).then_some(chunk_digests);
+    // Every thread has finished with it by now.
+    let digest = file_digest
+        .and_then(|digest| Arc::try_unwrap(digest).ok())
+        .map(|digest| digest.finish(infile_size));
+    Ok(CopiedFile {
+        source: metadata,
+        size: infile_size,
+        chunk_digests,
+        digest,
+    })
+}
+
+/// Copy all of `src` to `dest`, which can be any [`StorageBackend`], with
+/// the same parallel chunked copy [`copy_file`] uses, returning the number
+/// of bytes copied.
+///
+/// `dest` is first given the size of `src`. The number of threads, the
+/// buffer size, [`CopyOptions::verify`], [`CopyOptions::bwlimit`],
+/// [`CopyOptions::retries`], [`CopyOptions::cancel`] and progress apply as
+/// they do to files. Options that are about files on disk, such as
+/// preserving metadata or resuming, don't.
+pub fn copy_backend(
+    src: Arc<dyn StorageBackend>,
+    dest: Arc<dyn StorageBackend>,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let size = src
+        .len()
+        .map_err(|e| io_error("size", Path::new("source"), 0, e))?;
+    dest.allocate(size)
+        .map_err(|e| io_error("allocate", Path::new("destination"), 0, e))?;
+    let io = ChunkIo::new(
+        src,
+        Path::new("source"),
+        dest,
+        Path::new("destination"),
+        options,
+    );
+    let threads = if size < SMALL_FILE_SIZE {
+        1
+    } else {
+        options.threads as u64
+    };
+    let plan = ChunkPlan {
+        chunks: chunks(
+            std::slice::from_ref(&(0..size)),
+            chunk_size(size, threads, options.buffer_size),
+        ),
+        size,
+        done: 0,
+        threads,
+        buffer_size: options.buffer_size,
+    };
+    let algorithm = options
+        .verify
+        .then(|| options.verify_hash.unwrap_or(HashAlgorithm::Xxh3));
+    let (copied_bytes, mut chunk_digests) = copy_chunks(&io, plan, None, algorithm, None, options)?;
+    if copied_bytes < size && options.is_cancelled() {
+        return Err(cancelled(format!(
+            "Copy interrupted after {} of {} bytes",
+            copied_bytes, size
+        )));
+    }
+    if let Some(algorithm) = algorithm {
+        chunk_digests.sort_by_key(|chunk| chunk.range.start);
+        verify_chunks(
+            Path::new("destination"),
+            &*io.outfile,
+            size,
+            &chunk_digests,
+            algorithm,
+            options,
+        )?;
+    }
+    Ok(size)
+}
+
+/// Copy the file `src` to every one of `dests` in one pass, reading each
+/// chunk of it once and writing it to all of them at the same time,
+/// returning the number of bytes copied to each.
+///
+/// The threads, buffer size, bandwidth limit, retries and progress apply
+/// as they do to [`copy_file`], and so do [`CopyOptions::overwrite`], which
+/// leaves out destinations to be kept, backups, the space check,
+/// verification, which reads back every destination, and the metadata
+/// carried over. The data is always copied by the threads, without holes
+/// or the kernel's offloads. Existing files are replaced through temporary
+/// files as [`copy_file`] replaces them, or rewritten in place with
+/// [`CopyOptions::inplace`], once every destination has been opened, and
+/// the temporary files are renamed over them once all are copied.
+pub fn copy_fanout<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dests: &[Q],
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let src = src.as_ref();
+    let source = fs::metadata(src).map_err(|e| open_error(src, e))?;
+    let mut kept = Vec::new();
+    for dest in dests.iter().map(|dest| dest.as_ref()) {
+        match keep_existing(&source, dest, options)
+            .filter(|_| options.overwrite != Overwrite::Always)
+        {
+            Some(reason) => skip_existing(dest, reason, options),
+            None => kept.push(dest),
+        }
+    }
+    if kept.is_empty() {
+        return Ok(0);
+    }
+    if options.dry_run {
+        for dest in &kept {
+            let action = if dest.exists() { "overwrite" } else { "copy" };
+            println!(
+                "would {} '{}' -> '{}' ({} bytes)",
+                action,
+                src.display(),
+                dest.display(),
+                source.len()
+            );
+        }
+        return Ok(source.len());
+    }
+    // Destinations replaced through temporary files, with the path written.
+    let mut replaced = Vec::new();
+    let result = (|| {
+        let mut files = Vec::new();
+        for dest in &kept {
+            if options.space_check
+                && !fs::metadata(dest).is_ok_and(|metadata| is_block_device(&metadata))
+            {
+                check_space(dest, needed_space(&source, dest, options))?;
+            }
+            if let Some(backup) = backup_path(dest, options) {
+                if dest.symlink_metadata().is_ok() {
+                    back_up(dest, &backup, false)?;
+                }
+            }
+            let path = if updates_in_place(dest, options) {
+                dest.to_path_buf()
+            } else {
+                let temp = temp_path(dest, false);
+                replaced.push((temp.clone(), *dest, replaced_file(dest, options)));
+                temp
+            };
+            let file = open_options(options)?
+                .write(true)
+                .read(true)
+                .create(true)
+                .open(&path)
+                .map_err(|e| io_error("create", &path, 0, e))?;
+            files.push((path, file));
+        }
+        // Nothing is cut short until every destination could be opened.
+        for (path, file) in &files {
+            if file.metadata()?.is_file() {
+                file.set_len(0)
+                    .map_err(|e| format!("Failed to truncate '{}': {}", path.display(), e))?;
+            }
+        }
+        fan_out(src, &source, files, &kept, options)
+    })();
+    if result.is_err() {
+        for (temp, _, _) in &replaced {
+            let _ = fs::remove_file(temp);
+        }
+    }
+    let size = result?;
+    for (temp, dest, existing) in &replaced {
+        move_into_place(temp, dest, existing.as_ref())?;
+    }
+    if options.remove_source {
+        fs::remove_file(src)
+            .map_err(|e| format!("Failed to remove source '{}': {}", src.display(), e))?;
+    }
+    Ok(size)
+}
+
+/// The copy of [`copy_fanout`] once `files`, to be written for `dests`, are
+/// open.
+fn fan_out(
+    src: &Path,
+    source: &fs::Metadata,
+    files: Vec<(PathBuf, File)>,
+    kept: &[&Path],
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let start = Instant::now();
+    let infile = File::open(src).map_err(|e| open_error(src, e))?;
+    let size = StorageBackend::len(&infile)?;
+    let outfile = Arc::new(Fanout { files });
+    outfile.allocate(size)?;
+    log::info!("Copy '{}' -> {} destinations", src.display(), kept.len());
+    if !options.direct {
+        advise_sequential(&infile);
+    }
+    if options.progress && kept.len() == 1 {
+        eprintln!(" Copy {}", src.display());
+    } else if options.progress {
+        eprintln!(" Copy {} to {} destinations", src.display(), kept.len());
+    }
+    let io = ChunkIo::new(Arc::new(infile), src, outfile.clone(), kept[0], options);
+    let threads = if size < SMALL_FILE_SIZE {
+        1
+    } else {
+        options.threads as u64
+    };
+    let plan = ChunkPlan {
+        chunks: chunks(
+            std::slice::from_ref(&(0..size)),
+            chunk_size(size, threads, options.buffer_size),
+        ),
+        size,
+        done: 0,
+        threads,
+        buffer_size: options.buffer_size,
+    };
+    let algorithm = options
+        .verify
+        .then(|| options.verify_hash.unwrap_or(HashAlgorithm::Xxh3));
+    let (copied_bytes, mut chunk_digests) = copy_chunks(&io, plan, None, algorithm, None, options)?;
+    if copied_bytes < size && options.is_cancelled() {
+        // Every destination is removed or kept, the error is of the first.
+        let mut errors: Vec<Error> = outfile
+            .files
+            .iter()
+            .map(|(path, _)| interrupted(path, copied_bytes, Some(size), false, options))
+            .collect();
+        return Err(errors.remove(0));
+    }
+    chunk_digests.sort_by_key(|chunk| chunk.range.start);
+    for ((path, file), dest) in outfile.files.iter().zip(kept) {
+        if let Some(algorithm) = algorithm {
+            if let Err(e) = verify_chunks(path, file, size, &chunk_digests, algorithm, options) {
+                if let Some(stats) = &options.stats {
+                    stats.failed();
+                    stats.verify_failed(1);
+                }
+                return Err(e);
+            }
+        }
+        if !is_block_device(&file.metadata()?) {
+            preserve_metadata(src, source, path, options)?;
+        }
+        sync_file(path, options.fsync)?;
+        if let Some(stats) = &options.stats {
+            stats.copied(size);
+        }
+        if options.verbosity > 0 {
+            eprintln!("{}", copied_message(src, dest, size, start.elapsed()));
+        }
+    }
+    Ok(size)
+}
+
+/// The chunks of a file to copy, and how.
+struct ChunkPlan {
+    chunks: Vec<Range<u64>>,
+    /// Size of the whole file, and how much of it is in place already.
+    size: u64,
+    done: u64,
+    threads: u64,
+    buffer_size: usize,
+}
+
+/// Copy the chunks in `plan` with a pool of threads, hashing every chunk
+/// with `algorithm` when it is set. Returns the number of bytes in place
+/// once the threads stopped, which is short of the size only when the copy
+/// was cancelled, and the digests of the chunks in no particular order.
+fn copy_chunks(
+    io: &ChunkIo,
+    plan: ChunkPlan,
+    checkpoint: Option<Arc<Checkpoint>>,
+    algorithm: Option<HashAlgorithm>,
+    file_digest: Option<Arc<FileDigest>>,
+    options: &CopyOptions,
+) -> Result<(u64, Vec<ChunkDigest>), Error> {
+    // Direct IO does not go through the page cache at all.
+    let cached = !options.direct;
+    let drop_cache = cached && options.drop_cache;
+    // Dropping the cache already writes out every chunk.
+    let writeback = cached && options.writeback && !drop_cache;
+    let size = plan.size;
+
+    let mut threads = Vec::new();
+    // A recursive copy follows the file with a counter of its own.
+    let processed_bytes = options.progress_counter.clone().unwrap_or_default();
+    processed_bytes.fetch_add(plan.done, Ordering::SeqCst);
+
+    // Threads pull chunks off a shared queue until the file is done, so a
+    // slow thread never holds up work the others could be doing.
+    let chunks = Arc::new(plan.chunks);
+    let next_chunk = Arc::new(AtomicUsize::new(0));
+
+    // Set when a thread fails, so the others stop taking chunks.
+    let abort = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+
+    // Set up every thread's engine first, so a failure leaves nothing running.
+    // The pipeline has threads of its own.
+    let symmetric_threads = match options.engine {
+        Engine::Pipeline => 0,
+        _ => plan.threads.min(chunks.len() as u64),
+    };
+    let placement = options.numa_node.map(Placement::new).transpose()?;
+    let mut copiers = Vec::new();
+    for index in 0..symmetric_threads {
+        let wants_data = algorithm.is_some() || checkpoint.is_some() || file_digest.is_some();
+        // Only the first thread waits for memory for its buffers, the file
+        // gets fewer threads when there is none left for the others.
+        let copier = ChunkCopier::new(
+            options,
+            plan.buffer_size,
+            wants_data,
+            index == 0,
+            placement.as_ref(),
+        )?;
+        match copier {
+            Some(copier) => copiers.push(copier),
+            None => break,
+        }
+    }
+    // With adaptive threads only some of them take chunks at a time.
+    let scaler =
+        (options.adaptive && copiers.len() > 1).then(|| Arc::new(Scaler::new(copiers.len())));
+    let controller = scaler.clone().map(|scaler| {
+        let path = io.infile_path.clone();
+        let verbose = options.verbosity > 1;
+        thread::spawn(move || scaler.control(&path, verbose))
+    });
+
+    for (index, mut copier) in copiers.into_iter().enumerate() {
+        let io = io.clone();
+        let scaler = scaler.clone();
+        let processed_bytes = Arc::clone(&processed_bytes);
+        let checkpoint = checkpoint.clone();
+        let file_digest = file_digest.clone();
+        let chunks = Arc::clone(&chunks);
+        let next_chunk = Arc::clone(&next_chunk);
+        let abort = Arc::clone(&abort);
+        let cancel = options.cancel.clone();
+        let sender = sender.clone();
+
+        let t = thread::spawn(move || {
+            if let Some(placement) = placement {
+                placement.pin();
+            }
+            // Once this thread stops, no others need to wait for a turn.
+            let _finish = scaler.as_ref().map(|scaler| scaler.on_exit());
+            let mut copy_chunks = || -> Result<Vec<ChunkDigest>, Error> {
+                let mut digests = Vec::new();
+                // The chunk this thread copied last, still being written out.
+                let mut writing: Option<Range<u64>> = None;
+                let stopped = || {
+                    abort.load(Ordering::SeqCst)
+                        || cancel.as_ref().is_some_and(|c| c.load(Ordering::SeqCst))
+                };
+                loop {
+                    if let Some(scaler) = scaler.as_ref() {
+                        scaler.wait_turn(index);
+                    }
+                    if stopped() {
+                        break;
+                    }
+                    let number = next_chunk.fetch_add(1, Ordering::SeqCst);
+                    let Some(chunk) = chunks.get(number) else {
+                        break;
+                    };
+                    let mut hasher = algorithm.map(|a| a.hasher());
+                    let mut checkpoint_hasher = HashAlgorithm::Xxh3.hasher();
+                    if let Some(infile) = io.infile.as_file().filter(|_| cached) {
+                        will_need(infile, chunk);
+                    }
+
+                    let mut pos = chunk.start;
+                    let end = copier.copy_chunk(&io, chunk, &mut |data| {
+                        if let Some(hasher) = hasher.as_mut() {
+                            hasher.update(data);
+                        }
+                        if let Some(file_digest) = file_digest.as_ref() {
+                            file_digest.update(number, pos, data, &stopped);
+                            pos += data.len() as u64;
+                        }
+                        if checkpoint.is_some() {
+                            checkpoint_hasher.update(data);
+                        }
+                        processed_bytes.fetch_add(data.len() as u64, Ordering::SeqCst);
+                        if let Some(scaler) = scaler.as_ref() {
+                            scaler.count(data.len() as u64);
+                        }
+                    })?;
+                    if let Some(file_digest) = file_digest.as_ref() {
+                        file_digest.chunk_done(number, &stopped);
+                    }
+                    if !copier.shows_data() {
+                        processed_bytes.fetch_add(end - chunk.start, Ordering::SeqCst);
+                        if let Some(scaler) = scaler.as_ref() {
+                            scaler.count(end - chunk.start);
+                        }
+                    }
+                    if let (Some(infile), Some(outfile), true) =
+                        (io.infile.as_file(), io.outfile.as_file(), drop_cache)
+                    {
+                        drop_cached(infile, outfile, &(chunk.start..end));
+                    }
+                    if let Some(outfile) = io.outfile.as_file().filter(|_| writeback) {
+                        // Wait for the chunk before, which has had the time
+                        // this one took to be written out, so each thread
+                        // keeps at most two chunks dirty.
+                        start_writeback(outfile, &(chunk.start..end));
+                        if let Some(written) = writing.replace(chunk.start..end) {
+                            finish_writeback(outfile, &written);
+                        }
+                    }
+
+                    if let Some(checkpoint) = checkpoint.as_ref() {
+                        checkpoint.record(&(chunk.start..end), &checkpoint_hasher.finish())?;
+                    }
+                    if let Some(hasher) = hasher {
+                        digests.push(ChunkDigest {
+                            range: chunk.start..end,
+                            digest: hasher.finish(),
+                        });
+                    }
+                }
+                if let (Some(outfile), Some(written)) = (io.outfile.as_file(), writing) {
+                    finish_writeback(outfile, &written);
+                }
+                Ok(digests)
+            };
+            let result = copy_chunks();
+            if result.is_err() {
+                abort.store(true, Ordering::SeqCst);
+            }
+            // The receiver only goes away once every thread has reported.
+            let _ = sender.send(result);
+        });
+        threads.push(t);
+    }
+    if options.engine == Engine::Pipeline && !chunks.is_empty() {
+        // Small files are read and written by one thread each, as the
+        // other engines copy them with one.
+        let pool = |threads: Option<usize>| match plan.threads {
+            1 => 1,
+            _ => threads.unwrap_or(plan.threads as usize),
+        };
+        let pipeline = Pipeline {
+            io: io.clone(),
+            chunks: Arc::clone(&chunks),
+            buffer_size: plan.buffer_size,
+            algorithm,
+            checkpoint: checkpoint.clone(),
+            processed_bytes: Arc::clone(&processed_bytes),
+            abort: Arc::clone(&abort),
+            cancel: options.cancel.clone(),
+            drop_cache,
+            buffer_pool: options.buffer_pool.clone(),
+            placement,
+            file_digest: file_digest.clone(),
+        };
+        // Readers waiting their turn to hash could hold every buffer the
+        // one whose turn it is needs, so the digest of a file is taken by
+        // one reader.
+        let readers = match file_digest {
+            Some(_) => 1,
+            None => pool(options.read_threads).min(chunks.len()),
+        };
+        threads.extend(pipeline.spawn(readers, pool(options.write_threads), &sender));
+    }
+    drop(sender);
+
+    let mut chunk_digests = Vec::new();
+    let mut failure = None;
+    let collect = || {
+        for result in receiver {
+            match result {
+                Ok(digests) => chunk_digests.extend(digests),
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        threads.into_iter().any(|t| t.join().is_err())
+    };
+    // Files of a recursive copy are part of its progress.
+    let panicked = if options.progress_counter.is_none() {
+        let progress = Progress::new(size, options);
+        progress.follow(Arc::clone(&processed_bytes));
+        progress.show(collect)
+    } else {
+        collect()
+    };
+    if let Some(controller) = controller {
+        let _ = controller.join();
+    }
+
+    // The checkpoint is kept on failure so the copy can be resumed.
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    if panicked {
+        return Err(format!("A thread copying '{}' panicked", io.infile_path.display()).into());
+    }
+    Ok((processed_bytes.load(Ordering::SeqCst), chunk_digests))
+}
+
+/// Deal with a destination the copy was cancelled part way through, and
+/// describe what was copied of its `size`, when that is known. One with a checkpoint is left for resuming,
+/// others are removed or kept as `<dest>.partial`. Devices and other files
+/// that are not regular ones are left where they are, with the data copied
+/// so far.
+pub(crate) fn interrupted(
+    dest: &Path,
+    copied: u64,
+    size: Option<u64>,
+    resumable: bool,
+    options: &CopyOptions,
+) -> Error {
+    let regular = fs::metadata(dest).map_or(true, |metadata| metadata.is_file());
+    let outcome = if resumable {
+        "it can be resumed from its checkpoint".to_string()
+    } else if !regular {
+        "it holds the data copied so far".to_string()
+    } else if options.keep_partial {
+        let mut partial = dest.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        match fs::rename(dest, &partial) {
+            Ok(()) => format!("the partial copy was kept as '{}'", partial.display()),
+            Err(e) => format!("the partial copy could not be renamed: {}", e),
+        }
+    } else {
+        match fs::remove_file(dest) {
+            Ok(()) => "the partial copy was removed".to_string(),
+            Err(e) => format!("the partial copy could not be removed: {}", e),
+        }
+    };
+    let of_size = size.map(|size| format!(" of {}", size)).unwrap_or_default();
+    cancelled(format!(
+        "Copy to '{}' interrupted after {}{} bytes, {}",
+        dest.display(),
+        copied,
+        of_size,
+        outcome
+    ))
+}
+
+/// The storage a copy thread moves data between, and what happens to each
+/// block on the way.
+#[derive(Clone)]
+pub(crate) struct ChunkIo {
+    pub(crate) infile: Arc<dyn StorageBackend>,
+    pub(crate) outfile: Arc<dyn StorageBackend>,
+    pub(crate) infile_path: PathBuf,
+    pub(crate) outfile_path: PathBuf,
+    pub(crate) skip_zeros: bool,
+    bwlimit: Option<Arc<RateLimiter>>,
+    pub(crate) direct: bool,
+    pub(crate) retries: u32,
+    retry_delay: Duration,
+    stats: Option<Arc<CopyStats>>,
+}
+
+impl ChunkIo {
+    fn new(
+        infile: Arc<dyn StorageBackend>,
+        infile_path: &Path,
+        outfile: Arc<dyn StorageBackend>,
+        outfile_path: &Path,
+        options: &CopyOptions,
+    ) -> Self {
+        ChunkIo {
+            infile,
+            outfile,
+            infile_path: infile_path.to_path_buf(),
+            outfile_path: outfile_path.to_path_buf(),
+            skip_zeros: options.sparse == Sparse::Always,
+            bwlimit: options.bwlimit.clone(),
+            direct: options.direct,
+            retries: options.retries,
+            retry_delay: options.retry_delay,
+            stats: options.stats.clone(),
+        }
+    }
+
+    /// Read into `buffer` from `offset`, returning how many bytes were read.
+    pub(crate) fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<usize, Error> {
+        self.retry("read", &self.infile_path, || {
+            self.infile.read_at(buffer, offset).map_err(|e| (offset, e))
+        })
+    }
+
+    /// Whether `data` needs writing, waiting on the bandwidth limit when it
+    /// does. Blocks of zeros are left as holes with [`Sparse::Always`].
+    pub(crate) fn admit(&self, data: &[u8]) -> bool {
+        if self.skip_zeros && is_zero(data) {
+            return false;
+        }
+        self.throttle(data.len());
+        true
+    }
+
+    /// Wait until `len` bytes may be written under the bandwidth limit.
+    pub(crate) fn throttle(&self, len: usize) {
+        if let Some(bwlimit) = self.bwlimit.as_ref() {
+            bwlimit.take(len);
+        }
+    }
+
+    /// How many bytes to write for `len` bytes of data. Direct IO pads the
+    /// last block of the file out to the alignment, the buffers are big
+    /// enough for it and the file is truncated back afterwards.
+    pub(crate) fn write_len(&self, len: usize) -> usize {
+        if self.direct {
+            align_up(len as u64) as usize
+        } else {
+            len
+        }
+    }
+
+    /// Write all of `data` at `offset`.
+    pub(crate) fn write_all_at(&self, data: &[u8], offset: u64) -> Result<(), Error> {
+        self.retry("write", &self.outfile_path, || {
+            self.outfile.write_all_at(data, offset)
+        })
+    }
+
+    /// Run `io` until it succeeds, retrying transient errors as set with
+    /// [`CopyOptions::retries`]. The delay doubles after every attempt.
+    pub(crate) fn retry<T>(
+        &self,
+        action: &str,
+        path: &Path,
+        mut io: impl FnMut() -> Result<T, (u64, io::Error)>,
+    ) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            match io() {
+                Ok(value) => return Ok(value),
+                Err((offset, e)) if attempt < self.retries && is_transient(&e) => {
+                    let delay = self.retry_delay * 2u32.pow(attempt.min(16));
+                    attempt += 1;
+                    let message = format!(
+                        "{}, retry {} of {} in {:.1?}",
+                        io_error(action, path, offset, e),
+                        attempt,
+                        self.retries,
+                        delay
+                    );
+                    log::warn!("{}", message);
+                    if let Some(stats) = &self.stats {
+                        stats.retried();
+                    }
+                    thread::sleep(delay);
+                }
+                Err((offset, e)) => return Err(io_error(action, path, offset, e)),
+            }
+        }
+    }
+}
+
+/// The state each copy thread keeps for the [`Engine`] in use.
+enum ChunkCopier {
+    Pread(PooledBuffer),
+    #[cfg(feature = "io-uring")]
+    IoUring(Box<Ring>),
+    /// Writes straight out of a mapping of the source, `buffer_size` bytes
+    /// at a time.
+    #[cfg(unix)]
+    Mmap(usize),
+    #[cfg(target_os = "linux")]
+    Sendfile(Box<Splicer>),
+    #[cfg(target_os = "linux")]
+    Vectored(Vectored),
+}
+
+impl ChunkCopier {
+    /// A copier for the engine in `options`. Engines that copy without seeing
+    /// the data read it back when it `wants_data`, for its hashes.
+    ///
+    /// Buffers come from the pool of `options` when it has one. With `wait`
+    /// this waits for memory for them, otherwise there is no copier when
+    /// the pool has none left. With a `placement` they are put in the memory
+    /// of its node.
+    fn new(
+        options: &CopyOptions,
+        buffer_size: usize,
+        wants_data: bool,
+        wait: bool,
+        placement: Option<&Placement>,
+    ) -> Result<Option<Self>, Error> {
+        let pool = options.buffer_pool.as_ref();
+        let take = |wanted| {
+            let buffers = take_buffers(pool, buffer_size, wanted, wait as usize);
+            if let Some(placement) = placement {
+                buffers.iter().for_each(|buffer| placement.bind(buffer));
+            }
+            buffers
+        };
+        let copier = match options.engine {
+            Engine::Pread => match take(1).pop() {
+                Some(buffer) => ChunkCopier::Pread(buffer),
+                None => return Ok(None),
+            },
+            #[cfg(feature = "io-uring")]
+            Engine::IoUring => match take(crate::uring::QUEUE_DEPTH) {
+                buffers if buffers.is_empty() => return Ok(None),
+                buffers => ChunkCopier::IoUring(Box::new(Ring::new(buffers)?)),
+            },
+            #[cfg(not(feature = "io-uring"))]
+            Engine::IoUring => {
+                return Err(
+                    "This build has no io_uring support, rebuild with --features io-uring".into(),
+                )
+            }
+            #[cfg(unix)]
+            Engine::Mmap => ChunkCopier::Mmap(buffer_size),
+            #[cfg(not(unix))]
+            Engine::Mmap => return Err("The mmap engine is not supported on this platform".into()),
+            #[cfg(target_os = "linux")]
+            Engine::Sendfile => {
+                let read_back = if wants_data {
+                    match take(1).pop() {
+                        Some(buffer) => Some(buffer),
+                        None => return Ok(None),
+                    }
+                } else {
+                    None
+                };
+                ChunkCopier::Sendfile(Box::new(Splicer::new(buffer_size, read_back)))
+            }
+            #[cfg(not(target_os = "linux"))]
+            Engine::Sendfile => {
+                let _ = wants_data;
+                return Err("The sendfile engine is only supported on Linux".into());
+            }
+            #[cfg(target_os = "linux")]
+            Engine::Vectored => match take(vectored::BATCH) {
+                buffers if buffers.is_empty() => return Ok(None),
+                buffers => ChunkCopier::Vectored(Vectored::new(buffers)),
+            },
+            #[cfg(not(target_os = "linux"))]
+            Engine::Vectored => {
+                return Err("The preadv engine is only supported on Linux".into());
+            }
+            Engine::Pipeline => {
+                return Err("The pipeline engine does not copy chunk by chunk".into())
+            }
+        };
+        Ok(Some(copier))
+    }
+
+    /// Copy `chunk`, passing the data to `on_data` in order as it goes.
+    /// Returns where the copy stopped, which is short of the end of the
+    /// chunk only if the source is shorter than expected.
+    fn copy_chunk(
+        &mut self,
+        io: &ChunkIo,
+        chunk: &Range<u64>,
+        on_data: &mut dyn FnMut(&[u8]),
+    ) -> Result<u64, Error> {
+        match self {
+            ChunkCopier::Pread(buffer) => {
+                let mut pos = chunk.start;
+                while pos < chunk.end {
+                    // Never read past the end of this chunk.
+                    let want = buffer.len().min((chunk.end - pos) as usize);
+                    let bytes_read = io.read_at(&mut buffer[..want], pos)?;
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    let data = &buffer[..bytes_read];
+                    if io.admit(data) {
+                        io.write_all_at(&buffer[..io.write_len(bytes_read)], pos)?;
+                    }
+                    on_data(data);
+                    pos += bytes_read as u64;
+                    // Direct reads only come up short at the end of the file.
+                    if io.direct && bytes_read < want {
+                        break;
+                    }
+                }
+                Ok(pos)
+            }
+            #[cfg(feature = "io-uring")]
+            ChunkCopier::IoUring(ring) => ring.copy_chunk(io, chunk, on_data),
+            #[cfg(unix)]
+            ChunkCopier::Mmap(piece_size) => mmap::copy_chunk(io, chunk, *piece_size, on_data),
+            #[cfg(target_os = "linux")]
+            ChunkCopier::Sendfile(splicer) => splicer.copy_chunk(io, chunk, on_data),
+            #[cfg(target_os = "linux")]
+            ChunkCopier::Vectored(vectored) => vectored.copy_chunk(io, chunk, on_data),
+        }
+    }
+
+    /// Whether `on_data` is given all the data copied. When it is not the
+    /// copy is counted once each chunk is done.
+    fn shows_data(&self) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            ChunkCopier::Sendfile(splicer) => splicer.shows_data(),
+            _ => true,
+        }
+    }
+}
+
+/// Options for opening sources and destinations, bypassing the page cache
+/// with [`CopyOptions::direct`].
+fn open_options(options: &CopyOptions) -> Result<OpenOptions, Error> {
+    let mut open_options = OpenOptions::new();
+    if options.direct {
+        bypass_cache(&mut open_options)?;
+    }
+    Ok(open_options)
+}
+
+#[cfg(target_os = "linux")]
+fn bypass_cache(open_options: &mut OpenOptions) -> Result<(), Error> {
+    open_options.custom_flags(nix::libc::O_DIRECT);
+    Ok(())
+}
+
+/// FILE_FLAG_NO_BUFFERING, which has the same alignment rules as O_DIRECT.
+#[cfg(windows)]
+fn bypass_cache(open_options: &mut OpenOptions) -> Result<(), Error> {
+    open_options.custom_flags(0x2000_0000);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn bypass_cache(_open_options: &mut OpenOptions) -> Result<(), Error> {
+    Err("Direct IO is not supported on this platform".into())
+}
+
+pub(crate) fn open_error(path: &Path, e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => format!(
+            "The input file {} does not exist. Please check the file path and try again.",
+            path.display()
+        ),
+        _ => format!("Failed to open input file: {}, {:?}", path.display(), e),
+    }
+    .into()
+}
+
+pub(crate) fn io_error(action: &str, path: &Path, offset: u64, error: io::Error) -> Error {
+    format!(
+        "Failed to {} '{}' at offset {}: {}",
+        action,
+        path.display(),
+        offset,
+        error
+    )
+    .into()
+}
+
+/// Errors that can clear up by themselves, like a network filesystem timing
+/// out or its server restarting.
+fn is_transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    if matches!(
+        e.kind(),
+        Interrupted
+            | WouldBlock
+            | TimedOut
+            | ConnectionReset
+            | ConnectionAborted
+            | NotConnected
+            | StaleNetworkFileHandle
+            | ResourceBusy
+    ) {
+        return true;
+    }
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(nix::libc::EIO) {
+        return true;
+    }
+    false
+}
+
+/// Size of the chunks a file is copied in.
+///
+/// Aim for a few chunks per thread so fast threads can take over work from
+/// slow ones, without going below the buffer size or above 64Mb.
+pub(crate) fn chunk_size(size: u64, num_threads: u64, buffer_size: usize) -> u64 {
+    let buffer_size = buffer_size as u64;
+    (size / (num_threads * 4)).clamp(buffer_size, (64 * 1024 * 1024).max(buffer_size))
+}
+
+/// Split each of `ranges` into chunks of at most `chunk_size` bytes.
+pub(crate) fn chunks(ranges: &[Range<u64>], chunk_size: u64) -> Vec<Range<u64>> {
+    let mut chunks = Vec::new();
+    for range in ranges {
+        let mut start = range.start;
+        while start < range.end {
+            let end = range.end.min(start + chunk_size);
+            chunks.push(start..end);
+            start = end;
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn temp_paths_are_unique() {
+        let dest = Path::new("dir/file");
+        let temps: HashSet<PathBuf> = (0..1000).map(|_| temp_path(dest, false)).collect();
+        assert_eq!(temps.len(), 1000);
+        assert!(temps.iter().all(|temp| temp.starts_with("dir")));
+        assert_eq!(temp_path(dest, true), Path::new("dir/file.rpcp-tmp"));
+    }
+
+    #[test]
+    fn sizes_chunks() {
+        const MIB: u64 = 1024 * 1024;
+        for (size, threads, buffer_size, expected) in [
+            // Never below a buffer.
+            (0, 4, MIB, MIB),
+            (1000, 4, MIB, MIB),
+            (16 * MIB - 1, 4, MIB, MIB),
+            // Four chunks a thread.
+            (160 * MIB, 4, MIB, 10 * MIB),
+            (160 * MIB + 1, 4, MIB, 10 * MIB),
+            // Never above 64 MiB, unless a buffer is.
+            (64 * 1024 * MIB, 4, MIB, 64 * MIB),
+            (64 * 1024 * MIB, 4, 128 * MIB, 128 * MIB),
+        ] {
+            assert_eq!(
+                chunk_size(size, threads, buffer_size as usize),
+                expected,
+                "{} bytes with {} threads",
+                size,
+                threads
+            );
+        }
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn splits_ranges_into_chunks() {
+        assert_eq!(chunks(&[], 100), []);
+        assert_eq!(chunks(&[0..0], 100), []);
+        assert_eq!(chunks(&[0..50], 100), [0..50]);
+        assert_eq!(chunks(&[0..100], 100), [0..100]);
+        assert_eq!(chunks(&[0..300], 100), [0..100, 100..200, 200..300]);
+        assert_eq!(
+            chunks(&[0..301], 100),
+            [0..100, 100..200, 200..300, 300..301]
+        );
+        assert_eq!(
+            chunks(&[0..150, 150..150, 200..250], 100),
+            [0..100, 100..150, 200..250]
+        );
+    }
+
+    #[test]
+    fn copies_at_chunk_boundaries() {
+        let dir = crate::testing::scratch("chunk-boundaries");
+        // With 4 threads and 1 MiB buffers files of these sizes are copied
+        // in chunks of 1 MiB, by each engine reading and writing chunks.
+        const MIB: usize = 1024 * 1024;
+        let options = CopyOptions::new().threads(4).buffer_size(MIB).verify(true);
+        for size in [0, 1, MIB - 1, MIB, 4 * MIB, 4 * MIB + 1] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let (src, dest) = (
+                dir.join(format!("{}", size)),
+                dir.join(format!("{}.copy", size)),
+            );
+            fs::write(&src, &data).unwrap();
+            for engine in [Engine::Pread, Engine::Pipeline] {
+                let options = options.clone().engine(engine);
+                assert_eq!(copy_file(&src, &dest, &options).unwrap(), size as u64);
+                assert_eq!(fs::read(&dest).unwrap(), data, "{} bytes", size);
+                fs::remove_file(&dest).unwrap();
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn leaves_interrupted_devices_in_place() {
+        use nix::sys::stat::{mknod, Mode, SFlag};
+        use std::os::unix::fs::FileTypeExt;
+        let dir = crate::testing::scratch("interrupted");
+        let (fifo, file) = (dir.join("fifo"), dir.join("file"));
+        mknod(&fifo, SFlag::S_IFIFO, Mode::from_bits_truncate(0o600), 0).unwrap();
+        fs::write(&file, b"part").unwrap();
+        for keep_partial in [false, true] {
+            let options = CopyOptions::new().keep_partial(keep_partial);
+            let e = interrupted(&fifo, 4, Some(8), false, &options);
+            assert!(fs::symlink_metadata(&fifo).unwrap().file_type().is_fifo());
+            assert!(
+                e.to_string().ends_with("it holds the data copied so far"),
+                "{}",
+                e
+            );
+        }
+        assert!(!dir.join("fifo.partial").exists());
+
+        interrupted(&file, 4, Some(8), false, &CopyOptions::new());
+        assert!(!file.exists());
+    }
+}
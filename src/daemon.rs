@@ -0,0 +1,681 @@
+use rpcp::Error;
+use std::path::Path;
+
+/// Take copy jobs on the Unix socket `socket` and run them, `jobs` at a time
+/// and in the order they came, until interrupted.
+///
+/// Each job is the arguments of an rpcp copy or sync, run as a process of
+/// its own with a control socket next to `socket`, so the combined write
+/// rate of the jobs running can be kept to `bwlimit` by giving each its
+/// share. Commands come one per line and are answered with a line:
+///
+/// - `submit ARGS` queues the copy with the arguments ARGS, quoted as a
+///   shell would, answering `ok ID`. Jobs run in the daemon's directory,
+///   so their local paths must be absolute.
+/// - `status` answers with a JSON array of the jobs, and `status ID` with
+///   the job ID alone.
+/// - `cancel ID`, `pause ID` and `resume ID` stop, hold and release a job.
+///
+/// With `metrics` the totals of all jobs and how many are in each state are
+/// served to Prometheus on that address.
+pub(crate) fn run_daemon(
+    socket: &Path,
+    jobs: usize,
+    bwlimit: Option<u64>,
+    metrics: Option<&str>,
+) -> Result<(), Error> {
+    imp::run_daemon(socket, jobs, bwlimit, metrics)
+}
+
+#[cfg(unix)]
+mod imp {
+    use crate::{command, is_local, json_string, subcommand_matches, EXIT_INTERRUPTED};
+    use clap::parser::ValueSource;
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::stat::{umask, Mode};
+    use nix::unistd::Pid;
+    use rpcp::{serve_metrics, Error, Metrics};
+    use std::fmt::Write as _;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::os::unix::process::CommandExt;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// How long to wait for the daemon to be interrupted before looking again.
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum State {
+        Queued,
+        Running,
+        Done,
+        Failed,
+        Cancelled,
+    }
+
+    impl State {
+        fn name(self) -> &'static str {
+            match self {
+                State::Queued => "queued",
+                State::Running => "running",
+                State::Done => "done",
+                State::Failed => "failed",
+                State::Cancelled => "cancelled",
+            }
+        }
+    }
+
+    struct Job {
+        id: u64,
+        args: Vec<String>,
+        /// The job's own --bwlimit, which its share of the budget never
+        /// raises.
+        bwlimit: Option<u64>,
+        state: State,
+        pid: Option<u32>,
+        cancelled: bool,
+        exit_code: Option<i32>,
+        /// The last line the job printed, which says why it failed.
+        message: Option<String>,
+        /// Where the job writes its statistics once it is done, and whether
+        /// it was asked to itself rather than by the daemon.
+        stats_file: Option<(PathBuf, bool)>,
+        /// What the job had done when last asked.
+        metrics: Metrics,
+    }
+
+    /// What the daemon has to know of the arguments of a job.
+    struct JobArgs {
+        bwlimit: Option<u64>,
+        stats_file: Option<PathBuf>,
+        dry_run: bool,
+    }
+
+    struct Daemon {
+        socket: PathBuf,
+        max_jobs: usize,
+        bwlimit: Option<u64>,
+        /// Every job submitted, in order, finished ones included.
+        jobs: Mutex<Vec<Job>>,
+        /// Notified when a job is queued or finishes.
+        changed: Condvar,
+    }
+
+    pub(super) fn run_daemon(
+        socket: &Path,
+        max_jobs: usize,
+        bwlimit: Option<u64>,
+        metrics: Option<&str>,
+    ) -> Result<(), Error> {
+        let program = std::env::current_exe()
+            .map_err(|e| format!("Failed to find the rpcp executable: {}", e))?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let handler_stopping = Arc::clone(&stopping);
+        ctrlc::set_handler(move || {
+            if handler_stopping.swap(true, Ordering::SeqCst) {
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+            eprintln!("\nInterrupted, stopping the jobs. Interrupt again to quit at once.");
+        })
+        .map_err(|e| format!("Failed to set up the signal handler: {}", e))?;
+
+        let listener = listen(socket)?;
+        let daemon = Arc::new(Daemon {
+            socket: socket.to_path_buf(),
+            max_jobs: max_jobs.max(1),
+            bwlimit,
+            jobs: Mutex::new(Vec::new()),
+            changed: Condvar::new(),
+        });
+        if let Some(addr) = metrics {
+            let daemon = Arc::clone(&daemon);
+            serve_metrics(addr, move || daemon.metrics())?;
+        }
+        let served = Arc::clone(&daemon);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let daemon = Arc::clone(&served);
+                thread::spawn(move || daemon.serve(stream));
+            }
+        });
+        eprintln!(
+            "Taking jobs on '{}', running {} at a time",
+            socket.display(),
+            daemon.max_jobs
+        );
+        log::info!("Taking jobs on '{}'", socket.display());
+
+        let mut jobs = daemon.jobs.lock().unwrap();
+        while !stopping.load(Ordering::SeqCst) {
+            let running = jobs
+                .iter()
+                .filter(|job| job.state == State::Running)
+                .count();
+            let next = jobs.iter().position(|job| job.state == State::Queued);
+            match next {
+                Some(next) if running < daemon.max_jobs => {
+                    let share = daemon.share(running + 1);
+                    if let Err(e) = daemon.start(&program, &mut jobs[next], share) {
+                        jobs[next].state = State::Failed;
+                        jobs[next].message = Some(e.to_string());
+                        eprintln!("Job {} failed to start: {}", jobs[next].id, e);
+                    }
+                    // Those already running give up some of their share.
+                    drop(jobs);
+                    daemon.rebalance();
+                    jobs = daemon.jobs.lock().unwrap();
+                }
+                _ => jobs = daemon.changed.wait_timeout(jobs, POLL_INTERVAL).unwrap().0,
+            }
+        }
+
+        // Running jobs stop as they would on Ctrl-C, cleaning up after
+        // themselves, and those queued are dropped.
+        for job in jobs.iter_mut() {
+            match (job.state, job.pid) {
+                (State::Queued, _) => job.state = State::Cancelled,
+                (State::Running, Some(pid)) => {
+                    job.cancelled = true;
+                    let _ = kill(Pid::from_raw(pid as i32), Signal::SIGTERM);
+                }
+                _ => {}
+            }
+        }
+        while jobs.iter().any(|job| job.state == State::Running) {
+            jobs = daemon.changed.wait(jobs).unwrap();
+        }
+        drop(jobs);
+        let _ = std::fs::remove_file(socket);
+        Ok(())
+    }
+
+    /// Listen on `socket`, replacing one left behind by a daemon that is gone.
+    /// Jobs run as the daemon's user, so only that user can connect to it.
+    fn listen(socket: &Path) -> Result<UnixListener, Error> {
+        if socket.exists() {
+            if UnixStream::connect(socket).is_ok() {
+                return Err(
+                    format!("Socket '{}' is in use by another daemon", socket.display()).into(),
+                );
+            }
+            std::fs::remove_file(socket)
+                .map_err(|e| format!("Failed to remove '{}': {}", socket.display(), e))?;
+        }
+        // Created 0600 from the start, so nobody connects before a chmod.
+        let mask = umask(Mode::from_bits_truncate(0o177));
+        let listener = UnixListener::bind(socket);
+        umask(mask);
+        listener
+            .map_err(|e| format!("Failed to listen on socket '{}': {}", socket.display(), e).into())
+    }
+
+    impl Daemon {
+        /// The control socket of job `id`.
+        fn control_path(&self, id: u64) -> PathBuf {
+            let mut path = self.socket.clone().into_os_string();
+            path.push(format!(".{}", id));
+            PathBuf::from(path)
+        }
+
+        /// Where job `id` writes its statistics when it has no file of its own.
+        fn stats_path(&self, id: u64) -> PathBuf {
+            let mut path = self.control_path(id).into_os_string();
+            path.push(".stats");
+            PathBuf::from(path)
+        }
+
+        /// The write rate each of `running` jobs may have, if limited.
+        fn share(&self, running: usize) -> Option<u64> {
+            self.bwlimit
+                .map(|budget| (budget / running.max(1) as u64).max(1))
+        }
+
+        /// Run `job` with a limit of `share`, and see to it when it ends.
+        fn start(
+            self: &Arc<Self>,
+            program: &Path,
+            job: &mut Job,
+            share: Option<u64>,
+        ) -> Result<(), Error> {
+            let mut command = Command::new(program);
+            command
+                .args(&job.args)
+                .env("RPCP_CONTROL", self.control_path(job.id))
+                .env("RPCP_QUIET", "true")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                // Ctrl-C at the terminal reaches the daemon alone, which
+                // passes it on once.
+                .process_group(0);
+            if let Some(share) = share {
+                // A --bwlimit of the job's own takes precedence over this.
+                command.env("RPCP_BWLIMIT", share.to_string());
+            }
+            if let Some((path, false)) = &job.stats_file {
+                command.env("RPCP_STATS_FILE", path);
+            }
+            let mut child = command.spawn()?;
+            job.state = State::Running;
+            job.pid = Some(child.id());
+            eprintln!("Job {} started", job.id);
+            log::info!("Job {} started: {}", job.id, job.args.join(" "));
+
+            let (daemon, id) = (Arc::clone(self), job.id);
+            let stderr = child.stderr.take();
+            thread::spawn(move || {
+                let message = stderr.and_then(|stderr| {
+                    BufReader::new(stderr)
+                        .lines()
+                        .map_while(Result::ok)
+                        .filter(|line| !line.trim().is_empty())
+                        .last()
+                });
+                let status = child.wait();
+                let mut jobs = daemon.jobs.lock().unwrap();
+                if let Some(job) = jobs.iter_mut().find(|job| job.id == id) {
+                    job.exit_code = status.as_ref().ok().and_then(|status| status.code());
+                    job.state = if job.cancelled {
+                        State::Cancelled
+                    } else if status.is_ok_and(|status| status.success()) {
+                        State::Done
+                    } else {
+                        State::Failed
+                    };
+                    job.message = message;
+                    job.pid = None;
+                    // The final counts, which the job may not have been
+                    // asked for since it last copied something.
+                    if let Some((path, own)) = &job.stats_file {
+                        if let Ok(stats) = std::fs::read_to_string(path) {
+                            job.metrics = merge(job.metrics, metrics_from(&stats));
+                        }
+                        if !own {
+                            let _ = std::fs::remove_file(path);
+                        }
+                    }
+                    job.metrics.bytes_per_second = 0;
+                    eprintln!("Job {} {}", id, job.state.name());
+                    log::info!("Job {} {}", id, job.state.name());
+                }
+                drop(jobs);
+                let _ = std::fs::remove_file(daemon.control_path(id));
+                daemon.changed.notify_all();
+                daemon.rebalance();
+            });
+            Ok(())
+        }
+
+        /// Split the bandwidth budget evenly between the jobs running.
+        fn rebalance(&self) {
+            let running: Vec<(u64, Option<u64>)> = self
+                .jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|job| job.state == State::Running)
+                .map(|job| (job.id, job.bwlimit))
+                .collect();
+            let Some(share) = self.share(running.len()) else {
+                return;
+            };
+            for (id, own) in running {
+                let limit = own.map_or(share, |own| own.min(share));
+                // A job that has only just started has its share from its
+                // environment.
+                let _ = self.send(id, &format!("set-bwlimit {}", limit));
+            }
+        }
+
+        /// Send `command` to the control socket of job `id` and return its
+        /// answer.
+        fn send(&self, id: u64, command: &str) -> Result<String, Error> {
+            let mut stream = UnixStream::connect(self.control_path(id))?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            writeln!(stream, "{}", command)?;
+            let mut answer = String::new();
+            BufReader::new(stream).read_line(&mut answer)?;
+            Ok(answer.trim_end().to_string())
+        }
+
+        /// Answer the commands sent on `stream` until it is closed.
+        fn serve(&self, stream: UnixStream) {
+            let Ok(mut writer) = stream.try_clone() else {
+                return;
+            };
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else {
+                    return;
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let answer = self
+                    .answer(&line)
+                    .unwrap_or_else(|e| format!("error: {}", e));
+                if writeln!(writer, "{}", answer).is_err() {
+                    return;
+                }
+            }
+        }
+
+        /// The answer to the command `line`.
+        fn answer(&self, line: &str) -> Result<String, String> {
+            let line = line.trim();
+            let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            match name {
+                "submit" => {
+                    let args = shlex::split(rest)
+                        .filter(|args| !args.is_empty())
+                        .ok_or("submit takes the arguments of a copy, quoted as for a shell")?;
+                    let job_args = check_job(&args)?;
+                    let mut jobs = self.jobs.lock().unwrap();
+                    let id = jobs.last().map_or(1, |job| job.id + 1);
+                    // A dry run writes no statistics.
+                    let stats_file = match job_args.stats_file {
+                        Some(path) => Some((path, true)),
+                        None if job_args.dry_run => None,
+                        None => Some((self.stats_path(id), false)),
+                    };
+                    log::info!("Job {} queued: {}", id, args.join(" "));
+                    jobs.push(Job {
+                        id,
+                        args,
+                        bwlimit: job_args.bwlimit,
+                        state: State::Queued,
+                        pid: None,
+                        cancelled: false,
+                        exit_code: None,
+                        message: None,
+                        stats_file,
+                        metrics: Metrics::default(),
+                    });
+                    self.changed.notify_all();
+                    Ok(format!("ok {}", id))
+                }
+                "status" if rest.is_empty() => {
+                    let ids: Vec<u64> =
+                        self.jobs.lock().unwrap().iter().map(|job| job.id).collect();
+                    let statuses: Vec<String> =
+                        ids.into_iter().filter_map(|id| self.status(id)).collect();
+                    Ok(format!("[{}]", statuses.join(",")))
+                }
+                "status" => {
+                    let id = job_id(rest)?;
+                    self.status(id).ok_or_else(|| no_job(id))
+                }
+                "cancel" => {
+                    let id = job_id(rest)?;
+                    let mut jobs = self.jobs.lock().unwrap();
+                    let job = jobs
+                        .iter_mut()
+                        .find(|job| job.id == id)
+                        .ok_or_else(|| no_job(id))?;
+                    match (job.state, job.pid) {
+                        (State::Queued, _) => job.state = State::Cancelled,
+                        (State::Running, Some(pid)) => {
+                            job.cancelled = true;
+                            let _ = kill(Pid::from_raw(pid as i32), Signal::SIGTERM);
+                        }
+                        (state, _) => return Err(format!("job {} is {}", id, state.name())),
+                    }
+                    log::info!("Job {} cancelled", id);
+                    Ok("ok".to_string())
+                }
+                "pause" | "resume" => {
+                    let id = job_id(rest)?;
+                    let state = self
+                        .jobs
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .find(|job| job.id == id)
+                        .map(|job| job.state)
+                        .ok_or_else(|| no_job(id))?;
+                    if state != State::Running {
+                        return Err(format!("job {} is {}", id, state.name()));
+                    }
+                    self.send(id, name).map_err(|e| e.to_string())
+                }
+                _ => Err(format!(
+                    "unknown command '{}', expected submit, status, cancel, pause or resume",
+                    line
+                )),
+            }
+        }
+
+        /// Job `id` as a JSON object, with how far it has got if it is
+        /// running.
+        fn status(&self, id: u64) -> Option<String> {
+            let (mut status, running) = {
+                let jobs = self.jobs.lock().unwrap();
+                let job = jobs.iter().find(|job| job.id == id)?;
+                let args: Vec<String> = job.args.iter().map(|arg| json_string(arg)).collect();
+                let mut status = format!(
+                    "{{\"id\":{},\"state\":\"{}\",\"args\":[{}]",
+                    job.id,
+                    job.state.name(),
+                    args.join(",")
+                );
+                if let Some(code) = job.exit_code {
+                    let _ = write!(status, ",\"exit_code\":{}", code);
+                }
+                if let Some(message) = &job.message {
+                    let _ = write!(status, ",\"message\":{}", json_string(message));
+                }
+                (status, job.state == State::Running)
+            };
+            // Asked of the job without holding up the others.
+            if let Some(progress) = running
+                .then(|| self.send(id, "status").ok())
+                .flatten()
+                .filter(|progress| progress.starts_with('{'))
+            {
+                let _ = write!(status, ",\"progress\":{}", progress);
+            }
+            status.push('}');
+            Some(status)
+        }
+
+        /// The totals of every job, and how many are in each state, in the
+        /// Prometheus text format.
+        fn metrics(&self) -> String {
+            let running: Vec<u64> = self
+                .jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|job| job.state == State::Running)
+                .map(|job| job.id)
+                .collect();
+            for id in running {
+                let Ok(progress) = self.send(id, "status") else {
+                    continue;
+                };
+                let mut jobs = self.jobs.lock().unwrap();
+                if let Some(job) = jobs
+                    .iter_mut()
+                    .find(|job| job.id == id && job.state == State::Running)
+                {
+                    job.metrics = merge(job.metrics, metrics_from(&progress));
+                }
+            }
+
+            let jobs = self.jobs.lock().unwrap();
+            let total = jobs
+                .iter()
+                .fold(Metrics::default(), |total, job| total.add(&job.metrics));
+            let mut text = total.render();
+            text.push_str("# HELP rpcp_jobs Jobs of the daemon.\n# TYPE rpcp_jobs gauge\n");
+            for state in [
+                State::Queued,
+                State::Running,
+                State::Done,
+                State::Failed,
+                State::Cancelled,
+            ] {
+                let count = jobs.iter().filter(|job| job.state == state).count();
+                let _ = writeln!(text, "rpcp_jobs{{state=\"{}\"}} {}", state.name(), count);
+            }
+            text
+        }
+    }
+
+    /// The metrics of a job from its control status or statistics.
+    fn metrics_from(json: &str) -> Metrics {
+        let number = |key: &str| {
+            let key = format!("\"{}\":", key);
+            json.find(&key)
+                .map(|at| &json[at + key.len()..])
+                .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).next())
+                .and_then(|digits| digits.parse().ok())
+                .unwrap_or(0)
+        };
+        Metrics {
+            bytes_copied: number("bytes"),
+            bytes_per_second: number("bytes_per_second"),
+            files_copied: number("files_copied"),
+            files_skipped: number("files_skipped"),
+            files_failed: number("files_failed"),
+            retries: number("retries"),
+            verification_failures: number("verification_failures"),
+        }
+    }
+
+    /// `old` brought up to date with `new`, never counting down, so the
+    /// totals only grow.
+    fn merge(old: Metrics, new: Metrics) -> Metrics {
+        Metrics {
+            bytes_copied: old.bytes_copied.max(new.bytes_copied),
+            bytes_per_second: new.bytes_per_second,
+            files_copied: old.files_copied.max(new.files_copied),
+            files_skipped: old.files_skipped.max(new.files_skipped),
+            files_failed: old.files_failed.max(new.files_failed),
+            retries: old.retries.max(new.retries),
+            verification_failures: old.verification_failures.max(new.verification_failures),
+        }
+    }
+
+    /// Check that `args` are those of a copy or sync.
+    fn check_job(args: &[String]) -> Result<JobArgs, String> {
+        let matches = command()
+            .try_get_matches_from(std::iter::once("rpcp").chain(args.iter().map(String::as_str)))
+            .map_err(|e| {
+                let message = e.to_string();
+                let first = message.lines().next().unwrap_or_default();
+                first.trim_start_matches("error: ").to_string()
+            })?;
+        match matches.subcommand_name() {
+            None | Some("copy" | "sync") => {}
+            Some(name) => return Err(format!("{} can't be run as a job", name)),
+        }
+        let matches = subcommand_matches(&matches);
+        if matches.get_one::<PathBuf>("control").is_some() {
+            return Err("jobs are controlled through the daemon, not --control".to_string());
+        }
+        // Commands would run as the daemon's user, not the one submitting.
+        for id in ["on_complete", "on_error"] {
+            if matches.value_source(id) == Some(ValueSource::CommandLine) {
+                return Err(format!(
+                    "--{} can't be given to a job",
+                    id.replace('_', "-")
+                ));
+            }
+        }
+        // Jobs run in the daemon's directory, not the one they were
+        // submitted from.
+        for id in matches.ids() {
+            let Ok(Some(paths)) = matches.try_get_many::<PathBuf>(id.as_str()) else {
+                continue;
+            };
+            if let Some(path) = paths
+                .into_iter()
+                .find(|path| is_local(path) && path.is_relative())
+            {
+                return Err(format!(
+                    "'{}' is a relative path, jobs need absolute ones",
+                    path.display()
+                ));
+            }
+        }
+        Ok(JobArgs {
+            bwlimit: matches.get_one::<u64>("bwlimit").copied(),
+            stats_file: matches.get_one::<PathBuf>("stats_file").cloned(),
+            dry_run: matches.get_flag("dry_run"),
+        })
+    }
+
+    fn job_id(word: &str) -> Result<u64, String> {
+        word.parse()
+            .map_err(|_| format!("expected a job number, found '{}'", word))
+    }
+
+    fn no_job(id: u64) -> String {
+        format!("there is no job {}", id)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn job(args: &str) -> Result<JobArgs, String> {
+            check_job(&shlex::split(args).unwrap())
+        }
+
+        #[test]
+        fn checks_jobs() {
+            assert!(job("-r /data /backup").is_ok());
+            assert!(job("sync /data host:backup").is_ok());
+            assert!(job("-r /data s3://bucket/backup").is_ok());
+            assert!(job("verify /data /backup").is_err());
+            assert!(job("--control /tmp/c /data /backup").is_err());
+            let e = job("-r --on-complete 'rm -rf ~' /data /backup")
+                .err()
+                .unwrap();
+            assert!(e.contains("--on-complete"), "{}", e);
+            assert!(job("sync --on-error 'touch /tmp/x' /data /backup").is_err());
+        }
+
+        #[test]
+        fn listens_privately() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let socket =
+                std::env::temp_dir().join(format!("rpcp-test-{}-daemon.sock", std::process::id()));
+            let listener = listen(&socket).unwrap();
+            let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            drop(listener);
+            std::fs::remove_file(&socket).unwrap();
+        }
+
+        #[test]
+        fn refuses_relative_paths() {
+            let e = job("-r data /backup").err().unwrap();
+            assert!(e.contains("'data' is a relative path"), "{}", e);
+            assert!(job("-r --link-dest prev /data /backup").is_err());
+            assert!(job("sync --stats-file stats.json /data /backup").is_err());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use rpcp::Error;
+    use std::path::Path;
+
+    pub(super) fn run_daemon(
+        _socket: &Path,
+        _jobs: usize,
+        _bwlimit: Option<u64>,
+        _metrics: Option<&str>,
+    ) -> Result<(), Error> {
+        Err("The daemon is only supported on Unix".into())
+    }
+}
@@ -0,0 +1,193 @@
+use crate::backend::StorageBackend;
+use crate::cancel::cancelled;
+use crate::copy::{io_error, open_error};
+use crate::options::CopyOptions;
+use crate::pool::take_buffers;
+use crate::progress::Progress;
+use crate::verify::{mismatch, read_full};
+use crate::Error;
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The blocks a delta copy compares and rewrites, so a page changed in a
+/// database file rewrites 64K rather than a whole buffer.
+const BLOCK: usize = 64 * 1024;
+
+/// Bring the existing file `dest` up to date with `src` in place, writing
+/// only the blocks of it that differ. Returns the bytes written and the
+/// metadata of `src` from before the copy.
+///
+/// Both files are read a buffer at a time by `options.threads` threads,
+/// which compare each [`BLOCK`] of the buffers and write the runs of blocks
+/// that differ. `dest` is first cut or extended to the size of `src`. The
+/// blocks are compared where they are, so data that moved to another
+/// offset is written again. With [`CopyOptions::verify`] the blocks
+/// written are read back and compared again; the others were just found
+/// to be the same.
+///
+/// A copy that fails or is cancelled leaves `dest` with the blocks written
+/// so far, as it has no other copy of what they replaced.
+pub(crate) fn delta_copy(
+    src: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<(u64, fs::Metadata), Error> {
+    let infile = File::open(src).map_err(|e| open_error(src, e))?;
+    let source = infile.metadata()?;
+    let outfile = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dest)
+        .map_err(|e| open_error(dest, e))?;
+    let size = StorageBackend::len(&infile)?;
+    if outfile.metadata()?.len() != size {
+        outfile.set_len(size)?;
+    }
+    let buffer_size = options.buffer_size.max(BLOCK) / BLOCK * BLOCK;
+    let buffers = size.div_ceil(buffer_size as u64);
+    let threads = (options.threads as u64).min(buffers).max(1);
+
+    let next_buffer = AtomicUsize::new(0);
+    let failure: Mutex<Option<Error>> = Mutex::new(None);
+    let written = AtomicU64::new(0);
+    let processed_bytes = options.progress_counter.clone().unwrap_or_default();
+    let compare = || {
+        thread::scope(|scope| {
+            for index in 0..threads {
+                // Only the first thread waits for memory for its buffers.
+                let needed = if index == 0 { 2 } else { 0 };
+                let (next_buffer, failure, written) = (&next_buffer, &failure, &written);
+                let (infile, outfile, processed_bytes) = (&infile, &outfile, &processed_bytes);
+                scope.spawn(move || {
+                    let pool = options.buffer_pool.as_ref();
+                    let mut buffers = take_buffers(pool, buffer_size, 2, needed);
+                    let (Some(mut theirs), Some(mut ours)) = (buffers.pop(), buffers.pop()) else {
+                        return;
+                    };
+                    loop {
+                        let offset =
+                            next_buffer.fetch_add(1, Ordering::SeqCst) as u64 * buffer_size as u64;
+                        if offset >= size || failure.lock().unwrap().is_some() {
+                            break;
+                        }
+                        if options.is_cancelled() {
+                            failure.lock().unwrap().get_or_insert(cancelled(format!(
+                                "Copy of '{}' interrupted, '{}' is partly brought up to date",
+                                src.display(),
+                                dest.display()
+                            )));
+                            break;
+                        }
+                        let len = (size - offset).min(buffer_size as u64) as usize;
+                        let (ours, theirs) = (&mut ours[..len], &mut theirs[..len]);
+                        let result = read_block(infile, src, ours, offset)
+                            .and_then(|()| read_block(outfile, dest, theirs, offset))
+                            .and_then(|()| {
+                                write_differences(outfile, dest, ours, theirs, offset, options)
+                            });
+                        match result {
+                            Ok(bytes) => {
+                                written.fetch_add(bytes, Ordering::SeqCst);
+                                processed_bytes.fetch_add(len as u64, Ordering::SeqCst);
+                            }
+                            Err(e) => {
+                                failure.lock().unwrap().get_or_insert(e);
+                            }
+                        }
+                    }
+                });
+            }
+        })
+    };
+    // Files of a recursive copy are part of its progress.
+    if options.progress_counter.is_none() {
+        if options.progress {
+            eprintln!(" Compare {} with {}", src.display(), dest.display());
+        }
+        let progress = Progress::new(size, options);
+        progress.follow(Arc::clone(&processed_bytes));
+        progress.show(compare);
+    } else {
+        compare();
+    }
+    if let Some(e) = failure.into_inner().unwrap() {
+        return Err(e);
+    }
+    let written = written.into_inner();
+    log::info!(
+        "Wrote {} of the {} bytes of '{}' that differed from '{}'",
+        written,
+        size,
+        dest.display(),
+        src.display()
+    );
+    if options.progress && options.progress_counter.is_none() {
+        eprintln!(" Wrote the {} bytes of {} that differed", written, size);
+    }
+    Ok((written, source))
+}
+
+/// Fill `buffer` from `file` at `offset`, failing if it ends first.
+fn read_block(file: &File, path: &Path, buffer: &mut [u8], offset: u64) -> Result<(), Error> {
+    let read = read_full(file, buffer, offset).map_err(|e| io_error("read", path, offset, e))?;
+    if read < buffer.len() {
+        return Err(format!("'{}' got shorter while it was copied", path.display()).into());
+    }
+    Ok(())
+}
+
+/// Write the runs of blocks of `ours` that differ from `theirs`, read from
+/// `outfile` at `offset`, over them. Returns the bytes written.
+fn write_differences(
+    outfile: &File,
+    dest: &Path,
+    ours: &[u8],
+    theirs: &mut [u8],
+    offset: u64,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let blocks = ours.len().div_ceil(BLOCK);
+    let differs = |block: usize| {
+        let range = block * BLOCK..((block + 1) * BLOCK).min(ours.len());
+        ours[range.clone()] != theirs[range]
+    };
+    let mut runs = Vec::new();
+    let mut block = 0;
+    while block < blocks {
+        if !differs(block) {
+            block += 1;
+            continue;
+        }
+        let first = block;
+        while block < blocks && differs(block) {
+            block += 1;
+        }
+        runs.push(first * BLOCK..(block * BLOCK).min(ours.len()));
+    }
+    let mut written = 0;
+    for run in runs {
+        let at = offset + run.start as u64;
+        if let Some(limiter) = &options.bwlimit {
+            limiter.take(run.len());
+        }
+        outfile
+            .write_all_at(&ours[run.clone()], at)
+            .map_err(|(offset, e)| io_error("write", dest, offset, e))?;
+        if options.verify {
+            let check = &mut theirs[run.clone()];
+            read_block(outfile, dest, check, at)?;
+            if *check != ours[run.clone()] {
+                return Err(mismatch(format!(
+                    "'{}' differs from what was written at offset {}",
+                    dest.display(),
+                    at
+                )));
+            }
+        }
+        written += run.len() as u64;
+    }
+    Ok(written)
+}
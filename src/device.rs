@@ -0,0 +1,34 @@
+use std::fs::{self, File};
+use std::io;
+
+#[cfg(target_os = "linux")]
+nix::ioctl_read!(blkgetsize64, 0x12, 114, u64);
+
+/// Whether `metadata` is of a block device, such as a disk or partition.
+pub(crate) fn is_block_device(metadata: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileTypeExt::is_block_device(&metadata.file_type())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        false
+    }
+}
+
+/// The size of what `file` has open when its metadata has none, as for
+/// block devices, whose size Linux gives with `BLKGETSIZE64`. Elsewhere
+/// the end is found by seeking to it.
+pub(crate) fn device_size(file: &File) -> io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let mut size = 0;
+        if unsafe { blkgetsize64(file.as_raw_fd(), &mut size) }.is_ok() {
+            return Ok(size);
+        }
+    }
+    io::Seek::seek(&mut &*file, io::SeekFrom::End(0))
+}
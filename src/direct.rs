@@ -0,0 +1,73 @@
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut, Range};
+use std::ptr::NonNull;
+
+/// Alignment of offsets, lengths and buffers for `O_DIRECT`, which is
+/// enough for the logical block size of practically every device.
+pub(crate) const ALIGNMENT: usize = 4096;
+
+/// Round `n` up to a multiple of [`ALIGNMENT`].
+pub(crate) fn align_up(n: u64) -> u64 {
+    n.div_ceil(ALIGNMENT as u64) * ALIGNMENT as u64
+}
+
+/// Widen each of `ranges` out to [`ALIGNMENT`] boundaries, merging any that
+/// come to overlap. The last range may end past the end of the file.
+pub(crate) fn align_ranges(ranges: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut aligned: Vec<Range<u64>> = Vec::new();
+    for range in ranges {
+        let start = range.start / ALIGNMENT as u64 * ALIGNMENT as u64;
+        let end = align_up(range.end);
+        match aligned.last_mut() {
+            Some(last) if last.end >= start => last.end = last.end.max(end),
+            _ => aligned.push(start..end),
+        }
+    }
+    aligned
+}
+
+/// A zeroed buffer whose address is aligned to [`ALIGNMENT`], as `O_DIRECT`
+/// reads and writes need.
+#[derive(Debug)]
+pub(crate) struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// The buffer is owned memory like a Vec<u8>.
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub(crate) fn new(len: usize) -> Self {
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            alloc::handle_alloc_error(layout);
+        };
+        AlignedBuffer { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len.max(1), ALIGNMENT).expect("buffer size overflows")
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
@@ -0,0 +1,635 @@
+use crate::backend::StorageBackend;
+use crate::cancel::cancelled;
+use crate::copy::{io_error, open_error};
+use crate::device::is_block_device;
+use crate::manifest::FileDigest;
+use crate::options::CopyOptions;
+use crate::progress::Progress;
+use crate::verify::{mismatch, read_full};
+use crate::Error;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use hmac::{Hmac, KeyInit, Mac};
+use poly1305::universal_hash::UniversalHash;
+use poly1305::Poly1305;
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Plaintext is encrypted in chunks of this size, each with a tag of its own.
+const CHUNK: usize = 64 * 1024;
+const TAG: usize = 16;
+const VERSION_LINE: &[u8] = b"age-encryption.org/v1\n";
+/// The length of an X25519 stanza: its line of arguments and its body.
+const STANZA_LEN: u64 = 10 + 43 + 1 + 43 + 1;
+/// The length of the line that ends a header, with its MAC.
+const MAC_LINE_LEN: u64 = 4 + 43 + 1;
+
+/// The [age](https://age-encryption.org) recipients copies are encrypted to
+/// with [`CopyOptions::encrypt`](crate::CopyOptions::encrypt), parsed from
+/// `age:` and either X25519 public keys such as `age1ql3z7hjy...`,
+/// separated by commas, or the path of a file with one on each line.
+///
+/// Copies are age files, which `age --decrypt` reads with the identity of
+/// any of the recipients.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Recipients {
+    keys: Vec<[u8; 32]>,
+}
+
+impl Recipients {
+    /// The size of the copy of a file of `size` bytes.
+    pub(crate) fn encrypted_len(&self, size: u64) -> u64 {
+        let chunks = size.div_ceil(CHUNK as u64).max(1);
+        self.header_len() + 16 + size + chunks * TAG as u64
+    }
+
+    fn header_len(&self) -> u64 {
+        VERSION_LINE.len() as u64 + self.keys.len() as u64 * STANZA_LEN + MAC_LINE_LEN
+    }
+
+    /// The header of a file with `file_key`, with a stanza that wraps it for
+    /// each recipient.
+    fn header(&self, file_key: &[u8; 16]) -> Result<Vec<u8>, Error> {
+        let mut header = VERSION_LINE.to_vec();
+        for recipient in &self.keys {
+            let mut secret = [0; 32];
+            getrandom::fill(&mut secret)
+                .map_err(|e| format!("Failed to get random bytes: {}", e))?;
+            header.extend_from_slice(stanza(recipient, secret, file_key)?.as_bytes());
+        }
+        Ok(with_mac(header, file_key))
+    }
+}
+
+/// The stanza that wraps `file_key` for `recipient` with the ephemeral
+/// X25519 key `secret`.
+fn stanza(recipient: &[u8; 32], secret: [u8; 32], file_key: &[u8; 16]) -> Result<String, Error> {
+    let share = MontgomeryPoint::mul_base_clamped(secret).to_bytes();
+    let shared = MontgomeryPoint(*recipient).mul_clamped(secret).to_bytes();
+    if shared == [0; 32] {
+        return Err("An age recipient is not a valid X25519 key".into());
+    }
+    let mut salt = share.to_vec();
+    salt.extend_from_slice(recipient);
+    let wrap_key: [u8; 32] = hkdf(&salt, &shared, b"age-encryption.org/v1/X25519");
+    let mut body = file_key.to_vec();
+    let tag = seal(&wrap_key, &[0; 12], &mut body);
+    body.extend_from_slice(&tag);
+    Ok(format!("-> X25519 {}\n{}\n", base64(&share), base64(&body)))
+}
+
+/// `header`, the version line and stanzas, ended with the line holding its
+/// MAC under `file_key`.
+fn with_mac(mut header: Vec<u8>, file_key: &[u8; 16]) -> Vec<u8> {
+    header.extend_from_slice(b"---");
+    let mac_key: [u8; 32] = hkdf(&[], file_key, b"header");
+    let mut mac =
+        <Hmac<Sha256> as KeyInit>::new_from_slice(&mac_key).expect("HMAC takes keys of any length");
+    mac.update(&header);
+    header.extend_from_slice(format!(" {}\n", base64(&mac.finalize().into_bytes())).as_bytes());
+    header
+}
+
+impl fmt::Debug for Recipients {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Recipients({})", self)
+    }
+}
+
+impl fmt::Display for Recipients {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keys: Vec<String> = self.keys.iter().map(bech32_encode).collect();
+        write!(f, "age:{}", keys.join(","))
+    }
+}
+
+impl FromStr for Recipients {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(recipients) = s.strip_prefix("age:") else {
+            return Err(format!(
+                "invalid encryption '{}', expected age:RECIPIENT or age:FILE",
+                s
+            ));
+        };
+        let listed;
+        let lines: Vec<&str> = if recipients.starts_with("age1") {
+            recipients.split(',').collect()
+        } else {
+            listed = fs::read_to_string(recipients)
+                .map_err(|e| format!("Failed to read recipients from '{}': {}", recipients, e))?;
+            listed
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .collect()
+        };
+        let keys = lines
+            .iter()
+            .map(|line| {
+                bech32_decode(line.trim())
+                    .ok_or_else(|| format!("'{}' is not an age X25519 recipient, age1...", line))
+            })
+            .collect::<Result<Vec<[u8; 32]>, String>>()?;
+        if keys.is_empty() {
+            return Err(format!("There are no age recipients in '{}'", recipients));
+        }
+        Ok(Recipients { keys })
+    }
+}
+
+/// Copy `src` to `dest` encrypted to `recipients`, returning the size of
+/// `src`, its metadata from before the copy and, for
+/// [`CopyOptions::manifest`], the digest of what was read from it.
+///
+/// The file is encrypted in pieces of up to [`CopyOptions::buffer_size`],
+/// `options.threads` at a time, each written where its chunks go in the
+/// copy. With [`CopyOptions::verify`] the copy is read back and decrypted
+/// with the key it was encrypted with, and checked against digests of what
+/// was read.
+pub(crate) fn encrypt_file(
+    src: &Path,
+    dest: &Path,
+    recipients: &Recipients,
+    options: &CopyOptions,
+) -> Result<(u64, fs::Metadata, Option<String>), Error> {
+    if options.resume || options.follow.is_some() {
+        return Err("Encrypted copies can't be resumed or follow a growing file".into());
+    }
+    if options.skip > 0 || options.seek > 0 || options.count.is_some() {
+        return Err("Encrypted copies are of whole files".into());
+    }
+    let infile = File::open(src).map_err(|e| open_error(src, e))?;
+    let source = infile.metadata()?;
+    if is_block_device(&source)
+        || fs::metadata(dest).is_ok_and(|metadata| is_block_device(&metadata))
+    {
+        return Err("Encrypted copies are of files, not block devices".into());
+    }
+    let size = source.len();
+    let mut file_key = [0; 16];
+    let mut nonce = [0; 16];
+    getrandom::fill(&mut file_key)
+        .and_then(|_| getrandom::fill(&mut nonce))
+        .map_err(|e| format!("Failed to get random bytes: {}", e))?;
+    let mut header = recipients.header(&file_key)?;
+    header.extend_from_slice(&nonce);
+    let payload_key: [u8; 32] = hkdf(&nonce, &file_key, b"payload");
+
+    let outfile = File::create(dest).map_err(|e| io_error("create", dest, 0, e))?;
+    outfile.set_len(recipients.encrypted_len(size))?;
+    outfile
+        .write_all_at(&header, 0)
+        .map_err(|(offset, e)| io_error("write", dest, offset, e))?;
+    let start = header.len() as u64;
+    let chunks = size.div_ceil(CHUNK as u64).max(1);
+    let per_piece = (options.buffer_size / CHUNK).max(1) as u64;
+    let pieces = chunks.div_ceil(per_piece);
+    let threads = (options.threads as u64).min(pieces).max(1);
+
+    let next_piece = AtomicUsize::new(0);
+    let failure: Mutex<Option<Error>> = Mutex::new(None);
+    // Digests of the plaintext of each piece, to check the copy against.
+    let digests: Mutex<Vec<u64>> = Mutex::new(vec![0; pieces as usize]);
+    let processed_bytes = options.progress_counter.clone().unwrap_or_default();
+    let file_digest =
+        (options.manifest.as_ref()).map(|manifest| FileDigest::new(manifest.algorithm()));
+    let stopped = || failure.lock().unwrap().is_some() || options.is_cancelled();
+    let piece_range = |piece: u64| {
+        let first = piece * per_piece;
+        let offset = first * CHUNK as u64;
+        let len = (per_piece * CHUNK as u64).min(size - offset.min(size));
+        (first, offset, len as usize)
+    };
+    let encrypt = || {
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    let mut plain = vec![0; per_piece as usize * CHUNK];
+                    let mut sealed = Vec::with_capacity(per_piece as usize * (CHUNK + TAG));
+                    loop {
+                        let piece = next_piece.fetch_add(1, Ordering::SeqCst) as u64;
+                        if piece >= pieces || failure.lock().unwrap().is_some() {
+                            break;
+                        }
+                        if options.is_cancelled() {
+                            failure.lock().unwrap().get_or_insert(cancelled(format!(
+                                "Copy of '{}' interrupted",
+                                src.display()
+                            )));
+                            break;
+                        }
+                        let (first, offset, len) = piece_range(piece);
+                        let result = read_full(&infile, &mut plain[..len], offset)
+                            .map_err(|e| io_error("read", src, offset, e))
+                            .and_then(|read| match read < len {
+                                true => Err(format!(
+                                    "'{}' got shorter while it was copied",
+                                    src.display()
+                                )
+                                .into()),
+                                false => Ok(()),
+                            })
+                            .and_then(|_| {
+                                digests.lock().unwrap()[piece as usize] =
+                                    xxhash_rust::xxh3::xxh3_64(&plain[..len]);
+                                if let Some(file_digest) = file_digest.as_ref() {
+                                    let piece = piece as usize;
+                                    file_digest.update(piece, offset, &plain[..len], &stopped);
+                                    file_digest.chunk_done(piece, &stopped);
+                                }
+                                seal_piece(
+                                    &payload_key,
+                                    first,
+                                    chunks,
+                                    &mut plain[..len],
+                                    &mut sealed,
+                                );
+                                if let Some(limiter) = &options.bwlimit {
+                                    limiter.take(sealed.len());
+                                }
+                                let at = start + first * (CHUNK + TAG) as u64;
+                                outfile
+                                    .write_all_at(&sealed, at)
+                                    .map_err(|(offset, e)| io_error("write", dest, offset, e))
+                            });
+                        match result {
+                            Ok(()) => {
+                                processed_bytes.fetch_add(len as u64, Ordering::SeqCst);
+                            }
+                            Err(e) => {
+                                failure.lock().unwrap().get_or_insert(e);
+                            }
+                        }
+                    }
+                });
+            }
+        })
+    };
+    // Files of a recursive copy are part of its progress.
+    if options.progress_counter.is_none() {
+        let progress = Progress::new(size, options);
+        progress.follow(Arc::clone(&processed_bytes));
+        progress.show(encrypt);
+    } else {
+        encrypt();
+    }
+    if let Some(e) = failure.into_inner().unwrap() {
+        drop(outfile);
+        if !e.is::<crate::CancelledError>() || !options.keep_partial {
+            let _ = fs::remove_file(dest);
+        }
+        return Err(e);
+    }
+    if options.verify {
+        let digests = digests.into_inner().unwrap();
+        let written = File::open(dest).map_err(|e| open_error(dest, e))?;
+        for (piece, digest) in digests.iter().enumerate() {
+            let (first, offset, len) = piece_range(piece as u64);
+            let chunks_in = len.div_ceil(CHUNK).max(1);
+            let mut sealed = vec![0; len + chunks_in * TAG];
+            let at = start + first * (CHUNK + TAG) as u64;
+            let read =
+                read_full(&written, &mut sealed, at).map_err(|e| io_error("read", dest, at, e))?;
+            let plain = open_piece(&payload_key, first, chunks, &mut sealed[..read]);
+            if plain.is_none_or(|plain| xxhash_rust::xxh3::xxh3_64(plain) != *digest) {
+                return Err(mismatch(format!(
+                    "'{}' does not decrypt to what was read from '{}' at offset {}",
+                    dest.display(),
+                    src.display(),
+                    offset
+                )));
+            }
+        }
+    }
+    let digest = file_digest.map(|digest| digest.finish(size));
+    Ok((size, source, digest))
+}
+
+/// Encrypt the chunks of a piece, the first of them chunk `first` of
+/// `chunks`, from `plain` into `sealed`.
+fn seal_piece(key: &[u8; 32], first: u64, chunks: u64, plain: &mut [u8], sealed: &mut Vec<u8>) {
+    sealed.clear();
+    let len = plain.len();
+    for index in 0..len.div_ceil(CHUNK).max(1) {
+        let data = &mut plain[(index * CHUNK).min(len)..((index + 1) * CHUNK).min(len)];
+        let tag = seal(key, &chunk_nonce(first + index as u64, chunks), data);
+        sealed.extend_from_slice(data);
+        sealed.extend_from_slice(&tag);
+    }
+}
+
+/// Decrypt the chunks `seal_piece` made in place, returning the plaintext
+/// unless a tag does not match.
+fn open_piece<'a>(
+    key: &[u8; 32],
+    first: u64,
+    chunks: u64,
+    sealed: &'a mut [u8],
+) -> Option<&'a [u8]> {
+    let mut plain_len = 0;
+    let mut offset = 0;
+    let mut chunk = first;
+    while offset < sealed.len() || chunk == first {
+        let end = (offset + CHUNK + TAG).min(sealed.len());
+        let len = end.checked_sub(offset + TAG)?;
+        let (data, tag) = sealed[offset..end].split_at_mut(len);
+        let tag: [u8; TAG] = (&*tag).try_into().ok()?;
+        if !open(key, &chunk_nonce(chunk, chunks), data, &tag) {
+            return None;
+        }
+        sealed.copy_within(offset..offset + len, plain_len);
+        plain_len += len;
+        offset = end;
+        chunk += 1;
+    }
+    Some(&sealed[..plain_len])
+}
+
+/// The nonce of chunk `index`: its number and whether it is the last.
+fn chunk_nonce(index: u64, chunks: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = u8::from(index + 1 == chunks);
+    nonce
+}
+
+fn hkdf<const N: usize>(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; N] {
+    let mut okm = [0; N];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut okm)
+        .expect("the keys are short enough for HKDF");
+    okm
+}
+
+/// Encrypt `data` in place with ChaCha20-Poly1305 and no associated data,
+/// returning its tag.
+fn seal(key: &[u8; 32], nonce: &[u8; 12], data: &mut [u8]) -> [u8; TAG] {
+    let mut cipher = ChaCha20::new(key.into(), nonce.into());
+    // The first block of the key stream keys Poly1305, the data starts
+    // with the second.
+    let mut mac_key = [0; 64];
+    cipher.apply_keystream(&mut mac_key);
+    cipher.apply_keystream(data);
+    tag(&mac_key, data)
+}
+
+/// Decrypt what [`seal`] encrypted in place, if its tag matches.
+fn open(key: &[u8; 32], nonce: &[u8; 12], data: &mut [u8], expected: &[u8; TAG]) -> bool {
+    let mut cipher = ChaCha20::new(key.into(), nonce.into());
+    let mut mac_key = [0; 64];
+    cipher.apply_keystream(&mut mac_key);
+    if tag(&mac_key, data) != *expected {
+        return false;
+    }
+    cipher.apply_keystream(data);
+    true
+}
+
+fn tag(mac_key: &[u8; 64], ciphertext: &[u8]) -> [u8; TAG] {
+    let key: [u8; 32] = mac_key[..32].try_into().unwrap();
+    let mut mac = <Poly1305 as KeyInit>::new(&key.into());
+    mac.update_padded(ciphertext);
+    let mut lengths = [0; 16];
+    lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    mac.update_padded(&lengths);
+    mac.finalize().into()
+}
+
+/// Standard base64 without padding, as age writes it.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (index, &byte)| {
+            bits | (byte as u32) << (16 - 8 * index)
+        });
+        for index in 0..=group.len() {
+            encoded.push(ALPHABET[(bits >> (18 - 6 * index) & 63) as usize] as char);
+        }
+    }
+    encoded
+}
+
+const BECH32: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn bech32_polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    values.into_iter().fold(1, |check, value| {
+        let top = check >> 25;
+        let check = (check & 0x1ffffff) << 5 ^ value as u32;
+        (0..5)
+            .filter(|bit| top >> bit & 1 == 1)
+            .fold(check, |check, bit| check ^ GENERATOR[bit])
+    })
+}
+
+/// The human-readable part `age` expanded for the checksum.
+fn age_prefix() -> [u8; 7] {
+    [3, 3, 3, 0, 1, 7, 5]
+}
+
+/// The X25519 key of an `age1...` recipient.
+fn bech32_decode(recipient: &str) -> Option<[u8; 32]> {
+    let recipient = recipient.to_ascii_lowercase();
+    let data = recipient.strip_prefix("age1")?;
+    let values: Vec<u8> = data
+        .bytes()
+        .map(|c| BECH32.iter().position(|&b| b == c).map(|value| value as u8))
+        .collect::<Option<_>>()?;
+    if values.len() < 6
+        || bech32_polymod(age_prefix().into_iter().chain(values.iter().copied())) != 1
+    {
+        return None;
+    }
+    let mut key = Vec::with_capacity(32);
+    let (mut bits, mut count) = (0u32, 0);
+    for value in &values[..values.len() - 6] {
+        bits = bits << 5 | *value as u32;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            key.push((bits >> count) as u8);
+        }
+    }
+    // What is left over is padding, which has to be zeros.
+    if count >= 5 || bits & ((1 << count) - 1) != 0 {
+        return None;
+    }
+    key.try_into().ok()
+}
+
+fn bech32_encode(key: &[u8; 32]) -> String {
+    let mut values = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for &byte in key {
+        bits = bits << 8 | byte as u32;
+        count += 8;
+        while count >= 5 {
+            count -= 5;
+            values.push((bits >> count & 31) as u8);
+        }
+    }
+    if count > 0 {
+        values.push((bits << (5 - count) & 31) as u8);
+    }
+    let check = bech32_polymod(
+        age_prefix()
+            .into_iter()
+            .chain(values.iter().copied())
+            .chain([0; 6]),
+    ) ^ 1;
+    values.extend((0..6).map(|index| (check >> (5 * (5 - index)) & 31) as u8));
+    let data: String = values
+        .iter()
+        .map(|&value| BECH32[value as usize] as char)
+        .collect();
+    format!("age1{}", data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    // The vectors were made with the Python `cryptography` package and the
+    // reference bech32 code of BIP 173, for the identity 00 01 .. 1f, the
+    // ephemeral key 42 42 .. 42, the file key 00 01 .. 0f and the payload
+    // nonce 07 07 .. 07.
+    const RECIPIENT: &str = "age13aqvttdk3ujkyjh9kg2w5an6dmy5mq5a84a4uxk3hfhnugfc9p0sy5p2wh";
+    const PUBLIC_KEY: &str = "8f40c5adb68f25624ae5b214ea767a6ec94d829d3d7b5e1ad1ba6f3e2138285f";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn file_key() -> [u8; 16] {
+        std::array::from_fn(|index| index as u8)
+    }
+
+    #[test]
+    fn bech32_recipients() {
+        let key = bech32_decode(RECIPIENT).unwrap();
+        assert_eq!(hex(&key), PUBLIC_KEY);
+        assert_eq!(bech32_encode(&key), RECIPIENT);
+        assert_eq!(bech32_decode(&RECIPIENT.to_uppercase()), Some(key));
+
+        let example = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+        assert_eq!(bech32_encode(&bech32_decode(example).unwrap()), example);
+
+        let typo = RECIPIENT.replace("p2wh", "p2wx");
+        assert_eq!(bech32_decode(&typo), None);
+        assert_eq!(bech32_decode(&RECIPIENT[..RECIPIENT.len() - 1]), None);
+        assert_eq!(bech32_decode(&RECIPIENT.replacen("age1", "agf1", 1)), None);
+    }
+
+    #[test]
+    fn header_with_a_fixed_key() {
+        let recipient = bech32_decode(RECIPIENT).unwrap();
+        let mut header = VERSION_LINE.to_vec();
+        header.extend_from_slice(
+            stanza(&recipient, [0x42; 32], &file_key())
+                .unwrap()
+                .as_bytes(),
+        );
+        let header = with_mac(header, &file_key());
+        assert_eq!(
+            String::from_utf8(header.clone()).unwrap(),
+            "age-encryption.org/v1\n\
+             -> X25519 EyxEK+AQ+9V+cmAzKKp25x/MwVA6riGTJ9FNnJmT9HI\n\
+             ZGawODb2PvBJwpmgEqNN+yc7XPrI8YdGbDZu4A2rsVM\n\
+             --- oxkfnnXL1vuCakzNjgo4pF2OhhsfA7NIJcRkGAuYW/U\n"
+        );
+        let recipients = Recipients {
+            keys: vec![recipient],
+        };
+        assert_eq!(recipients.header_len(), header.len() as u64);
+        assert!(stanza(&[0; 32], [0x42; 32], &file_key()).is_err());
+    }
+
+    #[test]
+    fn chunk_nonces() {
+        assert_eq!(chunk_nonce(0, 1), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(chunk_nonce(0, 2), [0; 12]);
+        assert_eq!(chunk_nonce(1, 2), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(
+            chunk_nonce(0x0102, 0x10000),
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 0]
+        );
+    }
+
+    #[test]
+    fn payload_at_chunk_boundaries() {
+        let key: [u8; 32] = hkdf(&[7; 16], &file_key(), b"payload");
+        assert_eq!(
+            hex(&key),
+            "b7084986db66bfcb3613693bf4c6e240da58e3f528bcd0c4ee539f9cdc11195f"
+        );
+        let recipients = Recipients {
+            keys: vec![[9; 32]],
+        };
+        for (size, digest) in [
+            (
+                0,
+                "19850eb8abab9475cd9f6a6e45036c7f4d9c0593dcce3fa6fef5bf0adc65ba64",
+            ),
+            (
+                CHUNK,
+                "3f83f668059147aa689b4a0ae920114b9ebe01aa23c555e22b0b5980599aa3f0",
+            ),
+            (
+                2 * CHUNK,
+                "ceb5f8a937c9a9a43ff4ccd78778f065aeb26f93d3d85b49b2681464622b828b",
+            ),
+            (
+                CHUNK + 1,
+                "c3bf95e9c9725d6b55fd5e28cbaa1d9962a82b3649b4eb2ee669feeb7b14f52e",
+            ),
+        ] {
+            let data: Vec<u8> = (0..size).map(|index| (index % 251) as u8).collect();
+            let chunks = (size as u64).div_ceil(CHUNK as u64).max(1);
+            let mut plain = data.clone();
+            let mut sealed = Vec::new();
+            seal_piece(&key, 0, chunks, &mut plain, &mut sealed);
+            assert_eq!(hex(&Sha256::digest(&sealed)), digest, "{} bytes", size);
+            assert_eq!(
+                recipients.encrypted_len(size as u64),
+                recipients.header_len() + 16 + sealed.len() as u64
+            );
+            assert_eq!(
+                open_piece(&key, 0, chunks, &mut sealed),
+                Some(&data[..]),
+                "{} bytes",
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn pieces_open_on_their_own() {
+        let key = [3; 32];
+        let data: Vec<u8> = (0..3 * CHUNK).map(|index| (index % 13) as u8).collect();
+        let mut whole = Vec::new();
+        seal_piece(&key, 0, 3, &mut data.clone(), &mut whole);
+        // The last piece, sealed alone, is the end of the whole payload.
+        let mut piece = Vec::new();
+        seal_piece(&key, 2, 3, &mut data[2 * CHUNK..].to_vec(), &mut piece);
+        assert_eq!(piece, whole[2 * (CHUNK + TAG)..]);
+        assert_eq!(open_piece(&key, 2, 3, &mut piece), Some(&data[2 * CHUNK..]));
+        // Opened as the wrong chunk, or with a flipped bit, it fails.
+        let mut moved = whole[2 * (CHUNK + TAG)..].to_vec();
+        assert_eq!(open_piece(&key, 1, 3, &mut moved), None);
+        let mut flipped = whole[2 * (CHUNK + TAG)..].to_vec();
+        flipped[0] ^= 1;
+        assert_eq!(open_piece(&key, 2, 3, &mut flipped), None);
+    }
+}
@@ -0,0 +1,353 @@
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Include and exclude patterns deciding which entries of a tree get copied.
+///
+/// Rules work like rsync's: they are checked in the order they were added
+/// and the first one that matches decides. Entries no rule matches are
+/// included, and an excluded directory is skipped with everything in it.
+///
+/// - `*` matches anything except `/`, `**` matches anything, `?` matches one
+///   character and `[abc]`, `[a-z]` or `[!abc]` match a set of characters.
+/// - A pattern ending in `/` only matches directories.
+/// - A pattern starting with `/` is matched against the whole path relative
+///   to the source root. Other patterns containing a `/` are matched against
+///   the end of that path, and patterns without one against the file name.
+///
+/// Files can also be left out by their size and modification time, which
+/// never leaves out directories.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    include: bool,
+    pattern: Vec<char>,
+    dir_only: bool,
+    anchored: bool,
+    has_slash: bool,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule copying entries matching `pattern`.
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.rules.push(Rule::new(true, pattern));
+        self
+    }
+
+    /// Add a rule skipping entries matching `pattern`.
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.rules.push(Rule::new(false, pattern));
+        self
+    }
+
+    /// Leave out files smaller than `size` bytes.
+    pub fn min_size(mut self, size: u64) -> Self {
+        self.min_size = Some(size);
+        self
+    }
+
+    /// Leave out files larger than `size` bytes.
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Leave out files last modified before `time`.
+    pub fn newer_than(mut self, time: SystemTime) -> Self {
+        self.newer_than = Some(time);
+        self
+    }
+
+    /// Leave out files last modified after `time`.
+    pub fn older_than(mut self, time: SystemTime) -> Self {
+        self.older_than = Some(time);
+        self
+    }
+
+    /// Whether the filter lets everything through.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.newer_than.is_none()
+            && self.older_than.is_none()
+    }
+
+    /// Whether the entry with `metadata` is left out by its size or
+    /// modification time. Only regular files are.
+    pub fn is_filtered_out(&self, metadata: &fs::Metadata) -> bool {
+        if !metadata.is_file() {
+            return false;
+        }
+        let size = metadata.len();
+        if self.min_size.is_some_and(|min| size < min)
+            || self.max_size.is_some_and(|max| size > max)
+        {
+            return true;
+        }
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return false;
+        }
+        // Files without a modification time are kept.
+        let Ok(modified) = metadata.modified() else {
+            return false;
+        };
+        self.newer_than.is_some_and(|time| modified < time)
+            || self.older_than.is_some_and(|time| modified > time)
+    }
+
+    /// Whether the entry at `relative_path` from the source root should be
+    /// skipped.
+    pub fn is_excluded(&self, relative_path: &Path, is_dir: bool) -> bool {
+        let path = relative_path.to_string_lossy();
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&path, is_dir))
+            .is_some_and(|rule| !rule.include)
+    }
+
+    /// Whether the entry at `relative_path` should be skipped, or is in a
+    /// directory that is, for entries that are looked at without walking
+    /// down to them.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn is_excluded_with_parents(&self, relative_path: &Path, is_dir: bool) -> bool {
+        let mut parents = relative_path.ancestors().skip(1);
+        parents.any(|dir| !dir.as_os_str().is_empty() && self.is_excluded(dir, true))
+            || self.is_excluded(relative_path, is_dir)
+    }
+}
+
+impl Rule {
+    fn new(include: bool, pattern: &str) -> Self {
+        let dir_only = pattern.ends_with('/');
+        let anchored = pattern.starts_with('/');
+        let pattern = pattern.trim_end_matches('/').trim_start_matches('/');
+        Rule {
+            include,
+            pattern: pattern.chars().collect(),
+            dir_only,
+            anchored,
+            has_slash: anchored || pattern.contains('/'),
+        }
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path: Vec<char> = path.chars().collect();
+        if self.anchored {
+            return glob_match(&self.pattern, &path);
+        }
+        if !self.has_slash {
+            let name_start = path.iter().rposition(|&c| c == '/').map_or(0, |i| i + 1);
+            return glob_match(&self.pattern, &path[name_start..]);
+        }
+        // Try the pattern against every tail of the path that starts at a
+        // component boundary.
+        std::iter::once(0)
+            .chain(
+                path.iter()
+                    .enumerate()
+                    .filter(|(_, &c)| c == '/')
+                    .map(|(i, _)| i + 1),
+            )
+            .any(|start| glob_match(&self.pattern, &path[start..]))
+    }
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => !text.is_empty() && text[0] != '/' && glob_match(&pattern[1..], &text[1..]),
+        Some('[') => match (text.first(), class_match(&pattern[1..], text.first())) {
+            (Some(_), Some((true, len))) => glob_match(&pattern[1 + len..], &text[1..]),
+            (_, Some((false, _))) | (None, _) => false,
+            // No closing bracket, so it is a literal '['.
+            (Some(&c), None) => c == '[' && glob_match(&pattern[1..], &text[1..]),
+        },
+        Some(&c) => text.first() == Some(&c) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Match `c` against the character class starting after a `[`. Returns
+/// whether it matched and the length of the class including the `]`, or
+/// `None` when the class is not closed.
+fn class_match(class: &[char], c: Option<&char>) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!') | Some('^'));
+    let mut i = usize::from(negated);
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        if class[i] == ']' && !first {
+            let matched = c.is_some_and(|&c| c != '/') && matched != negated;
+            return Some((matched, i + 1));
+        }
+        if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+            matched |= c.is_some_and(|&c| class[i] <= c && c <= class[i + 2]);
+            i += 3;
+        } else {
+            matched |= c == Some(&class[i]);
+            i += 1;
+        }
+        first = false;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn matches_globs() {
+        for (pattern, text, expected) in [
+            ("", "", true),
+            ("*", "", true),
+            ("*.rs", "main.rs", true),
+            ("*.rs", "src/main.rs", false),
+            ("**.rs", "src/main.rs", true),
+            ("src/**", "src/a/b.rs", true),
+            ("**/b", "a/x/b", true),
+            ("a/**/z", "a/b/c/z", true),
+            ("a/**/z", "a/z", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("a?c", "a/c", false),
+            ("[abc]x", "bx", true),
+            ("[abc]x", "dx", false),
+            ("[!abc]x", "bx", false),
+            ("[!abc]x", "dx", true),
+            ("[^abc]x", "dx", true),
+            ("[a-c]", "b", true),
+            ("[a-c]", "d", false),
+            ("[!a-c]", "d", true),
+            ("[]]", "]", true),
+            ("[a-]", "-", true),
+            ("[a/]", "/", false),
+            ("[!a]", "/", false),
+            ("[ab", "[ab", true),
+            ("[ab", "a", false),
+        ] {
+            assert_eq!(
+                glob_match(&chars(pattern), &chars(text)),
+                expected,
+                "{:?} against {:?}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn matches_classes() {
+        for (class, c, expected) in [
+            ("b]", Some('b'), Some((true, 2))),
+            ("ab]x", Some('c'), Some((false, 3))),
+            ("!ab]", Some('a'), Some((false, 4))),
+            ("!ab]", Some('c'), Some((true, 4))),
+            ("a-z]", Some('m'), Some((true, 4))),
+            ("a-z]", Some('M'), Some((false, 4))),
+            ("]a]", Some(']'), Some((true, 3))),
+            ("a]", None, Some((false, 2))),
+            ("ab", Some('a'), None),
+            ("!", Some('a'), None),
+        ] {
+            assert_eq!(
+                class_match(&chars(class), c.as_ref()),
+                expected,
+                "[{}",
+                class
+            );
+        }
+    }
+
+    #[test]
+    fn excludes_paths() {
+        for (rules, path, is_dir, expected) in [
+            // Anchored patterns match from the root only.
+            ("/build", "build", true, true),
+            ("/build", "build", false, true),
+            ("/build", "src/build", true, false),
+            ("/src/*.rs", "src/main.rs", false, true),
+            ("/src/*.rs", "lib/src/main.rs", false, false),
+            // Others with a slash match the end of the path.
+            ("docs/*.md", "docs/a.md", false, true),
+            ("docs/*.md", "x/docs/a.md", false, true),
+            ("docs/*.md", "docs/sub/a.md", false, false),
+            ("docs/**.md", "docs/sub/a.md", false, true),
+            // And those without one the name.
+            ("*.o", "a/b/c.o", false, true),
+            ("*.o", "a/b.o/c", false, false),
+            // A trailing slash only matches directories.
+            ("target/", "target", true, true),
+            ("target/", "target", false, false),
+            ("target/", "a/target", true, true),
+            ("/target/", "a/target", true, false),
+            // The first rule that matches wins.
+            ("+keep.log -*.log", "keep.log", false, false),
+            ("+keep.log -*.log", "other.log", false, true),
+            ("-*.log +keep.log", "keep.log", false, true),
+            ("+*/ -*", "dir", true, false),
+            ("+*/ -*", "file", false, true),
+            ("-*.tmp", "a.txt", false, false),
+        ] {
+            let filter =
+                rules
+                    .split(' ')
+                    .fold(Filter::new(), |filter, rule| match rule.strip_prefix('+') {
+                        Some(pattern) => filter.include(pattern),
+                        None => filter.exclude(rule.trim_start_matches('-')),
+                    });
+            assert_eq!(
+                filter.is_excluded(Path::new(path), is_dir),
+                expected,
+                "{} with {}",
+                path,
+                rules
+            );
+        }
+    }
+
+    #[test]
+    fn excludes_entries_of_excluded_directories() {
+        let filter = Filter::new().exclude("node_modules/").exclude("/tmp");
+        assert!(filter.is_excluded_with_parents(Path::new("node_modules/pkg/index.js"), false));
+        assert!(filter.is_excluded_with_parents(Path::new("a/node_modules/pkg"), true));
+        assert!(filter.is_excluded_with_parents(Path::new("tmp/x"), false));
+        assert!(!filter.is_excluded_with_parents(Path::new("a/tmp/x"), false));
+        assert!(!filter.is_excluded(Path::new("node_modules/pkg/index.js"), false));
+    }
+}
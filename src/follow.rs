@@ -0,0 +1,99 @@
+use crate::backend::StorageBackend;
+use crate::copy::{interrupted, io_error, open_error};
+use crate::hash::HashAlgorithm;
+use crate::options::CopyOptions;
+use crate::verify::{read_full, ChunkDigest};
+use crate::Error;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a followed source is checked for new data.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Copy what is appended to `src` after its first `from` bytes were copied
+/// to `dest`, until it has not grown for `quiet`. Returns the size it ended
+/// at and, with `algorithm`, the digest of each range appended as it was
+/// read.
+pub(crate) fn follow_growth(
+    src: &Path,
+    dest: &Path,
+    from: u64,
+    quiet: Duration,
+    algorithm: Option<HashAlgorithm>,
+    options: &CopyOptions,
+) -> Result<(u64, Vec<ChunkDigest>), Error> {
+    let infile = File::open(src).map_err(|e| open_error(src, e))?;
+    let outfile = OpenOptions::new()
+        .write(true)
+        .open(dest)
+        .map_err(|e| open_error(dest, e))?;
+    if options.progress {
+        eprintln!(
+            " Following '{}' until it stops growing for {:?}",
+            src.display(),
+            quiet
+        );
+    }
+    let mut buffer = vec![0; options.buffer_size.max(1)];
+    let mut digests = Vec::new();
+    let mut size = from;
+    let mut last_growth = Instant::now();
+    while last_growth.elapsed() < quiet {
+        if options.is_cancelled() {
+            drop(outfile);
+            return Err(interrupted(dest, size, None, false, options));
+        }
+        thread::sleep(POLL_INTERVAL.min(quiet - last_growth.elapsed()));
+        let now = StorageBackend::len(&infile).map_err(|e| io_error("size", src, 0, e))?;
+        if now < size {
+            return Err(format!(
+                "'{}' shrank from {} to {} bytes while it was followed",
+                src.display(),
+                size,
+                now
+            )
+            .into());
+        }
+        if now == size {
+            continue;
+        }
+        let start = size;
+        let mut hasher = algorithm.map(|algorithm| algorithm.hasher());
+        while size < now && !options.is_cancelled() {
+            let want = buffer.len().min((now - size) as usize);
+            let len = read_full(&infile, &mut buffer[..want], size)
+                .map_err(|e| io_error("read", src, size, e))?;
+            if len == 0 {
+                break;
+            }
+            if let Some(bwlimit) = options.bwlimit.as_ref() {
+                bwlimit.take(len);
+            }
+            outfile
+                .write_all_at(&buffer[..len], size)
+                .map_err(|(offset, e)| io_error("write", dest, offset, e))?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[..len]);
+            }
+            size += len as u64;
+        }
+        if let Some(hasher) = hasher {
+            digests.push(ChunkDigest {
+                range: start..size,
+                digest: hasher.finish(),
+            });
+        }
+        if options.verbosity > 1 {
+            eprintln!(
+                " '{}' grew by {} bytes to {}",
+                src.display(),
+                size - start,
+                size
+            );
+        }
+        last_growth = Instant::now();
+    }
+    Ok((size, digests))
+}
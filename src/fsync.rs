@@ -0,0 +1,42 @@
+use crate::options::Fsync;
+use crate::Error;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+/// Flush `path` to the device as set with
+/// [`CopyOptions::fsync`](crate::CopyOptions::fsync).
+pub(crate) fn sync_file(path: &Path, fsync: Fsync) -> Result<(), Error> {
+    if fsync == Fsync::Off {
+        return Ok(());
+    }
+    // Windows only flushes files opened for writing. Elsewhere the file
+    // may have been made read-only by now.
+    let file = if cfg!(windows) {
+        OpenOptions::new().write(true).open(path)
+    } else {
+        File::open(path)
+    };
+    let result = file.and_then(|file| match fsync {
+        Fsync::Data => file.sync_data(),
+        _ => file.sync_all(),
+    });
+    result.map_err(|e| format!("Failed to sync '{}': {}", path.display(), e).into())
+}
+
+/// Flush the entries of the directory `path` to the device, so the files
+/// created and renamed in it are there after a crash. Directories can't
+/// be flushed on Windows, where this does nothing.
+pub(crate) fn sync_dir(path: &Path) -> Result<(), Error> {
+    if cfg!(windows) {
+        return Ok(());
+    }
+    // The current directory when `path` is a bare file name's parent.
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| format!("Failed to sync directory '{}': {}", path.display(), e).into())
+}
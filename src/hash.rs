@@ -0,0 +1,114 @@
+use crate::Error;
+use sha2::Digest;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Digest algorithms that can be used to verify a copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Blake3,
+    Sha256,
+    Xxh3,
+    Crc32,
+}
+
+impl HashAlgorithm {
+    pub(crate) fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new())),
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Crc32 => "crc32",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            "crc32" => Ok(HashAlgorithm::Crc32),
+            _ => Err(format!(
+                "unknown hash algorithm '{}', expected one of blake3, sha256, xxh3, crc32",
+                s
+            )),
+        }
+    }
+}
+
+/// Running digest for one of the [`HashAlgorithm`]s.
+pub(crate) enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Xxh3(h) => h.update(data),
+            Hasher::Crc32(h) => h.update(data),
+        }
+    }
+
+    /// Finish the digest and return it as lowercase hex.
+    pub(crate) fn finish(self) -> String {
+        match self {
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            Hasher::Sha256(h) => to_hex(&h.finalize()),
+            Hasher::Xxh3(h) => format!("{:032x}", h.digest128()),
+            Hasher::Crc32(h) => format!("{:08x}", h.finalize()),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compute the digest of the file at `path` as lowercase hex.
+pub fn file_digest<P: AsRef<Path>>(path: P, algorithm: HashAlgorithm) -> Result<String, Error> {
+    Ok(digest(path.as_ref(), algorithm, 10 * 1024 * 1024)?)
+}
+
+pub(crate) fn digest(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(hasher.finish())
+}
@@ -0,0 +1,154 @@
+use crate::{json_string, stats_fields};
+use rpcp::CopyStats;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+/// What to run and notify when the copy ends, as set with --on-complete,
+/// --on-error and --webhook.
+pub(crate) struct Hooks {
+    pub(crate) on_complete: Option<String>,
+    pub(crate) on_error: Option<String>,
+    pub(crate) webhook: Option<String>,
+    pub(crate) sources: Vec<PathBuf>,
+    pub(crate) dest: PathBuf,
+    pub(crate) stats: Arc<CopyStats>,
+    pub(crate) start: Instant,
+}
+
+/// The hooks of the copy running, which the exits after it has started
+/// run wherever they are.
+static HOOKS: OnceLock<Hooks> = OnceLock::new();
+
+pub(crate) fn set_hooks(hooks: Hooks) {
+    let _ = HOOKS.set(hooks);
+}
+
+/// Run the hooks of the copy, if it has any, for it ending with the exit
+/// status `status` and, when it failed, `error`.
+pub(crate) fn run_hooks(status: i32, error: Option<&str>) {
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    let command = if status == 0 {
+        &hooks.on_complete
+    } else {
+        &hooks.on_error
+    };
+    if command.is_none() && hooks.webhook.is_none() {
+        return;
+    }
+    let summary = hooks.summary(status, error);
+    if let Some(command) = command {
+        hooks.run(command, status, error, &summary);
+    }
+    if let Some(url) = &hooks.webhook {
+        if let Err(e) = post(url, &summary) {
+            // The error says which URL it was.
+            log::warn!("Failed to post to the webhook: {}", e);
+        }
+    }
+}
+
+impl Hooks {
+    /// How the copy ended as a JSON object, with a line of text that chat
+    /// webhooks such as Slack's show as the message.
+    fn summary(&self, status: i32, error: Option<&str>) -> String {
+        let sources: Vec<String> = self
+            .sources
+            .iter()
+            .map(|source| json_string(&source.to_string_lossy()))
+            .collect();
+        let dest = self.dest.to_string_lossy();
+        let seconds = self.start.elapsed().as_secs_f64();
+        let bytes = self.stats.bytes_transferred().max(self.stats.bytes());
+        let text = match error {
+            None => format!(
+                "rpcp copy to {} completed: {} file{}, {} bytes in {:.1}s",
+                dest,
+                self.stats.files_copied(),
+                if self.stats.files_copied() == 1 {
+                    ""
+                } else {
+                    "s"
+                },
+                bytes,
+                seconds
+            ),
+            Some(error) => format!("rpcp copy to {} failed: {}", dest, error),
+        };
+        let mut summary = format!(
+            "{{\"event\":\"{}\",\"text\":{},\"exit_status\":{},\"sources\":[{}],\"dest\":{},{}",
+            if error.is_none() {
+                "completed"
+            } else {
+                "failed"
+            },
+            json_string(&text),
+            status,
+            sources.join(","),
+            json_string(&dest),
+            stats_fields(&self.stats, bytes, seconds)
+        );
+        if let Some(error) = error {
+            summary.push_str(",\"error\":");
+            summary.push_str(&json_string(error));
+        }
+        summary.push('}');
+        summary
+    }
+
+    /// Run `command` with a shell, telling it how the copy ended in its
+    /// environment. Its output goes to stderr, so as not to mix with the
+    /// JSON events or data the copy writes to stdout.
+    fn run(&self, command: &str, status: i32, error: Option<&str>, summary: &str) {
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        shell
+            .arg(command)
+            .env("RPCP_EXIT_STATUS", status.to_string())
+            .env("RPCP_DEST", &self.dest)
+            .env("RPCP_SUMMARY", summary)
+            .stdout(Stdio::from(io::stderr()));
+        if let Some(source) = self.sources.first() {
+            shell.env("RPCP_SOURCE", source);
+        }
+        if let Some(error) = error {
+            shell.env("RPCP_ERROR", error);
+        }
+        log::info!("Run hook '{}'", command);
+        match shell.status() {
+            Ok(hook_status) if hook_status.success() => {}
+            Ok(hook_status) => {
+                log::warn!("Hook '{}' failed with {}", command, hook_status);
+            }
+            Err(e) => {
+                log::warn!("Failed to run hook '{}': {}", command, e);
+            }
+        }
+    }
+}
+
+/// POST `summary` to `url` as JSON.
+#[cfg(any(feature = "http", feature = "s3"))]
+fn post(url: &str, summary: &str) -> Result<(), rpcp::Error> {
+    ureq::post(url)
+        .timeout(std::time::Duration::from_secs(30))
+        .set("Content-Type", "application/json")
+        .send_string(summary)?;
+    Ok(())
+}
+
+#[cfg(not(any(feature = "http", feature = "s3")))]
+fn post(_url: &str, _summary: &str) -> Result<(), rpcp::Error> {
+    Err("webhooks need a build with the http feature".into())
+}
@@ -0,0 +1,491 @@
+use crate::options::CopyOptions;
+use crate::Error;
+use std::path::Path;
+
+/// Whether `location` is an `http://` or `https://` URL.
+pub fn is_url(location: &str) -> bool {
+    let scheme = location.split_once("://").map(|(scheme, _)| scheme);
+    matches!(scheme, Some(scheme) if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https"))
+}
+
+/// Download `url` to the local `dest`, returning the number of bytes
+/// copied. A download into an existing directory goes inside it, named
+/// after the last segment of the URL path.
+///
+/// When the server takes `Range` requests the file is split into chunks
+/// fetched by up to `options.threads` parallel GETs, each written to its
+/// place in `dest` as it arrives. Other servers send the whole file in one
+/// response. Requests carry `If-Range`, so a file that changes on the
+/// server part way fails the copy instead of mixing two versions.
+///
+/// With [`CopyOptions::verify`] the data is hashed as it arrives and `dest`
+/// is read back to check it was stored intact. [`CopyOptions::preserve`]
+/// sets the modification time from `Last-Modified`.
+/// [`CopyOptions::overwrite`] with [`Overwrite::Never`](crate::Overwrite::Never),
+/// [`CopyOptions::bwlimit`], [`CopyOptions::retries`],
+/// [`CopyOptions::cancel`], [`CopyOptions::keep_partial`] and
+/// [`CopyOptions::dry_run`] apply as they do to local copies.
+///
+/// A URL the server answers with 404 or 410 fails with an
+/// [`io::Error`](std::io::Error) of kind
+/// [`NotFound`](std::io::ErrorKind::NotFound).
+pub fn download_url<Q: AsRef<Path>>(
+    url: &str,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    imp::download(url, dest.as_ref(), options)
+}
+
+#[cfg(feature = "http")]
+mod imp {
+    use crate::backend::StorageBackend;
+    use crate::copy::{chunk_size, chunks, interrupted, io_error};
+    use crate::hash::HashAlgorithm;
+    use crate::metadata::set_times;
+    use crate::options::{CopyOptions, Overwrite};
+    use crate::verify::{verify_chunks, ChunkDigest};
+    use crate::Error;
+    use chrono::DateTime;
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::ops::Range;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::SystemTime;
+
+    /// What the first response told about the file.
+    struct Probe {
+        /// The size, if the server sent it.
+        size: Option<u64>,
+        /// Whether the server answered the probe with a range.
+        ranges: bool,
+        /// The `ETag`, or failing that the `Last-Modified` date, that later
+        /// ranges are asked for with `If-Range`.
+        validator: Option<String>,
+        modified: Option<SystemTime>,
+    }
+
+    pub(super) fn download(url: &str, dest: &Path, options: &CopyOptions) -> Result<u64, Error> {
+        let dest = if dest.is_dir() {
+            dest.join(file_name(url)?)
+        } else {
+            dest.to_path_buf()
+        };
+        if options.overwrite == Overwrite::Never && dest.exists() {
+            if options.dry_run {
+                println!("would skip '{}', it already exists", dest.display());
+            } else if options.progress {
+                eprintln!("Skipping '{}', it already exists", dest.display());
+            }
+            return Ok(0);
+        }
+
+        let agent = ureq::AgentBuilder::new().build();
+        // A one byte range shows whether the server takes ranges and gives
+        // the size in Content-Range. Servers that don't send the whole file,
+        // which is then copied from this response.
+        let response = retry(url, options, || agent.get(url).set("Range", "bytes=0-0"));
+        let response = match response {
+            // A range past the end of an empty file.
+            Err(RequestError::Status(416, _)) => None,
+            response => Some(response.map_err(|e| e.into_error(url))?),
+        };
+        let probe = match &response {
+            Some(response) => probe(response),
+            None => Probe {
+                size: Some(0),
+                ranges: false,
+                validator: None,
+                modified: None,
+            },
+        };
+
+        if options.dry_run {
+            let size = probe
+                .size
+                .map_or("unknown size".to_string(), |size| format!("{} bytes", size));
+            let action = if dest.exists() { "overwrite" } else { "copy" };
+            println!(
+                "would {} '{}' -> '{}' ({})",
+                action,
+                url,
+                dest.display(),
+                size
+            );
+            return Ok(probe.size.unwrap_or(0));
+        }
+
+        let file = File::create(&dest).map_err(|e| io_error("create", &dest, 0, e))?;
+        let algorithm = options.verify_hash.unwrap_or(HashAlgorithm::Blake3);
+        let mut digests = Vec::new();
+        let bytes_copied = AtomicU64::new(0);
+        let size = match (probe.ranges, probe.size) {
+            (true, Some(size)) => {
+                drop(response);
+                file.set_len(size)
+                    .map_err(|e| io_error("allocate", &dest, 0, e))?;
+                let ranges = chunks(
+                    std::slice::from_ref(&(0..size)),
+                    chunk_size(size, options.threads as u64, options.buffer_size),
+                );
+                digests = fetch_ranges(
+                    &agent,
+                    url,
+                    &probe,
+                    &file,
+                    &dest,
+                    &ranges,
+                    &bytes_copied,
+                    options,
+                )?;
+                size
+            }
+            _ => {
+                let mut hasher = algorithm.hasher();
+                // The probe only holds the first byte of a range of unknown
+                // size.
+                let response = match response {
+                    Some(response) if response.status() == 206 => Some(
+                        retry(url, options, || agent.get(url)).map_err(|e| e.into_error(url))?,
+                    ),
+                    response => response,
+                };
+                if let Some(response) = response {
+                    let mut body = response.into_reader();
+                    let mut buffer = vec![0; options.buffer_size];
+                    let mut offset = 0;
+                    while !options.is_cancelled() {
+                        let bytes_read = match body.read(&mut buffer) {
+                            Ok(0) => break,
+                            Ok(bytes_read) => bytes_read,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(e) => return Err(io_error("read", Path::new(url), offset, e)),
+                        };
+                        write_block(&file, &dest, &buffer[..bytes_read], offset, options)?;
+                        if options.verify {
+                            hasher.update(&buffer[..bytes_read]);
+                        }
+                        offset += bytes_read as u64;
+                        bytes_copied.store(offset, Ordering::SeqCst);
+                    }
+                }
+                let copied = bytes_copied.load(Ordering::SeqCst);
+                if options.verify {
+                    digests.push(ChunkDigest {
+                        range: 0..copied,
+                        digest: hasher.finish(),
+                    });
+                }
+                probe.size.unwrap_or(copied)
+            }
+        };
+        drop(file);
+
+        let bytes_copied = bytes_copied.into_inner();
+        if bytes_copied < size && options.is_cancelled() {
+            return Err(interrupted(&dest, bytes_copied, Some(size), false, options));
+        }
+        if bytes_copied != size {
+            return Err(format!("'{}' ended after {} of {} bytes", url, bytes_copied, size).into());
+        }
+        if options.verify {
+            let file = File::open(&dest).map_err(|e| io_error("open", &dest, 0, e))?;
+            verify_chunks(&dest, &file, size, &digests, algorithm, options)?;
+        }
+        if options.preserve {
+            if let Some(modified) = probe.modified {
+                set_times(&dest, SystemTime::now(), modified)?;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Fetch `ranges` of `url` into `file` on up to `options.threads`
+    /// threads, returning the digest of each range when verifying.
+    #[allow(clippy::too_many_arguments)]
+    fn fetch_ranges(
+        agent: &ureq::Agent,
+        url: &str,
+        probe: &Probe,
+        file: &File,
+        dest: &Path,
+        ranges: &[Range<u64>],
+        bytes_copied: &AtomicU64,
+        options: &CopyOptions,
+    ) -> Result<Vec<ChunkDigest>, Error> {
+        if options.progress {
+            eprintln!(" Downloading '{}' in {} ranges", url, ranges.len());
+        }
+        let next_range = AtomicUsize::new(0);
+        let digests = Mutex::new(Vec::new());
+        let failure: Mutex<Option<Error>> = Mutex::new(None);
+        thread::scope(|scope| {
+            for _ in 0..options.threads.min(ranges.len()) {
+                scope.spawn(|| {
+                    let mut buffer = vec![0; options.buffer_size];
+                    while let Some(range) = ranges.get(next_range.fetch_add(1, Ordering::SeqCst)) {
+                        if failure.lock().unwrap().is_some() || options.is_cancelled() {
+                            break;
+                        }
+                        let result = fetch_range(
+                            agent,
+                            url,
+                            probe,
+                            file,
+                            dest,
+                            range.clone(),
+                            &mut buffer,
+                            bytes_copied,
+                            options,
+                        );
+                        match result {
+                            Ok(Some(digest)) => digests.lock().unwrap().push(ChunkDigest {
+                                range: range.clone(),
+                                digest,
+                            }),
+                            Ok(None) => {}
+                            Err(e) => {
+                                failure.lock().unwrap().get_or_insert(e);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        if let Some(e) = failure.into_inner().unwrap() {
+            return Err(e);
+        }
+        let mut digests = digests.into_inner().unwrap();
+        digests.sort_by_key(|chunk| chunk.range.start);
+        Ok(digests)
+    }
+
+    /// Fetch `range` of `url` into `file`. Returns the digest of the range when
+    /// verifying.
+    #[allow(clippy::too_many_arguments)]
+    fn fetch_range(
+        agent: &ureq::Agent,
+        url: &str,
+        probe: &Probe,
+        file: &File,
+        dest: &Path,
+        range: Range<u64>,
+        buffer: &mut [u8],
+        bytes_copied: &AtomicU64,
+        options: &CopyOptions,
+    ) -> Result<Option<String>, Error> {
+        let algorithm = options.verify_hash.unwrap_or(HashAlgorithm::Blake3);
+        let mut hasher = algorithm.hasher();
+        let mut offset = range.start;
+        let mut attempt = 0;
+        while offset < range.end && !options.is_cancelled() {
+            let result = retry(url, options, || {
+                let mut request = agent
+                    .get(url)
+                    .set("Range", &format!("bytes={}-{}", offset, range.end - 1));
+                if let Some(validator) = &probe.validator {
+                    request = request.set("If-Range", validator);
+                }
+                request
+            });
+            let response = result.map_err(|e| e.into_error(url))?;
+            let start = response
+                .header("Content-Range")
+                .and_then(content_range)
+                .map(|(start, _)| start);
+            if response.status() != 206 || start != Some(offset) {
+                return Err(format!("'{}' changed on the server while it was copied", url).into());
+            }
+
+            let mut body = response.into_reader().take(range.end - offset);
+            let failed = loop {
+                if offset >= range.end || options.is_cancelled() {
+                    break None;
+                }
+                let len = buffer.len().min((range.end - offset) as usize);
+                match body.read(&mut buffer[..len]) {
+                    Ok(0) => break Some(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(bytes_read) => {
+                        let data = &buffer[..bytes_read];
+                        write_block(file, dest, data, offset, options)?;
+                        if options.verify {
+                            hasher.update(data);
+                        }
+                        offset += bytes_read as u64;
+                        bytes_copied.fetch_add(bytes_read as u64, Ordering::SeqCst);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => break Some(e),
+                }
+            };
+            // A response that broke off is asked for again from where it
+            // stopped.
+            if let Some(e) = failed {
+                let e = io_error("read", Path::new(url), offset, e);
+                if attempt >= options.retries {
+                    return Err(e);
+                }
+                let delay = options.retry_delay * 2u32.pow(attempt.min(16));
+                attempt += 1;
+                log::warn!(
+                    "{}, retry {} of {} in {:.1?}",
+                    e,
+                    attempt,
+                    options.retries,
+                    delay
+                );
+                thread::sleep(delay);
+            }
+        }
+        Ok(options.verify.then(|| hasher.finish()))
+    }
+
+    fn write_block(
+        file: &File,
+        dest: &Path,
+        data: &[u8],
+        offset: u64,
+        options: &CopyOptions,
+    ) -> Result<(), Error> {
+        if let Some(limit) = &options.bwlimit {
+            limit.take(data.len());
+        }
+        file.write_all_at(data, offset)
+            .map_err(|(offset, e)| io_error("write", dest, offset, e))
+    }
+
+    fn probe(response: &ureq::Response) -> Probe {
+        let ranged = response.status() == 206;
+        let size = if ranged {
+            response
+                .header("Content-Range")
+                .and_then(content_range)
+                .and_then(|(_, size)| size)
+        } else {
+            response
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())
+        };
+        let etag = response
+            .header("ETag")
+            .filter(|etag| !etag.starts_with("W/"));
+        let last_modified = response.header("Last-Modified");
+        Probe {
+            size,
+            ranges: ranged && size.is_some(),
+            validator: etag.or(last_modified).map(str::to_string),
+            modified: last_modified.and_then(http_date),
+        }
+    }
+
+    /// The start and total size of `bytes start-end/size`.
+    fn content_range(value: &str) -> Option<(u64, Option<u64>)> {
+        let (range, size) = value.strip_prefix("bytes ")?.split_once('/')?;
+        let (start, _) = range.split_once('-')?;
+        Some((start.trim().parse().ok()?, size.trim().parse().ok()))
+    }
+
+    /// The file name a URL is saved under in a directory.
+    fn file_name(url: &str) -> Result<PathBuf, Error> {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        let path = path.split_once("://").map_or(path, |(_, rest)| rest);
+        let name = path
+            .split_once('/')
+            .and_then(|(_, path)| path.rsplit('/').next())
+            .filter(|name| !name.is_empty() && *name != "." && *name != "..");
+        match name {
+            Some(name) => Ok(PathBuf::from(name)),
+            None => Err(format!(
+                "Cannot tell a file name from '{}', give the destination file",
+                url
+            )
+            .into()),
+        }
+    }
+
+    /// Parse an HTTP date, `Sun, 06 Nov 1994 08:49:37 GMT`.
+    fn http_date(value: &str) -> Option<SystemTime> {
+        DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(SystemTime::from)
+    }
+
+    /// A failed request, with the status of the response if there was one.
+    enum RequestError {
+        Status(u16, String),
+        Transport(String),
+    }
+
+    impl RequestError {
+        fn into_error(self, url: &str) -> Error {
+            match self {
+                RequestError::Status(status @ (404 | 410), text) => Box::new(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("GET of '{}' failed: {} {}", url, status, text),
+                )),
+                RequestError::Status(status, text) => {
+                    format!("GET of '{}' failed: {} {}", url, status, text).into()
+                }
+                RequestError::Transport(message) => {
+                    format!("GET of '{}' failed: {}", url, message).into()
+                }
+            }
+        }
+    }
+
+    /// Send a request, retrying throttling, server errors and dropped
+    /// connections as set with [`CopyOptions::retries`].
+    fn retry(
+        url: &str,
+        options: &CopyOptions,
+        mut request: impl FnMut() -> ureq::Request,
+    ) -> Result<ureq::Response, RequestError> {
+        let mut attempt = 0;
+        loop {
+            let error = match request().call() {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(status, response)) => {
+                    RequestError::Status(status, response.status_text().to_string())
+                }
+                Err(ureq::Error::Transport(transport)) => {
+                    RequestError::Transport(transport.to_string())
+                }
+            };
+            let transient = match &error {
+                RequestError::Status(status, _) => *status == 429 || *status >= 500,
+                RequestError::Transport(_) => true,
+            };
+            if !transient || attempt >= options.retries {
+                return Err(error);
+            }
+            let delay = options.retry_delay * 2u32.pow(attempt.min(16));
+            attempt += 1;
+            let message = format!(
+                "{}, retry {} of {} in {:.1?}",
+                error.into_error(url),
+                attempt,
+                options.retries,
+                delay
+            );
+            log::warn!("{}", message);
+            if let Some(stats) = &options.stats {
+                stats.retried();
+            }
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(not(feature = "http"))]
+mod imp {
+    use crate::options::CopyOptions;
+    use crate::Error;
+    use std::path::Path;
+
+    pub(super) fn download(_url: &str, _dest: &Path, _options: &CopyOptions) -> Result<u64, Error> {
+        Err("This build has no HTTP support, rebuild with --features http".into())
+    }
+}
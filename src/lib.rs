@@ -0,0 +1,104 @@
+//! Threaded copying of files to steal bandwidth.
+//!
+//! This is the copy engine behind the `rpcp` command line tool. Files are
+//! divided into chunks that a pool of threads copies in parallel using
+//! positional reads and writes, which makes it possible to saturate the
+//! bandwidth of storage that rewards many parallel requests, such as NAS
+//! devices.
+
+mod archive;
+mod backend;
+mod backup;
+mod budget;
+mod cache;
+mod cancel;
+mod checkpoint;
+mod control;
+mod copy;
+mod delta;
+mod device;
+mod direct;
+mod encrypt;
+mod filter;
+mod follow;
+mod fsync;
+mod hash;
+mod http;
+mod limit;
+mod logfile;
+mod manifest;
+mod metadata;
+mod metrics;
+#[cfg(unix)]
+mod mmap;
+mod numa;
+mod offload;
+mod options;
+mod partial;
+mod pipeline;
+mod pool;
+mod priority;
+mod progress;
+mod prompt;
+mod remote;
+mod s3;
+mod scale;
+mod schedule;
+mod space;
+mod sparse;
+mod special;
+#[cfg(target_os = "linux")]
+mod splice;
+mod split;
+#[cfg(feature = "ssh")]
+mod ssh;
+mod stats;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod tree;
+mod tune;
+#[cfg(feature = "io-uring")]
+mod uring;
+#[cfg(target_os = "linux")]
+mod vectored;
+mod verify;
+mod walker;
+mod watch;
+mod xattr;
+
+pub use archive::{extract_archive, write_archive};
+pub use backend::StorageBackend;
+pub use cancel::CancelledError;
+pub use control::{serve_control, Control, ControlSocket};
+pub use copy::{copy_backend, copy_fanout, copy_file};
+pub use encrypt::Recipients;
+pub use filter::Filter;
+pub use hash::{file_digest, HashAlgorithm};
+pub use http::{download_url, is_url};
+pub use logfile::set_up_logging;
+pub use manifest::{manifest_algorithm, verify_manifest, write_manifest, Manifest};
+pub use metrics::{serve_metrics, Metrics};
+pub use numa::{numa_node_of, numa_nodes};
+pub use options::{CopyOptions, Engine, FileOrder, Fsync, Overwrite, Symlinks};
+pub use partial::{CopyFailure, PartialCopyError};
+pub use priority::{parse_cpu_list, set_cpu_affinity, set_io_priority, set_nice, IoClass};
+pub use progress::parse_size;
+pub use remote::{download, upload, Remote};
+pub use s3::{upload_to_s3, S3Object};
+pub use schedule::BwSchedule;
+pub use sparse::Sparse;
+pub use split::join_parts;
+pub use stats::CopyStats;
+pub use stream::{copy_from_reader, copy_to_writer};
+pub use tree::{copy_into, copy_listed, copy_tree};
+pub use tune::auto_threads;
+pub use verify::{repair_copy, verify_copy, verify_tree, VerifyError, VerifyFailure};
+pub use watch::watch_tree;
+
+/// Error returned by the copy and verify functions.
+///
+/// Errors are `Send` so that they can be handed back from copy threads.
+/// Verification mismatches can be told apart from IO errors by
+/// downcasting to [`VerifyError`].
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
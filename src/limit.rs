@@ -0,0 +1,145 @@
+use crate::schedule::BwSchedule;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the limit is looked up in the schedule.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Token bucket shared by every thread writing, limiting their combined
+/// throughput to a number of bytes per second, or holding them while the
+/// copy is paused. With a schedule the limit changes with the time of day.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    resumed: Condvar,
+    /// Bytes taken so far.
+    taken: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// No limit when 0.
+    bytes_per_second: u64,
+    /// Bytes that may be written right now. Goes negative when a thread
+    /// takes more than is available, and the thread sleeps off the debt.
+    tokens: f64,
+    refilled: Instant,
+    paused: bool,
+    /// The limit outside the windows of the schedule.
+    base: u64,
+    schedule: Option<BwSchedule>,
+    /// The limit of the schedule when it was last looked up, `None` before
+    /// the first time.
+    scheduled: Option<Option<u64>>,
+    checked: Instant,
+}
+
+impl RateLimiter {
+    /// A limit of `bytes_per_second`, or none when it is 0.
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                bytes_per_second,
+                tokens: 0.0,
+                refilled: Instant::now(),
+                paused: false,
+                base: bytes_per_second,
+                schedule: None,
+                scheduled: None,
+                checked: Instant::now(),
+            }),
+            resumed: Condvar::new(),
+            taken: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait until `bytes` may be written.
+    pub(crate) fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            while bucket.paused {
+                bucket = self.resumed.wait(bucket).unwrap();
+            }
+            self.taken.fetch_add(bytes as u64, Ordering::SeqCst);
+            let now = Instant::now();
+            if bucket.schedule.is_some()
+                && (bucket.scheduled.is_none()
+                    || now.duration_since(bucket.checked) >= SCHEDULE_INTERVAL)
+            {
+                bucket.checked = now;
+                let scheduled = bucket.schedule.as_ref().and_then(BwSchedule::rate_now);
+                // A limit set since the schedule last changed stands until it
+                // changes again.
+                if bucket.scheduled != Some(scheduled) {
+                    bucket.scheduled = Some(scheduled);
+                    bucket.bytes_per_second = scheduled.unwrap_or(bucket.base);
+                    bucket.tokens = bucket.tokens.max(0.0);
+                }
+            }
+            let rate = bucket.bytes_per_second as f64;
+            if rate == 0.0 {
+                bucket.refilled = now;
+                return;
+            }
+            // Allow at most a second's worth of burst after being idle.
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.refilled).as_secs_f64() * rate)
+                .min(rate);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Change the limit to `bytes_per_second`, or lift it with 0, until the
+    /// schedule next changes it, and outside the windows of the schedule.
+    pub(crate) fn set_rate(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.bytes_per_second = bytes_per_second;
+        bucket.base = bytes_per_second;
+        // Debt run up under a lower limit is not carried over.
+        bucket.tokens = bucket.tokens.max(0.0);
+    }
+
+    pub(crate) fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_second
+    }
+
+    /// Change the limit with the time of day as `schedule` says, from the
+    /// next write on.
+    pub(crate) fn set_schedule(&self, schedule: Option<BwSchedule>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.schedule = schedule;
+        bucket.scheduled = None;
+        if bucket.schedule.is_none() {
+            bucket.bytes_per_second = bucket.base;
+        }
+    }
+
+    /// Hold every thread at its next write until [`resume`](Self::resume).
+    pub(crate) fn pause(&self) {
+        self.bucket.lock().unwrap().paused = true;
+    }
+
+    pub(crate) fn resume(&self) {
+        self.bucket.lock().unwrap().paused = false;
+        self.resumed.notify_all();
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.bucket.lock().unwrap().paused
+    }
+
+    pub(crate) fn taken(&self) -> u64 {
+        self.taken.load(Ordering::SeqCst)
+    }
+}
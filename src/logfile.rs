@@ -0,0 +1,112 @@
+use crate::Error;
+use chrono::{DateTime, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The target of records of failures that are reported all together once a
+/// copy ends, which only go to the log file.
+pub(crate) const FAILURE: &str = "rpcp::failure";
+
+/// Prints records to stderr as `Warning: ...` or `Error: ...`, and writes
+/// each to a file, if there is one, as a line of its time in UTC, its
+/// level, the module it came from and its message.
+struct Logger {
+    stderr: LevelFilter,
+    file: Option<(Mutex<LineWriter<File>>, LevelFilter)>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let file = self
+            .file
+            .as_ref()
+            .map_or(LevelFilter::Off, |(_, level)| *level);
+        metadata.level() <= self.stderr.max(file)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= self.stderr && record.target() != FAILURE {
+            match record.level() {
+                Level::Error => eprintln!("Error: {}", record.args()),
+                Level::Warn => eprintln!("Warning: {}", record.args()),
+                _ => eprintln!("{}", record.args()),
+            }
+        }
+        let Some((file, level)) = &self.file else {
+            return;
+        };
+        if record.level() > *level {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} {}: {}\n",
+            timestamp(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        // A log that can't be written to must not stop the copy.
+        let _ = file.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        if let Some((file, _)) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Print warnings and errors, or whatever is at `stderr` and above, to
+/// stderr, and with `log_file` record what copies do at its level and above
+/// in that file, which is appended to, so one log can hold a series of
+/// runs. Files started, finished, skipped, verified and failed are logged
+/// at info, retries and files that changed as they were copied at warn.
+///
+/// When the file can't be opened messages still go to stderr.
+pub fn set_up_logging(
+    log_file: Option<(&Path, LevelFilter)>,
+    stderr: LevelFilter,
+) -> Result<(), Error> {
+    let file = log_file.map(|(path, level)| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(|file| (Mutex::new(LineWriter::new(file)), level))
+            .map_err(|e| format!("Failed to open log file '{}': {}", path.display(), e))
+    });
+    let (file, opened) = match file {
+        Some(Ok(file)) => (Some(file), Ok(())),
+        Some(Err(e)) => (None, Err(e.into())),
+        None => (None, Ok(())),
+    };
+    let level = stderr.max(file.as_ref().map_or(LevelFilter::Off, |(_, level)| *level));
+    let logger = Logger { stderr, file };
+    log::set_boxed_logger(Box::new(logger)).map_err(|_| "A logger was already set".to_string())?;
+    log::set_max_level(level);
+    opened
+}
+
+/// `time` as an RFC 3339 timestamp in UTC to the millisecond.
+fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(951_827_696_789);
+        assert_eq!(timestamp(time), "2000-02-29T12:34:56.789Z");
+    }
+}
@@ -1,34 +1,1362 @@
-use clap::Parser;
-use nix::sys::uio::{pread, pwrite};
-use std::io;
-use std::io::Read;
-use std::path::Path;
-use std::sync::{atomic::AtomicUsize, atomic::Ordering, Arc};
-use std::thread;
-use std::{
-    fs::{create_dir_all, File},
-    path::PathBuf,
+use chrono::{NaiveDate, NaiveTime};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use config::{Config, Value};
+use daemon::run_daemon;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hooks::{run_hooks, set_hooks, Hooks};
+use rpcp::{
+    copy_fanout, copy_file, copy_from_reader, copy_into, copy_listed, copy_to_writer, copy_tree,
+    download, download_url, extract_archive, is_url, join_parts, manifest_algorithm, parse_size,
+    repair_copy, serve_control, serve_metrics, set_up_logging, upload, upload_to_s3, verify_copy,
+    verify_manifest, verify_tree, watch_tree, write_archive, BwSchedule, CancelledError, Control,
+    CopyOptions, CopyStats, Engine, FileOrder, Filter, Fsync, HashAlgorithm, IoClass, Manifest,
+    Metrics, Overwrite, PartialCopyError, Recipients, Remote, S3Object, Sparse, Symlinks,
+    VerifyError,
 };
-use walkdir::WalkDir;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod config;
+mod daemon;
+mod hooks;
 
 #[derive(Parser)]
 #[command(name = "Parallel copy")]
 #[command(author = "Matt S. <matt.storey@netvalue.nz>")]
 #[command(version = "0.1.0")]
 #[command(about = "Threaded copying of files to steal bandwidth", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    ///Source file path
-    in_file: PathBuf,
-    ///Destination file path
-    out_file: PathBuf,
-    #[arg(short, long, default_value_t = 10)]
-    threads: u8,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Without a subcommand rpcp copies, `rpcp SRC DEST` being the same as
+    /// `rpcp copy SRC DEST`.
+    #[command(flatten)]
+    copy: CopyArgs,
+}
+
+#[derive(Args)]
+struct CopyArgs {
+    /// Read defaults for the options from FILE instead of
+    /// ~/.config/rpcp/config.toml
+    #[arg(long, value_name = "FILE", conflicts_with = "no_config")]
+    config: Option<PathBuf>,
+    /// Use the defaults of the [profile.NAME] table of the config file
+    #[arg(long, value_name = "NAME", conflicts_with = "no_config")]
+    profile: Option<String>,
+    /// Ignore the config file
+    #[arg(long)]
+    no_config: bool,
+    ///Source paths, followed by the destination unless --target-directory
+    ///is given
+    #[arg(required = true, value_name = "PATHS")]
+    paths: Vec<PathBuf>,
+    /// Copy every source into DIR under its own name
+    #[arg(long, value_name = "DIR")]
+    target_directory: Option<PathBuf>,
+    /// Copy the first path, a file, to every path after it, reading each
+    /// chunk once and writing it to all of them
+    #[arg(long)]
+    fanout: bool,
+    /// Copy the paths listed in LIST, one per line and relative to the
+    /// source, into the destination. Use - to read the list from stdin
+    #[arg(long, value_name = "LIST")]
+    files_from: Option<PathBuf>,
+    /// Paths in the --files-from list are separated by NUL characters, as
+    /// printed by find -print0
+    #[arg(short = '0', long, requires = "files_from")]
+    from0: bool,
+    /// Number of threads, or auto to pick them for the storage copied
+    /// between and the size of the files
+    #[arg(short, long, default_value = "auto", value_parser = parse_threads)]
+    threads: Threads,
+    /// Change the number of threads while each file is copied, up to
+    /// --threads, to the number that copies fastest
+    #[arg(long)]
+    adaptive: bool,
+    /// Threads reading each file with --engine pipeline [default: --threads]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    read_threads: Option<u64>,
+    /// Threads writing each file with --engine pipeline [default: --threads]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    write_threads: Option<u64>,
+    /// Size of each thread's read buffer and of verification reads, e.g.
+    /// 64K or 8M
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = parse_size)]
+    buffer_size: u64,
+    /// Cap the memory of all copy and verification buffers at SIZE, running
+    /// fewer threads when more would not fit, e.g. 256M
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<u64>,
+    /// Run the copy threads on the CPUs of NUMA node N and keep their
+    /// buffers in its memory, or auto for the node of the destination's disk
+    #[arg(long, value_name = "N", value_parser = parse_numa_node)]
+    numa_node: Option<NumaNode>,
+    /// Run only on these CPUs, e.g. 0-3,8
+    #[arg(long, value_name = "LIST", value_parser = parse_cpuset)]
+    cpuset: Option<Cpus>,
+    /// Run at this nice value, from -20, the highest priority, to 19
+    #[arg(long, value_name = "N", allow_hyphen_values = true,
+          value_parser = clap::value_parser!(i32).range(-20..=19))]
+    nice: Option<i32>,
+    /// IO scheduling class: realtime, best-effort or idle
+    #[arg(long, value_name = "CLASS")]
+    ionice_class: Option<IoClass>,
+    /// IO priority within the class, from 0, the highest, to 7
+    /// [default: 4]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=7))]
+    ionice_level: Option<u8>,
     #[arg(short, long)]
     ///Copy all file in source directory to destination directory
     recursive: bool,
     #[arg(short, long)]
     /// Verifies the copy completed successfully
     verify: bool,
+    /// Verify by comparing digests: blake3, sha256, xxh3 or crc32. Implies --verify
+    #[arg(long, value_name = "ALGORITHM")]
+    verify_hash: Option<HashAlgorithm>,
+    /// Record progress in a checkpoint file next to the destination and
+    /// continue an interrupted copy
+    #[arg(long)]
+    resume: bool,
+    /// Start reading the source SIZE in, like dd skip=, e.g. 1G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    skip: Option<u64>,
+    /// Start writing the destination SIZE in, like dd seek=
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    seek: Option<u64>,
+    /// Copy no more than SIZE of the source, like dd count=
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    count: Option<u64>,
+    /// Bring files that exist at the destination up to date in place by
+    /// writing only the 64K blocks that differ from the source
+    #[arg(long)]
+    delta: bool,
+    /// Write files larger than SIZE as parts of SIZE, file.part0000,
+    /// file.part0001 and so on, for rpcp join to put back together
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    split: Option<u64>,
+    /// Keep copying what is appended to the source until it has not grown
+    /// for QUIET, 10s by default, then verify it
+    #[arg(long, value_name = "QUIET", num_args = 0..=1, require_equals = true, default_missing_value = "10s", value_parser = parse_duration)]
+    follow: Option<Duration>,
+    /// Fail files that change while they are copied, instead of warning
+    #[arg(long)]
+    fail_on_change: bool,
+    /// Preserve permissions and access/modification times
+    #[arg(short, long)]
+    preserve: bool,
+    /// Preserve owner and group, usually needs root. The same as -o -g
+    #[arg(long)]
+    preserve_ownership: bool,
+    /// Preserve the owner, needs root
+    #[arg(short, long)]
+    owner: bool,
+    /// Preserve the group
+    #[arg(short, long)]
+    group: bool,
+    /// Carry owners and groups over by id rather than by name. Local
+    /// copies always do, names and ids being the same on one machine
+    #[arg(long)]
+    numeric_ids: bool,
+    /// Preserve user and trusted extended attributes
+    #[arg(short = 'X', long)]
+    xattrs: bool,
+    /// Preserve POSIX ACLs
+    #[arg(short = 'A', long)]
+    acls: bool,
+    /// In recursive mode, recreate FIFOs and sockets instead of skipping them
+    #[arg(long)]
+    specials: bool,
+    /// In recursive mode, recreate device nodes instead of skipping them,
+    /// needs root
+    #[arg(long)]
+    devices: bool,
+    /// In recursive mode, recreate hard links between files instead of
+    /// copying each of them
+    #[arg(short = 'H', long)]
+    hard_links: bool,
+    /// Hard link files unchanged since the copy in the tree DIR, an earlier
+    /// snapshot of the same source, instead of copying them
+    #[arg(long, value_name = "DIR")]
+    link_dest: Option<PathBuf>,
+    /// Clone files unchanged since the copy in the tree DIR with a reflink
+    /// instead of copying them, so they share its data
+    #[arg(long, value_name = "DIR", conflicts_with = "link_dest")]
+    reflink_dest: Option<PathBuf>,
+    /// Recreate symlinks as symlinks instead of copying what they point to
+    #[arg(long, conflicts_with = "dereference")]
+    links: bool,
+    /// Copy what symlinks point to, descending into linked directories (default)
+    #[arg(short = 'L', long)]
+    dereference: bool,
+    /// Skip symlinks that point outside the source tree
+    #[arg(long)]
+    safe_links: bool,
+    /// Leave holes in the destination: always (also for blocks of zeros),
+    /// auto (where the source has holes) or never
+    #[arg(long, value_name = "WHEN", default_value_t = Sparse::Auto)]
+    sparse: Sparse,
+    /// Skip entries matching PATTERN in recursive mode, can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Copy entries matching PATTERN even if a later --exclude matches them,
+    /// can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+    /// In recursive mode, descend at most N levels below the source
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+    /// In recursive mode, the order files are started in: walk (as the tree
+    /// is walked), name, size (smallest first), largest-first or inode
+    #[arg(long, value_name = "ORDER", default_value_t = FileOrder::Walk)]
+    order: FileOrder,
+    /// In recursive mode, read directories with N threads at a time, for
+    /// trees of millions of entries
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    walk_threads: u64,
+    /// In recursive mode, copy files under 1M N at a time per thread, each
+    /// read whole and written out at once, for trees of many tiny files
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    batch: u64,
+    /// In recursive mode, skip files smaller than SIZE, e.g. 1G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+    /// In recursive mode, skip files larger than SIZE
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// In recursive mode, skip files modified before WHEN: an age like 30d
+    /// or 12h, or a UTC date like 2024-05-01 or '2024-05-01 12:00'
+    #[arg(long, value_name = "WHEN", value_parser = parse_when)]
+    newer_than: Option<SystemTime>,
+    /// In recursive mode, skip files modified after WHEN
+    #[arg(long, value_name = "WHEN", value_parser = parse_when)]
+    older_than: Option<SystemTime>,
+    /// In recursive mode, don't cross into other filesystems mounted below
+    /// the source
+    #[arg(short = 'x', long)]
+    one_file_system: bool,
+    /// Print what would be copied, created or overwritten without writing
+    /// anything
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Limit the combined write rate of all threads, in bytes per second,
+    /// e.g. 200M
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    bwlimit: Option<u64>,
+    /// Change the limit with the local time of day, e.g.
+    /// 09:00-18:00=100M,18:00-09:00=0 (0 for no limit). Outside the windows
+    /// --bwlimit applies
+    #[arg(long, value_name = "SCHEDULE")]
+    bwlimit_schedule: Option<BwSchedule>,
+    /// Listen on a Unix socket at PATH for commands to the running copy, one
+    /// per line: pause, resume, status or set-bwlimit RATE (0 or off lifts
+    /// the limit)
+    #[arg(long, value_name = "PATH")]
+    control: Option<PathBuf>,
+    /// How threads move data: pread (one blocking read and write at a time),
+    /// io_uring (batched, needs the io-uring cargo feature), mmap (writes
+    /// out of a mapping of the source), sendfile (copies in the kernel),
+    /// preadv (batches of buffers per call) or pipeline (separate reader and
+    /// writer threads)
+    #[arg(long, value_name = "ENGINE", default_value_t = Engine::Pread)]
+    engine: Engine,
+    /// Bypass the page cache with O_DIRECT, for huge files that would
+    /// otherwise push everything else out of memory
+    #[arg(long)]
+    direct: bool,
+    /// Don't allocate the destination's space with fallocate before writing
+    #[arg(long)]
+    no_preallocate: bool,
+    /// Don't check that the destination has room for the copy before it
+    /// starts
+    #[arg(long)]
+    no_space_check: bool,
+    /// Drop copied data from the page cache as the copy goes, so other
+    /// programs keep their cache
+    #[arg(long)]
+    drop_cache: bool,
+    /// Write out copied data as the copy goes, so dirty pages don't pile up
+    /// and stall the system when it writes them back
+    #[arg(long)]
+    writeback: bool,
+    /// Retry reads and writes that fail with errors that may clear up, like
+    /// EIO or ESTALE on network mounts, up to N times
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+    /// Wait before the first retry, doubled for each one after, e.g. 500ms
+    /// or 2s
+    #[arg(long, value_name = "DURATION", default_value = "1s", value_parser = parse_duration)]
+    retry_delay: Duration,
+    /// When interrupted, keep partly copied files as <dest>.partial instead
+    /// of removing them
+    #[arg(long)]
+    partial: bool,
+    /// Copy each file to a temporary file and rename it into place once it
+    /// is complete and verified
+    #[arg(long)]
+    atomic: bool,
+    /// Write each file as its name with SUFFIX added, .rpcp-part by
+    /// default, and rename it once it is complete
+    #[arg(long, value_name = "SUFFIX", num_args = 0..=1, require_equals = true, default_missing_value = ".rpcp-part")]
+    partial_suffix: Option<String>,
+    /// Rewrite files that already exist where they are, instead of through
+    /// a temporary file renamed over them
+    #[arg(long)]
+    inplace: bool,
+    /// Move files about to be overwritten or deleted aside to their name
+    /// with SUFFIX added, ~ by default
+    #[arg(long, value_name = "SUFFIX", num_args = 0..=1, require_equals = true, default_missing_value = "~")]
+    backup: Option<String>,
+    /// Move files about to be overwritten or deleted into DIR instead,
+    /// keeping their path relative to the destination
+    #[arg(long, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+    /// Flush each file and its directory to the disk before counting it as
+    /// copied, so the copy survives a power cut
+    #[arg(long, conflicts_with = "fdatasync")]
+    fsync: bool,
+    /// Like --fsync, but leave out metadata not needed to read the data back
+    #[arg(long)]
+    fdatasync: bool,
+    /// Never overwrite existing files
+    #[arg(long, conflicts_with_all = ["force", "update"])]
+    no_clobber: bool,
+    /// Ask before overwriting each existing file, answering all or none for
+    /// the rest
+    #[arg(short, long, conflicts_with_all = ["force", "update", "no_clobber"])]
+    interactive: bool,
+    /// Overwrite existing files (default)
+    #[arg(short, long)]
+    force: bool,
+    /// Only overwrite files that are older than their source
+    #[arg(short, long, conflicts_with = "force")]
+    update: bool,
+    /// In recursive mode, copy files even when the destination has the same
+    /// size and modification time
+    #[arg(short = 'I', long)]
+    ignore_times: bool,
+    /// In recursive mode, skip files whose destination has the same size
+    /// and digest rather than the same modification time
+    #[arg(short, long, conflicts_with = "ignore_times")]
+    checksum: bool,
+    /// In recursive mode, delete entries of the destination that are not in
+    /// the source
+    #[arg(long)]
+    delete: bool,
+    /// With --delete, don't delete anything if it would delete more than N
+    /// entries
+    #[arg(long, value_name = "N")]
+    max_delete: Option<usize>,
+    /// Remove each source file once it has been copied and verified, and
+    /// source directories left empty
+    #[arg(long = "move")]
+    move_files: bool,
+    /// Once the copy is done, write the digest of every file of the copy,
+    /// taken as it is copied, to FILE, with the algorithm its extension
+    /// names (.b3, .sha256, .xxh3 or .crc32), the --verify-hash one or
+    /// blake3
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    write_manifest: Option<PathBuf>,
+    /// Write the copies encrypted to the age recipients age:RECIPIENT, of
+    /// X25519 keys separated by commas or in a file, one per line
+    #[arg(long, value_name = "age:RECIPIENT")]
+    encrypt: Option<Recipients>,
+    /// Write the sources to FILE as a tar archive instead of copying them,
+    /// gzip-compressed when FILE ends in .gz or .tgz, or to stdout with -
+    #[arg(long, value_name = "FILE", conflicts_with = "from_archive")]
+    to_archive: Option<PathBuf>,
+    /// Extract the tar archive FILE, plain or gzip-compressed, into the
+    /// destination directory, or the one on stdin with -
+    #[arg(long, value_name = "FILE")]
+    from_archive: Option<PathBuf>,
+    /// Write newline-delimited JSON events to stdout as files are copied
+    /// and verified, with progress and a final summary
+    #[arg(long, conflicts_with = "dry_run")]
+    json: bool,
+    /// Print nothing but errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print every file once it is copied with its size and time, and
+    /// twice also skipped files and created directories and links
+    #[arg(long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// In recursive mode, print a line for every entry saying what was done
+    /// to it and why: created, updated, identical, metadata, skipped,
+    /// linked, cloned, failed or deleted
+    #[arg(long, conflicts_with_all = ["json", "dry_run"])]
+    itemize: bool,
+    /// In recursive mode, keep copying when a file or directory fails and
+    /// list the failures at the end
+    #[arg(long)]
+    continue_on_error: bool,
+    /// With --continue-on-error, give up once more than N entries failed
+    #[arg(long, value_name = "N", requires = "continue_on_error")]
+    max_errors: Option<usize>,
+    /// Write the statistics of the run to FILE as JSON, also when it fails
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    stats_file: Option<PathBuf>,
+    /// Serve the bytes and files copied, the rate, retries and verification
+    /// failures to Prometheus at /metrics on ADDR, e.g. 127.0.0.1:9184
+    #[arg(long, value_name = "ADDR")]
+    metrics: Option<String>,
+    /// Run CMD with the shell once the copy has succeeded, with how it went
+    /// in RPCP_SUMMARY as JSON
+    #[arg(long, value_name = "CMD")]
+    on_complete: Option<String>,
+    /// Run CMD with the shell when the copy fails, with why in RPCP_ERROR
+    #[arg(long, value_name = "CMD")]
+    on_error: Option<String>,
+    /// POST a JSON summary of how the copy ended to URL, e.g. a Slack
+    /// incoming webhook. Needs the http feature
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+    /// Append a record of every file started, finished, skipped or failed
+    /// to FILE
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+    /// What to record in the --log-file: error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL", default_value = "info", value_parser = parse_log_level, requires = "log_file")]
+    log_level: log::LevelFilter,
+    /// With watch, copy a changed file once nothing has changed it for
+    /// DURATION, 1s by default
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    debounce: Option<Duration>,
+    /// Keep copying changes once the tree is copied, set by watch
+    #[arg(skip)]
+    watch: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Copy files or trees, the same as rpcp without a subcommand
+    Copy(CopyArgs),
+    /// Make DEST a mirror of SRC, the same as copy with -r and --delete
+    Sync(CopyArgs),
+    /// Make DEST a mirror of SRC as sync does, then keep copying what
+    /// changes in SRC as it changes, until interrupted. Linux only
+    Watch(CopyArgs),
+    /// Run copy jobs sent to a Unix socket, a few at a time within one
+    /// bandwidth limit, until interrupted. Unix only
+    Daemon(DaemonArgs),
+    /// Compare two files or trees without copying anything
+    Verify(VerifyArgs),
+    /// Put the parts of a file written with --split back together and
+    /// check them against the digests written with them
+    Join(JoinArgs),
+    /// Time copies of a file or tree with each combination of engine,
+    /// threads and buffer size, to find the fastest settings
+    Bench(BenchArgs),
+    /// Print a completion script for SHELL, e.g. to load with
+    /// `source <(rpcp completions bash)`
+    Completions {
+        #[arg(value_name = "SHELL")]
+        shell: Shell,
+    },
+}
+
+#[derive(Args)]
+struct DaemonArgs {
+    /// The Unix socket to take jobs and commands on, one per line: submit
+    /// ARGS (the arguments of a copy or sync, quoted as for a shell, with
+    /// absolute paths), status [ID], cancel ID, pause ID or resume ID
+    #[arg(long, value_name = "PATH")]
+    socket: PathBuf,
+    /// How many jobs run at the same time
+    #[arg(short, long, default_value_t = 2)]
+    jobs: usize,
+    /// Limit the combined write rate of all jobs running, in bytes per
+    /// second, which is split evenly between them
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    bwlimit: Option<u64>,
+    /// Serve the totals of all jobs and how many are queued, running, done,
+    /// failed and cancelled to Prometheus at /metrics on ADDR
+    #[arg(long, value_name = "ADDR")]
+    metrics: Option<String>,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// The original, or with --manifest the tree to check
+    #[arg(value_name = "SRC")]
+    src: PathBuf,
+    #[arg(value_name = "DEST", required_unless_present = "manifest")]
+    dest: Option<PathBuf>,
+    /// Check the tree against the digests in FILE, written by
+    /// --write-manifest, instead of against SRC
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["dest", "repair"])]
+    manifest: Option<PathBuf>,
+    /// Compare every file under SRC with its counterpart under DEST
+    #[arg(short, long)]
+    recursive: bool,
+    /// Compare digests instead of bytes: blake3, sha256, xxh3 or crc32
+    #[arg(long, value_name = "ALGORITHM")]
+    hash: Option<HashAlgorithm>,
+    /// Number of threads, or auto to pick them for the storage read from
+    #[arg(short, long, default_value = "auto", value_parser = parse_threads)]
+    threads: Threads,
+    /// Size of each read, e.g. 64K or 8M
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = parse_size)]
+    buffer_size: u64,
+    /// Copy the chunks that differ from SRC again instead of failing
+    #[arg(long)]
+    repair: bool,
+}
+
+#[derive(Args)]
+struct JoinArgs {
+    /// The name the parts were written to, e.g. file for file.part0000 and
+    /// the parts after it, or the path of one of them
+    #[arg(value_name = "PARTS")]
+    parts: PathBuf,
+    /// The file to join them into, or a directory to join them into under
+    /// their name
+    #[arg(value_name = "DEST")]
+    dest: PathBuf,
+    /// Check the parts against what is written with digests: blake3,
+    /// sha256, xxh3 or crc32 [default: xxh3]
+    #[arg(long, value_name = "ALGORITHM")]
+    hash: Option<HashAlgorithm>,
+    /// Number of threads, or auto to pick them for the storage copied between
+    #[arg(short, long, default_value = "auto", value_parser = parse_threads)]
+    threads: Threads,
+    /// Size of each thread's read buffer, e.g. 64K or 8M
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = parse_size)]
+    buffer_size: u64,
+    /// Preserve the permissions and times of the first part
+    #[arg(short, long)]
+    preserve: bool,
+    /// Remove the parts once they are joined and checked
+    #[arg(long = "move")]
+    move_parts: bool,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// The file or tree to copy
+    #[arg(value_name = "SRC")]
+    src: PathBuf,
+    /// Where to copy it to, which must not exist yet. It is removed after
+    /// every run
+    #[arg(value_name = "DEST")]
+    dest: PathBuf,
+    /// Engines to try, separated by commas [default: all in this build]
+    #[arg(long, value_name = "ENGINES", value_delimiter = ',')]
+    engine: Vec<Engine>,
+    /// Thread counts to try, separated by commas
+    #[arg(
+        short,
+        long,
+        value_name = "COUNTS",
+        value_delimiter = ',',
+        default_value = "1,4,10,16"
+    )]
+    threads: Vec<usize>,
+    /// Buffer sizes to try, separated by commas
+    #[arg(long, value_name = "SIZES", value_delimiter = ',', default_value = "256K,1M,4M", value_parser = parse_size)]
+    buffer_size: Vec<u64>,
+    /// Copies made with each combination, of which the fastest counts
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    runs: usize,
+    /// Drop the copied data from the page cache, so every run reads the
+    /// source from the disk rather than from memory
+    #[arg(long)]
+    drop_cache: bool,
+}
+
+/// The engines this build can copy with.
+fn available_engines() -> Vec<Engine> {
+    let mut engines = vec![Engine::Pread, Engine::Pipeline];
+    if cfg!(unix) {
+        engines.push(Engine::Mmap);
+    }
+    if cfg!(target_os = "linux") {
+        engines.push(Engine::Sendfile);
+        engines.push(Engine::Vectored);
+    }
+    if cfg!(feature = "io-uring") {
+        engines.push(Engine::IoUring);
+    }
+    engines
+}
+
+/// `size` as `parse_size` reads it, e.g. `256K`.
+fn format_size(size: u64) -> String {
+    match size {
+        0 => "0".to_string(),
+        size if size % (1 << 30) == 0 => format!("{}G", size >> 30),
+        size if size % (1 << 20) == 0 => format!("{}M", size >> 20),
+        size if size % (1 << 10) == 0 => format!("{}K", size >> 10),
+        size => size.to_string(),
+    }
+}
+
+/// Run `rpcp bench`, printing a line for each combination as it is timed
+/// and the fastest at the end.
+///
+/// Copies always go through the engines, never through the filesystem
+/// offloads, so they are compared on the same footing.
+fn bench(args: &BenchArgs) -> Result<(), rpcp::Error> {
+    check_sources_exist([&args.src]);
+    if args.dest.symlink_metadata().is_ok() {
+        usage_error(&format!(
+            "'{}' already exists, bench needs a destination it can remove",
+            args.dest.display()
+        ));
+    }
+    if args.threads.contains(&0) || args.buffer_size.contains(&0) || args.runs == 0 {
+        usage_error("thread counts, buffer sizes and --runs must be above 0");
+    }
+    let engines = if args.engine.is_empty() {
+        available_engines()
+    } else {
+        args.engine.clone()
+    };
+    let recursive = args.src.is_dir();
+    let remove_dest = || {
+        let removed = if recursive {
+            fs::remove_dir_all(&args.dest)
+        } else {
+            fs::remove_file(&args.dest)
+        };
+        match removed {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(format!("Failed to remove '{}': {}", args.dest.display(), e))
+            }
+            _ => Ok(()),
+        }
+    };
+
+    println!(
+        "{:<10} {:>7} {:>7} {:>9} {:>10}",
+        "ENGINE", "THREADS", "BUFFER", "SECONDS", "GBITS/S"
+    );
+    let mut fastest: Option<(f64, Engine, usize, u64)> = None;
+    for &engine in &engines {
+        for &threads in &args.threads {
+            for &buffer_size in &args.buffer_size {
+                let options = CopyOptions::new()
+                    .engine(engine)
+                    .threads(threads)
+                    .buffer_size(buffer_size as usize)
+                    .sparse(Sparse::Never)
+                    .drop_cache(args.drop_cache);
+                let mut best: Result<(u64, f64), rpcp::Error> = Err("no runs".into());
+                for _ in 0..args.runs {
+                    let start = std::time::Instant::now();
+                    let copied = if recursive {
+                        copy_tree(&args.src, &args.dest, &options)
+                    } else {
+                        copy_file(&args.src, &args.dest, &options)
+                    };
+                    let seconds = start.elapsed().as_secs_f64();
+                    remove_dest()?;
+                    match copied {
+                        Ok(bytes) => {
+                            if best.as_ref().map_or(true, |&(_, best)| seconds < best) {
+                                best = Ok((bytes, seconds));
+                            }
+                        }
+                        Err(e) => {
+                            best = Err(e);
+                            break;
+                        }
+                    }
+                }
+                let row = format!(
+                    "{:<10} {:>7} {:>7}",
+                    engine,
+                    threads,
+                    format_size(buffer_size)
+                );
+                match best {
+                    Ok((bytes, seconds)) => {
+                        let rate = bytes as f64 * 8.0 / 1e9 / seconds;
+                        println!("{} {:>9.3} {:>10.3}", row, seconds, rate);
+                        if fastest.is_none_or(|(fastest, ..)| rate > fastest) {
+                            fastest = Some((rate, engine, threads, buffer_size));
+                        }
+                    }
+                    Err(e) => println!("{} failed: {}", row, e),
+                }
+            }
+        }
+    }
+    match fastest {
+        Some((rate, engine, threads, buffer_size)) => {
+            println!(
+                "Fastest: --engine {} -t {} --buffer-size {}, {:.3} Gbits/s",
+                engine,
+                threads,
+                format_size(buffer_size),
+                rate
+            );
+            Ok(())
+        }
+        None => Err("Every combination failed".into()),
+    }
+}
+
+/// Run `rpcp verify`, exiting with status 2 when the files differ.
+fn verify(args: &VerifyArgs) -> Result<(), rpcp::Error> {
+    let options = CopyOptions::new()
+        .threads(
+            args.threads
+                .resolve(&[&args.src], args.dest.as_ref().unwrap_or(&args.src), false),
+        )
+        .buffer_size(args.buffer_size as usize)
+        .verify_hash(args.hash)
+        .progress(true);
+    check_sources_exist(args.manifest.iter().chain([&args.src]));
+    if let Some(manifest) = &args.manifest {
+        return check_manifest(manifest, &args.src, args.hash, &options);
+    }
+    let (src, dest) = (&args.src, args.dest.as_ref().unwrap());
+    if !args.recursive {
+        if src.is_dir() {
+            usage_error(&format!(
+                "'{}' is a directory, use -r to verify it",
+                src.display()
+            ));
+        }
+        if args.repair {
+            return repair(src, dest, &options);
+        }
+        match verify_copy(src, dest, &options) {
+            Ok(_) => println!("'{}' is identical to '{}'", dest.display(), src.display()),
+            Err(e) if e.is::<VerifyError>() => {
+                println!(
+                    "'{}' differs from '{}': {}",
+                    dest.display(),
+                    src.display(),
+                    e
+                );
+                std::process::exit(EXIT_VERIFY);
+            }
+            Err(e) => return Err(e),
+        }
+        return Ok(());
+    }
+
+    let mut failures = verify_tree(src, dest, &options)?;
+    if args.repair {
+        // Links are recreated by a copy, not repaired in place.
+        failures.retain_mut(|failure| {
+            let (src, dest) = (src.join(&failure.path), dest.join(&failure.path));
+            if !src
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                return true;
+            }
+            match repair(&src, &dest, &options) {
+                Ok(()) => false,
+                Err(e) => {
+                    failure.reason =
+                        format!("{}, and it could not be repaired: {}", failure.reason, e);
+                    true
+                }
+            }
+        });
+    }
+    for failure in &failures {
+        println!(
+            "'{}' differs: {}",
+            dest.join(&failure.path).display(),
+            failure.reason
+        );
+    }
+    if !failures.is_empty() {
+        println!("{} files differ", failures.len());
+        std::process::exit(EXIT_VERIFY);
+    }
+    println!("'{}' is identical to '{}'", dest.display(), src.display());
+    Ok(())
+}
+
+/// Run `rpcp join`, exiting with status 2 when the parts or what they were
+/// joined into don't match their digests.
+fn join(args: &JoinArgs) -> Result<(), rpcp::Error> {
+    let dest = match args.parts.file_name() {
+        Some(name) if args.dest.is_dir() => args.dest.join(name),
+        _ => args.dest.clone(),
+    };
+    let options = CopyOptions::new()
+        .threads(args.threads.resolve(&[&args.parts], &dest, false))
+        .buffer_size(args.buffer_size as usize)
+        .verify(true)
+        .verify_hash(args.hash)
+        .preserve(args.preserve)
+        .remove_source(args.move_parts)
+        .progress(true);
+    let size = match join_parts(&args.parts, &dest, &options) {
+        Ok(size) => size,
+        Err(e) if e.is::<VerifyError>() => {
+            log::error!("{}", e);
+            std::process::exit(EXIT_VERIFY);
+        }
+        Err(e) => return Err(e),
+    };
+    println!(
+        "Joined {} bytes of '{}' into '{}'",
+        size,
+        args.parts.display(),
+        dest.display()
+    );
+    Ok(())
+}
+
+/// Check `dest` against `manifest`, exiting with status 2 when anything in
+/// it is missing or differs.
+fn check_manifest(
+    manifest: &Path,
+    dest: &Path,
+    algorithm: Option<HashAlgorithm>,
+    options: &CopyOptions,
+) -> Result<(), rpcp::Error> {
+    let algorithm = algorithm
+        .or_else(|| manifest_algorithm(manifest))
+        .unwrap_or(HashAlgorithm::Blake3);
+    let failures = verify_manifest(manifest, dest, algorithm, options)?;
+    for failure in &failures {
+        println!(
+            "'{}' differs: {}",
+            dest.join(&failure.path).display(),
+            failure.reason
+        );
+    }
+    if !failures.is_empty() {
+        println!("{} files differ", failures.len());
+        std::process::exit(EXIT_VERIFY);
+    }
+    println!("'{}' matches '{}'", dest.display(), manifest.display());
+    Ok(())
+}
+
+/// Repair `dest` from `src`, reporting what was copied again.
+fn repair(src: &Path, dest: &Path, options: &CopyOptions) -> Result<(), rpcp::Error> {
+    let repaired = repair_copy(src, dest, options)?;
+    println!(
+        "'{}' is identical to '{}', {} bytes copied again",
+        dest.display(),
+        src.display(),
+        repaired
+    );
+    Ok(())
+}
+
+/// A number of threads to copy with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Threads {
+    /// Picked by [`rpcp::auto_threads`].
+    Auto,
+    Count(usize),
+}
+
+impl Threads {
+    fn resolve<P: AsRef<Path>>(self, sources: &[P], dest: &Path, remote: bool) -> usize {
+        match self {
+            Threads::Auto => rpcp::auto_threads(sources, dest, remote),
+            Threads::Count(threads) => threads,
+        }
+    }
+}
+
+fn parse_threads(s: &str) -> Result<Threads, String> {
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(Threads::Auto);
+    }
+    match s.trim().parse() {
+        Ok(0) => Err("at least one thread is needed".to_string()),
+        Ok(threads) => Ok(Threads::Count(threads)),
+        Err(_) => Err(format!(
+            "invalid thread count '{}', expected a number or auto",
+            s
+        )),
+    }
+}
+
+/// A NUMA node to copy on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NumaNode {
+    /// The node of the destination's disk, or else the source's.
+    Auto,
+    Node(usize),
+}
+
+impl NumaNode {
+    fn resolve<P: AsRef<Path>>(self, sources: &[P], dest: &Path) -> Option<usize> {
+        match self {
+            NumaNode::Auto => rpcp::numa_node_of(dest)
+                .or_else(|| sources.iter().find_map(|s| rpcp::numa_node_of(s.as_ref()))),
+            NumaNode::Node(node) => Some(node),
+        }
+    }
+}
+
+fn parse_numa_node(s: &str) -> Result<NumaNode, String> {
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(NumaNode::Auto);
+    }
+    s.trim()
+        .parse()
+        .map(NumaNode::Node)
+        .map_err(|_| format!("invalid NUMA node '{}', expected a number or auto", s))
+}
+
+/// CPUs to run on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cpus(Vec<usize>);
+
+fn parse_cpuset(s: &str) -> Result<Cpus, String> {
+    rpcp::parse_cpu_list(s).map(Cpus)
+}
+
+fn parse_log_level(s: &str) -> Result<log::LevelFilter, String> {
+    s.parse().map_err(|_| {
+        format!(
+            "invalid log level '{}', expected one of error, warn, info, debug, trace",
+            s
+        )
+    })
+}
+
+/// Parse a duration such as `500ms`, `2s`, `1.5m` or `1h`. Plain numbers
+/// are seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let seconds = match unit.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => {
+            return Err(format!(
+                "invalid duration '{}', expected e.g. 500ms, 2s or 1m",
+                s
+            ))
+        }
+    };
+    let value: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}', expected e.g. 500ms, 2s or 1m", s))?;
+    Ok(Duration::from_secs_f64(value * seconds))
+}
+
+/// Parse a point in time: an age such as `90s`, `15m`, `12h`, `30d` or `2w`
+/// before now, or a date `YYYY-MM-DD` in UTC with an optional `HH:MM` or
+/// `HH:MM:SS` after a space or a `T`.
+fn parse_when(s: &str) -> Result<SystemTime, String> {
+    let invalid = || {
+        format!(
+            "invalid time '{}', expected an age like 30d or 12h, or a date like 2024-05-01",
+            s
+        )
+    };
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let unit_seconds = match unit.trim() {
+        "s" => Some(1.0),
+        "m" => Some(60.0),
+        "h" => Some(3600.0),
+        "d" => Some(86400.0),
+        "w" => Some(7.0 * 86400.0),
+        _ => None,
+    };
+    if let (Some(unit_seconds), Ok(number)) = (unit_seconds, number.parse::<f64>()) {
+        let age = Duration::from_secs_f64(number * unit_seconds);
+        return SystemTime::now().checked_sub(age).ok_or_else(invalid);
+    }
+
+    let (date, time) = trimmed
+        .split_once(['T', ' '])
+        .map_or((trimmed, None), |(date, time)| (date, Some(time)));
+    let numbers = |text: &str, max_parts: usize| -> Option<Vec<u64>> {
+        let parts: Vec<u64> = text
+            .split(['-', ':'])
+            .map(|n| n.parse().ok())
+            .collect::<Option<_>>()?;
+        (parts.len() <= max_parts).then_some(parts)
+    };
+    let date = numbers(date, 3)
+        .filter(|d| d.len() == 3)
+        .ok_or_else(invalid)?;
+    let time = match time {
+        Some(time) => numbers(time, 3)
+            .filter(|t| t.len() >= 2)
+            .ok_or_else(invalid)?,
+        None => vec![0, 0],
+    };
+    let (year, month, day) = (date[0], date[1], date[2]);
+    let (hour, minute, second) = (time[0], time[1], time.get(2).copied().unwrap_or(0));
+    if year > 9999
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(invalid());
+    }
+    let date =
+        NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32).ok_or_else(invalid)?;
+    let midnight = date.and_time(NaiveTime::MIN).and_utc().timestamp();
+    let seconds = midnight + (hour * 3600 + minute * 60 + second) as i64;
+    let since_epoch = Duration::from_secs(seconds.unsigned_abs());
+    let time = if seconds >= 0 {
+        UNIX_EPOCH.checked_add(since_epoch)
+    } else {
+        UNIX_EPOCH.checked_sub(since_epoch)
+    };
+    time.ok_or_else(invalid)
+}
+
+/// Build the filter from --include and --exclude in the order they were
+/// given on the command line, since the first matching rule wins, and the
+/// size and time limits.
+fn filter_from(matches: &ArgMatches, cli: &CopyArgs) -> Filter {
+    let mut rules: Vec<(usize, bool, &String)> = Vec::new();
+    for (name, patterns, include) in [
+        ("include", &cli.include, true),
+        ("exclude", &cli.exclude, false),
+    ] {
+        let indices = matches.indices_of(name).into_iter().flatten();
+        rules.extend(indices.zip(patterns).map(|(i, p)| (i, include, p)));
+    }
+    rules.sort_by_key(|(i, _, _)| *i);
+    let mut filter = rules
+        .into_iter()
+        .fold(Filter::new(), |filter, (_, include, pattern)| {
+            if include {
+                filter.include(pattern)
+            } else {
+                filter.exclude(pattern)
+            }
+        });
+    if let Some(size) = cli.min_size {
+        filter = filter.min_size(size);
+    }
+    if let Some(size) = cli.max_size {
+        filter = filter.max_size(size);
+    }
+    if let Some(time) = cli.newer_than {
+        filter = filter.newer_than(time);
+    }
+    if let Some(time) = cli.older_than {
+        filter = filter.older_than(time);
+    }
+    filter
+}
+
+/// Read the paths listed in the file `list`, or stdin when it is `-`, one
+/// per line or separated by NULs. Empty lines and lines starting with `#`
+/// are skipped.
+fn read_list(list: &Path, from0: bool) -> Result<Vec<PathBuf>, rpcp::Error> {
+    let mut contents = Vec::new();
+    let result = if list == Path::new("-") {
+        io::stdin().read_to_end(&mut contents).map(|_| ())
+    } else {
+        File::open(list).and_then(|mut file| file.read_to_end(&mut contents).map(|_| ()))
+    };
+    result.map_err(|e| format!("Failed to read the list '{}': {}", list.display(), e))?;
+
+    let separator = if from0 { b'\0' } else { b'\n' };
+    let mut paths = Vec::new();
+    for entry in contents.split(|&b| b == separator) {
+        let entry = match entry {
+            [entry @ .., b'\r'] if !from0 => entry,
+            entry => entry,
+        };
+        if entry.is_empty() || (!from0 && entry[0] == b'#') {
+            continue;
+        }
+        paths.push(path_from_bytes(entry)?);
+    }
+    Ok(paths)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf, rpcp::Error> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf, rpcp::Error> {
+    let path = std::str::from_utf8(bytes)
+        .map_err(|_| format!("'{}' is not valid UTF-8", String::from_utf8_lossy(bytes)))?;
+    Ok(PathBuf::from(path))
+}
+
+/// The remote location `path` names, if it is written `[user@]host:path`.
+fn remote_of(path: &Path) -> Option<Remote> {
+    path.to_str().and_then(Remote::parse)
+}
+
+/// The S3 object `path` names, if it is written `s3://bucket/key`.
+fn s3_of(path: &Path) -> Option<S3Object> {
+    path.to_str().and_then(S3Object::parse)
+}
+
+/// Whether `path` is an `http://` or `https://` URL.
+fn is_url_path(path: &Path) -> bool {
+    path.to_str().is_some_and(is_url)
+}
+
+/// Whether `path` names a local file, rather than a remote location, S3,
+/// a URL or a stream.
+fn is_local(path: &Path) -> bool {
+    remote_of(path).is_none()
+        && s3_of(path).is_none()
+        && !is_url_path(path)
+        && path != Path::new("-")
+}
+
+/// Why the copy asked for can't be made to S3, if it can't.
+fn s3_conflict(cli: &CopyArgs, sources: &[PathBuf]) -> Option<String> {
+    if sources.iter().any(|path| s3_of(path).is_some()) {
+        return Some("Copies from S3 are not supported, only to it".to_string());
+    }
+    if sources.len() > 1 || cli.files_from.is_some() {
+        return Some("Only one source can be copied to S3".to_string());
+    }
+    let unsupported = [
+        (cli.update, "--update"),
+        (cli.interactive, "--interactive"),
+        (cli.preserve, "--preserve"),
+    ];
+    unsupported_flag(cli, &unsupported, "to S3")
+}
+
+/// Why the copy asked for can't be made from a URL, if it can't.
+fn url_conflict(cli: &CopyArgs, sources: &[PathBuf], dest: &Path) -> Option<String> {
+    if is_url_path(dest) || remote_of(dest).is_some() || s3_of(dest).is_some() {
+        return Some("URLs can only be copied to local files".to_string());
+    }
+    if sources.len() > 1 || cli.files_from.is_some() {
+        return Some("Only one URL can be copied at a time".to_string());
+    }
+    let unsupported = [(cli.update, "--update"), (cli.interactive, "--interactive")];
+    unsupported_flag(cli, &unsupported, "from URLs")
+}
+
+/// Why the copy asked for can't be made from stdin or to stdout, if it can't.
+fn stream_conflict(cli: &CopyArgs, sources: &[PathBuf], dest: &Path) -> Option<String> {
+    let stdin_source = sources.iter().any(|path| path == Path::new("-"));
+    if stdin_source && dest == Path::new("-") {
+        return Some("- can be the source or the destination, not both".to_string());
+    }
+    if sources.len() > 1 || cli.files_from.is_some() || cli.target_directory.is_some() {
+        return Some("Only one file can be copied from stdin or to stdout".to_string());
+    }
+    if cli.recursive {
+        return Some("Directories can't be copied from stdin or to stdout".to_string());
+    }
+    let paths = sources.iter().map(PathBuf::as_path).chain([dest]);
+    if paths
+        .clone()
+        .any(|path| remote_of(path).is_some() || s3_of(path).is_some())
+        || paths.clone().any(is_url_path)
+    {
+        return Some("Only local files can be copied from stdin or to stdout".to_string());
+    }
+    let streams = [
+        (cli.direct, "--direct"),
+        (cli.update, "--update"),
+        (cli.interactive, "--interactive"),
+        (cli.dry_run, "--dry-run"),
+        (cli.preserve, "--preserve"),
+        (cli.skip.is_some(), "--skip"),
+        (cli.seek.is_some(), "--seek"),
+        (cli.count.is_some(), "--count"),
+        (cli.follow.is_some(), "--follow"),
+    ];
+    // Stdout is where the data goes, and nothing is there to read back.
+    let to_stdout = [
+        (cli.verify || cli.verify_hash.is_some(), "--verify"),
+        (cli.json, "--json"),
+        (cli.fsync || cli.fdatasync, "--fsync"),
+    ];
+    let extra: Vec<(bool, &str)> = match stdin_source {
+        true => streams.to_vec(),
+        false => streams.iter().chain(&to_stdout).copied().collect(),
+    };
+    unsupported_flag(cli, &extra, "from stdin or to stdout")
+}
+
+/// Why the copy asked for can't be made to or from an archive, if it can't.
+fn archive_conflict(cli: &CopyArgs, sources: &[PathBuf], dest: &Path) -> Option<String> {
+    let paths = sources.iter().map(PathBuf::as_path).chain([dest]);
+    if paths
+        .clone()
+        .any(|path| remote_of(path).is_some() || s3_of(path).is_some())
+        || paths.clone().any(is_url_path)
+    {
+        return Some("Only local files can be archived or extracted".to_string());
+    }
+    let to_stdout = cli.to_archive.as_deref() == Some(Path::new("-"));
+    let extra = [
+        (cli.target_directory.is_some(), "--target-directory"),
+        (cli.files_from.is_some(), "--files-from"),
+        (cli.verify || cli.verify_hash.is_some(), "--verify"),
+        (cli.dry_run, "--dry-run"),
+        (cli.direct, "--direct"),
+        (cli.interactive, "--interactive"),
+        (cli.skip.is_some(), "--skip"),
+        (cli.seek.is_some(), "--seek"),
+        (cli.count.is_some(), "--count"),
+        (cli.follow.is_some(), "--follow"),
+        (cli.watch, "watch"),
+        (cli.itemize, "--itemize"),
+        (cli.json && to_stdout, "--json"),
+        (cli.update && cli.to_archive.is_some(), "--update"),
+    ];
+    unsupported_flag(cli, &extra, "to or from archives")
+}
+
+/// Why the copy asked for can't be made over SSH, if it can't.
+fn remote_conflict(cli: &CopyArgs, sources: &[PathBuf], dest: &Path) -> Option<String> {
+    let paths = sources.iter().map(PathBuf::as_path).chain([dest]);
+    if paths.filter(|path| remote_of(path).is_some()).count() > 1 {
+        return Some("Copies between two remote locations are not supported".to_string());
+    }
+    if sources.len() > 1 || cli.files_from.is_some() {
+        return Some("Only one source can be copied to or from a remote host".to_string());
+    }
+    unsupported_flag(cli, &[], "to or from remote hosts")
+}
+
+/// Write `sources` to the tar archive `path`, or to stdout with -.
+fn archive_to(sources: &[PathBuf], path: &Path, options: &CopyOptions) -> Result<u64, rpcp::Error> {
+    let writer: Box<dyn io::Write> = if path == Path::new("-") {
+        Box::new(io::stdout().lock())
+    } else {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create '{}': {}", path.display(), e))?;
+        Box::new(BufWriter::new(file))
+    };
+    let gzip = path
+        .extension()
+        .is_some_and(|extension| extension == "gz" || extension == "tgz");
+    if !gzip {
+        return write_archive(sources, writer, options);
+    }
+    let mut encoder = GzEncoder::new(writer, Compression::default());
+    let written = write_archive(sources, &mut encoder, options)?;
+    encoder
+        .finish()
+        .and_then(|mut writer| writer.flush())
+        .map_err(|e| format!("Failed to write '{}': {}", path.display(), e))?;
+    Ok(written)
+}
+
+/// Extract the tar archive `path`, or the one on stdin with -, into `dest`.
+fn archive_from(path: &Path, dest: &Path, options: &CopyOptions) -> Result<u64, rpcp::Error> {
+    if path == Path::new("-") {
+        return extract_archive(io::stdin().lock(), dest, options);
+    }
+    let file =
+        File::open(path).map_err(|e| format!("Failed to open '{}': {}", path.display(), e))?;
+    extract_archive(file, dest, options)
+}
+
+/// The first flag set that only works for local copies, or is in `extra`.
+fn unsupported_flag(cli: &CopyArgs, extra: &[(bool, &str)], copies: &str) -> Option<String> {
+    let local_only = [
+        (cli.resume, "--resume"),
+        (cli.atomic, "--atomic"),
+        (cli.partial_suffix.is_some(), "--partial-suffix"),
+        (cli.fsync, "--fsync"),
+        (cli.fdatasync, "--fdatasync"),
+        (cli.xattrs, "--xattrs"),
+        (cli.acls, "--acls"),
+        (cli.owner, "--owner"),
+        (cli.group, "--group"),
+        (cli.preserve_ownership, "--preserve-ownership"),
+        (cli.specials, "--specials"),
+        (cli.devices, "--devices"),
+        (cli.hard_links, "--hard-links"),
+        (cli.link_dest.is_some(), "--link-dest"),
+        (cli.reflink_dest.is_some(), "--reflink-dest"),
+        (cli.backup.is_some(), "--backup"),
+        (cli.backup_dir.is_some(), "--backup-dir"),
+        (cli.delete, "--delete"),
+        (cli.move_files, "--move"),
+        (cli.checksum, "--checksum"),
+        (cli.write_manifest.is_some(), "--write-manifest"),
+        (cli.fail_on_change, "--fail-on-change"),
+    ];
+    local_only
+        .iter()
+        .chain(extra)
+        .find(|(set, _)| *set)
+        .map(|(_, flag)| format!("{} is not supported for copies {}", flag, copies))
+}
+
+/// The statistics of a run that copied `bytes` in `seconds`, as the fields
+/// of a JSON object.
+fn stats_fields(stats: &CopyStats, bytes: u64, seconds: f64) -> String {
+    let average = bytes as f64 / seconds;
+    format!(
+        "\"files_copied\":{},\"files_skipped\":{},\"files_failed\":{},\"dirs_created\":{},\"bytes\":{},\"seconds\":{:.3},\"average_bytes_per_second\":{:.0},\"peak_bytes_per_second\":{:.0},\"retries\":{},\"verification_failures\":{}",
+        stats.files_copied(),
+        stats.files_skipped(),
+        stats.files_failed(),
+        stats.dirs_created(),
+        bytes,
+        seconds,
+        average,
+        (stats.peak_rate() as f64).max(average),
+        stats.retries(),
+        stats.verification_failures()
+    )
+}
+
+/// `s` as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Write the statistics `fields` to `path` as a JSON object.
+fn write_stats(path: &Path, fields: &str) -> Result<(), rpcp::Error> {
+    fs::write(path, format!("{{{}}}\n", fields)).map_err(|e| {
+        format!(
+            "Failed to write the statistics to '{}': {}",
+            path.display(),
+            e
+        )
+        .into()
+    })
 }
 
 fn time_as_double() -> Result<f64, std::time::SystemTimeError> {
@@ -38,194 +1366,962 @@ fn time_as_double() -> Result<f64, std::time::SystemTimeError> {
     Ok(since_epoch.as_secs_f64())
 }
 
-fn verify_copy(
-    file1: &PathBuf,
-    file2: &PathBuf,
-    file_size: usize,
-) -> Result<String, Box<dyn std::error::Error>> {
-    eprintln!(
-        "Verifying '{}' and '{}' are the same after copy. Size {}",
-        file1.display(),
-        file2.display(),
-        file_size
-    );
-    let mut in1 = File::open(file1)?;
-    let mut in2 = File::open(file2)?;
+/// Exit statuses, so scripts can tell failures apart. Documented in the
+/// README.
+const EXIT_PARTIAL: i32 = 1;
+const EXIT_VERIFY: i32 = 2;
+const EXIT_USAGE: i32 = 3;
+const EXIT_MISSING: i32 = 4;
+const EXIT_ERROR: i32 = 5;
+const EXIT_INTERRUPTED: i32 = 130;
 
-    let buffer_size: usize = 10 * 1024 * 1024; // 10Mb
-    let mut buffer1 = vec![0; buffer_size];
-    let mut buffer2 = vec![0; buffer_size];
+/// The command line, with every option also read from an environment
+/// variable named after it, like `RPCP_BUFFER_SIZE` for `--buffer-size`.
+fn command() -> clap::Command {
+    fn with_env(command: clap::Command) -> clap::Command {
+        command.mut_args(|arg| match arg.get_long() {
+            // Counted flags have no value to read.
+            Some(long) if !matches!(arg.get_action(), ArgAction::Count) => {
+                let name = format!("RPCP_{}", long.to_uppercase().replace('-', "_"));
+                arg.env(name)
+            }
+            _ => arg,
+        })
+    }
+    ["copy", "sync", "watch", "daemon", "verify", "join", "bench"]
+        .into_iter()
+        .fold(with_env(Cli::command()), |command, name| {
+            command.mut_subcommand(name, with_env)
+        })
+}
 
-    for step in (0..file_size).step_by(buffer_size) {
-        let bytes_read_from_file1 = in1.read(&mut buffer1)?;
-        let bytes_read_from_file2 = in2.read(&mut buffer2)?;
+/// Report a command line that can't work, as clap does for its own errors,
+/// and exit.
+fn usage_error(message: &str) -> ! {
+    let _ = command()
+        .error(ErrorKind::ArgumentConflict, message)
+        .print();
+    std::process::exit(EXIT_USAGE)
+}
+
+/// Exit with `e`, with the usage status for errors rather than for help
+/// or the version.
+fn clap_exit(e: clap::Error) -> ! {
+    let _ = e.print();
+    std::process::exit(if e.use_stderr() { EXIT_USAGE } else { 0 })
+}
 
-        if bytes_read_from_file1 == bytes_read_from_file2 {
-            if &buffer1[..bytes_read_from_file1] != &buffer2[..bytes_read_from_file2] {
-                return Err(format!("File differ at range starting at {} bytes", step).into());
+/// Exit if any of the local `paths` does not exist.
+fn check_sources_exist<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) {
+    for path in paths {
+        if let Err(e) = path.symlink_metadata() {
+            if e.kind() == io::ErrorKind::NotFound {
+                let message = format!("'{}' does not exist", path.display());
+                log::error!("{}", message);
+                run_hooks(EXIT_MISSING, Some(&message));
+                std::process::exit(EXIT_MISSING);
             }
-        } else {
-            eprintln!("*warning* uneven reads during varificaion");
         }
     }
-    Ok("Verified files are identical.".into())
 }
 
-fn copy_file<P: AsRef<Path>>(
-    infile_path: P,
-    outfile_path: P,
-    num_threads: usize,
-) -> Result<usize, Box<dyn std::error::Error>> {
-    let mut num_threads = num_threads;
-    let infile = File::open(infile_path.as_ref()).map_err(|e| match e.kind() {
-        io::ErrorKind::NotFound => {
-            format!(
-                "The input file {} does not exist. Please check the file path and try again.",
-                infile_path.as_ref().display()
-            )
+fn main() {
+    match run() {
+        Ok(()) => run_hooks(0, None),
+        Err(e) => {
+            log::error!("{}", e);
+            run_hooks(EXIT_ERROR, Some(&e.to_string()));
+            std::process::exit(EXIT_ERROR);
         }
-        _ => format!(
-            "Failed to open input file: {}, {:?}",
-            infile_path.as_ref().display(),
-            e
-        ),
-    })?;
-    let infile_size = infile.metadata()?.len() as usize;
+    }
+}
 
-    if infile_size < 1 * 1024 * 1024 {
-        eprintln!("Samll file. Copy with one thread");
-        num_threads = 1
+/// Add the options set in the config file to `matches`, parsed from `args`,
+/// unless the command line or the environment sets them or ones they
+/// conflict with. Only copies, syncs and watches read the config file.
+fn with_config(args: &[OsString], matches: ArgMatches) -> ArgMatches {
+    let (subcommand, copy_matches) = match matches.subcommand() {
+        None => (None, &matches),
+        Some((name @ ("copy" | "sync" | "watch"), copy_matches)) => (Some(name), copy_matches),
+        Some(_) => return matches,
     };
-    let outfile = File::create(outfile_path.as_ref()).map_err(|e| {
-        format!(
-            "Failed to create output file '{}': {:?}",
-            outfile_path.as_ref().display(),
-            e
+    if copy_matches.get_flag("no_config") {
+        return matches;
+    }
+    let profile = copy_matches.get_one::<String>("profile");
+    let path = match copy_matches.get_one::<PathBuf>("config") {
+        Some(path) => path.clone(),
+        None => match config::default_path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None if profile.is_some() => usage_error("--profile needs a config file"),
+            None => return matches,
+        },
+    };
+    let options = Config::load(&path)
+        .and_then(|config| config.options(profile.map(String::as_str)))
+        .unwrap_or_else(|e| usage_error(&e));
+
+    let cli = command();
+    let copy_command = subcommand.map_or(&cli, |name| cli.find_subcommand(name).unwrap());
+    let on_command_line = |arg: &clap::Arg| {
+        // Environment variables take precedence over the config file too.
+        matches!(
+            copy_matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
         )
-    })?;
-    outfile.set_len(infile_size as u64).unwrap();
-
-    let mut threads = Vec::new();
-    let slice = infile_size / num_threads;
-    let processed_bytes = Arc::new(AtomicUsize::new(0));
-
-    eprintln!(" Copy {}", infile_path.as_ref().display());
-
-    //Wrap infiles in atomic reference counter.
-    let infile = Arc::new(infile);
-    let outfile = Arc::new(outfile);
-
-    for thrd_num in 0..num_threads {
-        let infile = Arc::clone(&infile);
-        let outfile = Arc::clone(&outfile);
-        let processed_bytes = Arc::clone(&processed_bytes);
-
-        let t = thread::spawn(move || {
-            let mut buffer = vec![0; 1024 * 1024];
-            let mut pos = thrd_num * slice;
-
-            while pos < (thrd_num + 1) * slice {
-                let size_bytes_read = pread(&*infile, &mut buffer, pos as i64).unwrap();
-                if size_bytes_read > 0 {
-                    pwrite(&*outfile, &buffer[..size_bytes_read], pos as i64).unwrap();
-                    pos += size_bytes_read;
-                    processed_bytes.fetch_add(size_bytes_read, Ordering::SeqCst);
-                } else {
-                    break;
+    };
+    let mut config_args = Vec::new();
+    for (key, value) in options {
+        let long = key.replace('_', "-");
+        let arg = copy_command
+            .get_arguments()
+            .filter(|arg| !matches!(arg.get_id().as_str(), "config" | "profile" | "no_config"))
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .unwrap_or_else(|| {
+                usage_error(&format!("unknown option '{}' in '{}'", key, path.display()))
+            });
+        let appends = matches!(arg.get_action(), ArgAction::Append);
+        // Conflicts are only listed on one of the two options.
+        let conflicts = |a: &clap::Arg, b: &clap::Arg| {
+            let listed = |a: &clap::Arg, b: &clap::Arg| {
+                copy_command
+                    .get_arg_conflicts_with(a)
+                    .iter()
+                    .any(|conflict| conflict.get_id() == b.get_id())
+            };
+            listed(a, b) || listed(b, a)
+        };
+        let overridden = copy_command
+            .get_arguments()
+            .any(|other| on_command_line(other) && conflicts(arg, other));
+        if (!appends && on_command_line(arg)) || overridden {
+            continue;
+        }
+        let invalid = |expected: &str| {
+            format!(
+                "'{}' in '{}' is set to {}, {}",
+                key,
+                path.display(),
+                value,
+                expected
+            )
+        };
+        match (&value, arg.get_action()) {
+            (Value::Array(values), _) if appends => {
+                for value in values {
+                    config_args.push(format!("--{}={}", long, value));
                 }
             }
-        });
-        threads.push(t);
+            (Value::Array(_), _) => usage_error(&invalid("it takes one value")),
+            (value, action) if action.takes_values() => {
+                config_args.push(format!("--{}={}", long, value))
+            }
+            (Value::Bool(set), _) => {
+                if *set {
+                    config_args.push(format!("--{}", long));
+                }
+            }
+            (Value::Number(count), ArgAction::Count) => {
+                let count: usize = count
+                    .parse()
+                    .unwrap_or_else(|_| usage_error(&invalid("expected a count")));
+                config_args.extend((0..count).map(|_| format!("--{}", long)));
+            }
+            _ => usage_error(&invalid("expected true or false")),
+        }
     }
+    if config_args.is_empty() {
+        return matches;
+    }
+    // Filters from the command line come first and take precedence, so the
+    // ones from the config file go after it, but before any `--`.
+    let split = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    let args = args[..split]
+        .iter()
+        .cloned()
+        .chain(config_args.into_iter().map(OsString::from))
+        .chain(args[split..].iter().cloned());
+    command()
+        .try_get_matches_from(args)
+        .unwrap_or_else(|e| clap_exit(e))
+}
 
-    // Progress monitoring thread
-    let progress_clone = Arc::clone(&processed_bytes);
-
-    let monitor_handle = thread::spawn(move || {
-        while progress_clone.load(Ordering::SeqCst) < infile_size {
-            let pct_prgrs =
-                (progress_clone.load(Ordering::SeqCst) as f64 / infile_size as f64) * 100.;
-            eprint!("\rProgress: {pct_prgrs:.1}%",);
-            thread::sleep(std::time::Duration::from_millis(50)); // Update every .25 second
+fn run() -> Result<(), rpcp::Error> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = command()
+        .try_get_matches_from(&args)
+        .unwrap_or_else(|e| clap_exit(e));
+    let matches = with_config(&args, matches);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| clap_exit(e));
+    // Copies set up their own, with --log-file.
+    if !matches!(
+        cli.command,
+        None | Some(Command::Copy(_) | Command::Sync(_) | Command::Watch(_))
+    ) {
+        set_up_logging(None, log::LevelFilter::Warn)?;
+    }
+    match cli.command {
+        Some(Command::Daemon(args)) => run_daemon(
+            &args.socket,
+            args.jobs,
+            args.bwlimit,
+            args.metrics.as_deref(),
+        ),
+        Some(Command::Verify(args)) => verify(&args),
+        Some(Command::Join(args)) => join(&args),
+        Some(Command::Bench(args)) => bench(&args),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut command(), "rpcp", &mut io::stdout());
+            Ok(())
         }
-        eprint!("\rProgress: 100.0%",);
-    });
-
-    for t in threads {
-        t.join().unwrap();
+        Some(Command::Copy(args)) => copy(&args, subcommand_matches(&matches)),
+        Some(Command::Sync(mut args)) => {
+            args.recursive = true;
+            args.delete = true;
+            copy(&args, subcommand_matches(&matches))
+        }
+        Some(Command::Watch(mut args)) => {
+            args.recursive = true;
+            args.delete = true;
+            args.watch = true;
+            copy(&args, subcommand_matches(&matches))
+        }
+        None => copy(&cli.copy, &matches),
     }
+}
 
-    monitor_handle.join().unwrap();
-    Ok(infile_size)
+/// The arguments given to the subcommand in `matches`.
+fn subcommand_matches(matches: &ArgMatches) -> &ArgMatches {
+    matches.subcommand().map_or(matches, |(_, matches)| matches)
 }
 
-fn copy_dir_recursive(
-    src: &Path,
-    dest: &Path,
-    num_threads: usize,
-) -> Result<usize, Box<dyn std::error::Error>> {
-    let mut total_bytes_copied = 0;
-    for entry in WalkDir::new(src) {
-        let entry = entry?;
-        let path = entry.path();
-        let relative_path = path.strip_prefix(src)?;
-        let dest_path = dest.join(relative_path);
-        eprint!("\r");
-        if path.is_dir() {
-            create_dir_all(&dest_path)?;
-        } else {
-            let bytes_copied = copy_file(path, &dest_path, num_threads)?;
-            total_bytes_copied += bytes_copied;
+/// Run a copy, or with `sync` a mirror, as set in `cli`. `matches` are the
+/// arguments it was parsed from, which hold the order of the filters.
+fn copy(cli: &CopyArgs, matches: &ArgMatches) -> Result<(), rpcp::Error> {
+    let log_file = cli.log_file.as_deref().map(|path| (path, cli.log_level));
+    // Warnings still go to the log file with --quiet.
+    let stderr = if cli.quiet {
+        log::LevelFilter::Error
+    } else {
+        log::LevelFilter::Warn
+    };
+    set_up_logging(log_file, stderr)?;
+    if cli.log_file.is_some() {
+        let args: Vec<String> = std::env::args_os()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        log::info!("Started {}", args.join(" "));
+    }
+    // Checked here rather than by clap, as sync and watch set -r and --delete.
+    if cli.delete && !cli.recursive {
+        usage_error("--delete is for recursive copies");
+    }
+    if cli.max_delete.is_some() && !cli.delete {
+        usage_error("--max-delete is for copies with --delete");
+    }
+    if cli.order != FileOrder::Walk && !cli.recursive {
+        usage_error("--order is for recursive copies");
+    }
+    let filter = filter_from(matches, cli);
+    let mut sources = cli.paths.clone();
+    // The paths after the source are all destinations.
+    let fanout = if cli.fanout {
+        if sources.len() < 3 {
+            usage_error("--fanout takes a source file and two or more destinations");
+        }
+        let fanout_conflict = [
+            (cli.recursive, "-r"),
+            (cli.target_directory.is_some(), "--target-directory"),
+            (cli.files_from.is_some(), "--files-from"),
+            (cli.to_archive.is_some(), "--to-archive"),
+            (cli.from_archive.is_some(), "--from-archive"),
+            (cli.encrypt.is_some(), "--encrypt"),
+            (cli.split.is_some(), "--split"),
+            (cli.resume, "--resume"),
+            (cli.atomic, "--atomic"),
+            (cli.partial_suffix.is_some(), "--partial-suffix"),
+            (cli.skip.is_some(), "--skip"),
+            (cli.seek.is_some(), "--seek"),
+            (cli.count.is_some(), "--count"),
+            (cli.follow.is_some(), "--follow"),
+            (cli.interactive, "--interactive"),
+        ];
+        if let Some((_, flag)) = fanout_conflict.iter().find(|(set, _)| *set) {
+            usage_error(&format!("{} is not supported with --fanout", flag));
+        }
+        let dests = sources.split_off(1);
+        if !is_local(&sources[0]) || !dests.iter().all(|dest| is_local(dest)) {
+            usage_error("--fanout is for copies of a local file to local files");
         }
+        if sources[0].is_dir() {
+            usage_error(&format!(
+                "'{}' is a directory, --fanout copies one file",
+                sources[0].display()
+            ));
+        }
+        // Directories are copied into, as by cp.
+        let name = sources[0]
+            .file_name()
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        dests
+            .into_iter()
+            .map(|dest| {
+                if dest.is_dir() {
+                    dest.join(&name)
+                } else {
+                    dest
+                }
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    if let Some(archive) = &cli.from_archive {
+        if sources.len() != 1 {
+            usage_error("--from-archive takes a single directory to extract into");
+        }
+        sources.insert(0, archive.clone());
     }
-    Ok(total_bytes_copied)
-}
+    let ouf = match &cli.target_directory {
+        None if cli.to_archive.is_some() => cli.to_archive.clone().unwrap(),
+        Some(dir) => dir.clone(),
+        None if cli.fanout => fanout[0].clone(),
+        None if sources.len() > 1 => sources.pop().unwrap(),
+        None => usage_error("a destination is required after the source, or --target-directory"),
+    };
+    let listed = match &cli.files_from {
+        Some(_) if sources.len() > 1 => {
+            usage_error("--files-from takes a single source to read the listed paths from")
+        }
+        Some(list) => Some(read_list(list, cli.from0)?),
+        None => None,
+    };
+    let inf = &sources[0];
+    // Copies to or from [user@]host:path go over SSH.
+    let remote_source = sources.iter().find_map(|path| remote_of(path));
+    let remote_dest = remote_of(&ouf);
+    // Copies to s3://bucket/key go to S3, and ones from http(s):// URLs are
+    // downloaded.
+    let s3_dest = s3_of(&ouf);
+    let url_source = sources
+        .iter()
+        .find_map(|path| path.to_str().filter(|path| is_url(path)));
+    let remote = remote_source.is_some()
+        || remote_dest.is_some()
+        || s3_dest.is_some()
+        || url_source.is_some();
+    // A source or destination of - is stdin or stdout, unless it is an
+    // archive.
+    let archive = cli.to_archive.is_some() || cli.from_archive.is_some();
+    let stdin_source = !archive && sources.iter().any(|path| path == Path::new("-"));
+    let stdout_dest = !archive && ouf == Path::new("-");
+    let conflict = if archive {
+        archive_conflict(cli, &sources, &ouf)
+    } else if stdin_source || stdout_dest {
+        stream_conflict(cli, &sources, &ouf)
+    } else if url_source.is_some() {
+        url_conflict(cli, &sources, &ouf)
+    } else if s3_dest.is_some() {
+        s3_conflict(cli, &sources)
+    } else if remote {
+        remote_conflict(cli, &sources, &ouf)
+    } else {
+        None
+    };
+    if let Some(conflict) = conflict {
+        usage_error(&conflict);
+    }
+    if cli.webhook.is_some() && !cfg!(any(feature = "http", feature = "s3")) {
+        usage_error("--webhook needs a build with the http feature");
+    }
+    let stats = Arc::new(CopyStats::new());
+    set_hooks(Hooks {
+        on_complete: cli.on_complete.clone(),
+        on_error: cli.on_error.clone(),
+        webhook: cli.webhook.clone(),
+        sources: sources.clone(),
+        dest: ouf.clone(),
+        stats: Arc::clone(&stats),
+        start: Instant::now(),
+    });
+    if listed.is_some() {
+        check_sources_exist([inf]);
+    } else if !remote && !stdin_source && cli.from_archive.as_deref() != Some(Path::new("-")) {
+        check_sources_exist(&sources);
+    }
+    // More than one source, or a target directory, copies into it.
+    let into_dir = !remote
+        && !stdin_source
+        && !archive
+        && listed.is_none()
+        && (sources.len() > 1 || cli.target_directory.is_some());
+    if stdin_source && ouf.is_dir() {
+        usage_error(&format!(
+            "'{}' is a directory, a copy from stdin needs a file to write to",
+            ouf.display()
+        ));
+    }
+    if (cli.read_threads.is_some() || cli.write_threads.is_some()) && cli.engine != Engine::Pipeline
+    {
+        usage_error("--read-threads and --write-threads need --engine pipeline");
+    }
+    if cli.write_manifest.is_some() && (into_dir || listed.is_some()) {
+        usage_error("--write-manifest needs a copy of one file or tree");
+    }
+    let offsets = cli.skip.is_some() || cli.seek.is_some() || cli.count.is_some();
+    if offsets && (remote || into_dir || listed.is_some() || cli.recursive) {
+        usage_error("--skip, --seek and --count need a copy of one local file");
+    }
+    if offsets && cli.resume {
+        usage_error("--resume can't be used with --skip, --seek or --count");
+    }
+    if cli.encrypt.is_some() && (remote || archive || stdin_source || stdout_dest) {
+        usage_error("--encrypt is for copies to local files");
+    }
+    let encrypt_conflict = [
+        (cli.resume, "--resume"),
+        (cli.checksum, "--checksum"),
+        (offsets, "--skip, --seek and --count"),
+        (cli.follow.is_some(), "--follow"),
+        (cli.link_dest.is_some(), "--link-dest"),
+        (cli.reflink_dest.is_some(), "--reflink-dest"),
+        // The manifest is of the sources.
+        (
+            cli.move_files && cli.write_manifest.is_some(),
+            "--move with --write-manifest",
+        ),
+    ];
+    if let Some((_, flag)) = encrypt_conflict
+        .iter()
+        .find(|(set, _)| cli.encrypt.is_some() && *set)
+    {
+        usage_error(&format!("{} is not supported with --encrypt", flag));
+    }
+    if cli.split == Some(0) {
+        usage_error("--split needs a part size above 0");
+    }
+    if cli.split.is_some() && (remote || archive || stdin_source || stdout_dest) {
+        usage_error("--split is for copies to local files");
+    }
+    let split_conflict = [
+        (cli.encrypt.is_some(), "--encrypt"),
+        (cli.resume, "--resume"),
+        (cli.seek.is_some(), "--seek"),
+        (cli.follow.is_some(), "--follow"),
+        // Parts would be deleted, or linked in place of the files.
+        (cli.delete, "--delete"),
+        (cli.hard_links, "--hard-links"),
+        (cli.link_dest.is_some(), "--link-dest"),
+        (cli.reflink_dest.is_some(), "--reflink-dest"),
+        (cli.write_manifest.is_some(), "--write-manifest"),
+    ];
+    if let Some((_, flag)) = split_conflict
+        .iter()
+        .find(|(set, _)| cli.split.is_some() && *set)
+    {
+        usage_error(&format!("{} is not supported with --split", flag));
+    }
+    if cli.delta && (remote || archive || stdin_source || stdout_dest) {
+        usage_error("--delta is for copies to local files");
+    }
+    let delta_conflict = [
+        (cli.fanout, "--fanout"),
+        (cli.encrypt.is_some(), "--encrypt"),
+        (cli.split.is_some(), "--split"),
+        (cli.resume, "--resume"),
+        (offsets, "--skip, --seek and --count"),
+        (cli.follow.is_some(), "--follow"),
+        // These replace the destination rather than update it.
+        (cli.atomic, "--atomic"),
+        (cli.partial_suffix.is_some(), "--partial-suffix"),
+        (cli.backup.is_some(), "--backup"),
+        (cli.backup_dir.is_some(), "--backup-dir"),
+    ];
+    if let Some((_, flag)) = delta_conflict.iter().find(|(set, _)| cli.delta && *set) {
+        usage_error(&format!("{} is not supported with --delta", flag));
+    }
+    if let Some((_, flag)) = [
+        (cli.atomic, "--atomic"),
+        (cli.partial_suffix.is_some(), "--partial-suffix"),
+    ]
+    .iter()
+    .find(|(set, _)| cli.inplace && *set)
+    {
+        usage_error(&format!("{} is not supported with --inplace", flag));
+    }
+    if cli.debounce.is_some() && !cli.watch {
+        usage_error("--debounce is for watch");
+    }
+    if cli.watch && (remote || into_dir || listed.is_some() || stdin_source || stdout_dest) {
+        usage_error("watch needs one local source directory and destination");
+    }
+    if cli.watch && !inf.is_dir() {
+        usage_error(&format!("'{}' is not a directory to watch", inf.display()));
+    }
+    let watch_conflict = [
+        (cli.dry_run, "--dry-run"),
+        (cli.verify || cli.verify_hash.is_some(), "--verify"),
+        (cli.write_manifest.is_some(), "--write-manifest"),
+    ];
+    if let Some((_, flag)) = watch_conflict.iter().find(|(set, _)| cli.watch && *set) {
+        usage_error(&format!("{} is not supported with watch", flag));
+    }
+    if cli.itemize && !cli.recursive {
+        usage_error("--itemize needs a recursive copy");
+    }
+    if cli.follow.is_some() && (remote || into_dir || listed.is_some() || cli.recursive) {
+        usage_error("--follow needs a copy of one local file");
+    }
+    if cli
+        .partial_suffix
+        .as_ref()
+        .is_some_and(|suffix| suffix.is_empty() || suffix.contains(std::path::is_separator))
+    {
+        usage_error("--partial-suffix needs a suffix that is not empty and has no /");
+    }
+    if cli.follow.is_some() && offsets {
+        usage_error("--follow can't be used with --skip, --seek or --count");
+    }
+    if !cli.recursive {
+        let mut copied = match &listed {
+            Some(paths) => paths.iter().map(|path| inf.join(path)).collect(),
+            None if into_dir || remote_dest.is_some() || s3_dest.is_some() => sources.clone(),
+            None if cli.to_archive.is_some() => sources.clone(),
+            None => Vec::new(),
+        }
+        .into_iter();
+        if let Some(dir) = copied.find(|path| path.is_dir()) {
+            usage_error(&format!(
+                "'{}' is a directory, use -r to copy it",
+                dir.display()
+            ));
+        }
+    }
+    let num_threads = cli.threads.resolve(&sources, &ouf, remote);
+    if cli.ionice_class == Some(IoClass::Idle) && cli.ionice_level.is_some() {
+        usage_error("--ionice-level has no effect with --ionice-class idle");
+    }
+    // Threads started from here on inherit these.
+    if let Some(Cpus(cpus)) = &cli.cpuset {
+        rpcp::set_cpu_affinity(cpus)?;
+    }
+    if let Some(nice) = cli.nice {
+        rpcp::set_nice(nice)?;
+    }
+    if cli.ionice_class.is_some() || cli.ionice_level.is_some() {
+        rpcp::set_io_priority(
+            cli.ionice_class.unwrap_or(IoClass::BestEffort),
+            cli.ionice_level.unwrap_or(4),
+        )?;
+    }
+    let numa_node = cli.numa_node.and_then(|node| node.resolve(&sources, &ouf));
+    if let Some(node) = numa_node.filter(|node| !rpcp::numa_nodes().contains(node)) {
+        usage_error(&format!("There is no NUMA node {}", node));
+    }
+    if cli.numa_node == Some(NumaNode::Auto) && numa_node.is_none() && cli.verbose > 0 {
+        eprintln!("No NUMA node found for the disks copied between, threads are not pinned");
+    }
+    let verify = cli.verify || cli.verify_hash.is_some();
+    // The first Ctrl-C or SIGTERM stops the copy and cleans up, a second
+    // one quits straight away.
+    let cancel = Arc::new(AtomicBool::new(false));
+    let handler_cancel = Arc::clone(&cancel);
+    let control = Control::new();
+    control.set_bwlimit(cli.bwlimit);
+    control.set_bwlimit_schedule(cli.bwlimit_schedule.clone());
+    let handler_control = control.clone();
+    ctrlc::set_handler(move || {
+        if handler_cancel.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        // Paused threads have to run on to see that they are cancelled.
+        handler_control.resume();
+        eprintln!("\nInterrupted, stopping the copy. Interrupt again to quit at once.");
+    })
+    .map_err(|e| format!("Failed to set up the signal handler: {}", e))?;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-    let inf = cli.in_file;
-    let ouf = cli.out_file;
-    let num_threads = cli.threads as usize;
+    if let Some(addr) = &cli.metrics {
+        let stats = Arc::clone(&stats);
+        serve_metrics(addr, move || Metrics::of(&stats).render())?;
+    }
+    let options = CopyOptions::new()
+        .threads(num_threads)
+        .adaptive(cli.adaptive)
+        .read_threads(cli.read_threads.map(|threads| threads as usize))
+        .write_threads(cli.write_threads.map(|threads| threads as usize))
+        .buffer_size(cli.buffer_size as usize)
+        .max_memory(cli.max_memory)
+        .numa_node(numa_node)
+        .verify_hash(cli.verify_hash)
+        .resume(cli.resume)
+        .skip(cli.skip.unwrap_or(0))
+        .seek(cli.seek.unwrap_or(0))
+        .count(cli.count)
+        .split(cli.split)
+        .delta(cli.delta)
+        .follow(cli.follow)
+        .fail_on_change(cli.fail_on_change)
+        .preserve(cli.preserve)
+        .owner(cli.owner || cli.preserve_ownership)
+        .group(cli.group || cli.preserve_ownership)
+        .xattrs(cli.xattrs)
+        .acls(cli.acls)
+        .specials(cli.specials)
+        .devices(cli.devices)
+        .hard_links(cli.hard_links)
+        .link_dest(cli.link_dest.clone())
+        .reflink_dest(cli.reflink_dest.clone())
+        .symlinks(if cli.links {
+            Symlinks::Recreate
+        } else {
+            Symlinks::Follow
+        })
+        .safe_links(cli.safe_links)
+        .sparse(cli.sparse)
+        .filter(filter)
+        .max_depth(cli.max_depth)
+        .order(cli.order)
+        .walk_threads(cli.walk_threads as usize)
+        .batch(cli.batch as usize)
+        .one_file_system(cli.one_file_system)
+        .dry_run(cli.dry_run)
+        .bwlimit(cli.bwlimit)
+        .bwlimit_schedule(cli.bwlimit_schedule.clone())
+        .engine(cli.engine)
+        .direct(cli.direct)
+        .preallocate(!cli.no_preallocate)
+        .space_check(!cli.no_space_check)
+        .drop_cache(cli.drop_cache)
+        .writeback(cli.writeback)
+        .retries(cli.retries)
+        .retry_delay(cli.retry_delay)
+        .cancel(cancel)
+        .keep_partial(cli.partial)
+        .atomic(cli.atomic)
+        .inplace(cli.inplace)
+        .part_suffix(cli.partial_suffix.clone())
+        .backup(cli.backup.clone())
+        .backup_dir(cli.backup_dir.clone())
+        .fsync(if cli.fsync {
+            Fsync::All
+        } else if cli.fdatasync {
+            Fsync::Data
+        } else {
+            Fsync::Off
+        })
+        .overwrite(if cli.no_clobber {
+            Overwrite::Never
+        } else if cli.interactive {
+            Overwrite::Ask
+        } else if cli.update {
+            Overwrite::Update
+        } else {
+            Overwrite::Always
+        })
+        .skip_identical(!cli.ignore_times)
+        .checksum(cli.checksum)
+        .delete(cli.delete)
+        .max_delete(cli.max_delete)
+        .remove_source(cli.move_files)
+        .json(cli.json)
+        .verbosity(cli.verbose)
+        .itemize(cli.itemize)
+        .stats(Arc::clone(&stats))
+        .continue_on_error(cli.continue_on_error)
+        .max_errors(cli.max_errors)
+        .progress(!cli.quiet);
+    let options = if cli.control.is_some() {
+        options.control(&control)
+    } else {
+        options
+    };
+    let options = match &cli.encrypt {
+        Some(recipients) => options.encrypt(recipients.clone()),
+        None => options,
+    };
+    let manifest = cli.write_manifest.as_ref().map(|path| {
+        let algorithm = manifest_algorithm(path)
+            .or(cli.verify_hash)
+            .unwrap_or(HashAlgorithm::Blake3);
+        Arc::new(Manifest::new(algorithm))
+    });
+    let options = match &manifest {
+        Some(manifest) => options.manifest(Arc::clone(manifest)),
+        None => options,
+    };
+    let control_socket = match &cli.control {
+        Some(path) => Some(serve_control(path, control, Some(Arc::clone(&stats)))?),
+        None => None,
+    };
 
-    eprintln!("Copying data with {} threads", num_threads);
+    if !cli.dry_run && !cli.quiet {
+        let on_node = numa_node
+            .map(|node| format!(" on NUMA node {}", node))
+            .unwrap_or_default();
+        if cli.adaptive {
+            eprintln!("Copying data with up to {} threads{}", num_threads, on_node);
+        } else {
+            eprintln!("Copying data with {} threads{}", num_threads, on_node);
+        }
+    }
 
-    // do recursive dir walk here
     let start_time = time_as_double().map_err(|e| format!("Error calculating time: {:?}", e))?;
 
-    let (copy_size, finish_time) = (|| -> Result<(usize, f64), Box<dyn std::error::Error>> {
-        if !cli.recursive {
-            let copy_size = copy_file(&inf, &ouf, num_threads)?;
-            let finish_time =
-                time_as_double().map_err(|e| format!("Error calculating time: {:?}", e))?;
-            Ok((copy_size, finish_time))
-        } else {
-            let copy_size = copy_dir_recursive(&inf, &ouf, num_threads)?;
-            let finish_time =
-                time_as_double().map_err(|e| format!("Error calculating time: {:?}", e))?;
-            Ok((copy_size, finish_time))
+    // Single files are verified inside the copy from digests taken on the
+    // fly, and so are moved files before their source is removed. Trees are
+    // verified once they are copied, unless they are encrypted or split,
+    // which only the copy can check.
+    let verify_inline = verify
+        && (!cli.recursive
+            || cli.move_files
+            || into_dir
+            || listed.is_some()
+            || remote
+            || cli.encrypt.is_some()
+            || cli.split.is_some());
+    let copy_options = options.clone().verify(verify_inline);
+    let result = if let Some(archive) = &cli.to_archive {
+        archive_to(&sources, archive, &copy_options)
+    } else if let Some(archive) = &cli.from_archive {
+        archive_from(archive, &ouf, &copy_options)
+    } else if stdin_source {
+        copy_from_reader(io::stdin().lock(), &ouf, &copy_options)
+    } else if stdout_dest {
+        copy_to_writer(inf, io::stdout().lock(), &copy_options)
+    } else if let Some(url) = url_source {
+        download_url(url, &ouf, &copy_options)
+    } else if let Some(dest) = &s3_dest {
+        upload_to_s3(inf, dest, &copy_options)
+    } else if let Some(dest) = &remote_dest {
+        upload(inf, dest, &copy_options)
+    } else if let Some(src) = &remote_source {
+        download(src, &ouf, &copy_options)
+    } else if let Some(paths) = &listed {
+        copy_listed(inf, paths, &ouf, &copy_options)
+    } else if cli.fanout {
+        copy_fanout(inf, &fanout, &copy_options)
+    } else if into_dir {
+        copy_into(&sources, &ouf, &copy_options)
+    } else if cli.watch {
+        let debounce = cli.debounce.unwrap_or(Duration::from_secs(1));
+        watch_tree(inf, &ouf, debounce, &copy_options)
+    } else if !cli.recursive {
+        copy_file(inf, &ouf, &copy_options)
+    } else {
+        copy_tree(inf, &ouf, &copy_options)
+    };
+    // Removed before any of the exits below.
+    drop(control_socket);
+    let finish_time = time_as_double().map_err(|e| format!("Error calculating time: {:?}", e))?;
+    let seconds = finish_time - start_time;
+    if let (Some(path), Err(_)) = (&cli.stats_file, &result) {
+        // A failed run still records how far it got.
+        write_stats(path, &stats_fields(&stats, stats.bytes(), seconds))?;
+    }
+    let copy_size = match result {
+        Ok(copy_size) => copy_size,
+        Err(e) if e.is::<VerifyError>() => {
+            log::error!("Verification failed: {}", e);
+            if e.downcast_ref::<VerifyError>()
+                .is_some_and(VerifyError::copy_removed)
+            {
+                eprintln!(
+                    "The invalid copy was removed, {} is untouched",
+                    ouf.display()
+                );
+            } else {
+                // Want to clean up file here but this might get run with sudo.
+                eprintln!("Go clean up the invalid copy at {}", ouf.display());
+            }
+            // Exit with a non-zero status code.
+            run_hooks(EXIT_VERIFY, Some(&e.to_string()));
+            std::process::exit(EXIT_VERIFY);
+        }
+        Err(e) if e.is::<CancelledError>() => {
+            log::warn!("{}", e);
+            run_hooks(EXIT_INTERRUPTED, Some(&e.to_string()));
+            std::process::exit(EXIT_INTERRUPTED);
         }
-    })()?;
+        // A URL that is not there, like a missing local source.
+        Err(e)
+            if url_source.is_some()
+                && e.downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+        {
+            log::error!("{}", e);
+            run_hooks(EXIT_MISSING, Some(&e.to_string()));
+            std::process::exit(EXIT_MISSING);
+        }
+        Err(e) => {
+            let Some(partial) = e.downcast_ref::<PartialCopyError>() else {
+                return Err(e);
+            };
+            for failure in partial.failures() {
+                eprintln!(
+                    "Failed to copy '{}': {}",
+                    failure.path.display(),
+                    failure.reason
+                );
+            }
+            log::error!("{}", partial);
+            run_hooks(EXIT_PARTIAL, Some(&partial.to_string()));
+            std::process::exit(EXIT_PARTIAL);
+        }
+    };
+
+    if cli.dry_run {
+        println!("Dry run, {} bytes would be copied", copy_size);
+        return Ok(());
+    }
 
-    eprintln!(
-        "\n Copy finished. {} bytes written in {:.1} seconds = {:.3} Gbits/s",
+    // Errors are all that is printed with --quiet.
+    let note = |message: String| {
+        if !cli.quiet {
+            eprintln!("{}", message);
+        }
+    };
+    let gbits = |rate: f64| rate * 8.0 / 1e9;
+    let average = copy_size as f64 / seconds;
+    log::info!(
+        "Finished, {} bytes in {:.1}s, {} files copied, {} skipped, {} failed",
         copy_size,
-        finish_time - start_time,
-        copy_size as f64 / (finish_time - start_time) * 8.0 / 1e9
+        seconds,
+        stats.files_copied(),
+        stats.files_skipped(),
+        stats.files_failed()
     );
+    note(format!(
+        " Copy finished. {} bytes written in {:.1} seconds = {:.3} Gbits/s",
+        copy_size,
+        seconds,
+        gbits(average)
+    ));
+    // Copies that do not go through local files are not counted.
+    if !remote {
+        note(format!(
+            " Files: {} copied, {} skipped, {} failed, {} directories created. Peak {:.3} Gbits/s",
+            stats.files_copied(),
+            stats.files_skipped(),
+            stats.files_failed(),
+            stats.dirs_created(),
+            gbits((stats.peak_rate() as f64).max(average))
+        ));
+    }
 
-    // varify only works for single file copy mode for now
-    if !cli.recursive & cli.verify {
-        match verify_copy(&inf, &ouf, copy_size) {
-            Ok(msg) => eprintln!("{}", msg),
-            Err(e) => {
-                eprintln!("File copy verification error: {}", e);
-                // Want to clean up file here but this might get run with sudo.
-                eprintln!("Go clean up the invalid copy at {}", ouf.display());
-                // Exit with a non-zero status code.
-                std::process::exit(1);
+    // Orchestration tools get the outcome as the last JSON event.
+    let fields = stats_fields(&stats, copy_size, seconds);
+    let summary = |fields: &str| -> Result<(), rpcp::Error> {
+        if cli.json {
+            println!(
+                "{{\"event\":\"summary\",{},\"verified\":{}}}",
+                fields, verify
+            );
+        }
+        match &cli.stats_file {
+            Some(path) => write_stats(path, fields),
+            None => Ok(()),
+        }
+    };
+    if verify_inline {
+        note("Verified files are identical.".to_string());
+    } else if verify {
+        let failures = verify_tree(inf, &ouf, &options)?;
+        if failures.is_empty() {
+            note("Verified all files are identical.".to_string());
+        } else {
+            for failure in &failures {
+                log::error!(
+                    "Verification failed: {}: {}",
+                    ouf.join(&failure.path).display(),
+                    failure.reason
+                );
             }
+            eprintln!("{} files failed verification", failures.len());
+            // Counted by the verification.
+            summary(&stats_fields(&stats, copy_size, seconds))?;
+            run_hooks(
+                EXIT_VERIFY,
+                Some(&format!("{} files failed verification", failures.len())),
+            );
+            std::process::exit(EXIT_VERIFY);
         }
     }
 
-    Ok(())
+    if let (Some(path), Some(manifest)) = (&cli.write_manifest, &manifest) {
+        let out = File::create(path)
+            .map_err(|e| format!("Failed to create '{}': {}", path.display(), e))?;
+        let files = manifest.write(&ouf, BufWriter::new(out), &options)?;
+        note(format!(
+            "Wrote the {} digests of {} files to '{}'",
+            manifest.algorithm(),
+            files,
+            path.display()
+        ));
+    }
+    summary(&fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(when: &str) -> i64 {
+        let time = parse_when(when).unwrap();
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        }
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(seconds("1970-01-01"), 0);
+        assert_eq!(seconds("2024-05-01"), 1_714_521_600);
+        assert_eq!(seconds("2000-02-29T12:34:56"), 951_827_696);
+        assert_eq!(seconds("2024-12-31 23:59"), 1_735_689_540);
+        assert_eq!(seconds("1969-12-31T23:59:59"), -1);
+        // Century years are leap years only every 400 years.
+        assert_eq!(seconds("1900-03-01"), -2_203_891_200);
+        assert_eq!(seconds("2100-03-01"), 4_107_542_400);
+    }
+
+    #[test]
+    fn parses_ages() {
+        let now = SystemTime::now();
+        for (age, expected) in [("90s", 90), ("15m", 900), ("1.5h", 5400), ("2w", 1_209_600)] {
+            let when = parse_when(age).unwrap();
+            let elapsed = now.duration_since(when).unwrap().as_secs_f64();
+            assert!(
+                (elapsed - expected as f64).abs() < 5.0,
+                "{}: {}",
+                age,
+                elapsed
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_other_times() {
+        for when in [
+            "",
+            "30",
+            "30y",
+            "yesterday",
+            "2024-05",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-05-32",
+            "2023-02-29",
+            "2024-05-01T24:00",
+            "2024-05-01T12",
+            "2024-05-01T12:60",
+            "10000-01-01",
+        ] {
+            assert!(parse_when(when).is_err(), "{:?}", when);
+        }
+    }
 }
@@ -0,0 +1,510 @@
+use crate::hash::{digest, HashAlgorithm, Hasher};
+use crate::options::CopyOptions;
+use crate::tree::walk;
+use crate::verify::VerifyFailure;
+use crate::Error;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// The digests of the files a copy made with [`CopyOptions::manifest`]
+/// wrote, taken from the data as it was copied, to write out with
+/// [`Manifest::write`] in the format of [`write_manifest`].
+///
+/// Files whose data the copy never saw, such as those already in the
+/// destination, hard links and copies the kernel made within one
+/// filesystem, are only read when the manifest is written.
+#[derive(Debug)]
+pub struct Manifest {
+    algorithm: HashAlgorithm,
+    files: Mutex<BTreeMap<PathBuf, Digest>>,
+}
+
+/// Where the digest of a file in a [`Manifest`] comes from.
+#[derive(Debug)]
+enum Digest {
+    Taken(String),
+    /// The file to hash, or the other file in the manifest it is a link to.
+    Read(PathBuf),
+}
+
+impl Manifest {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Manifest {
+            algorithm,
+            files: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Add `dest`, the copy of `src`, to be read when the manifest is
+    /// written unless its copy takes the digest. Encrypted copies are of
+    /// the data of their sources.
+    pub(crate) fn add(&self, src: &Path, dest: &Path, options: &CopyOptions) {
+        if options.dry_run {
+            return;
+        }
+        let read = if options.encrypt.is_some() { src } else { dest };
+        self.files
+            .lock()
+            .unwrap()
+            .insert(dest.to_path_buf(), Digest::Read(read.to_path_buf()));
+    }
+
+    /// Add `dest`, a hard link to `target`, which has the same digest.
+    pub(crate) fn link(&self, dest: &Path, target: &Path) {
+        let target = Digest::Read(target.to_path_buf());
+        self.files
+            .lock()
+            .unwrap()
+            .insert(dest.to_path_buf(), target);
+    }
+
+    /// Record the digest the copy to `dest` took.
+    pub(crate) fn taken(&self, dest: &Path, digest: String) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(dest.to_path_buf(), Digest::Taken(digest));
+    }
+
+    /// Record the digest of `data`, all that was copied to `dest`.
+    pub(crate) fn hashed(&self, dest: &Path, data: &[u8]) {
+        let mut hasher = self.algorithm.hasher();
+        hasher.update(data);
+        self.taken(dest, hasher.finish());
+    }
+
+    /// Write the manifest to `out`, with the paths relative to `root`, the
+    /// destination of the copy, and sorted. Files whose digest was not
+    /// taken by the copy are hashed `options.threads` at a time. Returns
+    /// the number of files listed.
+    pub fn write<P: AsRef<Path>, W: Write>(
+        &self,
+        root: P,
+        mut out: W,
+        options: &CopyOptions,
+    ) -> Result<usize, Error> {
+        let root = root.as_ref();
+        let files = self.files.lock().unwrap();
+        let mut unread = Vec::new();
+        for digest in files.values() {
+            if let Digest::Read(path) = resolve(&files, digest) {
+                unread.push(path.clone());
+            }
+        }
+        unread.sort();
+        unread.dedup();
+        let read: BTreeMap<&PathBuf, io::Result<String>> = unread
+            .iter()
+            .zip(digest_files(
+                Path::new(""),
+                &unread,
+                self.algorithm,
+                options,
+            ))
+            .collect();
+
+        for (dest, digest) in files.iter() {
+            let digest = match resolve(&files, digest) {
+                Digest::Taken(digest) => digest,
+                Digest::Read(path) => read[path]
+                    .as_ref()
+                    .map_err(|e| format!("Failed to hash '{}': {}", path.display(), e))?,
+            };
+            let relative = match dest.strip_prefix(root) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative,
+                _ => dest.file_name().map(Path::new).unwrap_or(dest),
+            };
+            out.write_all(manifest_line(digest, relative).as_bytes())?;
+        }
+        out.flush()?;
+        Ok(files.len())
+    }
+}
+
+/// Follow `digest` to the file it is read from, through the links to other
+/// files in `files`, or to the digest taken of one of them.
+fn resolve<'a>(files: &'a BTreeMap<PathBuf, Digest>, mut digest: &'a Digest) -> &'a Digest {
+    while let Digest::Read(path) = digest {
+        match files.get(path) {
+            Some(Digest::Read(next)) if next == path => break,
+            Some(next) => digest = next,
+            None => break,
+        }
+    }
+    digest
+}
+
+/// The digest of a whole file for a [`Manifest`], taken from its chunks as
+/// the threads copying them read them. A chunk is only hashed once every
+/// chunk before it is, so the threads take turns, and what lies between
+/// chunks, the holes of the file, is hashed as the zeros it reads as.
+pub(crate) struct FileDigest {
+    /// The chunk whose turn it is, how many bytes are hashed and the hasher.
+    state: Mutex<(usize, u64, Hasher)>,
+    turn: Condvar,
+}
+
+impl FileDigest {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Self {
+        FileDigest {
+            state: Mutex::new((0, 0, algorithm.hasher())),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// Hash `data`, read at `offset` for chunk `chunk`, once the chunks
+    /// before it are done. Nothing is hashed when the copy is `stopped`
+    /// while this waits.
+    pub(crate) fn update(
+        &self,
+        chunk: usize,
+        offset: u64,
+        data: &[u8],
+        stopped: &dyn Fn() -> bool,
+    ) {
+        if let Some(mut state) = self.wait(chunk, stopped) {
+            let (_, hashed, hasher) = &mut *state;
+            // Direct IO reads whole blocks, which can overlap what is hashed.
+            let skip = (*hashed).saturating_sub(offset).min(data.len() as u64);
+            hash_zeros(hasher, offset.saturating_sub(*hashed));
+            hasher.update(&data[skip as usize..]);
+            *hashed = (*hashed).max(offset + data.len() as u64);
+        }
+    }
+
+    /// Give the turn to the chunk after `chunk`, once `chunk` has it.
+    pub(crate) fn chunk_done(&self, chunk: usize, stopped: &dyn Fn() -> bool) {
+        if let Some(mut state) = self.wait(chunk, stopped) {
+            state.0 += 1;
+            drop(state);
+            self.turn.notify_all();
+        }
+    }
+
+    /// The digest of the first `size` bytes of the file, once every chunk
+    /// is done.
+    pub(crate) fn finish(self, size: u64) -> String {
+        let (_, hashed, mut hasher) = self.state.into_inner().unwrap();
+        hash_zeros(&mut hasher, size.saturating_sub(hashed));
+        hasher.finish()
+    }
+
+    fn wait(
+        &self,
+        chunk: usize,
+        stopped: &dyn Fn() -> bool,
+    ) -> Option<MutexGuard<'_, (usize, u64, Hasher)>> {
+        let mut state = self.state.lock().unwrap();
+        while state.0 != chunk {
+            // A thread that failed never gives up its turn.
+            if stopped() {
+                return None;
+            }
+            state = (self
+                .turn
+                .wait_timeout(state, Duration::from_millis(50))
+                .unwrap())
+            .0;
+        }
+        Some(state)
+    }
+}
+
+fn hash_zeros(hasher: &mut Hasher, mut len: u64) {
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+    while len > 0 {
+        let piece = len.min(ZEROS.len() as u64);
+        hasher.update(&ZEROS[..piece as usize]);
+        len -= piece;
+    }
+}
+
+/// Write a manifest of the files under `root` to `out`: one line per file
+/// with its digest and its path relative to `root`, sorted by path. A
+/// `root` that is a file gives one line with its file name.
+///
+/// The lines are in the format of `sha256sum` and `b3sum`, so a manifest
+/// written with [`HashAlgorithm::Sha256`] or [`HashAlgorithm::Blake3`] can
+/// also be checked with them. Files are hashed `options.threads` at a time,
+/// leaving out what [`CopyOptions::filter`] excludes. Returns the number of
+/// files listed.
+pub fn write_manifest<P: AsRef<Path>, W: Write>(
+    root: P,
+    mut out: W,
+    algorithm: HashAlgorithm,
+    options: &CopyOptions,
+) -> Result<usize, Error> {
+    let root = root.as_ref();
+    let mut files = Vec::new();
+    let dir = if fs::metadata(root)?.is_dir() {
+        for entry in walk(root, options) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                files.push(entry.path().strip_prefix(root)?.to_path_buf());
+            }
+        }
+        files.sort();
+        root
+    } else {
+        let name = root
+            .file_name()
+            .ok_or_else(|| format!("'{}' has no file name", root.display()))?;
+        files.push(PathBuf::from(name));
+        root.parent().unwrap_or(Path::new(""))
+    };
+
+    let digests = digest_files(dir, &files, algorithm, options);
+    for (path, digest) in files.iter().zip(digests) {
+        let digest =
+            digest.map_err(|e| format!("Failed to hash '{}': {}", dir.join(path).display(), e))?;
+        out.write_all(manifest_line(&digest, path).as_bytes())?;
+    }
+    out.flush()?;
+    Ok(files.len())
+}
+
+/// Check the files under `dest` against a manifest written by
+/// [`write_manifest`], returning the files that are missing or whose
+/// digest differs. The list is empty when every file in the manifest
+/// matches. Files that are not in the manifest are not looked at.
+pub fn verify_manifest<P: AsRef<Path>, Q: AsRef<Path>>(
+    manifest: P,
+    dest: Q,
+    algorithm: HashAlgorithm,
+    options: &CopyOptions,
+) -> Result<Vec<VerifyFailure>, Error> {
+    let (manifest, dest) = (manifest.as_ref(), dest.as_ref());
+    let contents = fs::read_to_string(manifest).map_err(|e| {
+        format!(
+            "Failed to read the manifest '{}': {}",
+            manifest.display(),
+            e
+        )
+    })?;
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let entry = parse_line(line).ok_or_else(|| {
+            format!(
+                "Line {} of the manifest '{}' is not a digest and a path",
+                number + 1,
+                manifest.display()
+            )
+        })?;
+        entries.push(entry);
+    }
+
+    if options.progress {
+        eprintln!(
+            "Verifying {} files in '{}' against '{}'",
+            entries.len(),
+            dest.display(),
+            manifest.display()
+        );
+    }
+    let paths: Vec<PathBuf> = entries.iter().map(|(_, path)| path.clone()).collect();
+    let digests = digest_files(dest, &paths, algorithm, options);
+    let mut failures = Vec::new();
+    for ((expected, path), digest) in entries.into_iter().zip(digests) {
+        let reason = match digest {
+            Ok(digest) if digest.eq_ignore_ascii_case(&expected) => continue,
+            Ok(digest) => format!(
+                "{} digest is {}, the manifest has {}",
+                algorithm, digest, expected
+            ),
+            Err(e) => e.to_string(),
+        };
+        failures.push(VerifyFailure { path, reason });
+    }
+    Ok(failures)
+}
+
+/// The algorithm a manifest is written with, going by its extension, such
+/// as `.b3` or `.sha256`.
+pub fn manifest_algorithm<P: AsRef<Path>>(manifest: P) -> Option<HashAlgorithm> {
+    let extension = manifest
+        .as_ref()
+        .extension()?
+        .to_str()?
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "b3" | "blake3" => Some(HashAlgorithm::Blake3),
+        "sha256" => Some(HashAlgorithm::Sha256),
+        "xxh3" => Some(HashAlgorithm::Xxh3),
+        "crc32" => Some(HashAlgorithm::Crc32),
+        _ => None,
+    }
+}
+
+/// Hash `dir.join(path)` for every path, `options.threads` files at a time.
+fn digest_files(
+    dir: &Path,
+    paths: &[PathBuf],
+    algorithm: HashAlgorithm,
+    options: &CopyOptions,
+) -> Vec<io::Result<String>> {
+    let next = AtomicUsize::new(0);
+    let digests = Mutex::new(Vec::with_capacity(paths.len()));
+    thread::scope(|scope| {
+        for _ in 0..options.threads.min(paths.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(path) = paths.get(index) else {
+                    break;
+                };
+                let digest = digest(&dir.join(path), algorithm, options.buffer_size);
+                digests.lock().unwrap().push((index, digest));
+            });
+        }
+    });
+    let mut digests = digests.into_inner().unwrap();
+    digests.sort_by_key(|(index, _)| *index);
+    digests.into_iter().map(|(_, digest)| digest).collect()
+}
+
+/// A line of the manifest. Like `sha256sum`, paths with a newline or a
+/// backslash in them are escaped and the line starts with a backslash.
+pub(crate) fn manifest_line(digest: &str, path: &Path) -> String {
+    // The manifest is read back on any platform.
+    let path = path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    if path.contains(['\n', '\\']) {
+        let escaped = path.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}\n", digest, escaped)
+    } else {
+        format!("{}  {}\n", digest, path)
+    }
+}
+
+pub(crate) fn parse_line(line: &str) -> Option<(String, PathBuf)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (digest, path) = line.split_once("  ")?;
+    if digest.is_empty() || !digest.bytes().all(|b| b.is_ascii_hexdigit()) || path.is_empty() {
+        return None;
+    }
+    let path = if escaped {
+        let mut unescaped = String::new();
+        let mut chars = path.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+            match chars.next()? {
+                'n' => unescaped.push('\n'),
+                '\\' => unescaped.push('\\'),
+                _ => return None,
+            }
+        }
+        unescaped
+    } else {
+        path.to_string()
+    };
+    Some((digest.to_string(), PathBuf::from(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse::Sparse;
+    use crate::testing::scratch;
+    use crate::tree::copy_tree;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = HashAlgorithm::Sha256.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    #[test]
+    fn hashes_chunks_in_order() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut expected = data.clone();
+        // Chunks 1 and 3 are holes, which are never copied.
+        let chunks = [0..1000, 3000..4000, 4000..6500, 9000..10_000];
+        expected[1000..3000].fill(0);
+        expected[6500..9000].fill(0);
+        expected.extend([0; 500]);
+
+        let file_digest = FileDigest::new(HashAlgorithm::Sha256);
+        let stop = AtomicBool::new(false);
+        let stopped = || stop.load(Ordering::SeqCst);
+        thread::scope(|scope| {
+            for (chunk, range) in chunks.iter().enumerate().rev() {
+                let (file_digest, data, stopped) = (&file_digest, &data, &stopped);
+                scope.spawn(move || {
+                    for start in range.clone().step_by(700) {
+                        let end = (start + 700).min(range.end);
+                        let piece = &data[start..end];
+                        file_digest.update(chunk, start as u64, piece, stopped);
+                    }
+                    file_digest.chunk_done(chunk, stopped);
+                });
+            }
+        });
+        assert_eq!(file_digest.finish(10_500), sha256(&expected));
+
+        // A chunk that never comes holds up the others only until the copy
+        // stops.
+        let file_digest = FileDigest::new(HashAlgorithm::Sha256);
+        thread::scope(|scope| {
+            scope.spawn(|| file_digest.chunk_done(1, &stopped));
+            stop.store(true, Ordering::SeqCst);
+        });
+    }
+
+    #[test]
+    fn lists_what_was_copied() {
+        let dir = scratch("manifest");
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(src.join("big"), &big).unwrap();
+        fs::write(src.join("sub/small"), b"small").unwrap();
+        fs::hard_link(src.join("big"), src.join("link")).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("other"), b"not from src").unwrap();
+
+        let manifest = Arc::new(Manifest::new(HashAlgorithm::Sha256));
+        // Copied by the threads, rather than by the kernel.
+        let options = CopyOptions::new()
+            .threads(4)
+            .buffer_size(4096)
+            .sparse(Sparse::Never)
+            .hard_links(true)
+            .progress(false)
+            .manifest(Arc::clone(&manifest));
+        copy_tree(&src, &dest, &options).unwrap();
+        // The digests are those the copy took, not of what is there now.
+        fs::write(dest.join("big"), vec![0; big.len()]).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(manifest.write(&dest, &mut out, &options).unwrap(), 3);
+        let expected = format!(
+            "{big}  big\n{big}  link\n{}  sub/small\n",
+            sha256(b"small"),
+            big = sha256(&big)
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
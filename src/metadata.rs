@@ -0,0 +1,136 @@
+use crate::options::CopyOptions;
+use crate::xattr::{copy_acls, copy_xattrs};
+use crate::Error;
+#[cfg(unix)]
+use nix::sys::stat::{utimensat, UtimensatFlags};
+#[cfg(unix)]
+use nix::sys::time::TimeSpec;
+#[cfg(unix)]
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::SystemTime;
+#[cfg(unix)]
+use std::time::UNIX_EPOCH;
+
+/// Apply the metadata of `src`, the source, to `dest` as selected by
+/// `options`: ownership, extended attributes, ACLs, permissions and
+/// access/modification times. `source` is the metadata of `src`.
+///
+/// Ownership is set first since changing it can clear the setuid and setgid
+/// bits, extended attributes and ACLs before permissions that could make
+/// `dest` read-only, and times last since every other change touches the
+/// ctime.
+#[cfg(unix)]
+pub(crate) fn preserve_metadata(
+    src: &Path,
+    source: &fs::Metadata,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    if options.owner || options.group {
+        fchownat(
+            None,
+            dest,
+            options.owner.then(|| Uid::from_raw(source.uid())),
+            options.group.then(|| Gid::from_raw(source.gid())),
+            FchownatFlags::NoFollowSymlink,
+        )
+        .map_err(|e| format!("Failed to set owner of '{}': {}", dest.display(), e))?;
+    }
+    // Links can't have user attributes or ACLs.
+    if options.xattrs && !source.file_type().is_symlink() {
+        copy_xattrs(src, dest)?;
+    }
+    if options.acls && !source.file_type().is_symlink() {
+        copy_acls(src, dest)?;
+    }
+    if options.preserve {
+        if !source.file_type().is_symlink() {
+            fs::set_permissions(dest, fs::Permissions::from_mode(source.mode()))
+                .map_err(|e| format!("Failed to set permissions of '{}': {}", dest.display(), e))?;
+        }
+        set_times(dest, source.accessed()?, source.modified()?)?;
+    }
+    Ok(())
+}
+
+/// Set the access and modification times of `dest`, or of the link itself
+/// if it is a symbolic link.
+#[cfg(unix)]
+pub(crate) fn set_times(
+    dest: &Path,
+    accessed: SystemTime,
+    modified: SystemTime,
+) -> Result<(), Error> {
+    let spec = |time: SystemTime| match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => TimeSpec::from_duration(since),
+        Err(e) => -TimeSpec::from_duration(e.duration()),
+    };
+    utimensat(
+        None,
+        dest,
+        &spec(accessed),
+        &spec(modified),
+        UtimensatFlags::NoFollowSymlink,
+    )
+    .map_err(|e| format!("Failed to set times of '{}': {}", dest.display(), e).into())
+}
+
+/// Windows has no owner to copy here, and of the permissions only the
+/// read-only flag. Times go first, since a read-only file can't be opened
+/// to set them.
+#[cfg(windows)]
+pub(crate) fn preserve_metadata(
+    src: &Path,
+    source: &fs::Metadata,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    if options.owner || options.group {
+        return Err("Preserving ownership is not supported on Windows".into());
+    }
+    if options.xattrs {
+        copy_xattrs(src, dest)?;
+    }
+    if options.acls {
+        copy_acls(src, dest)?;
+    }
+    if options.preserve {
+        set_times(dest, source.accessed()?, source.modified()?)?;
+        if !source.file_type().is_symlink() {
+            fs::set_permissions(dest, source.permissions())
+                .map_err(|e| format!("Failed to set permissions of '{}': {}", dest.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Set the access and modification times of `dest`, or of the link itself
+/// if it is a symbolic link.
+#[cfg(windows)]
+pub(crate) fn set_times(
+    dest: &Path,
+    accessed: SystemTime,
+    modified: SystemTime,
+) -> Result<(), Error> {
+    use std::fs::{FileTimes, OpenOptions};
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x0100;
+    // Needed to open directories, and to open links rather than their target.
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+
+    let times = FileTimes::new()
+        .set_accessed(accessed)
+        .set_modified(modified);
+    OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT)
+        .open(dest)
+        .and_then(|file| file.set_times(times))
+        .map_err(|e| format!("Failed to set times of '{}': {}", dest.display(), e).into())
+}
@@ -0,0 +1,151 @@
+use crate::stats::CopyStats;
+use crate::Error;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// What a copy, or a set of them, has done so far, as it is exported to
+/// Prometheus by [`serve_metrics`].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    pub bytes_copied: u64,
+    /// The current rate, in bytes per second.
+    pub bytes_per_second: u64,
+    pub files_copied: u64,
+    pub files_skipped: u64,
+    pub files_failed: u64,
+    pub retries: u64,
+    pub verification_failures: u64,
+}
+
+impl Metrics {
+    /// The metrics of the copy given `stats`.
+    pub fn of(stats: &CopyStats) -> Self {
+        Metrics {
+            bytes_copied: stats.bytes_transferred().max(stats.bytes()),
+            bytes_per_second: stats.current_rate(),
+            files_copied: stats.files_copied(),
+            files_skipped: stats.files_skipped(),
+            files_failed: stats.files_failed(),
+            retries: stats.retries(),
+            verification_failures: stats.verification_failures(),
+        }
+    }
+
+    /// The metrics of two sets of copies together.
+    pub fn add(&self, other: &Metrics) -> Self {
+        Metrics {
+            bytes_copied: self.bytes_copied + other.bytes_copied,
+            bytes_per_second: self.bytes_per_second + other.bytes_per_second,
+            files_copied: self.files_copied + other.files_copied,
+            files_skipped: self.files_skipped + other.files_skipped,
+            files_failed: self.files_failed + other.files_failed,
+            retries: self.retries + other.retries,
+            verification_failures: self.verification_failures + other.verification_failures,
+        }
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "rpcp_bytes_copied_total",
+                "counter",
+                "Bytes copied.",
+                self.bytes_copied,
+            ),
+            (
+                "rpcp_bytes_per_second",
+                "gauge",
+                "Bytes copied per second over the last couple of seconds.",
+                self.bytes_per_second,
+            ),
+            (
+                "rpcp_files_copied_total",
+                "counter",
+                "Files copied.",
+                self.files_copied,
+            ),
+            (
+                "rpcp_files_skipped_total",
+                "counter",
+                "Files left alone because they were up to date or were not to be overwritten.",
+                self.files_skipped,
+            ),
+            (
+                "rpcp_files_failed_total",
+                "counter",
+                "Files that failed to copy.",
+                self.files_failed,
+            ),
+            (
+                "rpcp_retries_total",
+                "counter",
+                "Reads, writes and requests that failed and were tried again.",
+                self.retries,
+            ),
+            (
+                "rpcp_verification_failures_total",
+                "counter",
+                "Files whose copy did not match the source.",
+                self.verification_failures,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+        text
+    }
+}
+
+/// Serve the metrics `render` returns, in the Prometheus text format, at
+/// `/metrics` over HTTP on `addr`, such as `127.0.0.1:9184`, from a thread
+/// of its own for as long as the process runs. Returns the address
+/// listened on, whose port is picked when `addr` gives port 0.
+pub fn serve_metrics(
+    addr: &str,
+    render: impl Fn() -> String + Send + Sync + 'static,
+) -> Result<SocketAddr, Error> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| format!("Failed to serve metrics on '{}': {}", addr, e))?;
+    let local = listener.local_addr()?;
+    thread::spawn(move || {
+        // Scrapes are few and quick, so they are answered one at a time.
+        for stream in listener.incoming().flatten() {
+            let _ = answer(stream, &render);
+        }
+    });
+    Ok(local)
+}
+
+/// Answer the HTTP request on `stream`.
+fn answer(mut stream: TcpStream, render: &impl Fn() -> String) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers say nothing that changes the answer.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use crate::copy::ChunkIo;
+use crate::Error;
+use nix::libc;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::ptr::NonNull;
+
+/// A read-only mapping of part of a file, unmapped when dropped.
+struct Mapping {
+    ptr: NonNull<libc::c_void>,
+    len: usize,
+}
+
+impl Mapping {
+    /// Map `range` of `file`, whose start has to be a multiple of the page
+    /// size and which must not be empty.
+    fn new(file: &File, range: Range<u64>) -> io::Result<Self> {
+        let len = (range.end - range.start) as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                range.start as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Only a hint, the copy works the same without it.
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        let ptr = NonNull::new(ptr).ok_or_else(|| io::Error::other("mmap returned null"))?;
+        Ok(Mapping { ptr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // Mapped readable for `len` bytes until dropped.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().cast(), self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr(), self.len) };
+    }
+}
+
+/// Copy `chunk` by mapping it from the source and writing it out of the
+/// mapping `piece_size` bytes at a time, with no read buffer in between.
+///
+/// The chunk is cut short at the current end of the source. A source that
+/// is truncated while mapped kills the process with `SIGBUS`, so this is
+/// for sources nothing else is writing to.
+pub(crate) fn copy_chunk(
+    io: &ChunkIo,
+    chunk: &Range<u64>,
+    piece_size: usize,
+    on_data: &mut dyn FnMut(&[u8]),
+) -> Result<u64, Error> {
+    let Some(infile) = io.infile.as_file() else {
+        return Err("The mmap engine only copies from local files".into());
+    };
+    if io.direct {
+        return Err(
+            "The mmap engine reads through the page cache, it can't be used with direct IO".into(),
+        );
+    }
+    let end = chunk.end.min(io.infile.len()?);
+    if end <= chunk.start {
+        return Ok(chunk.start);
+    }
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let map_start = chunk.start - chunk.start % page_size;
+    let mapping = Mapping::new(infile, map_start..end).map_err(|e| {
+        format!(
+            "Failed to map '{}' at offset {}: {}",
+            io.infile_path.display(),
+            map_start,
+            e
+        )
+    })?;
+    let mut pos = chunk.start;
+    for data in mapping.as_slice()[(chunk.start - map_start) as usize..].chunks(piece_size) {
+        if io.admit(data) {
+            io.write_all_at(data, pos)?;
+        }
+        on_data(data);
+        pos += data.len() as u64;
+    }
+    Ok(pos)
+}
@@ -0,0 +1,154 @@
+#[cfg(target_os = "linux")]
+use crate::priority::parse_cpu_list;
+use crate::Error;
+use std::path::Path;
+
+/// Where the threads copying a file run and their buffers live: on the CPUs
+/// and memory of one NUMA node, so the data does not cross between sockets
+/// on its way to the device on that node.
+#[derive(Clone, Copy)]
+pub(crate) struct Placement {
+    #[cfg(target_os = "linux")]
+    node: usize,
+    #[cfg(target_os = "linux")]
+    cpus: nix::libc::cpu_set_t,
+}
+
+#[cfg(target_os = "linux")]
+impl Placement {
+    /// The placement on `node`, which must exist.
+    pub(crate) fn new(node: usize) -> Result<Self, Error> {
+        use nix::libc;
+
+        let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+        let list = std::fs::read_to_string(&path)
+            .map_err(|_| format!("There is no NUMA node {}", node))?;
+        let list = parse_cpu_list(&list).map_err(|_| {
+            format!(
+                "Failed to read the CPUs of NUMA node {} from {}",
+                node, path
+            )
+        })?;
+        let mut cpus: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let mut any = false;
+        for cpu in list
+            .into_iter()
+            .filter(|&cpu| cpu < libc::CPU_SETSIZE as usize)
+        {
+            unsafe { libc::CPU_SET(cpu, &mut cpus) };
+            any = true;
+        }
+        if !any {
+            return Err(format!("NUMA node {} has no CPUs to run threads on", node).into());
+        }
+        Ok(Placement { node, cpus })
+    }
+
+    /// Run the calling thread on the CPUs of the node only, of those it may
+    /// already run on, as set by `--cpuset` or `taskset`. When it may run on
+    /// none of them, or that is not allowed, it carries on where it was.
+    pub(crate) fn pin(&self) {
+        use nix::libc;
+
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        let mut allowed: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        if unsafe { libc::sched_getaffinity(0, size, &mut allowed) } != 0 {
+            return;
+        }
+        let mut cpus: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let mut any = false;
+        for cpu in 0..libc::CPU_SETSIZE as usize {
+            if unsafe { libc::CPU_ISSET(cpu, &allowed) && libc::CPU_ISSET(cpu, &self.cpus) } {
+                unsafe { libc::CPU_SET(cpu, &mut cpus) };
+                any = true;
+            }
+        }
+        if any {
+            unsafe { libc::sched_setaffinity(0, size, &cpus) };
+        }
+    }
+
+    /// Have the memory of `buffer` come from the node, moving the pages it
+    /// already has there. This is only a preference, memory still comes
+    /// from other nodes when the node has none left.
+    pub(crate) fn bind(&self, buffer: &[u8]) {
+        use nix::libc;
+
+        const MPOL_PREFERRED: libc::c_long = 1;
+        const MPOL_MF_MOVE: libc::c_ulong = 1 << 1;
+        const BITS: usize = libc::c_ulong::BITS as usize;
+
+        let mut mask = vec![0 as libc::c_ulong; self.node / BITS + 1];
+        mask[self.node / BITS] |= 1 << (self.node % BITS);
+        // Buffers start on a page boundary, which mbind needs.
+        unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                buffer.as_ptr(),
+                buffer.len(),
+                MPOL_PREFERRED,
+                mask.as_ptr(),
+                mask.len() * BITS + 1,
+                MPOL_MF_MOVE,
+            )
+        };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Placement {
+    pub(crate) fn new(_node: usize) -> Result<Self, Error> {
+        Err("NUMA placement is only supported on Linux".into())
+    }
+
+    pub(crate) fn pin(&self) {}
+
+    pub(crate) fn bind(&self, _buffer: &[u8]) {}
+}
+
+/// The NUMA nodes of this machine, none where they can't be told.
+pub fn numa_nodes() -> Vec<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::read_to_string("/sys/devices/system/node/online")
+            .ok()
+            .and_then(|list| parse_cpu_list(&list).ok())
+            .unwrap_or_default()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Vec::new()
+    }
+}
+
+/// The NUMA node of the device `path` is stored on, as the kernel reports
+/// it for the disk's controller, or `None` when that can't be told, as for
+/// network filesystems or machines with a single node.
+pub fn numa_node_of(path: &Path) -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = path.ancestors().find(|path| path.exists())?;
+        let dev = std::fs::metadata(path).ok()?.dev();
+        let device = format!(
+            "/sys/dev/block/{}:{}",
+            nix::sys::stat::major(dev),
+            nix::sys::stat::minor(dev)
+        );
+        // Partitions have no device of their own, the disk they are on does.
+        [
+            format!("{}/device/numa_node", device),
+            format!("{}/../device/numa_node", device),
+        ]
+        .iter()
+        .find_map(|file| std::fs::read_to_string(file).ok())
+        // Nodes are -1 when there is only one.
+        .and_then(|node| node.trim().parse().ok())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = path;
+        None
+    }
+}
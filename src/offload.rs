@@ -0,0 +1,131 @@
+#[cfg(target_os = "linux")]
+use nix::{errno::Errno, libc};
+use std::fs::{self, File};
+use std::io;
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Whether two files are on the same filesystem, which is when the kernel
+/// might copy between them by itself.
+pub(crate) fn same_filesystem(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        a.dev() == b.dev()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        false
+    }
+}
+
+/// Have the filesystem copy `ranges` of `infile` to `outfile` without the
+/// data passing through rpcp. `whole_file` is set when everything is to be
+/// copied rather than what is left of a resumed copy.
+///
+/// When `whole_file` is set the destination is first cloned with `FICLONE`,
+/// sharing the source's extents on filesystems with reflinks such as btrfs
+/// and XFS. Otherwise, or when that is not supported, each range is copied
+/// with `copy_file_range`, which the kernel can still do without reading
+/// the data into userspace.
+///
+/// Returns `false` when the filesystem cannot do it. Anything written by
+/// then is written again by the chunked copy, so it can simply carry on.
+#[cfg(target_os = "linux")]
+pub(crate) fn offload_copy(
+    infile: &File,
+    outfile: &File,
+    _paths: (&Path, &Path),
+    ranges: &[Range<u64>],
+    whole_file: bool,
+) -> io::Result<bool> {
+    if whole_file {
+        let result = unsafe { libc::ioctl(outfile.as_raw_fd(), libc::FICLONE, infile.as_raw_fd()) };
+        if result == 0 {
+            return Ok(true);
+        }
+    }
+
+    for range in ranges {
+        let (mut off_in, mut off_out) = (range.start as i64, range.start as i64);
+        while (off_in as u64) < range.end {
+            let len = (range.end - off_in as u64).min(isize::MAX as u64) as usize;
+            let copied = unsafe {
+                libc::copy_file_range(
+                    infile.as_raw_fd(),
+                    &mut off_in,
+                    outfile.as_raw_fd(),
+                    &mut off_out,
+                    len,
+                    0,
+                )
+            };
+            match Errno::result(copied) {
+                // The source got shorter, the chunked copy does the same.
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(Errno::EINTR) => {}
+                Err(
+                    Errno::EXDEV
+                    | Errno::EINVAL
+                    | Errno::EOPNOTSUPP
+                    | Errno::ENOSYS
+                    | Errno::EBADF
+                    | Errno::EPERM,
+                ) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// On APFS the whole file is cloned with `clonefile`. It only creates new
+/// files, so the clone is made next to the destination and renamed over it.
+#[cfg(target_os = "macos")]
+pub(crate) fn offload_copy(
+    _infile: &File,
+    _outfile: &File,
+    (infile_path, outfile_path): (&Path, &Path),
+    _ranges: &[Range<u64>],
+    whole_file: bool,
+) -> io::Result<bool> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    if !whole_file {
+        return Ok(false);
+    }
+    let mut clone_path = outfile_path.as_os_str().to_owned();
+    clone_path.push(".rpcp-clone");
+    let c_path = |path: &std::ffi::OsStr| {
+        CString::new(path.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (src, clone) = (c_path(infile_path.as_os_str())?, c_path(&clone_path)?);
+    // Left behind by an earlier run that was killed.
+    let _ = fs::remove_file(&clone_path);
+    if unsafe { libc::clonefile(src.as_ptr(), clone.as_ptr(), 0) } != 0 {
+        // Not APFS, or not the same volume.
+        return Ok(false);
+    }
+    if let Err(e) = fs::rename(&clone_path, outfile_path) {
+        let _ = fs::remove_file(&clone_path);
+        return Err(e);
+    }
+    Ok(true)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn offload_copy(
+    _infile: &File,
+    _outfile: &File,
+    _paths: (&Path, &Path),
+    _ranges: &[Range<u64>],
+    _whole_file: bool,
+) -> io::Result<bool> {
+    Ok(false)
+}
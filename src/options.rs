@@ -0,0 +1,915 @@
+use crate::control::Control;
+use crate::encrypt::Recipients;
+use crate::filter::Filter;
+use crate::hash::HashAlgorithm;
+use crate::limit::RateLimiter;
+use crate::manifest::Manifest;
+use crate::pool::BufferPool;
+use crate::schedule::BwSchedule;
+use crate::sparse::Sparse;
+use crate::stats::CopyStats;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How recursive copies treat symbolic links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Copy what links point to, descending into linked directories.
+    Follow,
+    /// Recreate links as links pointing to the same target.
+    Recreate,
+}
+
+/// What happens to destinations that already exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Replace them.
+    Always,
+    /// Leave them alone.
+    Never,
+    /// Replace them only when the source was modified more recently.
+    Update,
+    /// Ask on the terminal before replacing each of them. Dry runs don't
+    /// ask, they list them as replaced.
+    Ask,
+}
+
+/// Whether copies are flushed to the device before they count as done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// Leave writing them out to the operating system.
+    Off,
+    /// Flush the data of each file with `fdatasync`, leaving out metadata
+    /// such as times that is not needed to read it back.
+    Data,
+    /// Flush each file with `fsync`, its metadata too.
+    All,
+}
+
+/// How copy threads move data between the files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// One blocking `pread` and `pwrite` at a time per thread.
+    Pread,
+    /// Batches of reads and writes submitted through io_uring, each thread
+    /// with its own ring and registered buffers. Needs the `io-uring` cargo
+    /// feature and Linux 5.1 or later.
+    IoUring,
+    /// Writes straight out of a memory mapping of the source, without a
+    /// read buffer. Only on Unix, and not with direct IO.
+    Mmap,
+    /// Copies each chunk inside the kernel with `copy_file_range`, or
+    /// `splice` through a pipe where that does not work, so the data never
+    /// reaches rpcp. Only on Linux, and not with direct IO.
+    Sendfile,
+    /// Reads and writes a batch of buffers per call with `preadv2` and
+    /// `pwritev2`, which saves calls with small buffers. Reads first try
+    /// `RWF_NOWAIT` to take what is in the page cache. Only on Linux.
+    Vectored,
+    /// Separate pools of threads reading chunks into a fixed number of
+    /// buffers and writing them out, for storage much faster at one than
+    /// the other. The pools are sized with
+    /// [`read_threads`](CopyOptions::read_threads) and
+    /// [`write_threads`](CopyOptions::write_threads).
+    Pipeline,
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Engine::Pread => "pread",
+            Engine::IoUring => "io_uring",
+            Engine::Mmap => "mmap",
+            Engine::Sendfile => "sendfile",
+            Engine::Vectored => "preadv",
+            Engine::Pipeline => "pipeline",
+        };
+        f.pad(name)
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pread" => Ok(Engine::Pread),
+            "io_uring" | "io-uring" | "uring" => Ok(Engine::IoUring),
+            "mmap" => Ok(Engine::Mmap),
+            "sendfile" | "splice" => Ok(Engine::Sendfile),
+            "preadv" | "pwritev" | "vectored" => Ok(Engine::Vectored),
+            "pipeline" => Ok(Engine::Pipeline),
+            _ => Err(format!(
+                "unknown engine '{}', expected one of pread, io_uring, mmap, sendfile, preadv, pipeline",
+                s
+            )),
+        }
+    }
+}
+
+/// The order the files of recursive copies are started in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOrder {
+    /// The order the tree is walked in.
+    Walk,
+    /// By path.
+    Name,
+    /// Smallest first.
+    Size,
+    /// Largest first, so the biggest files don't start last and leave one
+    /// thread copying them long after the others are done.
+    LargestFirst,
+    /// By inode number, which on many filesystems is close to where the
+    /// data is on disk. Walk order where there are no inode numbers.
+    Inode,
+}
+
+impl fmt::Display for FileOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FileOrder::Walk => "walk",
+            FileOrder::Name => "name",
+            FileOrder::Size => "size",
+            FileOrder::LargestFirst => "largest-first",
+            FileOrder::Inode => "inode",
+        };
+        f.pad(name)
+    }
+}
+
+impl FromStr for FileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "walk" | "none" => Ok(FileOrder::Walk),
+            "name" => Ok(FileOrder::Name),
+            "size" | "smallest-first" => Ok(FileOrder::Size),
+            "largest-first" => Ok(FileOrder::LargestFirst),
+            "inode" => Ok(FileOrder::Inode),
+            _ => Err(format!(
+                "unknown order '{}', expected one of walk, name, size, largest-first, inode",
+                s
+            )),
+        }
+    }
+}
+
+/// Settings shared by [`copy_file`](crate::copy_file),
+/// [`copy_tree`](crate::copy_tree) and [`verify_copy`](crate::verify_copy).
+///
+/// Built with chained setters starting from [`CopyOptions::new`]:
+/// `CopyOptions::new().threads(32).verify(true)`.
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    pub(crate) threads: usize,
+    pub(crate) adaptive: bool,
+    pub(crate) read_threads: Option<usize>,
+    pub(crate) write_threads: Option<usize>,
+    pub(crate) verify: bool,
+    pub(crate) verify_hash: Option<HashAlgorithm>,
+    pub(crate) encrypt: Option<Recipients>,
+    pub(crate) buffer_size: usize,
+    pub(crate) progress: bool,
+    pub(crate) resume: bool,
+    pub(crate) skip: u64,
+    pub(crate) seek: u64,
+    pub(crate) count: Option<u64>,
+    pub(crate) split: Option<u64>,
+    pub(crate) delta: bool,
+    pub(crate) follow: Option<Duration>,
+    pub(crate) fail_on_change: bool,
+    pub(crate) preserve: bool,
+    pub(crate) owner: bool,
+    pub(crate) group: bool,
+    pub(crate) xattrs: bool,
+    pub(crate) acls: bool,
+    pub(crate) specials: bool,
+    pub(crate) devices: bool,
+    pub(crate) hard_links: bool,
+    pub(crate) link_dest: Option<PathBuf>,
+    pub(crate) reflink_dest: Option<PathBuf>,
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) order: FileOrder,
+    pub(crate) walk_threads: usize,
+    pub(crate) batch: usize,
+    pub(crate) one_file_system: bool,
+    pub(crate) symlinks: Symlinks,
+    pub(crate) safe_links: bool,
+    pub(crate) sparse: Sparse,
+    pub(crate) filter: Filter,
+    pub(crate) dry_run: bool,
+    pub(crate) bwlimit: Option<Arc<RateLimiter>>,
+    pub(crate) buffer_pool: Option<Arc<BufferPool>>,
+    pub(crate) numa_node: Option<usize>,
+    pub(crate) engine: Engine,
+    pub(crate) direct: bool,
+    pub(crate) preallocate: bool,
+    pub(crate) space_check: bool,
+    pub(crate) drop_cache: bool,
+    pub(crate) writeback: bool,
+    pub(crate) retries: u32,
+    pub(crate) retry_delay: Duration,
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+    pub(crate) keep_partial: bool,
+    pub(crate) atomic: bool,
+    pub(crate) inplace: bool,
+    pub(crate) part_suffix: Option<String>,
+    pub(crate) fsync: Fsync,
+    pub(crate) overwrite: Overwrite,
+    pub(crate) skip_identical: bool,
+    pub(crate) checksum: bool,
+    pub(crate) delete: bool,
+    pub(crate) max_delete: Option<usize>,
+    pub(crate) remove_source: bool,
+    pub(crate) json: bool,
+    pub(crate) verbosity: u8,
+    pub(crate) itemize: bool,
+    pub(crate) stats: Option<Arc<CopyStats>>,
+    pub(crate) manifest: Option<Arc<Manifest>>,
+    pub(crate) continue_on_error: bool,
+    pub(crate) max_errors: Option<usize>,
+    pub(crate) backup: Option<String>,
+    pub(crate) backup_dir: Option<PathBuf>,
+    /// The destination of the recursive copy a file is part of, which
+    /// backups in [`backup_dir`](CopyOptions::backup_dir) are relative to.
+    pub(crate) backup_root: Option<PathBuf>,
+    /// Counts the bytes of a file in place, for the progress of the
+    /// recursive copy it is part of.
+    pub(crate) progress_counter: Option<Arc<AtomicU64>>,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            threads: 10,
+            adaptive: false,
+            read_threads: None,
+            write_threads: None,
+            verify: false,
+            verify_hash: None,
+            encrypt: None,
+            buffer_size: 1024 * 1024, // 1Mb
+            progress: false,
+            resume: false,
+            skip: 0,
+            seek: 0,
+            count: None,
+            split: None,
+            delta: false,
+            follow: None,
+            fail_on_change: false,
+            preserve: false,
+            owner: false,
+            group: false,
+            xattrs: false,
+            acls: false,
+            specials: false,
+            devices: false,
+            hard_links: false,
+            link_dest: None,
+            reflink_dest: None,
+            max_depth: None,
+            order: FileOrder::Walk,
+            walk_threads: 1,
+            batch: 1,
+            one_file_system: false,
+            symlinks: Symlinks::Follow,
+            safe_links: false,
+            sparse: Sparse::Auto,
+            filter: Filter::new(),
+            dry_run: false,
+            bwlimit: None,
+            buffer_pool: None,
+            numa_node: None,
+            engine: Engine::Pread,
+            direct: false,
+            preallocate: true,
+            space_check: true,
+            drop_cache: false,
+            writeback: false,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            cancel: None,
+            keep_partial: false,
+            atomic: false,
+            inplace: false,
+            part_suffix: None,
+            fsync: Fsync::Off,
+            overwrite: Overwrite::Always,
+            skip_identical: false,
+            checksum: false,
+            delete: false,
+            max_delete: None,
+            remove_source: false,
+            json: false,
+            verbosity: 0,
+            itemize: false,
+            stats: None,
+            manifest: None,
+            continue_on_error: false,
+            max_errors: None,
+            backup: None,
+            backup_dir: None,
+            backup_root: None,
+            progress_counter: None,
+        }
+    }
+}
+
+impl CopyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of threads each file is copied with. Files under 1Mb always
+    /// use a single thread.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Change the number of threads copying each file while it is copied,
+    /// up to [`threads`](CopyOptions::threads), to the number that copies
+    /// fastest. The rate is measured every second, and threads are added
+    /// while they make it faster and taken away when they make it slower.
+    pub fn adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Number of threads reading each file with [`Engine::Pipeline`].
+    /// Defaults to [`threads`](CopyOptions::threads).
+    pub fn read_threads(mut self, threads: Option<usize>) -> Self {
+        self.read_threads = threads.map(|threads| threads.max(1));
+        self
+    }
+
+    /// Number of threads writing each file with [`Engine::Pipeline`].
+    /// Defaults to [`threads`](CopyOptions::threads).
+    pub fn write_threads(mut self, threads: Option<usize>) -> Self {
+        self.write_threads = threads.map(|threads| threads.max(1));
+        self
+    }
+
+    /// Compare source and destination after each file is copied.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Verify by comparing digests computed with `algorithm` instead of
+    /// comparing the files byte by byte.
+    pub fn verify_hash(mut self, algorithm: Option<HashAlgorithm>) -> Self {
+        self.verify_hash = algorithm;
+        self
+    }
+
+    /// Write every file copied encrypted to `recipients`, as an age file
+    /// that `age --decrypt` reads with the identity of one of them. Names
+    /// are kept, copies are always made by the threads rather than offloaded,
+    /// and [`verify`](Self::verify) decrypts them again with the key they
+    /// were encrypted with to check them.
+    pub fn encrypt(mut self, recipients: Recipients) -> Self {
+        self.encrypt = Some(recipients);
+        self
+    }
+
+    /// Size of the buffer each copy thread reads into, and of the reads made
+    /// to verify copies. Defaults to 1Mb.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    /// Print per-file progress to stderr.
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Keep a checkpoint next to each destination so an interrupted copy can
+    /// be resumed by running it again.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Start reading the source `bytes` in, like `dd skip=`.
+    pub fn skip(mut self, bytes: u64) -> Self {
+        self.skip = bytes;
+        self
+    }
+
+    /// Start writing the destination `bytes` in, like `dd seek=`. Files
+    /// keep the data before it, and end where the copy does.
+    pub fn seek(mut self, bytes: u64) -> Self {
+        self.seek = bytes;
+        self
+    }
+
+    /// Copy no more than `bytes` of the source, like `dd count=`.
+    pub fn count(mut self, bytes: Option<u64>) -> Self {
+        self.count = bytes;
+        self
+    }
+
+    /// Write files larger than `part_size` as parts of that size named
+    /// after the destination, `file.part0000`, `file.part0001` and so on,
+    /// each copied by the threads as a file of its own, and their digests
+    /// to `file.parts.b3`. [`join_parts`] puts them back together.
+    ///
+    /// [`join_parts`]: crate::join_parts
+    pub fn split(mut self, part_size: Option<u64>) -> Self {
+        self.split = part_size.filter(|&size| size > 0);
+        self
+    }
+
+    /// Bring files that already exist at the destination up to date in
+    /// place, comparing them with the source block by block and writing
+    /// only the blocks that differ, for large files that change a little
+    /// between copies, such as VM images. Copies to a temporary file, with
+    /// [`atomic`](Self::atomic) or [`part_suffix`](Self::part_suffix), and
+    /// files that are backed up first are copied whole.
+    pub fn delta(mut self, delta: bool) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    /// Keep copying what is appended to a file after it was first copied,
+    /// such as a log being written, until it has not grown for `quiet`, and
+    /// only then verify it and carry over its metadata.
+    pub fn follow(mut self, quiet: Option<Duration>) -> Self {
+        self.follow = quiet;
+        self
+    }
+
+    /// Fail a file whose size or modification time changed while it was
+    /// copied, instead of only warning that the copy may be torn.
+    pub fn fail_on_change(mut self, fail_on_change: bool) -> Self {
+        self.fail_on_change = fail_on_change;
+        self
+    }
+
+    /// Copy permissions and access/modification times onto the files and
+    /// directories that are created.
+    pub fn preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Copy the owner and group onto the files and directories that are
+    /// created. This usually needs root.
+    pub fn preserve_ownership(self, preserve_ownership: bool) -> Self {
+        self.owner(preserve_ownership).group(preserve_ownership)
+    }
+
+    /// Copy the owner onto the files and directories that are created,
+    /// by user id. This needs root.
+    pub fn owner(mut self, owner: bool) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    /// Copy the group onto the files and directories that are created, by
+    /// group id. Without root the group has to be one the user is in.
+    pub fn group(mut self, group: bool) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// Copy the `user` and `trusted` extended attributes onto the files and
+    /// directories that are created. Only supported on Linux, and `trusted`
+    /// attributes need root.
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Copy the POSIX ACLs onto the files and directories that are created.
+    /// They are left out with a warning where the destination filesystem
+    /// has none. Only supported on Linux.
+    pub fn acls(mut self, acls: bool) -> Self {
+        self.acls = acls;
+        self
+    }
+
+    /// Recreate the FIFOs and sockets of recursive copies. Without it they
+    /// are skipped with a warning.
+    pub fn specials(mut self, specials: bool) -> Self {
+        self.specials = specials;
+        self
+    }
+
+    /// Recreate the character and block devices of recursive copies, which
+    /// needs root. Without it they are skipped with a warning.
+    pub fn devices(mut self, devices: bool) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// Copy files of recursive copies that are hard links to one another
+    /// once, and link them the same way in the destination, instead of
+    /// copying each of them. Not supported on Windows.
+    pub fn hard_links(mut self, hard_links: bool) -> Self {
+        self.hard_links = hard_links;
+        self
+    }
+
+    /// Hard link files of recursive copies from the tree `previous`, an
+    /// earlier copy of the same source, when the file at the same relative
+    /// path there is unchanged, instead of copying them. Unchanged means
+    /// as for [`skip_identical`](CopyOptions::skip_identical), with the same
+    /// permissions. `previous` has to be on the filesystem of the destination.
+    pub fn link_dest(mut self, previous: Option<PathBuf>) -> Self {
+        self.link_dest = previous;
+        self
+    }
+
+    /// Like [`link_dest`](CopyOptions::link_dest), but clone unchanged
+    /// files from `previous` with a reflink instead of linking them, so
+    /// they share their data with the earlier copy while each can be
+    /// changed on its own. They get the metadata of the source. Files the
+    /// filesystem can't clone, as on ones without reflinks, are copied.
+    pub fn reflink_dest(mut self, previous: Option<PathBuf>) -> Self {
+        self.reflink_dest = previous;
+        self
+    }
+
+    /// Whether recursive copies follow symbolic links or recreate them.
+    /// Links are followed by default.
+    pub fn symlinks(mut self, symlinks: Symlinks) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Skip symbolic links that point outside the source tree in recursive
+    /// copies.
+    pub fn safe_links(mut self, safe_links: bool) -> Self {
+        self.safe_links = safe_links;
+        self
+    }
+
+    /// When to leave holes in the destination instead of writing zeros.
+    /// Defaults to [`Sparse::Auto`].
+    pub fn sparse(mut self, sparse: Sparse) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Include and exclude rules for the entries of recursive copies.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Descend at most `max_depth` levels below the source of recursive
+    /// copies, 1 being the entries directly in it. Deeper entries are left
+    /// out.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// The order the files of recursive copies are handed to the threads
+    /// in. Defaults to [`FileOrder::Walk`].
+    pub fn order(mut self, order: FileOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Read the directories of recursive copies with `threads` threads at
+    /// a time, for trees with so many entries that walking them one
+    /// directory after another takes longer than copying them. With more
+    /// than one, directories still come before the entries in them, but
+    /// the files are otherwise found in no particular order, and they are
+    /// copied as they are found rather than once the whole tree is walked,
+    /// unless [`order`](CopyOptions::order) sorts them,
+    /// [`Overwrite::Ask`] asks about them or it is a dry run. Defaults to 1.
+    /// Only on Unix, elsewhere trees are walked by one thread.
+    pub fn walk_threads(mut self, threads: usize) -> Self {
+        self.walk_threads = threads.max(1);
+        self
+    }
+
+    /// Hand the files under 1Mb of recursive copies to the threads up to
+    /// `files` at a time, and copy each by reading it whole and writing it
+    /// out at once, for trees of many tiny files whose copies are mostly
+    /// opening, creating and closing them. Defaults to 1, one file at a
+    /// time through the engine. Files are still copied one at a time with
+    /// [`verify`](Self::verify), [`resume`](Self::resume),
+    /// [`atomic`](Self::atomic), backups and the other options that need
+    /// the whole machinery of [`copy_file`](crate::copy_file).
+    pub fn batch(mut self, files: usize) -> Self {
+        self.batch = files.max(1);
+        self
+    }
+
+    /// Stay on the filesystem of the source of recursive copies. Directories
+    /// other filesystems are mounted on are created empty.
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    /// Print what would be copied to stdout without writing anything.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Limit the combined write throughput of all copy threads to
+    /// `bytes_per_second`. Clones of these options share the same limit, so
+    /// files copied at the same time share it too.
+    pub fn bwlimit(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.bwlimit = bytes_per_second
+            .filter(|&rate| rate > 0)
+            .map(|rate| Arc::new(RateLimiter::new(rate)));
+        self
+    }
+
+    /// Change the limit of [`bwlimit`](Self::bwlimit), which is set first,
+    /// with the time of day as `schedule` says while the copy runs. The
+    /// limit is looked up again every few seconds, and outside the windows
+    /// of the schedule the one of `bwlimit` applies.
+    pub fn bwlimit_schedule(mut self, schedule: Option<BwSchedule>) -> Self {
+        if let Some(schedule) = schedule {
+            let limiter = self
+                .bwlimit
+                .get_or_insert_with(|| Arc::new(RateLimiter::new(0)));
+            limiter.set_schedule(Some(schedule));
+        }
+        self
+    }
+
+    /// Let `control` pause, resume and throttle the copy while it runs. This
+    /// takes the place of [`bwlimit`](Self::bwlimit), whose limit is set with
+    /// [`Control::set_bwlimit`] instead, and like it keeps the kernel from
+    /// copying data itself so every write can be held.
+    pub fn control(mut self, control: &Control) -> Self {
+        self.bwlimit = Some(control.limiter.clone());
+        self
+    }
+
+    /// Cap the memory of the buffers of all copy and verification threads at
+    /// `bytes`. Threads that would go over it are not started, so files are
+    /// copied with fewer threads, never with less than one. Clones of these
+    /// options share the same memory, so files copied at the same time
+    /// share it too.
+    pub fn max_memory(mut self, bytes: Option<u64>) -> Self {
+        self.buffer_pool = bytes.map(|bytes| Arc::new(BufferPool::new(bytes)));
+        self
+    }
+
+    /// Run the threads copying and verifying each file on the CPUs of NUMA
+    /// `node`, with their buffers in its memory, as for the node the disk
+    /// controller or network card is attached to. See
+    /// [`numa_node_of`](crate::numa_node_of). Only supported on Linux.
+    pub fn numa_node(mut self, node: Option<usize>) -> Self {
+        self.numa_node = node;
+        self
+    }
+
+    /// How data is moved between the files. Defaults to [`Engine::Pread`].
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Open sources and destinations with `O_DIRECT`, bypassing the page
+    /// cache. Buffers and chunks are aligned to 4Kb, and the filesystem
+    /// has to support it.
+    pub fn direct(mut self, direct: bool) -> Self {
+        self.direct = direct;
+        self
+    }
+
+    /// Allocate the space destinations need with `fallocate` before writing
+    /// to them, so threads writing at scattered offsets do not fragment
+    /// them. On by default.
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// Check that the destination's filesystem has room for the data before
+    /// any of it is copied, failing at once rather than running out of
+    /// space part way. On by default.
+    pub fn space_check(mut self, space_check: bool) -> Self {
+        self.space_check = space_check;
+        self
+    }
+
+    /// Drop each chunk of the source and destination from the page cache
+    /// once it is copied, so a large copy does not push out the cache of
+    /// everything else running. Destination chunks are written out first.
+    pub fn drop_cache(mut self, drop_cache: bool) -> Self {
+        self.drop_cache = drop_cache;
+        self
+    }
+
+    /// Write out each chunk of the destination while the next one is being
+    /// copied, and drop it from the page cache once it is written, so a
+    /// large copy keeps at most two chunks per thread of dirty pages rather
+    /// than piling them up until the system stalls writing them all back.
+    /// Only Linux supports it, others ignore it.
+    pub fn writeback(mut self, writeback: bool) -> Self {
+        self.writeback = writeback;
+        self
+    }
+
+    /// Retry reads and writes failing with errors that may clear up, such
+    /// as `EIO` or `ESTALE` on a flaky network mount, this many times
+    /// before the file fails. No retries by default.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry, doubled for each one after. Defaults
+    /// to one second.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Stop copying once `flag` is set, for example from a signal handler.
+    /// The copy then fails with a [`CancelledError`](crate::CancelledError).
+    pub fn cancel(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    /// Count the files copied, skipped and failed, the directories created
+    /// and the peak rate in `stats`.
+    pub fn stats(mut self, stats: Arc<CopyStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Add the digest of every file copied to `manifest`, taken as its data
+    /// is copied, to write out once the copy is done.
+    pub fn manifest(mut self, manifest: Arc<Manifest>) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    /// Carry on with the rest of a recursive copy when a file or directory
+    /// can't be copied, and fail at the end with a
+    /// [`PartialCopyError`](crate::PartialCopyError) listing what wasn't.
+    /// Nothing is deleted with [`delete`](CopyOptions::delete) after a
+    /// failure.
+    pub fn continue_on_error(mut self, continue_on_error: bool) -> Self {
+        self.continue_on_error = continue_on_error;
+        self
+    }
+
+    /// With [`continue_on_error`](CopyOptions::continue_on_error), stop
+    /// starting files once more than `max_errors` have failed.
+    pub fn max_errors(mut self, max_errors: Option<usize>) -> Self {
+        self.max_errors = max_errors;
+        self
+    }
+
+    /// Move each destination file about to be replaced, or deleted with
+    /// [`delete`](CopyOptions::delete), aside to its path with `suffix`
+    /// added, such as `~`, replacing the backup of an earlier copy.
+    pub fn backup(mut self, suffix: Option<String>) -> Self {
+        self.backup = suffix;
+        self
+    }
+
+    /// Move destination files about to be replaced or deleted into `dir`
+    /// instead, under their path relative to the destination of the copy,
+    /// with the [`backup`](CopyOptions::backup) suffix if there is one.
+    pub fn backup_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.backup_dir = dir;
+        self
+    }
+
+    /// Keep a destination the copy was cancelled part way through as
+    /// `<dest>.partial` instead of removing it. Destinations with a
+    /// [`resume`](CopyOptions::resume) checkpoint are always kept in place.
+    pub fn keep_partial(mut self, keep_partial: bool) -> Self {
+        self.keep_partial = keep_partial;
+        self
+    }
+
+    /// Write each file to a temporary file next to its destination and only
+    /// rename it into place once it is complete, so nothing ever sees a
+    /// half-copied destination.
+    pub fn atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Rewrite files that already exist at the destination where they are,
+    /// instead of copying to a temporary file next to them and renaming it
+    /// over them once it is complete, which is the default. In place keeps
+    /// hard links to the destination and needs no room for a second copy,
+    /// but a copy that fails part way leaves the old file half overwritten.
+    /// Block devices, [`delta`](Self::delta) copies, [`resume`](Self::resume)
+    /// and copies to an offset are always in place.
+    pub fn inplace(mut self, inplace: bool) -> Self {
+        self.inplace = inplace;
+        self
+    }
+
+    /// Write each file as its destination with `suffix` added, such as
+    /// `.rpcp-part`, and rename it into place once it is complete, so what
+    /// is still being copied can be told apart from what is done.
+    pub fn part_suffix(mut self, suffix: Option<String>) -> Self {
+        self.part_suffix = suffix;
+        self
+    }
+
+    /// Flush each file and the directory it is in to the device before it
+    /// counts as copied, so a copy that is done survives a power cut. The
+    /// directories of recursive copies are flushed once all their files
+    /// are. Off by default.
+    pub fn fsync(mut self, fsync: Fsync) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Whether existing destinations are replaced. Defaults to
+    /// [`Overwrite::Always`].
+    pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Leave out files of recursive copies whose destination already has
+    /// the same size and modification time, so copying a tree again only
+    /// copies what changed. Times only match when they were preserved.
+    pub fn skip_identical(mut self, skip_identical: bool) -> Self {
+        self.skip_identical = skip_identical;
+        self
+    }
+
+    /// With [`skip_identical`](CopyOptions::skip_identical), compare the
+    /// digests of files of the same size instead of their modification
+    /// times.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Remove entries of the destination of recursive copies that are not
+    /// in the source, making the destination a mirror of it. Entries left
+    /// out with [`filter`](CopyOptions::filter) are kept.
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    /// With [`delete`](CopyOptions::delete), fail rather than remove more
+    /// than `max_delete` entries.
+    pub fn max_delete(mut self, max_delete: Option<usize>) -> Self {
+        self.max_delete = max_delete;
+        self
+    }
+
+    /// Move rather than copy: remove each source file once it has been
+    /// copied and, with [`verify`](CopyOptions::verify), verified, and the
+    /// source directories of recursive copies once they are empty.
+    pub fn remove_source(mut self, remove_source: bool) -> Self {
+        self.remove_source = remove_source;
+        self
+    }
+
+    /// Write newline-delimited JSON events to stdout as files are started,
+    /// skipped, copied and verified, and progress events in place of the
+    /// bar that [`progress`](CopyOptions::progress) draws.
+    pub fn json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    /// How much to print to stderr beyond the progress: at 1 every file
+    /// once it is copied, with its size and how long it took, and at 2 also
+    /// the files skipped as up to date and the directories and links
+    /// created by recursive copies.
+    pub fn verbosity(mut self, verbosity: u8) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Print a line to stdout for every entry of a recursive copy saying
+    /// what was done to it, created, updated, left identical, given new
+    /// metadata, skipped, linked, failed or deleted, and why.
+    pub fn itemize(mut self, itemize: bool) -> Self {
+        self.itemize = itemize;
+        self
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::SeqCst))
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// A file or directory that could not be copied with
+/// [`CopyOptions::continue_on_error`](crate::CopyOptions::continue_on_error).
+#[derive(Debug)]
+pub struct CopyFailure {
+    /// Path of the source.
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Returned by recursive copies made with
+/// [`CopyOptions::continue_on_error`](crate::CopyOptions::continue_on_error)
+/// when some of the files could not be copied. Everything else was.
+#[derive(Debug)]
+pub struct PartialCopyError {
+    failures: Vec<CopyFailure>,
+    /// The limit that made the copy give up early, if it did.
+    max_errors: Option<usize>,
+}
+
+impl PartialCopyError {
+    pub(crate) fn new(failures: Vec<CopyFailure>, max_errors: Option<usize>) -> Self {
+        PartialCopyError {
+            failures,
+            max_errors,
+        }
+    }
+
+    /// What could not be copied, in the order it failed.
+    pub fn failures(&self) -> &[CopyFailure] {
+        &self.failures
+    }
+
+    /// Whether the copy stopped early after more failures than
+    /// [`CopyOptions::max_errors`](crate::CopyOptions::max_errors).
+    pub fn gave_up(&self) -> bool {
+        self.max_errors.is_some()
+    }
+}
+
+impl fmt::Display for PartialCopyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.max_errors {
+            Some(max_errors) => write!(
+                f,
+                "Gave up after {} entries could not be copied, more than the limit of {}",
+                self.failures.len(),
+                max_errors
+            ),
+            None => write!(f, "{} entries could not be copied", self.failures.len()),
+        }
+    }
+}
+
+impl std::error::Error for PartialCopyError {}
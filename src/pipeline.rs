@@ -0,0 +1,283 @@
+use crate::cache::drop_cached;
+use crate::checkpoint::Checkpoint;
+use crate::copy::ChunkIo;
+use crate::hash::HashAlgorithm;
+use crate::manifest::FileDigest;
+use crate::numa::Placement;
+use crate::pool::{take_buffers, BufferPool, PooledBuffer};
+use crate::verify::ChunkDigest;
+use crate::Error;
+use std::collections::VecDeque;
+use std::iter;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// How many buffers each reader and writer thread adds to the pipeline.
+/// Readers wait for a free one once they are all full, so this bounds both
+/// the memory used and how far reads get ahead of writes.
+pub(crate) const BUFFERS_PER_THREAD: usize = 2;
+
+/// A queue threads wait on until there is something in it or it is closed.
+pub(crate) struct Queue<T> {
+    items: Mutex<(VecDeque<T>, bool)>,
+    changed: Condvar,
+}
+
+impl<T> Queue<T> {
+    pub(crate) fn new(items: impl IntoIterator<Item = T>) -> Self {
+        Queue {
+            items: Mutex::new((items.into_iter().collect(), false)),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn push(&self, item: T) {
+        self.items.lock().unwrap().0.push_back(item);
+        self.changed.notify_one();
+    }
+
+    /// The next item, or `None` once the queue is closed and empty.
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut items = self.items.lock().unwrap();
+        loop {
+            if let Some(item) = items.0.pop_front() {
+                return Some(item);
+            }
+            if items.1 {
+                return None;
+            }
+            items = self.changed.wait(items).unwrap();
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        self.items.lock().unwrap().1 = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Data read from the source, waiting to be written at `offset`.
+struct Piece {
+    chunk: usize,
+    offset: u64,
+    len: usize,
+    buffer: PooledBuffer,
+}
+
+/// How far a chunk has got. It is done once it has been read and every
+/// piece read from it written.
+#[derive(Default)]
+struct ChunkState {
+    /// Set once the chunk is read: how many pieces it was read in, where
+    /// the reads stopped and the digest of the data for the checkpoint.
+    read: Option<(usize, u64, String)>,
+    written: usize,
+}
+
+/// The settings of a copy made by separate pools of reader and writer
+/// threads, for storage that is much faster at one than the other.
+pub(crate) struct Pipeline {
+    pub(crate) io: ChunkIo,
+    pub(crate) chunks: Arc<Vec<Range<u64>>>,
+    pub(crate) buffer_size: usize,
+    pub(crate) algorithm: Option<HashAlgorithm>,
+    pub(crate) checkpoint: Option<Arc<Checkpoint>>,
+    pub(crate) processed_bytes: Arc<AtomicU64>,
+    pub(crate) abort: Arc<AtomicBool>,
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+    pub(crate) drop_cache: bool,
+    pub(crate) buffer_pool: Option<Arc<BufferPool>>,
+    pub(crate) placement: Option<Placement>,
+    pub(crate) file_digest: Option<Arc<FileDigest>>,
+}
+
+struct Shared {
+    pipeline: Pipeline,
+    next_chunk: AtomicUsize,
+    states: Vec<Mutex<ChunkState>>,
+    pieces: Queue<Piece>,
+    free: Queue<PooledBuffer>,
+    readers_left: AtomicUsize,
+}
+
+impl Pipeline {
+    /// Start `readers` threads taking chunks and reading them into buffers,
+    /// and `writers` threads writing the buffers out in whatever order they
+    /// come. Like the threads of the other engines, each sends its result
+    /// to `sender` when it is done, with the digests of the chunks it read.
+    pub(crate) fn spawn(
+        self,
+        readers: usize,
+        writers: usize,
+        sender: &mpsc::Sender<Result<Vec<ChunkDigest>, Error>>,
+    ) -> Vec<JoinHandle<()>> {
+        let buffers = (readers + writers) * BUFFERS_PER_THREAD;
+        // Fewer buffers only mean the readers wait for the writers more.
+        let buffers = take_buffers(self.buffer_pool.as_ref(), self.buffer_size, buffers, 1);
+        if let Some(placement) = self.placement.as_ref() {
+            buffers.iter().for_each(|buffer| placement.bind(buffer));
+        }
+        let shared = Arc::new(Shared {
+            next_chunk: AtomicUsize::new(0),
+            states: self.chunks.iter().map(|_| Mutex::default()).collect(),
+            pieces: Queue::new([]),
+            free: Queue::new(buffers),
+            readers_left: AtomicUsize::new(readers),
+            pipeline: self,
+        });
+        let mut threads = Vec::new();
+        let roles = iter::repeat_n(true, readers);
+        for reader in roles.chain(iter::repeat_n(false, writers)) {
+            let shared = Arc::clone(&shared);
+            let sender = sender.clone();
+            threads.push(thread::spawn(move || {
+                if let Some(placement) = shared.pipeline.placement.as_ref() {
+                    placement.pin();
+                }
+                let result = if reader {
+                    let result = shared.read();
+                    // Writers finish what was read and stop once every
+                    // reader is done.
+                    if shared.readers_left.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        shared.pieces.close();
+                    }
+                    result
+                } else {
+                    shared.write().map(|()| Vec::new())
+                };
+                if result.is_err() {
+                    shared.pipeline.abort.store(true, Ordering::SeqCst);
+                }
+                // Nothing waiting on the queues would be taken off them.
+                if shared.stopped() {
+                    shared.pieces.close();
+                    shared.free.close();
+                }
+                // The receiver only goes away once every thread has reported.
+                let _ = sender.send(result);
+            }));
+        }
+        threads
+    }
+}
+
+impl Shared {
+    fn stopped(&self) -> bool {
+        let pipeline = &self.pipeline;
+        pipeline.abort.load(Ordering::SeqCst)
+            || (pipeline.cancel.as_ref()).is_some_and(|c| c.load(Ordering::SeqCst))
+    }
+
+    fn read(&self) -> Result<Vec<ChunkDigest>, Error> {
+        let io = &self.pipeline.io;
+        let mut digests = Vec::new();
+        while !self.stopped() {
+            let index = self.next_chunk.fetch_add(1, Ordering::SeqCst);
+            let Some(chunk) = self.pipeline.chunks.get(index) else {
+                break;
+            };
+            let mut hasher = self.pipeline.algorithm.map(|a| a.hasher());
+            let mut checkpoint_hasher = HashAlgorithm::Xxh3.hasher();
+            let mut pos = chunk.start;
+            let mut pieces = 0;
+            while pos < chunk.end {
+                let Some(mut buffer) = self.free.pop().filter(|_| !self.stopped()) else {
+                    return Ok(digests);
+                };
+                // Never read past the end of this chunk.
+                let want = buffer.len().min((chunk.end - pos) as usize);
+                let bytes_read = io.read_at(&mut buffer[..want], pos)?;
+                if bytes_read == 0 {
+                    self.free.push(buffer);
+                    break;
+                }
+                let data = &buffer[..bytes_read];
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(data);
+                }
+                if let Some(file_digest) = self.pipeline.file_digest.as_ref() {
+                    file_digest.update(index, pos, data, &|| self.stopped());
+                }
+                if self.pipeline.checkpoint.is_some() {
+                    checkpoint_hasher.update(data);
+                }
+                self.pieces.push(Piece {
+                    chunk: index,
+                    offset: pos,
+                    len: bytes_read,
+                    buffer,
+                });
+                pieces += 1;
+                pos += bytes_read as u64;
+                // Direct reads only come up short at the end of the file.
+                if io.direct && bytes_read < want {
+                    break;
+                }
+            }
+            if let Some(file_digest) = self.pipeline.file_digest.as_ref() {
+                file_digest.chunk_done(index, &|| self.stopped());
+            }
+            let mut state = self.states[index].lock().unwrap();
+            state.read = Some((pieces, pos, checkpoint_hasher.finish()));
+            if state.written == pieces {
+                self.chunk_done(index, &state)?;
+            }
+            drop(state);
+            if let Some(hasher) = hasher {
+                digests.push(ChunkDigest {
+                    range: chunk.start..pos,
+                    digest: hasher.finish(),
+                });
+            }
+        }
+        Ok(digests)
+    }
+
+    fn write(&self) -> Result<(), Error> {
+        let io = &self.pipeline.io;
+        while let Some(piece) = self.pieces.pop() {
+            if self.stopped() {
+                break;
+            }
+            let data = &piece.buffer[..piece.len];
+            if io.admit(data) {
+                io.write_all_at(&piece.buffer[..io.write_len(piece.len)], piece.offset)?;
+            }
+            let (chunk, len) = (piece.chunk, piece.len);
+            self.free.push(piece.buffer);
+            (self.pipeline.processed_bytes).fetch_add(len as u64, Ordering::SeqCst);
+            let mut state = self.states[chunk].lock().unwrap();
+            state.written += 1;
+            if state
+                .read
+                .as_ref()
+                .is_some_and(|read| read.0 == state.written)
+            {
+                self.chunk_done(chunk, &state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a chunk that is all written.
+    fn chunk_done(&self, index: usize, state: &ChunkState) -> Result<(), Error> {
+        let Some((_, end, digest)) = &state.read else {
+            return Ok(());
+        };
+        let io = &self.pipeline.io;
+        let range = self.pipeline.chunks[index].start..*end;
+        if let (Some(infile), Some(outfile), true) = (
+            io.infile.as_file(),
+            io.outfile.as_file(),
+            self.pipeline.drop_cache,
+        ) {
+            drop_cached(infile, outfile, &range);
+        }
+        if let Some(checkpoint) = self.pipeline.checkpoint.as_ref() {
+            checkpoint.record(&range, digest)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use crate::direct::AlignedBuffer;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Memory for the buffers of every thread copying or verifying, shared by
+/// all the files being copied at once so that together they never hold
+/// more than a set number of bytes.
+///
+/// Buffers given back are kept for reuse by the next thread wanting one of
+/// the same size, as long as they fit within the limit.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    limit: u64,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Bytes of the buffers handed out.
+    in_use: u64,
+    free: Vec<AlignedBuffer>,
+}
+
+impl State {
+    fn free_bytes(&self) -> u64 {
+        self.free.iter().map(|buffer| buffer.len() as u64).sum()
+    }
+}
+
+impl BufferPool {
+    pub(crate) fn new(limit: u64) -> Self {
+        BufferPool {
+            limit,
+            state: Mutex::new(State {
+                in_use: 0,
+                free: Vec::new(),
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    fn give_back(&self, buffer: AlignedBuffer) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use -= buffer.len() as u64;
+        if state.in_use + state.free_bytes() + buffer.len() as u64 <= self.limit {
+            state.free.push(buffer);
+        }
+        drop(state);
+        self.released.notify_all();
+    }
+}
+
+/// A buffer of a thread, given back to its pool when dropped.
+pub(crate) struct PooledBuffer {
+    buffer: Option<AlignedBuffer>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let (Some(buffer), Some(pool)) = (self.buffer.take(), self.pool.as_ref()) {
+            pool.give_back(buffer);
+        }
+    }
+}
+
+/// Take up to `wanted` buffers of `len` bytes from `pool`, after waiting
+/// until at least `needed` of them fit in it. Without a pool they are all
+/// allocated.
+///
+/// Threads that already hold buffers only take more with `needed` at 0, so
+/// that none of them waits for memory another one is waiting for too. When
+/// `needed` buffers are more than the whole pool they are handed out once
+/// nothing else is, rather than never.
+pub(crate) fn take_buffers(
+    pool: Option<&Arc<BufferPool>>,
+    len: usize,
+    wanted: usize,
+    needed: usize,
+) -> Vec<PooledBuffer> {
+    let Some(pool) = pool else {
+        return (0..wanted)
+            .map(|_| PooledBuffer {
+                buffer: Some(AlignedBuffer::new(len)),
+                pool: None,
+            })
+            .collect();
+    };
+    // Buffers of no bytes still count as one, for the division below.
+    let size = len.max(1) as u64;
+    let mut state = pool.state.lock().unwrap();
+    let room = loop {
+        let room = pool.limit.saturating_sub(state.in_use);
+        if room >= needed as u64 * len as u64 || state.in_use == 0 {
+            break room;
+        }
+        state = pool.released.wait(state).unwrap();
+    };
+    let count = wanted.min(((room / size) as usize).max(needed));
+    state.in_use += (count * len) as u64;
+    let mut buffers = Vec::new();
+    while buffers.len() < count {
+        match state.free.iter().position(|buffer| buffer.len() == len) {
+            Some(index) => buffers.push(state.free.swap_remove(index)),
+            None => break,
+        }
+    }
+    // Make room for the new ones by dropping buffers kept for other sizes.
+    while !state.free.is_empty() && state.in_use + state.free_bytes() > pool.limit {
+        state.free.pop();
+    }
+    drop(state);
+    let mut buffers: Vec<PooledBuffer> = buffers
+        .into_iter()
+        .map(|buffer| PooledBuffer {
+            buffer: Some(buffer),
+            pool: Some(Arc::clone(pool)),
+        })
+        .collect();
+    while buffers.len() < count {
+        buffers.push(PooledBuffer {
+            buffer: Some(AlignedBuffer::new(len)),
+            pool: Some(Arc::clone(pool)),
+        });
+    }
+    buffers
+}
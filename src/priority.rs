@@ -0,0 +1,155 @@
+use crate::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// The IO scheduling class of a copy, as set by `ionice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    /// Served before everything else, which needs root.
+    Realtime,
+    /// The class processes have by default.
+    BestEffort,
+    /// Only served when no other process wants to use the disk.
+    Idle,
+}
+
+impl fmt::Display for IoClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            IoClass::Realtime => "realtime",
+            IoClass::BestEffort => "best-effort",
+            IoClass::Idle => "idle",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for IoClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "realtime" | "rt" | "1" => Ok(IoClass::Realtime),
+            "best-effort" | "be" | "2" => Ok(IoClass::BestEffort),
+            "idle" | "3" => Ok(IoClass::Idle),
+            _ => Err(format!(
+                "unknown IO class '{}', expected one of realtime, best-effort, idle",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse a list of CPUs such as `0-3,8,10-11`, as taken by `taskset -c`
+/// and printed by the kernel.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let (Ok(first), Ok(last)) = (first.trim().parse::<usize>(), last.trim().parse()) else {
+            return Err(format!("invalid CPU range '{}'", range));
+        };
+        if first > last {
+            return Err(format!("invalid CPU range '{}'", range));
+        }
+        cpus.extend(first..=last);
+    }
+    if cpus.is_empty() {
+        return Err("no CPUs given".to_string());
+    }
+    Ok(cpus)
+}
+
+/// Run the calling thread, and the threads it starts from then on, only on
+/// `cpus`.
+pub fn set_cpu_affinity(cpus: &[usize]) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use nix::libc;
+
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(format!("There is no CPU {}", cpu).into());
+            }
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        if unsafe { libc::sched_setaffinity(0, size, &set) } != 0 {
+            let cpus: Vec<String> = cpus.iter().map(|cpu| cpu.to_string()).collect();
+            return Err(format!(
+                "Failed to run on CPUs {}: {}",
+                cpus.join(","),
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = cpus;
+        Err("Setting the CPUs to run on is only supported on Linux".into())
+    }
+}
+
+/// Set the nice value of the calling thread, and of the threads it starts
+/// from then on, from -20, the highest priority, to 19, the lowest. Values
+/// below the current one need privileges.
+pub fn set_nice(nice: i32) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use nix::libc;
+
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(format!(
+                "Failed to set the nice value to {}: {}",
+                nice,
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = nice;
+        Err("Setting the nice value is not supported on this platform".into())
+    }
+}
+
+/// Set the IO priority of the calling thread, and of the threads it starts
+/// from then on, to `class` and `level`, from 0, the highest, to 7. Levels
+/// only apply to the realtime and best-effort classes.
+pub fn set_io_priority(class: IoClass, level: u8) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use nix::libc;
+
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+        let number = match class {
+            IoClass::Realtime => 1,
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        };
+        let level = if class == IoClass::Idle { 0 } else { level };
+        let priority = (number << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level.min(7));
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+            return Err(format!(
+                "Failed to set the IO priority to {} {}: {}",
+                class,
+                level,
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (class, level);
+        Err("Setting the IO priority is only supported on Linux".into())
+    }
+}
@@ -0,0 +1,451 @@
+use crate::options::CopyOptions;
+use crate::stats::CopyStats;
+use std::fmt::{self, Write as _};
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the bar is redrawn on a terminal.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// How often a line is printed when stderr is not a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// How often a progress event is written in JSON mode.
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+/// The window the current rate is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+const BAR_WIDTH: usize = 20;
+
+/// Progress of a copy of `total` bytes, made of files copied one after the
+/// other or at the same time. Each file being copied counts its bytes in
+/// its own counter.
+pub(crate) struct Progress {
+    /// `None` for streams, whose size is only known once they end.
+    total: Option<AtomicU64>,
+    finished: AtomicU64,
+    running: Mutex<Vec<Arc<AtomicU64>>>,
+    /// The most bytes copied counted in the stats so far.
+    counted: AtomicU64,
+    /// When the progress started being shown, which the average rate is
+    /// measured from.
+    start: Mutex<Instant>,
+    /// Whether a bar is drawn on the current line of the terminal.
+    drawn: Mutex<bool>,
+    terminal: bool,
+    /// Whether the progress is shown at all.
+    visible: bool,
+    /// Write progress events instead of drawing a bar.
+    json: bool,
+    /// Where the peak rate is kept.
+    stats: Option<Arc<CopyStats>>,
+}
+
+impl Progress {
+    /// Progress shown as set in `options`.
+    pub(crate) fn new(total: u64, options: &CopyOptions) -> Self {
+        Progress {
+            total: Some(AtomicU64::new(total)),
+            finished: AtomicU64::new(0),
+            running: Mutex::new(Vec::new()),
+            counted: AtomicU64::new(0),
+            start: Mutex::new(Instant::now()),
+            drawn: Mutex::new(false),
+            terminal: io::stderr().is_terminal(),
+            visible: options.progress,
+            json: options.json,
+            stats: options.stats.clone(),
+        }
+    }
+
+    /// Progress of a copy of unknown size, shown as the bytes copied so far
+    /// with no percentage.
+    pub(crate) fn unbounded(options: &CopyOptions) -> Self {
+        Progress {
+            total: None,
+            ..Progress::new(0, options)
+        }
+    }
+
+    /// Add `size` bytes to the total, for a copy that finds what it copies
+    /// as it goes.
+    pub(crate) fn grow(&self, size: u64) {
+        if let Some(total) = &self.total {
+            total.fetch_add(size, Ordering::SeqCst);
+        }
+    }
+
+    fn total(&self) -> Option<u64> {
+        self.total
+            .as_ref()
+            .map(|total| total.load(Ordering::SeqCst))
+    }
+
+    /// Count the bytes added to `counter` as copied.
+    pub(crate) fn follow(&self, counter: Arc<AtomicU64>) {
+        self.running.lock().unwrap().push(counter);
+    }
+
+    /// A new counter for a file about to be copied.
+    pub(crate) fn track(&self) -> Arc<AtomicU64> {
+        let counter = Arc::new(AtomicU64::new(0));
+        self.follow(Arc::clone(&counter));
+        counter
+    }
+
+    /// Count a file that was not followed as done with its `size` bytes.
+    pub(crate) fn add(&self, size: u64) {
+        self.finished.fetch_add(size, Ordering::SeqCst);
+    }
+
+    /// Count the file `counter` was following as done with all its `size`
+    /// bytes, however many of them it had to copy.
+    pub(crate) fn finish(&self, counter: &Arc<AtomicU64>, size: u64) {
+        self.running
+            .lock()
+            .unwrap()
+            .retain(|running| !Arc::ptr_eq(running, counter));
+        self.finished.fetch_add(size, Ordering::SeqCst);
+    }
+
+    fn copied(&self) -> u64 {
+        let running = self.running.lock().unwrap();
+        let running: u64 = running.iter().map(|c| c.load(Ordering::SeqCst)).sum();
+        let copied = self.finished.load(Ordering::SeqCst) + running;
+        self.total().map_or(copied, |total| copied.min(total))
+    }
+
+    /// Count the bytes copied since last time in the stats. The bytes of a
+    /// file dip while it moves from running to finished, which the most
+    /// seen so far hides.
+    fn count(&self, stats: &CopyStats) -> u64 {
+        let copied = self.copied();
+        let counted = self.counted.fetch_max(copied, Ordering::SeqCst);
+        stats.transferred(copied.saturating_sub(counted));
+        copied
+    }
+
+    /// Print `message` on a line of its own, above the bar.
+    pub(crate) fn message(&self, message: &str) {
+        let mut drawn = self.drawn.lock().unwrap();
+        if *drawn {
+            eprint!("\r\x1b[K");
+            *drawn = false;
+        }
+        eprintln!("{}", message);
+    }
+
+    /// Show the progress while `f` runs, and once more when it is done.
+    ///
+    /// The display stops as soon as `f` returns, however it ends, rather
+    /// than when the bytes add up to the total, which they never do for a
+    /// failed copy.
+    pub(crate) fn show<T>(&self, f: impl FnOnce() -> T) -> T {
+        if !self.visible && self.stats.is_none() {
+            return f();
+        }
+        *self.start.lock().unwrap() = Instant::now();
+        let (done, finished) = mpsc::channel::<()>();
+        let result = thread::scope(|scope| {
+            scope.spawn(move || {
+                let mut samples = vec![(Instant::now(), self.copied())];
+                let mut logged = Instant::now();
+                while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(REDRAW_INTERVAL) {
+                    let now = Instant::now();
+                    let copied = match self.stats.as_ref() {
+                        Some(stats) => self.count(stats),
+                        None => self.copied(),
+                    };
+                    samples.push((now, copied));
+                    samples.retain(|(time, _)| now - *time <= RATE_WINDOW);
+                    if let Some(stats) = self.stats.as_ref() {
+                        stats.current(rate(&samples) as u64);
+                        // Rates over shorter spans jump around too much to be a peak.
+                        if now - samples[0].0 >= RATE_WINDOW / 2 {
+                            stats.rate(rate(&samples) as u64);
+                        }
+                    }
+                    if !self.visible {
+                        continue;
+                    }
+                    if self.json {
+                        if now - logged >= EVENT_INTERVAL {
+                            self.draw(rate(&samples), true, false);
+                            logged = now;
+                        }
+                    } else if self.terminal {
+                        self.draw(rate(&samples), false, false);
+                    } else if now - logged >= LOG_INTERVAL {
+                        self.draw(rate(&samples), true, false);
+                        logged = now;
+                    }
+                }
+            });
+            // Dropped when f returns or panics, which wakes the display.
+            let _done = done;
+            f()
+        });
+        if let Some(stats) = self.stats.as_ref() {
+            self.count(stats);
+            stats.current(0);
+        }
+        if self.visible {
+            let elapsed = self.start.lock().unwrap().elapsed();
+            let average = self.copied() as f64 / elapsed.as_secs_f64();
+            self.draw(average, true, true);
+        }
+        result
+    }
+
+    /// Draw the progress at `rate` bytes per second, starting a new line
+    /// after it with `end_line`. The `last` line has no ETA.
+    fn draw(&self, rate: f64, end_line: bool, last: bool) {
+        let copied = self.copied();
+        let total = self.total();
+        let elapsed = self.start.lock().unwrap().elapsed().as_secs_f64();
+        let average = if elapsed > 0.0 {
+            copied as f64 / elapsed
+        } else {
+            0.0
+        };
+        let fraction = total.map(|total| match total {
+            0 => 1.0,
+            total => copied as f64 / total as f64,
+        });
+        if self.json {
+            let mut event = Event::new("progress").value("bytes", copied);
+            if let (Some(total), Some(fraction)) = (total, fraction) {
+                event = event
+                    .value("total", total)
+                    .value("percent", format_args!("{:.1}", fraction * 100.0));
+            }
+            event
+                .value("bytes_per_second", rate as u64)
+                .value("average_bytes_per_second", average as u64)
+                .emit();
+            return;
+        }
+
+        let mut line = String::new();
+        if self.terminal {
+            line.push('\r');
+        }
+        match (total, fraction) {
+            (Some(total), Some(fraction)) => {
+                let filled = (fraction * BAR_WIDTH as f64) as usize;
+                let _ = write!(
+                    line,
+                    "[{}{}] {:5.1}% {} of {}, ",
+                    "#".repeat(filled),
+                    "-".repeat(BAR_WIDTH - filled),
+                    fraction * 100.0,
+                    human_size(copied),
+                    human_size(total),
+                );
+            }
+            _ => {
+                let _ = write!(line, "{} copied, ", human_size(copied));
+            }
+        }
+        let _ = write!(
+            line,
+            "{}/s, average {}/s",
+            human_size(rate as u64),
+            human_size(average as u64),
+        );
+        if let Some(total) = total.filter(|&total| !last && copied < total && rate > 0.0) {
+            let eta = Duration::from_secs_f64((total - copied) as f64 / rate);
+            let _ = write!(line, ", ETA {}", duration(eta));
+        }
+        if self.terminal {
+            line.push_str("\x1b[K");
+        }
+
+        let mut drawn = self.drawn.lock().unwrap();
+        let mut stderr = io::stderr().lock();
+        let _ = if end_line {
+            writeln!(stderr, "{}", line)
+        } else {
+            write!(stderr, "{}", line).and_then(|_| stderr.flush())
+        };
+        *drawn = self.terminal && !end_line;
+    }
+}
+
+/// An event written as a line of JSON on stdout, for
+/// [`CopyOptions::json`](crate::CopyOptions::json).
+pub(crate) struct Event(String);
+
+impl Event {
+    pub(crate) fn new(event: &str) -> Self {
+        Event(format!("{{\"event\":{}", json_string(event)))
+    }
+
+    pub(crate) fn string(mut self, name: &str, value: &str) -> Self {
+        let _ = write!(self.0, ",\"{}\":{}", name, json_string(value));
+        self
+    }
+
+    pub(crate) fn path(self, name: &str, path: &Path) -> Self {
+        self.string(name, &path.to_string_lossy())
+    }
+
+    /// A number or boolean, written as it displays.
+    pub(crate) fn value(mut self, name: &str, value: impl fmt::Display) -> Self {
+        let _ = write!(self.0, ",\"{}\":{}", name, value);
+        self
+    }
+
+    /// Write the event on a line of its own.
+    pub(crate) fn emit(self) {
+        println!("{}}}", self.0);
+    }
+}
+
+/// `s` as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Bytes per second between the first and last of `samples`.
+fn rate(samples: &[(Instant, u64)]) -> f64 {
+    match (samples.first(), samples.last()) {
+        (Some((start, from)), Some((end, to))) if end > start => {
+            to.saturating_sub(*from) as f64 / (*end - *start).as_secs_f64()
+        }
+        _ => 0.0,
+    }
+}
+
+/// Parse a size such as `512`, `64K`, `200M` or `1.5G`. Suffixes are
+/// powers of 1024, and an optional trailing `B` or `iB` is ignored.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let number = match trimmed.strip_suffix(['B', 'b']) {
+        Some(number) => number.strip_suffix(['i', 'I']).unwrap_or(number),
+        None => trimmed,
+    };
+    let (number, multiplier) = match number.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&number[..number.len() - 1], 1u64 << 10),
+        Some('M') => (&number[..number.len() - 1], 1 << 20),
+        Some('G') => (&number[..number.len() - 1], 1 << 30),
+        Some('T') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+    let value: f64 = number
+        .trim()
+        .parse()
+        .ok()
+        .filter(|value: &f64| value.is_finite())
+        .ok_or_else(|| format!("invalid size '{}', expected e.g. 512K, 8M or 1G", s))?;
+    if value < 0.0 {
+        return Err(format!("invalid size '{}', it can't be negative", s));
+    }
+    let bytes = value * multiplier as f64;
+    // u64::MAX rounds up to 2^64 as a float, so this refuses it too.
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("invalid size '{}', it is too large", s));
+    }
+    Ok(bytes as u64)
+}
+
+/// `bytes` in the largest unit of 1024 that keeps it above 1, e.g. `1.5 GiB`.
+pub(crate) fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// `duration` as `m:ss`, or `h:mm:ss` past an hour.
+fn duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        for (size, expected) in [
+            ("0", 0),
+            ("512", 512),
+            (" 512 ", 512),
+            ("512B", 512),
+            ("64K", 64 << 10),
+            ("64k", 64 << 10),
+            ("64KB", 64 << 10),
+            ("64kb", 64 << 10),
+            ("64KiB", 64 << 10),
+            ("64kib", 64 << 10),
+            ("64KIB", 64 << 10),
+            ("200M", 200 << 20),
+            ("200MiB", 200 << 20),
+            ("1.5G", 3 << 29),
+            ("1g", 1 << 30),
+            ("2T", 2 << 40),
+            ("2TiB", 2 << 40),
+            ("8 M", 8 << 20),
+            ("0.5K", 512),
+            ("16777215T", 16_777_215 << 40),
+        ] {
+            assert_eq!(parse_size(size), Ok(expected), "{:?}", size);
+        }
+    }
+
+    #[test]
+    fn refuses_other_sizes() {
+        for size in [
+            "",
+            "K",
+            "iB",
+            "abc",
+            "12X",
+            "1i",
+            "1KiBB",
+            "1BB",
+            "-1",
+            "-1K",
+            "inf",
+            "NaN",
+            "1..5M",
+            "16777216T",
+            "18446744073709551616",
+            "1e30",
+        ] {
+            assert!(parse_size(size).is_err(), "{:?}", size);
+        }
+    }
+}
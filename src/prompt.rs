@@ -0,0 +1,40 @@
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+use std::sync::Mutex;
+
+/// The answer given for all the files left, once "all" or "none" was.
+static ANSWER_FOR_ALL: Mutex<Option<bool>> = Mutex::new(None);
+
+/// Ask on stderr whether to overwrite `dest`, for
+/// [`Overwrite::Ask`](crate::Overwrite::Ask), and read the answer from
+/// stdin. "all" and "none" answer for the rest of the files too. Only one
+/// question is asked at a time, and the end of stdin means no.
+pub(crate) fn confirm_overwrite(dest: &dyn Display) -> bool {
+    let mut answer_for_all = ANSWER_FOR_ALL.lock().unwrap();
+    if let Some(answer) = *answer_for_all {
+        return answer;
+    }
+    let stdin = io::stdin();
+    loop {
+        eprint!("Overwrite '{}'? [y]es, [n]o, [a]ll, none: ", dest);
+        let _ = io::stderr().flush();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            eprintln!();
+            return false;
+        }
+        match line.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => return true,
+            "n" | "no" => return false,
+            "a" | "all" => {
+                *answer_for_all = Some(true);
+                return true;
+            }
+            "none" => {
+                *answer_for_all = Some(false);
+                return false;
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::options::CopyOptions;
+use crate::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A path on a host reached over SSH, written `[user@]host:path` as with
+/// scp, or `ssh://[user@]host[:port]/path` to give a port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remote {
+    pub(crate) user: Option<String>,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) path: PathBuf,
+}
+
+impl Remote {
+    /// Parse `location` if it names a remote path. Local paths, including
+    /// ones with a `:` after a `/` such as `./a:b`, and URLs of other
+    /// schemes give `None`.
+    pub fn parse(location: &str) -> Option<Remote> {
+        if let Some(rest) = location.strip_prefix("ssh://") {
+            let (authority, path) = match rest.find('/') {
+                Some(slash) => (&rest[..slash], &rest[slash..]),
+                None => (rest, "."),
+            };
+            let (user, host) = split_user(authority);
+            let (host, port) = match host.rsplit_once(':') {
+                Some((name, port)) if !host.ends_with(']') => (name, port.parse().ok()?),
+                _ => (host, 22),
+            };
+            return Remote::new(user, host, port, path);
+        }
+
+        // Other URLs, such as `s3://` or `https://`, are not hosts.
+        if location.contains("://") {
+            return None;
+        }
+        let host_start = location
+            .find('@')
+            .filter(|&at| !location[..at].contains('/'))
+            .map_or(0, |at| at + 1);
+        // IPv6 addresses are written in brackets, `[::1]:path`.
+        let colon = if location[host_start..].starts_with('[') {
+            let close = host_start + location[host_start..].find(']')?;
+            location[close + 1..]
+                .starts_with(':')
+                .then_some(close + 1)?
+        } else {
+            host_start + location[host_start..].find(':')?
+        };
+        let (authority, path) = (&location[..colon], &location[colon + 1..]);
+        // `C:\dir` is a drive on Windows, not a host called C.
+        let drive = cfg!(windows) && authority.len() == 1;
+        if authority.contains(['/', '\\']) || drive {
+            return None;
+        }
+        let (user, host) = split_user(authority);
+        Remote::new(user, host, 22, if path.is_empty() { "." } else { path })
+    }
+
+    fn new(user: Option<&str>, host: &str, port: u16, path: &str) -> Option<Remote> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || user == Some("") {
+            return None;
+        }
+        Some(Remote {
+            user: user.map(str::to_string),
+            host: host.to_string(),
+            port,
+            path: PathBuf::from(path),
+        })
+    }
+
+    /// The same host with `path` in place of this path.
+    #[cfg(feature = "ssh")]
+    pub(crate) fn with_path(&self, path: PathBuf) -> Remote {
+        Remote {
+            path,
+            ..self.clone()
+        }
+    }
+}
+
+fn split_user(authority: &str) -> (Option<&str>, &str) {
+    match authority.rsplit_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, authority),
+    }
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            f.write_str(&self.host)?;
+        }
+        write!(f, ":{}", self.path.display())
+    }
+}
+
+impl FromStr for Remote {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Remote::parse(s).ok_or_else(|| format!("'{}' is not a [user@]host:path location", s))
+    }
+}
+
+/// Copy the local file or directory `src` to `dest` over SSH, returning the
+/// total number of bytes copied.
+///
+/// The files are split into chunks that are sent over up to
+/// `options.threads` SSH connections at once, each with its own SFTP
+/// channel. Directories are copied with everything in them but what
+/// [`CopyOptions::filter`] excludes, and a file copied to an existing
+/// directory goes inside it.
+///
+/// The host has to be in `~/.ssh/known_hosts`, and the login is made with
+/// the keys held by ssh-agent or the default keys in `~/.ssh` without a
+/// passphrase.
+///
+/// Thread counts, verification, [`CopyOptions::preserve`] (permissions and
+/// times to the second), [`CopyOptions::overwrite`],
+/// [`CopyOptions::skip_identical`], [`CopyOptions::bwlimit`],
+/// [`CopyOptions::cancel`] and [`CopyOptions::dry_run`] apply as they do to
+/// local copies. Options about how local files are read and written do not.
+pub fn upload<P: AsRef<Path>>(src: P, dest: &Remote, options: &CopyOptions) -> Result<u64, Error> {
+    #[cfg(feature = "ssh")]
+    return crate::ssh::upload(src.as_ref(), dest, options);
+    #[cfg(not(feature = "ssh"))]
+    no_ssh(src.as_ref(), dest, options)
+}
+
+/// Copy the file or directory `src` on a remote host to the local `dest`
+/// over SSH, returning the total number of bytes copied. Works the same way
+/// as [`upload`] in the other direction.
+pub fn download<P: AsRef<Path>>(
+    src: &Remote,
+    dest: P,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    #[cfg(feature = "ssh")]
+    return crate::ssh::download(src, dest.as_ref(), options);
+    #[cfg(not(feature = "ssh"))]
+    no_ssh(dest.as_ref(), src, options)
+}
+
+#[cfg(not(feature = "ssh"))]
+fn no_ssh(_local: &Path, _remote: &Remote, _options: &CopyOptions) -> Result<u64, Error> {
+    Err("This build has no SSH support, rebuild with --features ssh".into())
+}
@@ -0,0 +1,202 @@
+use crate::progress::human_size;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long each number of threads copies before its rate is measured.
+const INTERVAL: Duration = Duration::from_secs(1);
+/// Rates that differ by less than this fraction count as the same.
+const TOLERANCE: f64 = 0.05;
+/// How many intervals a number of threads is kept before more are tried,
+/// in case what the storage can take changed.
+const HOLD_INTERVALS: u32 = 10;
+/// Threads a copy starts with, when it may use that many.
+const START_THREADS: usize = 4;
+
+struct State {
+    active: usize,
+    finished: bool,
+}
+
+/// Lets only some of the threads copying a file take chunks, and changes
+/// how many while the file is copied to settle on the number that copies
+/// fastest: more streams for as long as they add throughput, as on NFS,
+/// and fewer when they start to get in each other's way, as on a spinning
+/// disk.
+pub(crate) struct Scaler {
+    state: Mutex<State>,
+    changed: Condvar,
+    max: usize,
+    /// Bytes the threads have copied, the rate is measured from.
+    copied: AtomicU64,
+}
+
+impl Scaler {
+    /// A scaler for `max` threads, numbered from 0.
+    pub(crate) fn new(max: usize) -> Self {
+        Scaler {
+            state: Mutex::new(State {
+                active: START_THREADS.min(max),
+                finished: false,
+            }),
+            changed: Condvar::new(),
+            max,
+            copied: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn count(&self, bytes: u64) {
+        self.copied.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Wait while thread `index` is not one of those taking chunks.
+    pub(crate) fn wait_turn(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        while index >= state.active && !state.finished {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Let every thread go on, once one found no chunks left or stopped
+    /// the copy, and stop changing their number.
+    pub(crate) fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.changed.notify_all();
+    }
+
+    /// A guard calling [`Scaler::finish`] when dropped, so it is also
+    /// called when a thread panics.
+    pub(crate) fn on_exit(&self) -> Finish<'_> {
+        Finish(self)
+    }
+
+    /// Measure the rate of the threads every interval and change their
+    /// number until [`Scaler::finish`] is called. With `verbose` every
+    /// change is printed.
+    pub(crate) fn control(&self, path: &Path, verbose: bool) {
+        let mut climber = Climber::new(self.max, self.state.lock().unwrap().active);
+        let mut copied = 0;
+        let mut measured = Instant::now();
+        loop {
+            let state = self.state.lock().unwrap();
+            let (mut state, _) = self
+                .changed
+                .wait_timeout_while(state, INTERVAL, |state| !state.finished)
+                .unwrap();
+            if state.finished {
+                return;
+            }
+            let now = self.copied.load(Ordering::SeqCst);
+            let rate = (now - copied) as f64 / measured.elapsed().as_secs_f64();
+            (copied, measured) = (now, Instant::now());
+            let active = climber.next(state.active, rate);
+            if active != state.active {
+                if verbose {
+                    eprintln!(
+                        " {}/s with {} threads, copying '{}' with {}",
+                        human_size(rate as u64),
+                        state.active,
+                        path.display(),
+                        active
+                    );
+                }
+                state.active = active;
+                self.changed.notify_all();
+            }
+        }
+    }
+}
+
+pub(crate) struct Finish<'a>(&'a Scaler);
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Up,
+    Down,
+}
+
+/// Picks the next number of threads from the rate of the last one, by
+/// moving in one direction for as long as the rate improves and going back
+/// to the best number found once it doesn't.
+struct Climber {
+    max: usize,
+    best: usize,
+    best_rate: f64,
+    /// Where the current climb is going, `None` while holding at `best`.
+    direction: Option<Direction>,
+    /// The number the current climb started from.
+    start: usize,
+    held: u32,
+}
+
+impl Climber {
+    fn new(max: usize, active: usize) -> Self {
+        Climber {
+            max,
+            best: active,
+            best_rate: 0.0,
+            direction: Some(Direction::Up),
+            start: active,
+            held: 0,
+        }
+    }
+
+    fn next(&mut self, active: usize, rate: f64) -> usize {
+        if active == self.best {
+            // Measured again, as the rate the storage gives changes.
+            self.best_rate = rate;
+        } else if rate > self.best_rate * (1.0 + TOLERANCE) {
+            self.best = active;
+            self.best_rate = rate;
+        } else {
+            // More threads that did not help are tried with fewer instead.
+            self.direction = match self.direction {
+                Some(Direction::Up) if self.best == self.start => Some(Direction::Down),
+                _ => None,
+            };
+            return self.best;
+        }
+        match self.direction {
+            Some(Direction::Up) if active < self.max => self.grow(active),
+            // Already at the most threads there are, so only fewer are left.
+            Some(Direction::Up) if active == self.start && active > 1 => {
+                self.direction = Some(Direction::Down);
+                shrink(active)
+            }
+            Some(Direction::Down) if active > 1 => shrink(active),
+            _ => {
+                self.direction = None;
+                self.held += 1;
+                if self.held < HOLD_INTERVALS {
+                    return active;
+                }
+                self.held = 0;
+                self.start = active;
+                if active < self.max {
+                    self.direction = Some(Direction::Up);
+                    self.grow(active)
+                } else if active > 1 {
+                    self.direction = Some(Direction::Down);
+                    shrink(active)
+                } else {
+                    active
+                }
+            }
+        }
+    }
+
+    fn grow(&self, active: usize) -> usize {
+        (active + (active / 2).max(1)).min(self.max)
+    }
+}
+
+fn shrink(active: usize) -> usize {
+    active - (active / 4).max(1)
+}
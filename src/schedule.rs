@@ -0,0 +1,157 @@
+use crate::progress::parse_size;
+use chrono::Timelike;
+use std::str::FromStr;
+
+/// Bandwidth limits by time of day, such as
+/// `09:00-18:00=100M,18:00-09:00=0`, handed to copies with
+/// [`CopyOptions::bwlimit_schedule`](crate::CopyOptions::bwlimit_schedule).
+///
+/// Each window runs from its start up to its end in local time, past
+/// midnight when the end comes first, and limits writes to its rate in
+/// bytes per second, or not at all with 0. The first window a time falls in
+/// applies, and outside them all the copy's own limit does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BwSchedule {
+    windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    /// Minutes since midnight.
+    start: u32,
+    end: u32,
+    bytes_per_second: u64,
+}
+
+impl BwSchedule {
+    /// The limit at `minute` minutes past midnight, if a window has one.
+    pub(crate) fn rate_at(&self, minute: u32) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|window| {
+                if window.start < window.end {
+                    (window.start..window.end).contains(&minute)
+                } else {
+                    minute >= window.start || minute < window.end
+                }
+            })
+            .map(|window| window.bytes_per_second)
+    }
+
+    /// The limit now, going by the local time.
+    pub(crate) fn rate_now(&self) -> Option<u64> {
+        let now = chrono::Local::now();
+        self.rate_at(now.hour() * 60 + now.minute())
+    }
+}
+
+impl FromStr for BwSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |window: &str| {
+            format!(
+                "invalid bandwidth window '{}', expected e.g. 09:00-18:00=100M",
+                window
+            )
+        };
+        let windows = s
+            .split(',')
+            .map(|window| {
+                let window = window.trim();
+                let (times, rate) = window.split_once('=').ok_or_else(|| invalid(window))?;
+                let (start, end) = times.split_once('-').ok_or_else(|| invalid(window))?;
+                let (start, end) = match (minute_of_day(start), minute_of_day(end)) {
+                    (Some(start), Some(end)) if start % 1440 != end => (start % 1440, end),
+                    _ => return Err(invalid(window)),
+                };
+                Ok(Window {
+                    start,
+                    end,
+                    bytes_per_second: parse_size(rate)?,
+                })
+            })
+            .collect::<Result<Vec<Window>, String>>()?;
+        Ok(BwSchedule { windows })
+    }
+}
+
+/// `HH:MM` as minutes past midnight, with `24:00` for the end of the day.
+fn minute_of_day(time: &str) -> Option<u32> {
+    let (hour, minute) = time.trim().split_once(':')?;
+    let (hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+    match (hour, minute) {
+        (24, 0) => Some(24 * 60),
+        (0..=23, 0..=59) => Some(hour * 60 + minute),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(s: &str) -> BwSchedule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_times_of_day() {
+        for (time, expected) in [
+            ("00:00", Some(0)),
+            ("9:05", Some(545)),
+            (" 23:59 ", Some(1439)),
+            ("24:00", Some(1440)),
+            ("24:01", None),
+            ("25:00", None),
+            ("12:60", None),
+            ("12", None),
+            ("12:", None),
+            ("-1:00", None),
+            ("noon", None),
+        ] {
+            assert_eq!(minute_of_day(time), expected, "{:?}", time);
+        }
+    }
+
+    #[test]
+    fn finds_the_rate_of_a_time() {
+        let day = schedule("09:00-18:00=100M, 18:00-09:00=0");
+        assert_eq!(day.rate_at(8 * 60 + 59), Some(0));
+        assert_eq!(day.rate_at(9 * 60), Some(100 << 20));
+        assert_eq!(day.rate_at(18 * 60 - 1), Some(100 << 20));
+        assert_eq!(day.rate_at(18 * 60), Some(0));
+        assert_eq!(day.rate_at(0), Some(0));
+
+        // Outside every window the copy's own limit applies.
+        let night = schedule("22:00-06:00=1G");
+        assert_eq!(night.rate_at(23 * 60), Some(1 << 30));
+        assert_eq!(night.rate_at(5 * 60 + 59), Some(1 << 30));
+        assert_eq!(night.rate_at(6 * 60), None);
+        assert_eq!(night.rate_at(12 * 60), None);
+
+        // The first window a time falls in wins.
+        let overlapping = schedule("00:00-24:00=1M,12:00-13:00=2M");
+        assert_eq!(overlapping.rate_at(12 * 60 + 30), Some(1 << 20));
+        assert_eq!(overlapping.rate_at(1439), Some(1 << 20));
+        assert_eq!(schedule("24:00-01:00=5K").rate_at(30), Some(5 << 10));
+    }
+
+    #[test]
+    fn refuses_other_schedules() {
+        for s in [
+            "",
+            "09:00-18:00",
+            "09:00=100M",
+            "09:00-18:00=",
+            "09:00-18:00=fast",
+            "09:00-09:00=1M",
+            "00:00-00:00=1M",
+            "24:00-00:00=1M",
+            "09:00-25:00=1M",
+            "09:00-18:00=1M,",
+        ] {
+            assert!(s.parse::<BwSchedule>().is_err(), "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::backup::backup_path;
+use crate::copy::updates_in_place;
+use crate::options::CopyOptions;
+use crate::progress::human_size;
+use crate::sparse::Sparse;
+use crate::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The space a copy of the file with `source` metadata to `dest` takes up
+/// on its filesystem, give or take the blocks of directories and the last
+/// block of each file. The holes of sparse sources stay holes unless every
+/// byte is written, and the space of a file overwritten in place is freed
+/// as it is.
+pub(crate) fn needed_space(source: &fs::Metadata, dest: &Path, options: &CopyOptions) -> u64 {
+    let size = source
+        .len()
+        .saturating_sub(options.skip)
+        .min(options.count.unwrap_or(u64::MAX));
+    let needed = match options.sparse {
+        Sparse::Never => size,
+        _ => size.min(allocated(source)),
+    };
+    // A replaced file is only gone once its copy is renamed over it or it
+    // has been backed up.
+    let replaced_in_place = updates_in_place(dest, options)
+        && options.seek == 0
+        && backup_path(dest, options).is_none();
+    let freed = match fs::metadata(dest) {
+        Ok(existing) if replaced_in_place && existing.is_file() => allocated(&existing),
+        _ => 0,
+    };
+    needed.saturating_sub(freed)
+}
+
+/// Fail unless the filesystem `dest` is to go on has `needed` bytes
+/// available, which is checked when it can be told.
+pub(crate) fn check_space(dest: &Path, needed: u64) -> Result<(), Error> {
+    Space::of(dest).map_or(Ok(()), |space| space.check(needed))
+}
+
+/// The space available on the filesystem a copy goes on, taken once for a
+/// copy that checks what it needs as it finds what to copy.
+pub(crate) struct Space {
+    mount: PathBuf,
+    available: u64,
+}
+
+impl Space {
+    /// The space available for a copy to `dest`, when it can be told.
+    pub(crate) fn of(dest: &Path) -> Option<Self> {
+        let (mount, available) = available_space(dest)?;
+        Some(Space {
+            mount: mount.to_path_buf(),
+            available,
+        })
+    }
+
+    /// Fail unless `needed` bytes fit in the space available.
+    pub(crate) fn check(&self, needed: u64) -> Result<(), Error> {
+        if needed > self.available {
+            return Err(format!(
+                "Not enough space on '{}' for the copy, it needs {} and {} is available",
+                self.mount.display(),
+                human_size(needed),
+                human_size(self.available)
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// The closest existing directory to `path` and the space available to
+/// this user on its filesystem.
+fn available_space(path: &Path) -> Option<(&Path, u64)> {
+    #[cfg(unix)]
+    {
+        let dir = path
+            .ancestors()
+            .find(|dir| dir.as_os_str().is_empty() || dir.is_dir())?;
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let stats = nix::sys::statvfs::statvfs(dir).ok()?;
+        let available = stats.blocks_available() as u64 * stats.fragment_size() as u64;
+        Some((dir, available))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// The bytes of storage the file with `metadata` takes up.
+fn allocated(metadata: &fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::MetadataExt::blocks(metadata) * 512
+    }
+    #[cfg(not(unix))]
+    {
+        metadata.len()
+    }
+}
@@ -0,0 +1,184 @@
+#[cfg(target_os = "linux")]
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::fcntl::{fallocate, FallocateFlags};
+#[cfg(target_os = "linux")]
+use nix::unistd::{lseek, Whence};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+
+/// When holes are left in the destination instead of writing zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sparse {
+    /// Skip the holes of sparse sources and any block of zeros in the data.
+    Always,
+    /// Skip the holes of sparse sources.
+    Auto,
+    /// Write every byte.
+    Never,
+}
+
+impl fmt::Display for Sparse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Sparse::Always => "always",
+            Sparse::Auto => "auto",
+            Sparse::Never => "never",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Sparse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(Sparse::Always),
+            "auto" => Ok(Sparse::Auto),
+            "never" => Ok(Sparse::Never),
+            _ => Err(format!(
+                "unknown sparse mode '{}', expected one of always, auto, never",
+                s
+            )),
+        }
+    }
+}
+
+/// The ranges of `file` that hold data, found with `SEEK_DATA` and
+/// `SEEK_HOLE`. Filesystems that do not track holes report the whole file.
+#[cfg(target_os = "linux")]
+pub(crate) fn data_ranges(file: &File, size: u64) -> io::Result<Vec<Range<u64>>> {
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut pos = 0;
+    while pos < size {
+        let start = match lseek(fd, pos as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // Only a hole is left.
+            Err(Errno::ENXIO) => break,
+            Err(Errno::EINVAL) | Err(Errno::EOPNOTSUPP) => {
+                return Ok(std::iter::once(0..size).collect())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let end = match lseek(fd, start as i64, Whence::SeekHole) {
+            Ok(end) => (end as u64).min(size),
+            Err(e) => return Err(e.into()),
+        };
+        if end <= start {
+            break;
+        }
+        ranges.push(start..end);
+        pos = end;
+    }
+    Ok(ranges)
+}
+
+/// Allocate space for `ranges` of `file` up front, so the extents are laid
+/// out together rather than in the order threads happen to write them.
+/// Only the given ranges are allocated, so holes stay holes, and the file
+/// size is left alone. Filesystems without `fallocate` are left to
+/// allocate as the data is written.
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, ranges: &[Range<u64>]) -> io::Result<()> {
+    for range in ranges {
+        let result = fallocate(
+            file.as_raw_fd(),
+            FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            range.start as i64,
+            (range.end - range.start) as i64,
+        );
+        match result {
+            Ok(()) => {}
+            Err(Errno::EOPNOTSUPP) | Err(Errno::ENOSYS) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Elsewhere files are treated as all data.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn data_ranges(_file: &File, size: u64) -> io::Result<Vec<Range<u64>>> {
+    Ok(std::iter::once(0..size).collect())
+}
+
+/// Whether the file `metadata` is of takes up less space than its size, as
+/// a file with holes does. Compressed files do too.
+#[cfg(target_os = "linux")]
+pub(crate) fn may_have_holes(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn may_have_holes(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// macOS allocates with `F_PREALLOCATE`, contiguously if it can. It only
+/// allocates a length from the end of the allocated space, so this
+/// reserves as much as the ranges add up to.
+#[cfg(target_os = "macos")]
+pub(crate) fn preallocate(file: &File, ranges: &[Range<u64>]) -> io::Result<()> {
+    use nix::libc;
+    use std::os::unix::io::AsRawFd;
+
+    let length: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+    let mut store = libc::fstore_t {
+        fst_flags: 0,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: length as libc::off_t,
+        fst_bytesalloc: 0,
+    };
+    for flags in [
+        libc::F_ALLOCATECONTIG | libc::F_ALLOCATEALL,
+        libc::F_ALLOCATEALL,
+    ] {
+        store.fst_flags = flags;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) } != -1 {
+            return Ok(());
+        }
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENOTSUP) | Some(libc::EINVAL) => Ok(()),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn preallocate(_file: &File, _ranges: &[Range<u64>]) -> io::Result<()> {
+    Ok(())
+}
+
+/// The parts covered by both `a` and `b`, which must each be sorted and
+/// free of overlaps.
+pub(crate) fn intersect(a: &[Range<u64>], b: &[Range<u64>]) -> Vec<Range<u64>> {
+    let (mut i, mut j) = (0, 0);
+    let mut both = Vec::new();
+    while i < a.len() && j < b.len() {
+        let start = a[i].start.max(b[j].start);
+        let end = a[i].end.min(b[j].end);
+        if start < end {
+            both.push(start..end);
+        }
+        if a[i].end < b[j].end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    both
+}
+
+pub(crate) fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|&b| b == 0)
+}
@@ -0,0 +1,83 @@
+use crate::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A file that is neither a regular file, a directory nor a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+// Never seen on Windows.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) enum Special {
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl Special {
+    /// What kind of special file `file_type` is, if it is one.
+    #[cfg(unix)]
+    pub(crate) fn of(file_type: &fs::FileType) -> Option<Special> {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            Some(Special::Fifo)
+        } else if file_type.is_socket() {
+            Some(Special::Socket)
+        } else if file_type.is_char_device() {
+            Some(Special::CharDevice)
+        } else if file_type.is_block_device() {
+            Some(Special::BlockDevice)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn of(_file_type: &fs::FileType) -> Option<Special> {
+        None
+    }
+
+    pub(crate) fn is_device(self) -> bool {
+        matches!(self, Special::CharDevice | Special::BlockDevice)
+    }
+}
+
+impl fmt::Display for Special {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Special::Fifo => "FIFO",
+            Special::Socket => "socket",
+            Special::CharDevice => "character device",
+            Special::BlockDevice => "block device",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Create a special file like the one `source` is the metadata of at
+/// `dest`, with its permissions and, for devices, its device number.
+/// Device nodes need root.
+#[cfg(unix)]
+pub(crate) fn create_special(dest: &Path, source: &fs::Metadata) -> Result<(), Error> {
+    use nix::libc;
+    use nix::sys::stat::{mknod, Mode, SFlag};
+    use std::os::unix::fs::MetadataExt;
+
+    let mode = source.mode() as libc::mode_t;
+    mknod(
+        dest,
+        SFlag::from_bits_truncate(mode & libc::S_IFMT),
+        Mode::from_bits_truncate(mode & 0o7777),
+        source.rdev() as libc::dev_t,
+    )
+    .map_err(|e| format!("Failed to create '{}': {}", dest.display(), e).into())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_special(dest: &Path, _source: &fs::Metadata) -> Result<(), Error> {
+    Err(format!(
+        "Special files like '{}' can't be created here",
+        dest.display()
+    )
+    .into())
+}
@@ -0,0 +1,204 @@
+use crate::copy::{io_error, ChunkIo};
+use crate::pool::PooledBuffer;
+use crate::Error;
+use nix::libc;
+use std::io;
+use std::ops::Range;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+/// Copies chunks inside the kernel for one copy thread, without the data
+/// passing through rpcp: with `copy_file_range`, or with `splice` through a
+/// pipe of the thread's own between filesystems that can't do that.
+///
+/// Both take the offsets to read and write at, so every thread can work on
+/// the same pair of files at once.
+pub(crate) struct Splicer {
+    /// How much is moved by one call, and taken from the bandwidth limit.
+    piece_size: usize,
+    /// Set once `copy_file_range` turned out not to work for the files.
+    use_pipe: bool,
+    /// The read and write ends of the pipe, made when first needed.
+    pipe: Option<(OwnedFd, OwnedFd)>,
+    /// Each chunk is read back into this once copied, for hashes of its
+    /// data that could not be taken on the way.
+    read_back: Option<PooledBuffer>,
+}
+
+impl Splicer {
+    /// A splicer moving `piece_size` bytes at a time, which reads each chunk
+    /// back from the source into `read_back` when given one.
+    pub(crate) fn new(piece_size: usize, read_back: Option<PooledBuffer>) -> Self {
+        Splicer {
+            piece_size,
+            use_pipe: false,
+            pipe: None,
+            read_back,
+        }
+    }
+
+    /// Whether the data of the chunks is passed on to be hashed and counted.
+    /// Without it the progress is counted a whole chunk at a time.
+    pub(crate) fn shows_data(&self) -> bool {
+        self.read_back.is_some()
+    }
+
+    pub(crate) fn copy_chunk(
+        &mut self,
+        io: &ChunkIo,
+        chunk: &Range<u64>,
+        on_data: &mut dyn FnMut(&[u8]),
+    ) -> Result<u64, Error> {
+        let (Some(infile), Some(outfile)) = (io.infile.as_file(), io.outfile.as_file()) else {
+            return Err("The sendfile engine only copies between local files".into());
+        };
+        if io.direct {
+            return Err("The sendfile engine can't be used with direct IO".into());
+        }
+        if io.skip_zeros {
+            return Err("The sendfile engine never sees the data, it can't leave out zeros".into());
+        }
+        let (infd, outfd) = (infile.as_raw_fd(), outfile.as_raw_fd());
+        let mut pos = chunk.start;
+        while pos < chunk.end {
+            let len = self.piece_size.min((chunk.end - pos) as usize);
+            io.throttle(len);
+            let copied = if self.use_pipe {
+                self.splice(io, infd, outfd, pos, len)?
+            } else {
+                match copy_file_range(infd, outfd, pos, len) {
+                    Ok(copied) => copied,
+                    Err(e) if can_splice_instead(&e) => {
+                        self.use_pipe = true;
+                        self.splice(io, infd, outfd, pos, len)?
+                    }
+                    Err(e) => return Err(io_error("copy", &io.infile_path, pos, e)),
+                }
+            };
+            // The source got shorter, as the other engines do.
+            if copied == 0 {
+                break;
+            }
+            pos += copied as u64;
+        }
+
+        if let Some(buffer) = self.read_back.as_mut() {
+            let mut read = chunk.start;
+            while read < pos {
+                let want = buffer.len().min((pos - read) as usize);
+                let bytes_read = io.read_at(&mut buffer[..want], read)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                on_data(&buffer[..bytes_read]);
+                read += bytes_read as u64;
+            }
+        }
+        Ok(pos)
+    }
+
+    /// Move up to `len` bytes at `pos` from `infd` into the pipe and from
+    /// there to the same offset of `outfd`, returning how many were moved.
+    fn splice(
+        &mut self,
+        io: &ChunkIo,
+        infd: RawFd,
+        outfd: RawFd,
+        pos: u64,
+        len: usize,
+    ) -> Result<usize, Error> {
+        let (read_end, write_end) = match &self.pipe {
+            Some((read_end, write_end)) => (read_end.as_raw_fd(), write_end.as_raw_fd()),
+            None => {
+                let pipe = make_pipe(len).map_err(|e| format!("Failed to create a pipe: {}", e))?;
+                let fds = (pipe.0.as_raw_fd(), pipe.1.as_raw_fd());
+                self.pipe = Some(pipe);
+                fds
+            }
+        };
+        let mut off_in = pos as libc::loff_t;
+        let moved = loop {
+            let moved = unsafe {
+                libc::splice(
+                    infd,
+                    &mut off_in,
+                    write_end,
+                    std::ptr::null_mut(),
+                    len,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if moved >= 0 {
+                break moved as usize;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(io_error("read", &io.infile_path, pos, e));
+            }
+        };
+        let mut off_out = pos as libc::loff_t;
+        let mut left = moved;
+        while left > 0 {
+            let written = unsafe {
+                libc::splice(
+                    read_end,
+                    std::ptr::null_mut(),
+                    outfd,
+                    &mut off_out,
+                    left,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if written > 0 {
+                left -= written as usize;
+                continue;
+            }
+            let e = match written {
+                0 => io::ErrorKind::WriteZero.into(),
+                _ => io::Error::last_os_error(),
+            };
+            if e.kind() != io::ErrorKind::Interrupted {
+                // What is left in the pipe would end up in the next write.
+                self.pipe = None;
+                return Err(io_error("write", &io.outfile_path, off_out as u64, e));
+            }
+        }
+        Ok(moved)
+    }
+}
+
+fn copy_file_range(infd: RawFd, outfd: RawFd, pos: u64, len: usize) -> io::Result<usize> {
+    let (mut off_in, mut off_out) = (pos as libc::loff_t, pos as libc::loff_t);
+    loop {
+        let copied =
+            unsafe { libc::copy_file_range(infd, &mut off_in, outfd, &mut off_out, len, 0) };
+        if copied >= 0 {
+            return Ok(copied as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Errors from `copy_file_range` for files it does not work between, such
+/// as ones on different filesystems before Linux 5.3.
+fn can_splice_instead(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP | libc::ENOSYS | libc::EBADF)
+    )
+}
+
+/// A pipe holding `size` bytes if the kernel lets it, 64 KiB otherwise.
+fn make_pipe(size: usize) -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let pipe = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // Smaller pipes only mean more calls.
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    unsafe { libc::fcntl(fds[1], libc::F_SETPIPE_SZ, size) };
+    Ok(pipe)
+}
@@ -0,0 +1,431 @@
+use crate::copy::{copy_file, open_error};
+use crate::hash::{HashAlgorithm, Hasher};
+use crate::manifest::{manifest_line, parse_line};
+use crate::options::{CopyOptions, Overwrite};
+use crate::verify::mismatch;
+use crate::Error;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// The path of part `index` of a file split to `dest`, e.g. `file.part0003`.
+fn part_path(dest: &Path, index: u64) -> PathBuf {
+    let mut name = OsString::from(dest.as_os_str());
+    name.push(format!(".part{:04}", index));
+    PathBuf::from(name)
+}
+
+/// The digests of a file split to `dest` and of its parts, which `join`
+/// checks them with, e.g. `file.parts.b3`. It lists the file first and then
+/// the parts, like `b3sum` does.
+fn digests_path(dest: &Path) -> PathBuf {
+    let mut name = OsString::from(dest.as_os_str());
+    name.push(".parts.b3");
+    PathBuf::from(name)
+}
+
+/// `path` without a `.partNNNN` suffix, so either the name parts were
+/// split to or the path of one of them can be joined.
+fn split_name(path: &Path) -> PathBuf {
+    let name = path.to_string_lossy();
+    match name.rsplit_once(".part") {
+        Some((base, index)) if index.len() >= 4 && index.bytes().all(|b| b.is_ascii_digit()) => {
+            PathBuf::from(base)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Copy `src`, of `size` bytes, to parts of [`CopyOptions::split`] bytes
+/// named after `dest`, each with [`copy_file`], and remove parts left over
+/// from an earlier split into more of them. The parts are then read back
+/// for the digests of the file and of each part, which are written next to
+/// them for [`join_parts`]. Fails when `dest` is a directory, as a copy to
+/// it would. Returns the bytes copied.
+pub(crate) fn split_file(
+    src: &Path,
+    dest: &Path,
+    size: u64,
+    part_size: u64,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    if dest.is_dir() {
+        return Err(format!(
+            "Failed to create output file '{}': {}",
+            dest.display(),
+            io::Error::from(io::ErrorKind::IsADirectory)
+        )
+        .into());
+    }
+    // The source can only be moved once all of it is copied.
+    let part_options = options.clone().split(None).remove_source(false);
+    let parts = size.div_ceil(part_size);
+    let mut copied = 0;
+    for index in 0..parts {
+        let offset = index * part_size;
+        let options = part_options
+            .clone()
+            .skip(options.skip + offset)
+            .count(Some(part_size.min(size - offset)));
+        copied += copy_file(src, part_path(dest, index), &options)?;
+    }
+    if options.dry_run {
+        return Ok(copied);
+    }
+    for index in parts.. {
+        let stale = part_path(dest, index);
+        match fs::remove_file(&stale) {
+            Ok(()) => log::info!("Removed '{}' left from an earlier split", stale.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(format!("Failed to remove '{}': {}", stale.display(), e).into()),
+        }
+    }
+    write_digests(dest, parts)?;
+    if options.remove_source {
+        fs::remove_file(src)
+            .map_err(|e| format!("Failed to remove source '{}': {}", src.display(), e))?;
+    }
+    Ok(copied)
+}
+
+/// Hash the `parts` of the file split to `dest` into its digests file.
+fn write_digests(dest: &Path, parts: u64) -> Result<(), Error> {
+    let file_name = |path: &Path| PathBuf::from(path.file_name().unwrap_or_default());
+    let mut whole = HashAlgorithm::Blake3.hasher();
+    let mut lines = String::new();
+    for index in 0..parts {
+        let part = part_path(dest, index);
+        let digest = hash_range(&part, 0, u64::MAX, Some(&mut whole))?;
+        lines.push_str(&manifest_line(&digest, &file_name(&part)));
+    }
+    let lines = manifest_line(&whole.finish(), &file_name(dest)) + &lines;
+    let path = digests_path(dest);
+    fs::write(&path, lines)
+        .map_err(|e| format!("Failed to write '{}': {}", path.display(), e).into())
+}
+
+/// The BLAKE3 digest of `len` bytes of `path` from `offset`, or of the rest
+/// of it, which is also fed to `whole`.
+fn hash_range(
+    path: &Path,
+    offset: u64,
+    len: u64,
+    mut whole: Option<&mut Hasher>,
+) -> Result<String, Error> {
+    let mut file = File::open(path).map_err(|e| open_error(path, e))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut hasher = HashAlgorithm::Blake3.hasher();
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut file = file.take(len);
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..read]);
+        if let Some(whole) = whole.as_mut() {
+            whole.update(&buffer[..read]);
+        }
+    }
+}
+
+/// The digests of the file split to `name` and of its `parts`, from the
+/// digests file written with them.
+fn read_digests(name: &Path, parts: usize) -> Result<(String, Vec<String>), Error> {
+    let path = digests_path(name);
+    let text = fs::read_to_string(&path).map_err(|e| {
+        format!(
+            "Failed to read the digests of '{}' from '{}': {}",
+            name.display(),
+            path.display(),
+            e
+        )
+    })?;
+    let mut digests = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let Some((digest, listed)) = parse_line(line) else {
+            return Err(format!("'{}' line {} is not a digest", path.display(), number + 1).into());
+        };
+        let expected = match number {
+            0 => name.to_path_buf(),
+            _ => part_path(name, number as u64 - 1),
+        };
+        if Some(listed.as_os_str()) != expected.file_name() {
+            return Err(format!(
+                "'{}' lists '{}' where '{}' was expected",
+                path.display(),
+                listed.display(),
+                expected.display()
+            )
+            .into());
+        }
+        digests.push(digest);
+    }
+    if digests.len() != parts + 1 {
+        return Err(format!(
+            "'{}' lists {} parts, but there are {}",
+            path.display(),
+            digests.len().saturating_sub(1),
+            parts
+        )
+        .into());
+    }
+    let whole = digests.remove(0);
+    Ok((whole, digests))
+}
+
+/// Put the parts a file was split into with [`CopyOptions::split`] back
+/// together into `dest`. `parts` is the name they were split to, such as
+/// `file` for `file.part0000` and the parts after it, or the path of one of
+/// them.
+///
+/// Every part has to be as large as the first, except the last, which can
+/// be smaller, and a part missing between them fails the join before
+/// anything is written. Each part is copied into place with [`copy_file`]
+/// and so by `options.threads` threads. With [`CopyOptions::verify`] the
+/// parts are checked against the digests written when they were split
+/// before anything is joined, each is checked as it is written, and what
+/// was joined is read back and checked against the digest of the file that
+/// was split. `dest` is written in place, with
+/// [`CopyOptions::atomic`] and [`CopyOptions::part_suffix`] left out.
+/// A file no larger than a part, which is copied unsplit under its own
+/// name, is copied to `dest` as it is. Returns the bytes joined.
+pub fn join_parts<P: AsRef<Path>, Q: AsRef<Path>>(
+    parts: P,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let (name, dest) = (split_name(parts.as_ref()), dest.as_ref());
+    let first = options
+        .clone()
+        .split(None)
+        .skip(0)
+        .count(None)
+        .atomic(false)
+        .inplace(true)
+        .part_suffix(None);
+    let first_part = part_path(&name, 0);
+    if !first_part.exists() && name.is_file() {
+        return copy_file(&name, dest, &first);
+    }
+    let mut sizes = Vec::new();
+    loop {
+        let part = part_path(&name, sizes.len() as u64);
+        match fs::metadata(&part) {
+            Ok(metadata) => sizes.push(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !sizes.is_empty() => break,
+            Err(e) => return Err(open_error(&part, e)),
+        }
+    }
+    if let Some(later) = later_part(&name, sizes.len() as u64)? {
+        return Err(format!(
+            "'{}' is missing, but '{}' is there",
+            part_path(&name, sizes.len() as u64).display(),
+            later.display()
+        )
+        .into());
+    }
+    if let Some(index) = sizes[..sizes.len() - 1]
+        .iter()
+        .position(|&size| size != sizes[0])
+        .or_else(|| (sizes[sizes.len() - 1] > sizes[0]).then_some(sizes.len() - 1))
+    {
+        return Err(format!(
+            "'{}' is {} bytes, but the parts of '{}' are {} bytes",
+            part_path(&name, index as u64).display(),
+            sizes[index],
+            name.display(),
+            sizes[0]
+        )
+        .into());
+    }
+    let digests = if options.verify {
+        let (whole, parts) = read_digests(&name, sizes.len())?;
+        for (index, digest) in parts.iter().enumerate() {
+            let part = part_path(&name, index as u64);
+            if hash_range(&part, 0, u64::MAX, None)? != *digest {
+                return Err(mismatch(format!(
+                    "'{}' does not match its digest in '{}'",
+                    part.display(),
+                    digests_path(&name).display()
+                )));
+            }
+        }
+        Some(whole)
+    } else {
+        None
+    };
+    // What is joined so far is neither kept nor backed up again.
+    let rest = first
+        .clone()
+        .overwrite(Overwrite::Always)
+        .backup(None)
+        .backup_dir(None);
+    let mut offset = 0;
+    for (index, size) in sizes.iter().enumerate() {
+        let options = if index == 0 { &first } else { &rest };
+        let copied = copy_file(
+            part_path(&name, index as u64),
+            dest,
+            &options.clone().seek(options.seek + offset),
+        )?;
+        if copied == 0 && *size > 0 {
+            // The destination was kept, as Overwrite says.
+            break;
+        }
+        offset += size;
+    }
+    // Nothing was written on a dry run or when the destination was kept.
+    let joined = !options.dry_run && offset == sizes.iter().sum::<u64>();
+    if let Some(whole) = digests.filter(|_| joined) {
+        let joined = hash_range(dest, options.seek, offset, None)?;
+        if joined != whole {
+            return Err(mismatch(format!(
+                "'{}' does not match the digest of '{}' in '{}'",
+                dest.display(),
+                name.display(),
+                digests_path(&name).display()
+            )));
+        }
+    }
+    if options.remove_source && !options.dry_run {
+        let path = digests_path(&name);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(format!("Failed to remove '{}': {}", path.display(), e).into())
+            }
+            _ => {}
+        }
+    }
+    Ok(offset)
+}
+
+/// A part of `name` past part `missing`, which would be left out of a join.
+fn later_part(name: &Path, missing: u64) -> Result<Option<PathBuf>, Error> {
+    let dir = match name.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let base = name.file_name().unwrap_or_default().to_string_lossy();
+    for entry in fs::read_dir(dir).map_err(|e| open_error(dir, e))? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let later = match file_name.to_string_lossy().rsplit_once(".part") {
+            Some((stem, index)) if stem == base && index.len() >= 4 => {
+                index.parse().is_ok_and(|index: u64| index > missing)
+            }
+            _ => false,
+        };
+        if later {
+            return Ok(Some(name.with_file_name(file_name)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    #[test]
+    fn names_parts() {
+        assert_eq!(
+            part_path(Path::new("d/file"), 3),
+            Path::new("d/file.part0003")
+        );
+        assert_eq!(
+            split_name(Path::new("d/file.part0003")),
+            Path::new("d/file")
+        );
+        assert_eq!(
+            split_name(Path::new("d/file.part12345")),
+            Path::new("d/file")
+        );
+        assert_eq!(
+            split_name(Path::new("d/file.part1")),
+            Path::new("d/file.part1")
+        );
+        assert_eq!(split_name(Path::new("d/file")), Path::new("d/file"));
+    }
+
+    #[test]
+    fn splits_and_joins() {
+        let dir = scratch("split-join");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("big"), &data).unwrap();
+        fs::write(dir.join("small"), b"small").unwrap();
+        let options = CopyOptions::new().split(Some(40_000));
+        copy_file(dir.join("big"), dir.join("out"), &options).unwrap();
+        copy_file(dir.join("small"), dir.join("unsplit"), &options).unwrap();
+        assert_eq!(
+            fs::metadata(dir.join("out.part0002")).unwrap().len(),
+            20_000
+        );
+        assert!(dir.join("unsplit").is_file());
+
+        let options = CopyOptions::new().verify(true);
+        join_parts(dir.join("out.part0001"), dir.join("joined"), &options).unwrap();
+        assert_eq!(fs::read(dir.join("joined")).unwrap(), data);
+        join_parts(dir.join("unsplit"), dir.join("small2"), &options).unwrap();
+        assert_eq!(fs::read(dir.join("small2")).unwrap(), b"small");
+
+        fs::remove_file(dir.join("out.part0001")).unwrap();
+        assert!(join_parts(dir.join("out"), dir.join("gap"), &options).is_err());
+        assert!(!dir.join("gap").exists());
+    }
+
+    #[test]
+    fn checks_parts_against_digests() {
+        let dir = scratch("split-digests");
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("big"), &data).unwrap();
+        copy_file(
+            dir.join("big"),
+            dir.join("out"),
+            &CopyOptions::new().split(Some(400)),
+        )
+        .unwrap();
+        let digests = fs::read_to_string(dir.join("out.parts.b3")).unwrap();
+        let listed: Vec<_> = digests
+            .lines()
+            .map(|line| parse_line(line).unwrap().1)
+            .collect();
+        assert_eq!(
+            listed,
+            ["out", "out.part0000", "out.part0001", "out.part0002"].map(PathBuf::from)
+        );
+        let (whole, _) = read_digests(&dir.join("out"), 3).unwrap();
+        assert_eq!(whole, blake3::hash(&data).to_hex().as_str());
+
+        // A part changed after the split, as the same part of another file.
+        let mut changed = data[400..800].to_vec();
+        changed[0] ^= 1;
+        fs::write(dir.join("out.part0001"), &changed).unwrap();
+        let options = CopyOptions::new().verify(true);
+        let e = join_parts(dir.join("out"), dir.join("joined"), &options).unwrap_err();
+        assert!(e.is::<crate::VerifyError>());
+        assert!(!dir.join("joined").exists());
+        join_parts(dir.join("out"), dir.join("joined"), &CopyOptions::new()).unwrap();
+
+        fs::write(dir.join("out.part0001"), &data[400..800]).unwrap();
+        join_parts(dir.join("out"), dir.join("joined"), &options).unwrap();
+        assert_eq!(fs::read(dir.join("joined")).unwrap(), data);
+
+        fs::remove_file(dir.join("out.parts.b3")).unwrap();
+        assert!(join_parts(dir.join("out"), dir.join("joined"), &options).is_err());
+    }
+
+    #[test]
+    fn refuses_directory_destination() {
+        let dir = scratch("split-dir");
+        fs::write(dir.join("big"), [0; 1000]).unwrap();
+        fs::create_dir(dir.join("d")).unwrap();
+        let options = CopyOptions::new().split(Some(100));
+        assert!(copy_file(dir.join("big"), dir.join("d"), &options).is_err());
+        assert!(!dir.join("d.part0000").exists());
+    }
+}
@@ -0,0 +1,659 @@
+use crate::backend::StorageBackend;
+use crate::cancel::cancelled;
+use crate::copy::{chunk_size, chunks, io_error};
+use crate::metadata::set_times;
+use crate::options::{CopyOptions, Overwrite};
+use crate::prompt::confirm_overwrite;
+use crate::remote::Remote;
+use crate::tree::walk;
+use crate::verify::mismatch;
+use crate::Error;
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An SSH connection with an SFTP channel open on it.
+struct Connection {
+    sftp: Sftp,
+    // The channel only works as long as its session is alive.
+    _session: Session,
+}
+
+/// Connect and log in to the host of `remote`, after checking its key
+/// against `~/.ssh/known_hosts`.
+fn connect(remote: &Remote) -> Result<Connection, Error> {
+    let failed = |e: &dyn std::fmt::Display| -> Error {
+        format!("Failed to connect to {}: {}", remote.host, e).into()
+    };
+    let tcp = TcpStream::connect((remote.host.as_str(), remote.port)).map_err(|e| failed(&e))?;
+    let mut session = Session::new().map_err(|e| failed(&e))?;
+    session.set_tcp_stream(tcp);
+    session.handshake().map_err(|e| failed(&e))?;
+    check_host_key(&session, remote)?;
+
+    let user = match &remote.user {
+        Some(user) => user.clone(),
+        None => local_user().ok_or("Cannot tell which user to log in as, give it as user@host")?,
+    };
+    authenticate(&session, &user);
+    if !session.authenticated() {
+        return Err(format!(
+            "Failed to log in to {} as {}, load a key into ssh-agent",
+            remote.host, user
+        )
+        .into());
+    }
+    let sftp = session.sftp().map_err(|e| failed(&e))?;
+    Ok(Connection {
+        sftp,
+        _session: session,
+    })
+}
+
+/// The name of the user running the copy, to log in as by default.
+fn local_user() -> Option<String> {
+    if let Ok(user) = std::env::var("USER").or_else(|_| std::env::var("USERNAME")) {
+        return Some(user);
+    }
+    #[cfg(unix)]
+    if let Ok(Some(user)) = nix::unistd::User::from_uid(nix::unistd::getuid()) {
+        return Some(user.name);
+    }
+    None
+}
+
+fn home() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+fn check_host_key(session: &Session, remote: &Remote) -> Result<(), Error> {
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(home) = home() {
+        // Without the file every host is unknown.
+        let _ = known_hosts.read_file(
+            &home.join(".ssh").join("known_hosts"),
+            KnownHostFileKind::OpenSSH,
+        );
+    }
+    let (key, _) = session.host_key().ok_or("The server sent no host key")?;
+    let problem = match known_hosts.check_port(&remote.host, remote.port, key) {
+        CheckResult::Match => return Ok(()),
+        CheckResult::NotFound => "is not in known_hosts, connect with ssh once to check and add it",
+        CheckResult::Mismatch => "does not match the one in known_hosts",
+        CheckResult::Failure => "could not be checked",
+    };
+    Err(format!("The host key of {} {}", remote.host, problem).into())
+}
+
+/// Log in with the keys held by ssh-agent, or else the default keys in
+/// `~/.ssh` if they have no passphrase.
+fn authenticate(session: &Session, user: &str) {
+    if session.userauth_agent(user).is_ok() {
+        return;
+    }
+    let Some(home) = home() else {
+        return;
+    };
+    for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+        let key = home.join(".ssh").join(name);
+        if key.exists() && session.userauth_pubkey_file(user, None, &key, None).is_ok() {
+            return;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+/// A file to transfer.
+struct Transfer {
+    local: PathBuf,
+    remote: PathBuf,
+    size: u64,
+    /// Permissions and times of the source.
+    stat: FileStat,
+}
+
+/// Everything one upload or download is going to transfer.
+struct Plan {
+    direction: Direction,
+    host: Remote,
+    files: Vec<Transfer>,
+    /// Directories created at the destination, with the permissions and
+    /// times of their source.
+    dirs: Vec<(PathBuf, FileStat)>,
+}
+
+pub(crate) fn upload(src: &Path, dest: &Remote, options: &CopyOptions) -> Result<u64, Error> {
+    let connection = connect(dest)?;
+    let sftp = &connection.sftp;
+    let mut plan = Plan::new(Direction::Upload, dest);
+    let metadata =
+        fs::metadata(src).map_err(|e| format!("Failed to open '{}': {}", src.display(), e))?;
+    if !metadata.is_dir() {
+        let remote = match sftp.stat(&dest.path) {
+            Ok(stat) if stat.is_dir() => join(&dest.path, file_name(src)?),
+            _ => dest.path.clone(),
+        };
+        let existing = sftp.stat(&remote).ok();
+        plan.add(
+            src.to_path_buf(),
+            remote,
+            stat_of(&metadata),
+            existing,
+            options,
+        );
+        return plan.run(connection, options);
+    }
+
+    for entry in walk(src, options) {
+        let entry = entry?;
+        let remote = join(&dest.path, entry.path().strip_prefix(src)?);
+        let stat = stat_of(&entry.metadata()?);
+        if entry.file_type().is_dir() {
+            if sftp.stat(&remote).is_err() {
+                if options.dry_run {
+                    println!(
+                        "would create directory '{}'",
+                        dest.with_path(remote.clone())
+                    );
+                } else {
+                    sftp.mkdir(&remote, 0o755).map_err(|e| {
+                        format!(
+                            "Failed to create '{}': {}",
+                            dest.with_path(remote.clone()),
+                            e
+                        )
+                    })?;
+                }
+            }
+            plan.dirs.push((remote, stat));
+        } else if entry.file_type().is_symlink() {
+            if options.progress {
+                eprintln!(
+                    "Skipping '{}', links are not recreated on remote hosts",
+                    entry.path().display()
+                );
+            }
+        } else {
+            let existing = sftp.stat(&remote).ok();
+            plan.add(entry.path().to_path_buf(), remote, stat, existing, options);
+        }
+    }
+    plan.run(connection, options)
+}
+
+pub(crate) fn download(src: &Remote, dest: &Path, options: &CopyOptions) -> Result<u64, Error> {
+    let connection = connect(src)?;
+    let sftp = &connection.sftp;
+    let mut plan = Plan::new(Direction::Download, src);
+    let stat = sftp
+        .stat(&src.path)
+        .map_err(|e| format!("Failed to open '{}': {}", src, e))?;
+    if !stat.is_dir() {
+        let local = if dest.is_dir() {
+            dest.join(file_name(&src.path)?)
+        } else {
+            dest.to_path_buf()
+        };
+        let existing = fs::metadata(&local).ok().map(|m| stat_of(&m));
+        plan.add(local, src.path.clone(), stat, existing, options);
+        return plan.run(connection, options);
+    }
+
+    // Walked by hand, depth first, since there is no remote walkdir.
+    let mut pending = vec![(src.path.clone(), dest.to_path_buf(), stat)];
+    while let Some((remote, local, stat)) = pending.pop() {
+        if !local.is_dir() {
+            if options.dry_run {
+                println!("would create directory '{}'", local.display());
+            } else {
+                fs::create_dir_all(&local)?;
+            }
+        }
+        let entries = sftp
+            .readdir(&remote)
+            .map_err(|e| format!("Failed to list '{}': {}", src.with_path(remote.clone()), e))?;
+        plan.dirs.push((local.clone(), stat));
+        for (path, stat) in entries {
+            let name = file_name(&path)?;
+            let relative = path.strip_prefix(&src.path).unwrap_or(&path);
+            let local = local.join(name);
+            let is_dir = stat.is_dir();
+            if !options.filter.is_empty() && options.filter.is_excluded(relative, is_dir) {
+                continue;
+            }
+            if is_dir {
+                pending.push((path, local, stat));
+            } else if stat.is_file() {
+                let existing = fs::metadata(&local).ok().map(|m| stat_of(&m));
+                plan.add(local, path, stat, existing, options);
+            } else if options.progress {
+                eprintln!(
+                    "Skipping '{}', only files and directories are copied from remote hosts",
+                    src.with_path(path)
+                );
+            }
+        }
+    }
+    plan.run(connection, options)
+}
+
+impl Plan {
+    fn new(direction: Direction, host: &Remote) -> Self {
+        Plan {
+            direction,
+            host: host.clone(),
+            files: Vec::new(),
+            dirs: Vec::new(),
+        }
+    }
+
+    /// Where the data of `transfer` is read from and where it is written,
+    /// for messages.
+    fn describe(&self, transfer: &Transfer) -> (String, String) {
+        let local = transfer.local.display().to_string();
+        let remote = self.host.with_path(transfer.remote.clone()).to_string();
+        match self.direction {
+            Direction::Upload => (local, remote),
+            Direction::Download => (remote, local),
+        }
+    }
+
+    /// Add a file, with the metadata `stat` of its source, unless the
+    /// destination with the metadata `existing` is to be left alone.
+    fn add(
+        &mut self,
+        local: PathBuf,
+        remote: PathBuf,
+        stat: FileStat,
+        existing: Option<FileStat>,
+        options: &CopyOptions,
+    ) {
+        let transfer = Transfer {
+            local,
+            remote,
+            size: stat.size.unwrap_or(0),
+            stat,
+        };
+        let (from, to) = self.describe(&transfer);
+        if let Some(existing) = existing {
+            let stat = &transfer.stat;
+            if options.skip_identical && existing.size == stat.size && existing.mtime == stat.mtime
+            {
+                return;
+            }
+            let reason = match options.overwrite {
+                Overwrite::Always => None,
+                Overwrite::Never => Some("it already exists"),
+                Overwrite::Update => {
+                    (stat.mtime <= existing.mtime).then_some("it is not older than the source")
+                }
+                Overwrite::Ask if options.dry_run => None,
+                Overwrite::Ask => {
+                    (!confirm_overwrite(&to)).then_some("it was not to be overwritten")
+                }
+            };
+            if let Some(reason) = reason {
+                if options.dry_run {
+                    println!("would skip '{}', {}", to, reason);
+                }
+                return;
+            }
+        }
+        if options.dry_run {
+            println!(
+                "would copy '{}' -> '{}' ({} bytes)",
+                from, to, transfer.size
+            );
+        }
+        self.files.push(transfer);
+    }
+
+    /// Transfer the files in chunks over as many connections as there are
+    /// threads, the first one being `connection`.
+    fn run(self, connection: Connection, options: &CopyOptions) -> Result<u64, Error> {
+        let total_bytes: u64 = self.files.iter().map(|transfer| transfer.size).sum();
+        if options.dry_run {
+            return Ok(total_bytes);
+        }
+        // Created up front, so that chunks can be written to them in any order.
+        for transfer in &self.files {
+            self.create(&connection.sftp, transfer)?;
+        }
+
+        let mut pieces = Vec::new();
+        for (index, transfer) in self.files.iter().enumerate() {
+            let chunk_size = chunk_size(transfer.size, options.threads as u64, options.buffer_size);
+            let whole_file = 0..transfer.size;
+            for chunk in chunks(std::slice::from_ref(&whole_file), chunk_size) {
+                pieces.push((index, chunk));
+            }
+        }
+        let mut connections = vec![connection];
+        // One at a time, since servers drop connections that arrive together
+        // once too many are still logging in.
+        while connections.len() < options.threads.min(pieces.len()) {
+            connections.push(connect(&self.host)?);
+        }
+        if options.progress {
+            eprintln!(
+                " Copying {} files over {} connections to {}",
+                self.files.len(),
+                connections.len(),
+                self.host.host
+            );
+        }
+
+        let next_piece = AtomicUsize::new(0);
+        let bytes_copied = AtomicU64::new(0);
+        let failure: Mutex<Option<Error>> = Mutex::new(None);
+        thread::scope(|scope| {
+            for connection in &connections {
+                scope.spawn(|| {
+                    let mut buffers = (vec![0; options.buffer_size], Vec::new());
+                    while let Some((index, range)) =
+                        pieces.get(next_piece.fetch_add(1, Ordering::SeqCst))
+                    {
+                        if failure.lock().unwrap().is_some() || options.is_cancelled() {
+                            break;
+                        }
+                        let transfer = &self.files[*index];
+                        let result = self.copy_piece(
+                            &connection.sftp,
+                            transfer,
+                            range.clone(),
+                            &mut buffers,
+                            &bytes_copied,
+                            options,
+                        );
+                        if let Err(e) = result {
+                            failure.lock().unwrap().get_or_insert(e);
+                        }
+                    }
+                });
+            }
+        });
+
+        let bytes_copied = bytes_copied.into_inner();
+        if options.is_cancelled() && bytes_copied < total_bytes {
+            return Err(cancelled(format!(
+                "Copy over SSH interrupted after {} of {} bytes, the partial copies were kept",
+                bytes_copied, total_bytes
+            )));
+        }
+        if let Some(e) = failure.into_inner().unwrap() {
+            return Err(e);
+        }
+
+        if options.preserve {
+            let sftp = &connections[0].sftp;
+            for transfer in &self.files {
+                let dest = match self.direction {
+                    Direction::Upload => &transfer.remote,
+                    Direction::Download => &transfer.local,
+                };
+                self.set_stat(sftp, dest, &transfer.stat)?;
+            }
+            // Deepest first, since setting the times of a directory's
+            // contents does not change its own times.
+            for (dest, stat) in self.dirs.iter().rev() {
+                self.set_stat(sftp, dest, stat)?;
+            }
+        }
+        Ok(bytes_copied)
+    }
+
+    /// Create or truncate the destination of `transfer`.
+    fn create(&self, sftp: &Sftp, transfer: &Transfer) -> Result<(), Error> {
+        let (_, to) = self.describe(transfer);
+        let result = match self.direction {
+            Direction::Upload => sftp
+                .open_mode(
+                    &transfer.remote,
+                    OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                    0o644,
+                    OpenType::File,
+                )
+                .map(drop)
+                .map_err(io::Error::from),
+            Direction::Download => File::create(&transfer.local).map(drop),
+        };
+        result.map_err(|e| format!("Failed to create '{}': {}", to, e).into())
+    }
+
+    /// Copy `range` of `transfer`, then read it back from the destination
+    /// when verifying.
+    fn copy_piece(
+        &self,
+        sftp: &Sftp,
+        transfer: &Transfer,
+        range: Range<u64>,
+        (buffer, check): &mut (Vec<u8>, Vec<u8>),
+        bytes_copied: &AtomicU64,
+        options: &CopyOptions,
+    ) -> Result<(), Error> {
+        let remote_path = self.host.with_path(transfer.remote.clone()).to_string();
+        let remote_path = Path::new(&remote_path);
+        let (mut source, mut dest) = match self.direction {
+            Direction::Upload => {
+                let local = File::open(&transfer.local)
+                    .map_err(|e| io_error("open", &transfer.local, 0, e))?;
+                let flags = OpenFlags::READ | OpenFlags::WRITE;
+                let remote = sftp
+                    .open_mode(&transfer.remote, flags, 0o644, OpenType::File)
+                    .map_err(|e| io_error("open", remote_path, 0, e.into()))?;
+                (End::Local(local), End::Remote(remote))
+            }
+            Direction::Download => {
+                let remote = sftp
+                    .open(&transfer.remote)
+                    .map_err(|e| io_error("open", remote_path, 0, e.into()))?;
+                let local = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&transfer.local)
+                    .map_err(|e| io_error("open", &transfer.local, 0, e))?;
+                (End::Remote(remote), End::Local(local))
+            }
+        };
+        let (source_path, dest_path) = match self.direction {
+            Direction::Upload => (transfer.local.as_path(), remote_path),
+            Direction::Download => (remote_path, transfer.local.as_path()),
+        };
+
+        let mut offset = range.start;
+        while offset < range.end {
+            if options.is_cancelled() {
+                return Ok(());
+            }
+            let len = buffer.len().min((range.end - offset) as usize);
+            let data = &mut buffer[..len];
+            let bytes_read = source
+                .read_full(data, offset)
+                .map_err(|e| io_error("read", source_path, offset, e))?;
+            if bytes_read < len {
+                return Err(format!(
+                    "'{}' shrank to {} bytes while it was copied",
+                    source_path.display(),
+                    offset + bytes_read as u64
+                )
+                .into());
+            }
+            if let Some(limit) = &options.bwlimit {
+                limit.take(len);
+            }
+            dest.write_all_at(data, offset)
+                .map_err(|e| io_error("write", dest_path, offset, e))?;
+
+            if options.verify {
+                check.resize(len, 0);
+                let bytes_read = dest
+                    .read_full(check, offset)
+                    .map_err(|e| io_error("read", dest_path, offset, e))?;
+                if let Some(at) = (0..len).find(|&i| i >= bytes_read || check[i] != data[i]) {
+                    return Err(mismatch(format!(
+                        "'{}' differs from '{}' at byte {}",
+                        dest_path.display(),
+                        source_path.display(),
+                        offset + at as u64
+                    )));
+                }
+            }
+            bytes_copied.fetch_add(len as u64, Ordering::SeqCst);
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Give `dest` the permissions and times in `stat`.
+    fn set_stat(&self, sftp: &Sftp, dest: &Path, stat: &FileStat) -> Result<(), Error> {
+        let attributes = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: stat.perm.map(|perm| perm & 0o7777),
+            atime: stat.atime,
+            mtime: stat.mtime,
+        };
+        match self.direction {
+            Direction::Upload => sftp.setstat(dest, attributes).map_err(|e| {
+                format!(
+                    "Failed to set permissions and times of '{}': {}",
+                    self.host.with_path(dest.to_path_buf()),
+                    e
+                )
+                .into()
+            }),
+            Direction::Download => {
+                let time = |seconds: u64| UNIX_EPOCH + Duration::from_secs(seconds);
+                if let Some(mtime) = stat.mtime {
+                    set_times(dest, time(stat.atime.unwrap_or(mtime)), time(mtime))?;
+                }
+                if let Some(perm) = attributes.perm {
+                    fs::set_permissions(dest, permissions(dest, perm)?).map_err(|e| {
+                        format!("Failed to set permissions of '{}': {}", dest.display(), e)
+                    })?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// One end of a transfer, read and written at offsets.
+enum End {
+    Local(File),
+    Remote(ssh2::File),
+}
+
+impl End {
+    /// Read until `buffer` is full or the end of the file.
+    fn read_full(&mut self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut total = 0;
+        while total < buffer.len() {
+            let bytes_read = match self {
+                End::Local(file) => file.read_at(&mut buffer[total..], offset + total as u64)?,
+                End::Remote(file) => {
+                    file.seek(SeekFrom::Start(offset + total as u64))?;
+                    file.read(&mut buffer[total..])?
+                }
+            };
+            if bytes_read == 0 {
+                break;
+            }
+            total += bytes_read;
+        }
+        Ok(total)
+    }
+
+    fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()> {
+        match self {
+            End::Local(file) => {
+                StorageBackend::write_all_at(file, data, offset).map_err(|(_, e)| e)
+            }
+            End::Remote(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)
+            }
+        }
+    }
+}
+
+/// The permissions and times of a local file as SFTP attributes.
+fn stat_of(metadata: &fs::Metadata) -> FileStat {
+    let seconds = |time: io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs())
+    };
+    FileStat {
+        size: Some(metadata.len()),
+        uid: None,
+        gid: None,
+        perm: Some(mode_of(metadata)),
+        atime: seconds(metadata.accessed()),
+        mtime: seconds(metadata.modified()),
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+/// Windows only has the read-only flag to go on.
+#[cfg(not(unix))]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+    if metadata.permissions().readonly() {
+        mode & !0o222
+    } else {
+        mode
+    }
+}
+
+#[cfg(unix)]
+fn permissions(_dest: &Path, perm: u32) -> io::Result<fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::Permissions::from_mode(perm))
+}
+
+#[cfg(not(unix))]
+fn permissions(dest: &Path, perm: u32) -> io::Result<fs::Permissions> {
+    let mut permissions = fs::metadata(dest)?.permissions();
+    permissions.set_readonly(perm & 0o222 == 0);
+    Ok(permissions)
+}
+
+/// `relative` under the remote directory `base`, joined with `/` whatever
+/// the local separator.
+fn join(base: &Path, relative: &Path) -> PathBuf {
+    let mut path = base.to_string_lossy().into_owned();
+    for component in relative.components() {
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        path.push_str(&component.as_os_str().to_string_lossy());
+    }
+    PathBuf::from(path)
+}
+
+fn file_name(path: &Path) -> Result<&Path, Error> {
+    path.file_name()
+        .map(Path::new)
+        .ok_or_else(|| format!("Cannot copy '{}', it has no file name", path.display()).into())
+}
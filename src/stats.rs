@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts of what a copy did, handed to it with
+/// [`CopyOptions::stats`](crate::CopyOptions::stats) and read once it is
+/// done. They are kept for copies of local files.
+#[derive(Debug, Default)]
+pub struct CopyStats {
+    files_copied: AtomicU64,
+    files_skipped: AtomicU64,
+    files_failed: AtomicU64,
+    dirs_created: AtomicU64,
+    bytes: AtomicU64,
+    transferred: AtomicU64,
+    current_rate: AtomicU64,
+    peak_rate: AtomicU64,
+    retries: AtomicU64,
+    verification_failures: AtomicU64,
+}
+
+impl CopyStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn files_copied(&self) -> u64 {
+        self.files_copied.load(Ordering::SeqCst)
+    }
+
+    /// Files left alone because they were up to date or were not to be
+    /// overwritten.
+    pub fn files_skipped(&self) -> u64 {
+        self.files_skipped.load(Ordering::SeqCst)
+    }
+
+    pub fn files_failed(&self) -> u64 {
+        self.files_failed.load(Ordering::SeqCst)
+    }
+
+    pub fn dirs_created(&self) -> u64 {
+        self.dirs_created.load(Ordering::SeqCst)
+    }
+
+    /// Size of the files copied.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Bytes copied so far, those of files still being copied included.
+    /// Counted while the copy runs rather than as files finish.
+    pub fn bytes_transferred(&self) -> u64 {
+        self.transferred.load(Ordering::SeqCst)
+    }
+
+    /// The rate over the last couple of seconds, in bytes per second. 0 when
+    /// nothing is being copied.
+    pub fn current_rate(&self) -> u64 {
+        self.current_rate.load(Ordering::SeqCst)
+    }
+
+    /// The highest rate seen over a couple of seconds, in bytes per second.
+    /// 0 for copies too short to measure it.
+    pub fn peak_rate(&self) -> u64 {
+        self.peak_rate.load(Ordering::SeqCst)
+    }
+
+    /// Reads, writes and requests that failed and were tried again.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::SeqCst)
+    }
+
+    /// Files whose copy did not match the source.
+    pub fn verification_failures(&self) -> u64 {
+        self.verification_failures.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn copied(&self, bytes: u64) {
+        self.files_copied.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub(crate) fn skipped(&self) {
+        self.files_skipped.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn failed(&self) {
+        self.files_failed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn dir_created(&self) {
+        self.dirs_created.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn transferred(&self, bytes: u64) {
+        self.transferred.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub(crate) fn current(&self, rate: u64) {
+        self.current_rate.store(rate, Ordering::SeqCst);
+    }
+
+    pub(crate) fn rate(&self, rate: u64) {
+        self.peak_rate.fetch_max(rate, Ordering::SeqCst);
+    }
+
+    pub(crate) fn retried(&self) {
+        self.retries.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn verify_failed(&self, files: u64) {
+        self.verification_failures
+            .fetch_add(files, Ordering::SeqCst);
+    }
+}
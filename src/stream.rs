@@ -0,0 +1,294 @@
+use crate::backend::StorageBackend;
+use crate::cancel::cancelled;
+use crate::copy::{interrupted, io_error, open_error, skip_existing};
+use crate::fsync::sync_file;
+use crate::hash::HashAlgorithm;
+use crate::options::{CopyOptions, Overwrite};
+use crate::pipeline::{Queue, BUFFERS_PER_THREAD};
+use crate::pool::{take_buffers, PooledBuffer};
+use crate::progress::Progress;
+use crate::sparse::{is_zero, Sparse};
+use crate::verify::{read_full, verify_chunks, ChunkDigest};
+use crate::Error;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// Copy everything `reader` gives, such as stdin, to the file `dest`,
+/// returning the number of bytes copied.
+///
+/// Streams can only be read in order, so the data is read into buffers
+/// that up to `options.threads` writer threads write to their places in
+/// `dest`, as the writers of [`Engine::Pipeline`](crate::Engine::Pipeline)
+/// do, and a slow destination does not hold up the reads. The size is
+/// only known once the stream ends, so progress shows the bytes copied
+/// with no percentage.
+///
+/// With [`CopyOptions::verify`] the data is hashed as it is read and `dest`
+/// is read back to check it. [`CopyOptions::sparse`] with
+/// [`Sparse::Always`], [`CopyOptions::bwlimit`], [`CopyOptions::cancel`],
+/// [`CopyOptions::keep_partial`] and [`CopyOptions::fsync`] apply as they
+/// do to files, and [`Overwrite::Never`](crate::Overwrite::Never) leaves an
+/// existing `dest` alone. Other overwrite modes always replace it, as a
+/// stream has no time to compare.
+pub fn copy_from_reader<R: Read, Q: AsRef<Path>>(
+    mut reader: R,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let dest = dest.as_ref();
+    if options.overwrite == Overwrite::Never && dest.exists() {
+        skip_existing(dest, "it already exists", options);
+        return Ok(0);
+    }
+    let file = File::create(dest).map_err(|e| io_error("create", dest, 0, e))?;
+    let algorithm = options
+        .verify
+        .then(|| options.verify_hash.unwrap_or(HashAlgorithm::Xxh3));
+    let writers = options.threads.max(1);
+    let free = Queue::new(take_buffers(
+        options.buffer_pool.as_ref(),
+        options.buffer_size,
+        (writers + 1) * BUFFERS_PER_THREAD,
+        1,
+    ));
+    let pieces: Queue<(u64, usize, PooledBuffer)> = Queue::new([]);
+    let copied = Arc::new(AtomicU64::new(0));
+    let abort = AtomicBool::new(false);
+    let failure: Mutex<Option<Error>> = Mutex::new(None);
+    let skip_zeros = options.sparse == Sparse::Always;
+
+    let progress = Progress::unbounded(options);
+    progress.follow(Arc::clone(&copied));
+    let read = progress.show(|| {
+        thread::scope(|scope| {
+            for _ in 0..writers {
+                scope.spawn(|| {
+                    while let Some((offset, len, buffer)) = pieces.pop() {
+                        let data = &buffer[..len];
+                        let skip = skip_zeros && is_zero(data);
+                        if !abort.load(Ordering::SeqCst) && !skip {
+                            if let Some(bwlimit) = options.bwlimit.as_ref() {
+                                bwlimit.take(len);
+                            }
+                            if let Err((offset, e)) = file.write_all_at(data, offset) {
+                                failure
+                                    .lock()
+                                    .unwrap()
+                                    .get_or_insert(io_error("write", dest, offset, e));
+                                // The reader may be waiting for a buffer.
+                                abort.store(true, Ordering::SeqCst);
+                                free.close();
+                            }
+                        }
+                        copied.fetch_add(len as u64, Ordering::SeqCst);
+                        free.push(buffer);
+                    }
+                });
+            }
+            let read = read_stream(
+                &mut reader,
+                dest,
+                &free,
+                &pieces,
+                &abort,
+                algorithm,
+                options,
+            );
+            // The writers finish what was read and stop.
+            pieces.close();
+            read
+        })
+    });
+    if let Some(e) = failure.into_inner().unwrap() {
+        return Err(e);
+    }
+    let (size, digest) = read?;
+    if options.is_cancelled() {
+        drop(file);
+        return Err(interrupted(dest, size, None, false, options));
+    }
+    // Blocks of zeros left out at the end are holes up to it.
+    file.set_len(size)
+        .map_err(|e| io_error("allocate", dest, 0, e))?;
+    drop(file);
+
+    if let (Some(algorithm), Some(digest)) = (algorithm, digest) {
+        let chunk = ChunkDigest {
+            range: 0..size,
+            digest,
+        };
+        let file = File::open(dest).map_err(|e| io_error("open", dest, 0, e))?;
+        verify_chunks(dest, &file, size, &[chunk], algorithm, options)?;
+    }
+    sync_file(dest, options.fsync)?;
+    if let Some(stats) = &options.stats {
+        stats.copied(size);
+    }
+    Ok(size)
+}
+
+/// Read `reader` into the buffers from `free` until it ends, queuing each
+/// for the writers. Returns how much was read and, with `algorithm`, its
+/// digest.
+fn read_stream(
+    reader: &mut dyn Read,
+    dest: &Path,
+    free: &Queue<PooledBuffer>,
+    pieces: &Queue<(u64, usize, PooledBuffer)>,
+    abort: &AtomicBool,
+    algorithm: Option<HashAlgorithm>,
+    options: &CopyOptions,
+) -> Result<(u64, Option<String>), Error> {
+    let mut hasher = algorithm.map(|algorithm| algorithm.hasher());
+    let mut offset = 0;
+    while !abort.load(Ordering::SeqCst) && !options.is_cancelled() {
+        let Some(mut buffer) = free.pop() else {
+            break;
+        };
+        // Pipes hand over what they have, so reads are made until the
+        // buffer is full and the writes stay whole buffers.
+        let mut len = 0;
+        while len < buffer.len() {
+            match reader.read(&mut buffer[len..]) {
+                Ok(0) => break,
+                Ok(bytes_read) => len += bytes_read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to read the input for '{}' after {} bytes: {}",
+                        dest.display(),
+                        offset + len as u64,
+                        e
+                    )
+                    .into())
+                }
+            }
+        }
+        if len == 0 {
+            break;
+        }
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[..len]);
+        }
+        let full = len == buffer.len();
+        pieces.push((offset, len, buffer));
+        offset += len as u64;
+        if !full {
+            break;
+        }
+    }
+    Ok((offset, hasher.map(|hasher| hasher.finish())))
+}
+
+/// Copy the whole of `src` to `writer`, such as stdout, returning the
+/// number of bytes copied.
+///
+/// Up to `options.threads` reader threads read the file a buffer at a time
+/// from wherever they are at, as the readers of
+/// [`Engine::Pipeline`](crate::Engine::Pipeline) do, and the buffers are
+/// written out in order as they come in, so a pipe is kept full from
+/// storage that needs several reads in flight. [`CopyOptions::bwlimit`] and
+/// [`CopyOptions::cancel`] apply, and nothing can be read back to verify.
+pub fn copy_to_writer<P: AsRef<Path>, W: Write>(
+    src: P,
+    mut writer: W,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let src = src.as_ref();
+    let file = File::open(src).map_err(|e| open_error(src, e))?;
+    let size = StorageBackend::len(&file).map_err(|e| io_error("size", src, 0, e))?;
+    let buffer_size = options.buffer_size.max(1);
+    let readers = (options.threads.max(1) as u64).min(size.div_ceil(buffer_size as u64).max(1));
+    let free = Queue::new(take_buffers(
+        options.buffer_pool.as_ref(),
+        buffer_size,
+        (readers as usize + 1) * BUFFERS_PER_THREAD,
+        1,
+    ));
+    // Buffers read, by their number in the file, until they are written.
+    let ready: Mutex<BTreeMap<u64, io::Result<(usize, PooledBuffer)>>> = Mutex::default();
+    let arrived = Condvar::new();
+    let next_read = AtomicU64::new(0);
+    let stop = AtomicBool::new(false);
+    let copied = Arc::new(AtomicU64::new(0));
+
+    let progress = Progress::new(size, options);
+    progress.follow(Arc::clone(&copied));
+    let written = progress.show(|| {
+        thread::scope(|scope| {
+            for _ in 0..readers {
+                scope.spawn(|| {
+                    // A buffer is taken before the number to read into it, so
+                    // the one the writer waits for always has one.
+                    while let Some(mut buffer) = free.pop() {
+                        let index = next_read.fetch_add(1, Ordering::SeqCst);
+                        let offset = index * buffer_size as u64;
+                        if stop.load(Ordering::SeqCst) || offset >= size {
+                            break;
+                        }
+                        let want = buffer.len().min((size - offset) as usize);
+                        let read = read_full(&file, &mut buffer[..want], offset)
+                            .map(|bytes_read| (bytes_read, buffer));
+                        ready.lock().unwrap().insert(index, read);
+                        arrived.notify_all();
+                    }
+                });
+            }
+            let mut write = || -> Result<u64, Error> {
+                let mut written = 0;
+                let mut index = 0;
+                while written < size && !options.is_cancelled() {
+                    let mut pending = ready.lock().unwrap();
+                    let read = loop {
+                        if let Some(read) = pending.remove(&index) {
+                            break read;
+                        }
+                        pending = arrived.wait(pending).unwrap();
+                    };
+                    drop(pending);
+                    let (len, buffer) = read.map_err(|e| io_error("read", src, written, e))?;
+                    if let Some(bwlimit) = options.bwlimit.as_ref() {
+                        bwlimit.take(len);
+                    }
+                    writer
+                        .write_all(&buffer[..len])
+                        .map_err(|e| io_error("write", src, written, e))?;
+                    free.push(buffer);
+                    written += len as u64;
+                    copied.store(written, Ordering::SeqCst);
+                    index += 1;
+                    // The file got shorter while it was copied.
+                    if len < buffer_size && written < size {
+                        break;
+                    }
+                }
+                writer
+                    .flush()
+                    .map_err(|e| io_error("write", src, written, e))?;
+                Ok(written)
+            };
+            let written = write();
+            // Readers waiting for a buffer are let go.
+            stop.store(true, Ordering::SeqCst);
+            free.close();
+            written
+        })
+    })?;
+    if options.is_cancelled() && written < size {
+        return Err(cancelled(format!(
+            "Copy of '{}' interrupted after {} of {} bytes",
+            src.display(),
+            written,
+            size
+        )));
+    }
+    if let Some(stats) = &options.stats {
+        stats.copied(written);
+    }
+    Ok(written)
+}
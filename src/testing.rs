@@ -0,0 +1,13 @@
+//! Helpers for the unit tests.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// An empty directory for the test `name` to work in, left behind for a
+/// look when the test fails.
+pub(crate) fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rpcp-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,1485 @@
+use crate::backup::{back_up, backup_path, is_backup};
+use crate::budget::ThreadBudget;
+use crate::cancel::{cancelled, CancelledError};
+use crate::copy::{
+    can_batch, copied_message, copy_file, copy_small_file, itemize, keep_existing, skip_existing,
+    SMALL_FILE_SIZE,
+};
+use crate::fsync::{sync_dir, sync_file};
+use crate::hash::{digest, HashAlgorithm};
+use crate::logfile::FAILURE;
+use crate::metadata::preserve_metadata;
+use crate::offload::offload_copy;
+use crate::options::{CopyOptions, FileOrder, Fsync, Overwrite, Symlinks};
+use crate::partial::{CopyFailure, PartialCopyError};
+use crate::pipeline::Queue;
+use crate::progress::{Event, Progress};
+use crate::space::{check_space, needed_space, Space};
+use crate::special::{create_special, Special};
+use crate::walker::{walk_tree, TreeEntry};
+use crate::Error;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use walkdir::{DirEntry, WalkDir};
+
+/// Recursively copy the directory `src` into `dest`, returning the total
+/// number of bytes copied.
+///
+/// Directories are created as the tree is walked, and files are copied
+/// several at a time with [`copy_file`] once it is or, with
+/// [`CopyOptions::walk_threads`], as they are found. Small files get one
+/// thread each and large files are split over as many threads as are free,
+/// without going over `options.threads` in total.
+///
+/// Symbolic links are handled as set with [`CopyOptions::symlinks`] and
+/// [`CopyOptions::safe_links`], and entries can be left out with
+/// [`CopyOptions::filter`], [`CopyOptions::max_depth`] and
+/// [`CopyOptions::one_file_system`].
+///
+/// Files already copied are left out with [`CopyOptions::skip_identical`],
+/// and existing files and links are otherwise replaced or left alone as set
+/// with [`CopyOptions::overwrite`].
+///
+/// Files about to be replaced or deleted are moved aside with
+/// [`CopyOptions::backup`] and [`CopyOptions::backup_dir`], the latter
+/// keeping their path relative to `dest`.
+///
+/// With [`CopyOptions::hard_links`] files linked to one another in `src`
+/// are copied once and linked the same way in `dest`.
+///
+/// With [`CopyOptions::remove_source`] files are removed from `src` as they
+/// are copied, and the directories left empty once the copy is done.
+///
+/// With [`CopyOptions::delete`], entries of `dest` that are not in `src` are
+/// removed once all files are copied.
+///
+/// Once [`CopyOptions::cancel`] is set no more files are started, and the
+/// copy fails with a [`CancelledError`] saying how far it got. The first
+/// file that fails stops the copy, unless [`CopyOptions::continue_on_error`]
+/// is set.
+///
+/// With [`CopyOptions::dry_run`] nothing is written, the directories, links
+/// and files that would be created or overwritten are printed to stdout
+/// instead, along with what would be deleted, and the returned total is what
+/// would be copied.
+pub fn copy_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    Plan::new(dest, options).run(options, |plan| plan.add_tree(src, dest, options))
+}
+
+/// Copy each of `sources` into the existing directory `dir`, under its own
+/// file name, returning the total number of bytes copied.
+///
+/// Sources that are directories are copied as with [`copy_tree`]. The files
+/// of all sources share one pool of `options.threads` threads, so several
+/// small sources are copied at the same time.
+pub fn copy_into<P: AsRef<Path>, Q: AsRef<Path>>(
+    sources: &[P],
+    dir: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let dir = dir.as_ref();
+    if !dir.is_dir() {
+        return Err(format!("Target '{}' is not a directory", dir.display()).into());
+    }
+    Plan::new(dir, options).run(options, |plan| {
+        for src in sources {
+            let src = src.as_ref();
+            let name = src
+                .file_name()
+                .ok_or_else(|| format!("Cannot copy '{}', it has no file name", src.display()))?;
+            let dest = dir.join(name);
+            let metadata = fs::metadata(src)
+                .map_err(|e| format!("Failed to open '{}': {}", src.display(), e))?;
+            if metadata.is_dir() {
+                plan.add_tree(src, &dest, options)?;
+            } else if let Some(special) = Special::of(&metadata.file_type()) {
+                copy_special(src, &dest, special, &metadata, options)?;
+            } else {
+                plan.add_file(src, dest, &metadata, options)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Copy the entries of `src` listed in `paths`, which are relative to `src`,
+/// to the same relative paths under `dest`, returning the total number of
+/// bytes copied.
+///
+/// Directories the entries are in are created as needed, and listed
+/// directories are copied as with [`copy_tree`]. Entries listed more than
+/// once are copied once. The files of all entries share one pool of
+/// `options.threads` threads.
+pub fn copy_listed<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    src: P,
+    paths: &[R],
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    let mut relative_paths = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        let mut relative_path = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => relative_path.push(name),
+                Component::CurDir => {}
+                _ => {
+                    return Err(format!(
+                        "Cannot copy '{}', listed paths must be inside the source",
+                        path.display()
+                    )
+                    .into())
+                }
+            }
+        }
+        relative_paths.push(relative_path);
+    }
+    Plan::new(dest, options).run(options, |plan| {
+        for relative_path in &relative_paths {
+            let (path, dest_path) = (src.join(relative_path), dest.join(relative_path));
+            let result = (|| {
+                let metadata = fs::metadata(&path)
+                    .map_err(|e| format!("Failed to open '{}': {}", path.display(), e))?;
+                if let Some(parent) = dest_path.parent().filter(|_| !options.dry_run) {
+                    create_dir_all(parent)?;
+                }
+                if metadata.is_dir() {
+                    plan.add_tree(&path, &dest_path, options)
+                } else if let Some(special) = Special::of(&metadata.file_type()) {
+                    copy_special(&path, &dest_path, special, &metadata, options)
+                } else {
+                    plan.add_file(&path, dest_path, &metadata, options)
+                }
+            })();
+            plan.record(&path, result, options)?;
+        }
+        Ok(())
+    })
+}
+
+/// A file a copy of a tree is to copy.
+struct FileCopy {
+    src: PathBuf,
+    dest: PathBuf,
+    size: u64,
+    /// Why it is copied over what is at `dest`, with [`CopyOptions::itemize`].
+    update: Option<&'static str>,
+    /// The metadata of `src` when `dest` is a file of the same size and the
+    /// thread copying it compares their digests first, with
+    /// [`CopyOptions::checksum`].
+    compare: Option<fs::Metadata>,
+}
+
+/// What the threads copying the files of a [`Plan`] share with it.
+struct Shared {
+    /// Runs of files threads take at once, as [`batches`] splits them.
+    queue: Queue<Vec<FileCopy>>,
+    /// Files queued so far.
+    queued: AtomicUsize,
+    /// What could not be copied, with [`CopyOptions::continue_on_error`].
+    failures: Mutex<Vec<CopyFailure>>,
+    /// The error that stops the copy.
+    failure: Mutex<Option<Error>>,
+    /// Whether more entries failed than [`CopyOptions::max_errors`] allows.
+    gave_up: AtomicBool,
+    /// One bar follows all the files, rather than one bar for each, and the
+    /// lines for copied files are printed above it.
+    progress: Progress,
+}
+
+impl Shared {
+    /// Whether no more files are to be copied.
+    fn stopped(&self, options: &CopyOptions) -> bool {
+        self.failure.lock().unwrap().is_some()
+            || self.gave_up.load(Ordering::SeqCst)
+            || options.is_cancelled()
+    }
+
+    /// Stop the copy with `e`, unless it was stopped already.
+    fn stop(&self, e: Error) {
+        self.failure.lock().unwrap().get_or_insert(e);
+    }
+
+    /// Note down that `path` could not be copied, giving up once more
+    /// entries failed than [`CopyOptions::max_errors`] allows.
+    fn fail(&self, path: &Path, e: &Error, options: &CopyOptions) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.push(CopyFailure {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        });
+        if options.max_errors.is_some_and(|max| failures.len() > max) {
+            self.gave_up.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// What a copy of one or more trees does, gathered as they are walked.
+///
+/// With [`CopyOptions::walk_threads`] above 1 files are handed to the
+/// threads copying them as they are found. Otherwise, and when the order
+/// they are copied in or the questions asked about them need all of them
+/// first, they are copied once the walk is done.
+struct Plan {
+    /// The destination of the copy.
+    root: PathBuf,
+    /// Whether files are copied as they are found.
+    streaming: bool,
+    /// How many small files a thread takes at once.
+    batch: usize,
+    /// Files to copy once the walk is done or, when streaming, the small
+    /// files waiting for a batch to fill.
+    files: Vec<FileCopy>,
+    /// Directories created, with their source and metadata.
+    dirs: Vec<(PathBuf, PathBuf, fs::Metadata)>,
+    /// Destination trees to delete extraneous entries from, with the
+    /// relative paths of their sources.
+    mirrors: Vec<(PathBuf, HashSet<PathBuf>)>,
+    /// Destinations of the files, so none is copied to twice.
+    dests: HashSet<PathBuf>,
+    /// Destination of the first file seen with each device and inode, with
+    /// [`CopyOptions::hard_links`].
+    inodes: HashMap<(u64, u64), PathBuf>,
+    /// Files to link to the copy of another, or to their unchanged copy in
+    /// [`CopyOptions::link_dest`], with their destination and the file they
+    /// are linked to.
+    links: Vec<(PathBuf, PathBuf, PathBuf)>,
+    /// Files to clone from their unchanged copy in
+    /// [`CopyOptions::reflink_dest`], with their destination and the copy.
+    clones: Vec<(PathBuf, PathBuf, PathBuf)>,
+    /// Space the files take up once copied.
+    space: u64,
+    /// The space available when streaming, which the files found are
+    /// checked against before they are copied.
+    available: Option<Space>,
+    /// Whether a walk stopped before the end of its tree.
+    incomplete: bool,
+    shared: Arc<Shared>,
+}
+
+impl Plan {
+    fn new(root: &Path, options: &CopyOptions) -> Self {
+        let streaming = options.walk_threads > 1
+            && !options.dry_run
+            && options.order == FileOrder::Walk
+            && options.overwrite != Overwrite::Ask;
+        Plan {
+            root: root.to_path_buf(),
+            streaming,
+            batch: if can_batch(options) { options.batch } else { 1 },
+            files: Vec::new(),
+            dirs: Vec::new(),
+            mirrors: Vec::new(),
+            dests: HashSet::new(),
+            inodes: HashMap::new(),
+            links: Vec::new(),
+            clones: Vec::new(),
+            space: 0,
+            available: (streaming && options.space_check)
+                .then(|| Space::of(root))
+                .flatten(),
+            incomplete: false,
+            shared: Arc::new(Shared {
+                queue: Queue::new([]),
+                queued: AtomicUsize::new(0),
+                failures: Mutex::new(Vec::new()),
+                failure: Mutex::new(None),
+                gave_up: AtomicBool::new(false),
+                progress: Progress::new(0, options),
+            }),
+        }
+    }
+
+    /// Walk the tree `src`, creating the directories and links it holds
+    /// under `dest` and adding its files.
+    fn add_tree(&mut self, src: &Path, dest: &Path, options: &CopyOptions) -> Result<(), Error> {
+        let mut sources = HashSet::new();
+        for entry in walk_tree(src, options) {
+            if self.shared.stopped(options) {
+                self.incomplete = true;
+                break;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err((path, e)) => {
+                    self.record(&path, Err(e), options)?;
+                    continue;
+                }
+            };
+            let path = entry.path.as_path();
+            let relative_path = path.strip_prefix(src)?;
+            if options.delete {
+                sources.insert(relative_path.to_path_buf());
+            }
+            let dest_path = if entry.depth == 0 {
+                dest.to_path_buf()
+            } else {
+                dest.join(relative_path)
+            };
+            let result = self.add_entry(&entry, dest_path, options);
+            self.record(path, result, options)?;
+        }
+        if options.delete {
+            self.mirrors.push((dest.to_path_buf(), sources));
+        }
+        Ok(())
+    }
+
+    /// Create the directory or link `entry` of a walked tree at `dest`, or
+    /// add the file.
+    fn add_entry(
+        &mut self,
+        entry: &TreeEntry,
+        dest_path: PathBuf,
+        options: &CopyOptions,
+    ) -> Result<(), Error> {
+        let (path, metadata) = (entry.path.as_path(), &entry.metadata);
+        if metadata.is_dir() {
+            if options.dry_run {
+                if !dest_path.exists() {
+                    println!("would create directory '{}'", dest_path.display());
+                }
+                return Ok(());
+            }
+            if !dest_path.is_dir() {
+                itemize("created", &dest_path.join(""), None, options);
+                if options.verbosity > 1 {
+                    eprintln!(" Create directory '{}'", dest_path.display());
+                }
+                if let Some(stats) = &options.stats {
+                    stats.dir_created();
+                }
+            }
+            create_dir_all(&dest_path)?;
+            self.dirs
+                .push((path.to_path_buf(), dest_path, metadata.clone()));
+        } else if metadata.is_symlink() {
+            // Only seen when links are recreated rather than followed.
+            let target = fs::read_link(path)?;
+            let existing = dest_path.symlink_metadata();
+            if options.skip_identical
+                && existing.as_ref().is_ok_and(|m| m.file_type().is_symlink())
+                && fs::read_link(&dest_path).is_ok_and(|existing| existing == target)
+            {
+                log::info!("Skipped '{}', it is up to date", dest_path.display());
+                itemize("identical", &dest_path, None, options);
+                if let Some(stats) = &options.stats {
+                    stats.skipped();
+                }
+                return Ok(());
+            }
+            if let Some(reason) = keep_existing(metadata, &dest_path, options) {
+                if options.dry_run {
+                    println!("would skip '{}', {}", dest_path.display(), reason);
+                }
+                return Ok(());
+            }
+            if existing.is_ok_and(|m| m.is_dir()) {
+                return Err(format!(
+                    "Cannot replace directory '{}' with a symlink",
+                    dest_path.display()
+                )
+                .into());
+            }
+            if options.dry_run {
+                println!(
+                    "would link '{}' -> '{}'",
+                    dest_path.display(),
+                    target.display()
+                );
+                return Ok(());
+            }
+            if options.verbosity > 1 {
+                eprintln!(" Link '{}' -> '{}'", dest_path.display(), target.display());
+            }
+            let replaced = dest_path.symlink_metadata().is_ok();
+            copy_symlink(path, &dest_path)?;
+            preserve_metadata(path, metadata, &dest_path, options)?;
+            let target = format!("-> {}", target.display());
+            itemize(
+                if replaced { "updated" } else { "created" },
+                &dest_path,
+                Some(&target),
+                options,
+            );
+            if options.remove_source {
+                fs::remove_file(path)?;
+            }
+        } else if let Some(special) = Special::of(&metadata.file_type()) {
+            copy_special(path, &dest_path, special, metadata, options)?;
+        } else {
+            self.add_file(path, dest_path, metadata, options)?;
+        }
+        Ok(())
+    }
+
+    /// Pass on the error in `result` from copying `path`, or note it down
+    /// with [`CopyOptions::continue_on_error`].
+    fn record(
+        &mut self,
+        path: &Path,
+        result: Result<(), Error>,
+        options: &CopyOptions,
+    ) -> Result<(), Error> {
+        match result {
+            Err(e) if options.continue_on_error => {
+                log::error!(target: FAILURE, "Failed to copy '{}': {}", path.display(), e);
+                self.shared.fail(path, &e, options);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Add the file `src`, which has the metadata `metadata`, unless `dest`
+    /// already holds a copy of it or is already being copied to. Files with
+    /// an unchanged copy in [`CopyOptions::link_dest`] are linked to it, and
+    /// those with one in [`CopyOptions::reflink_dest`] cloned from it.
+    fn add_file(
+        &mut self,
+        src: &Path,
+        dest: PathBuf,
+        metadata: &fs::Metadata,
+        options: &CopyOptions,
+    ) -> Result<(), Error> {
+        if self.dests.contains(&dest) {
+            return Ok(());
+        }
+        // Files that are not copied are read for the manifest, unless their
+        // digest is taken while they are.
+        let manifest = options.manifest.as_ref().filter(|_| !options.dry_run);
+        if let Some(inode) = inode(metadata).filter(|_| options.hard_links) {
+            match self.inodes.entry(inode) {
+                Entry::Occupied(first) => {
+                    if let Some(manifest) = manifest {
+                        manifest.link(&dest, first.get());
+                    }
+                    self.dests.insert(dest.clone());
+                    self.links
+                        .push((src.to_path_buf(), dest, first.get().clone()));
+                    return Ok(());
+                }
+                Entry::Vacant(first) => {
+                    first.insert(dest.clone());
+                }
+            }
+        }
+        if let Some(manifest) = manifest {
+            manifest.add(src, &dest, options);
+        }
+        let mut compare = None;
+        if options.skip_identical {
+            // The digests of files that may be identical are taken by the
+            // threads copying them, unless a previous copy is looked for
+            // first.
+            let deferred = options.checksum
+                && !options.dry_run
+                && options.link_dest.is_none()
+                && options.reflink_dest.is_none();
+            if deferred {
+                compare = same_size(metadata, &dest, options).then(|| metadata.clone());
+            } else if is_identical(src, metadata, &dest, options)? {
+                return skip_identical(src, metadata, &dest, options);
+            }
+        }
+        let relative = dest
+            .strip_prefix(&self.root)
+            .ok()
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .or_else(|| dest.file_name().map(Path::new));
+        if let Some(relative) = relative {
+            if let Some(previous) = options.link_dest.as_ref().map(|dir| dir.join(relative)) {
+                if is_unchanged(src, metadata, &previous, options)? {
+                    self.dests.insert(dest.clone());
+                    self.links.push((src.to_path_buf(), dest, previous));
+                    return Ok(());
+                }
+            }
+            if let Some(previous) = options.reflink_dest.as_ref().map(|dir| dir.join(relative)) {
+                // The clone gets the metadata of the source, whatever the
+                // previous copy has.
+                if is_identical(src, metadata, &previous, options)? {
+                    self.dests.insert(dest.clone());
+                    self.clones.push((src.to_path_buf(), dest, previous));
+                    return Ok(());
+                }
+            }
+        }
+        // Decided before any file is copied, so questions are not asked over
+        // the progress and only files that are copied are itemized as such.
+        let declined = (options.overwrite != Overwrite::Always)
+            .then(|| keep_existing(metadata, &dest, options))
+            .flatten();
+        if let Some(reason) = declined {
+            skip_existing(&dest, reason, options);
+        } else {
+            // Files that turn out to be identical take no more space.
+            if compare.is_none() {
+                self.space += needed_space(metadata, &dest, options);
+            }
+            self.dests.insert(dest.clone());
+            self.queue(FileCopy {
+                src: src.to_path_buf(),
+                update: update_reason(metadata, &dest, options),
+                dest,
+                size: metadata.len(),
+                compare,
+            });
+        }
+        Ok(())
+    }
+
+    /// Hand `file` to the threads copying the files when streaming, once
+    /// the space it needs is checked, or keep it until the walk is done.
+    fn queue(&mut self, file: FileCopy) {
+        if self.streaming {
+            if let Some(Err(e)) = self.available.as_ref().map(|space| space.check(self.space)) {
+                self.shared.stop(e);
+                return;
+            }
+        }
+        // Files compared first are counted once they turn out to differ.
+        if file.compare.is_none() {
+            self.shared.progress.grow(file.size);
+        }
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        if !self.streaming {
+            self.files.push(file);
+        } else if file.size < SMALL_FILE_SIZE && self.batch > 1 {
+            self.files.push(file);
+            if self.files.len() >= self.batch {
+                self.flush();
+            }
+        } else {
+            self.shared.queue.push(vec![file]);
+        }
+    }
+
+    /// Hand the small files waiting for a batch to fill to the threads.
+    fn flush(&mut self) {
+        if !self.files.is_empty() {
+            self.shared.queue.push(std::mem::take(&mut self.files));
+        }
+    }
+
+    /// Copy the files `add` adds to the plan, then delete extraneous
+    /// entries and finish the directories.
+    fn run(
+        mut self,
+        options: &CopyOptions,
+        add: impl FnOnce(&mut Plan) -> Result<(), Error>,
+    ) -> Result<u64, Error> {
+        let mut options = options.clone();
+        options.backup_root = Some(self.root.clone());
+        let options = &options;
+        let shared = Arc::clone(&self.shared);
+        let mut add = Some(add);
+        let threads = if self.streaming {
+            options.threads
+        } else {
+            (add.take().unwrap())(&mut self)?;
+            if options.dry_run {
+                return self.dry_run(options);
+            }
+            if options.space_check {
+                check_space(&self.root, self.space)?;
+            }
+            let mut files = std::mem::take(&mut self.files);
+            sort_files(&mut files, options.order);
+            let batches = batches(files, self.batch);
+            let threads = options.threads.min(batches.len());
+            for batch in batches {
+                shared.queue.push(batch);
+            }
+            shared.queue.close();
+            threads
+        };
+
+        // The space for all the files was checked already.
+        let mut file_options = options
+            .clone()
+            .progress(false)
+            .verbosity(0)
+            .space_check(false);
+        if options.overwrite != Overwrite::Always {
+            // Every file was decided on already.
+            file_options = file_options.overwrite(Overwrite::Always);
+        }
+        let copier = Copier {
+            shared: &shared,
+            budget: ThreadBudget::new(options.threads),
+            batching: can_batch(options),
+            file_options,
+            options,
+            bytes_copied: AtomicU64::new(0),
+            files_copied: AtomicUsize::new(0),
+        };
+        shared.progress.show(|| {
+            thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(|| copier.copy_queued());
+                }
+                if let Some(add) = add {
+                    if let Err(e) = add(&mut self) {
+                        shared.stop(e);
+                    }
+                    self.flush();
+                    shared.queue.close();
+                }
+            })
+        });
+
+        let failure = shared.failure.lock().unwrap().take();
+        let files_copied = copier.files_copied.into_inner();
+        let queued = shared.queued.load(Ordering::SeqCst);
+        if options.is_cancelled() && (self.incomplete || files_copied < queued) {
+            // Files being copied when it happened report what became of them.
+            let in_progress = failure
+                .filter(|e| e.is::<CancelledError>())
+                .map_or(String::new(), |e| format!(". {}", e));
+            let found = if self.incomplete { " found" } else { "" };
+            return Err(cancelled(format!(
+                "Interrupted after copying {} of {}{} files, {} bytes{}",
+                files_copied,
+                queued,
+                found,
+                copier.bytes_copied.into_inner(),
+                in_progress
+            )));
+        }
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        let mut failures = std::mem::take(&mut *shared.failures.lock().unwrap());
+        if options.max_errors.is_some_and(|max| failures.len() > max) {
+            return Err(Box::new(PartialCopyError::new(
+                failures,
+                options.max_errors,
+            )));
+        }
+
+        let Plan {
+            dirs,
+            mirrors,
+            links,
+            clones,
+            ..
+        } = self;
+        let mut total_bytes_copied = copier.bytes_copied.into_inner();
+        for (path, dest_path, previous) in &clones {
+            match clone_file(path, dest_path, previous, options) {
+                Ok(bytes_copied) => {
+                    let reason = format!("from {}", previous.display());
+                    itemize("cloned", dest_path, Some(&reason), options);
+                    total_bytes_copied += bytes_copied
+                }
+                Err(e) if options.continue_on_error => failures.push(CopyFailure {
+                    path: path.clone(),
+                    reason: e.to_string(),
+                }),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Links go in once the files they link to are copied.
+        for (path, dest_path, target) in &links {
+            match link_file(path, dest_path, target, options) {
+                Err(e) if options.continue_on_error => failures.push(CopyFailure {
+                    path: path.clone(),
+                    reason: e.to_string(),
+                }),
+                result => result?,
+            }
+        }
+
+        // Entries that failed to copy would look extraneous, so nothing is
+        // deleted after a failure.
+        if failures.is_empty() {
+            for (dest, sources) in &mirrors {
+                delete_extraneous(dest, sources, options)?;
+            }
+        }
+
+        // Directory times change as files are created and deleted in them, so
+        // they are set last, deepest first.
+        for (path, dest_path, metadata) in dirs.iter().rev() {
+            preserve_metadata(path, metadata, dest_path, options)?;
+        }
+        if options.fsync != Fsync::Off {
+            // The parent of the top directory holds the entry for it.
+            let parents = dirs.first().and_then(|(_, top, _)| top.parent());
+            for dest_path in dirs.iter().rev().map(|(_, dest, _)| &**dest).chain(parents) {
+                sync_dir(dest_path)?;
+            }
+        }
+        if options.remove_source {
+            for (path, _, _) in dirs.iter().rev() {
+                match fs::remove_dir(path) {
+                    Ok(()) => {}
+                    // Files left out of the copy are left in the source too.
+                    Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {}
+                    Err(e) => {
+                        return Err(
+                            format!("Failed to remove source '{}': {}", path.display(), e).into(),
+                        )
+                    }
+                }
+            }
+        }
+        if !failures.is_empty() {
+            return Err(Box::new(PartialCopyError::new(failures, None)));
+        }
+        Ok(total_bytes_copied)
+    }
+
+    /// Print what the copy would do, returning what it would copy.
+    fn dry_run(self, options: &CopyOptions) -> Result<u64, Error> {
+        // Listed in walk order rather than in the order threads get to them.
+        let mut total_bytes = 0;
+        for file in &self.files {
+            total_bytes += copy_file(&file.src, &file.dest, options)?;
+        }
+        for (_, dest_path, target) in &self.links {
+            println!(
+                "would hard link '{}' -> '{}'",
+                dest_path.display(),
+                target.display()
+            );
+        }
+        for (_, dest_path, previous) in &self.clones {
+            println!(
+                "would clone '{}' from '{}'",
+                dest_path.display(),
+                previous.display()
+            );
+        }
+        let failures = std::mem::take(&mut *self.shared.failures.lock().unwrap());
+        if !failures.is_empty() {
+            return Err(Box::new(PartialCopyError::new(failures, None)));
+        }
+        for (dest, sources) in &self.mirrors {
+            delete_extraneous(dest, sources, options)?;
+        }
+        Ok(total_bytes)
+    }
+}
+
+/// What each of the threads copying the files of a [`Plan`] works with.
+struct Copier<'a> {
+    shared: &'a Shared,
+    budget: ThreadBudget,
+    batching: bool,
+    /// The options files are copied with.
+    file_options: CopyOptions,
+    options: &'a CopyOptions,
+    bytes_copied: AtomicU64,
+    files_copied: AtomicUsize,
+}
+
+impl Copier<'_> {
+    /// Copy the files queued until there are no more or the copy stops.
+    fn copy_queued(&self) {
+        let mut buffer = Vec::new();
+        while let Some(batch) = self.shared.queue.pop() {
+            for file in &batch {
+                if self.shared.stopped(self.options) {
+                    return;
+                }
+                if let Some(source) = &file.compare {
+                    match is_identical(&file.src, source, &file.dest, self.options) {
+                        Ok(true) => {
+                            if let Err(e) =
+                                skip_identical(&file.src, source, &file.dest, self.options)
+                            {
+                                self.failed(file, e);
+                            }
+                            continue;
+                        }
+                        Ok(false) => self.shared.progress.grow(file.size),
+                        Err(e) => {
+                            self.failed(file, e);
+                            continue;
+                        }
+                    }
+                }
+                self.copy(file, &mut buffer);
+            }
+        }
+    }
+
+    /// Copy `file`, small files in a batch through `buffer`.
+    fn copy(&self, file: &FileCopy, buffer: &mut Vec<u8>) {
+        let (options, progress) = (self.options, &self.shared.progress);
+        let (path, dest_path, size) = (&file.src, &file.dest, file.size);
+        let wanted = if size < SMALL_FILE_SIZE {
+            1
+        } else {
+            options.threads
+        };
+        let threads = self.budget.acquire(wanted);
+        if options.progress {
+            progress.message(&format!(
+                " Copy {} with {} threads",
+                path.display(),
+                threads
+            ));
+        }
+        let start = Instant::now();
+        let result = if self.batching && size < SMALL_FILE_SIZE {
+            let result = copy_small_file(path, dest_path, buffer, &self.file_options);
+            progress.add(size);
+            result
+        } else {
+            let counter = progress.track();
+            let mut file_options = self.file_options.clone().threads(threads);
+            file_options.progress_counter = Some(Arc::clone(&counter));
+            let result = copy_file(path, dest_path, &file_options);
+            progress.finish(&counter, size);
+            result
+        };
+        if options.verbosity > 0 && result.is_ok() {
+            progress.message(&copied_message(path, dest_path, size, start.elapsed()));
+        }
+        self.budget.release(threads);
+        match result {
+            Ok(bytes_copied) => {
+                match file.update {
+                    Some(reason) => itemize("updated", dest_path, Some(reason), options),
+                    None => itemize("created", dest_path, None, options),
+                }
+                self.bytes_copied.fetch_add(bytes_copied, Ordering::SeqCst);
+                self.files_copied.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(e) => self.failed(file, e),
+        }
+    }
+
+    /// Note down that `file` failed with `e` with
+    /// [`CopyOptions::continue_on_error`], or stop the copy.
+    fn failed(&self, file: &FileCopy, e: Error) {
+        // Cancelled files are reported as such once the copy stops.
+        if self.options.continue_on_error && !e.is::<CancelledError>() {
+            itemize("failed", &file.dest, Some(&e.to_string()), self.options);
+            self.shared.fail(&file.src, &e, self.options);
+        } else {
+            self.shared.stop(e);
+        }
+    }
+}
+
+/// Remove the entries of `dest` whose path relative to it is not among
+/// `sources`, the relative paths copied from the source tree, deepest first.
+///
+/// Entries excluded by [`CopyOptions::filter`] are left alone, and nothing
+/// is removed when there are more entries to remove than
+/// [`CopyOptions::max_delete`].
+fn delete_extraneous(
+    dest: &Path,
+    sources: &HashSet<PathBuf>,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    if !dest.is_dir() {
+        return Ok(());
+    }
+    let filter = &options.filter;
+    let mut extraneous = Vec::new();
+    // Entries below what the copy walked were left out of it, not removed
+    // from the source.
+    let entries = WalkDir::new(dest)
+        .min_depth(1)
+        .max_depth(options.max_depth.unwrap_or(usize::MAX))
+        .same_file_system(options.one_file_system)
+        .into_iter()
+        .filter_entry(|entry| {
+            if is_backup(entry.path(), options) {
+                return false;
+            }
+            let relative_path = entry.path().strip_prefix(dest).unwrap_or(entry.path());
+            // Copies of files left out by size or time are left out the same.
+            filter.is_empty()
+                || !(filter.is_excluded(relative_path, entry.file_type().is_dir())
+                    || entry.metadata().is_ok_and(|m| filter.is_filtered_out(&m)))
+        });
+    for entry in entries {
+        let entry = entry?;
+        if !sources.contains(entry.path().strip_prefix(dest)?) {
+            extraneous.push((entry.path().to_path_buf(), entry.file_type().is_dir()));
+        }
+    }
+    if let Some(max_delete) = options.max_delete {
+        if extraneous.len() > max_delete {
+            return Err(format!(
+                "Refusing to delete {} entries from '{}', more than the limit of {}",
+                extraneous.len(),
+                dest.display(),
+                max_delete
+            )
+            .into());
+        }
+    }
+
+    for (path, is_dir) in extraneous.iter().rev() {
+        let backup = backup_path(path, options).filter(|_| !*is_dir);
+        if options.dry_run {
+            match &backup {
+                Some(backup) => println!(
+                    "would delete '{}', backed up to '{}'",
+                    path.display(),
+                    backup.display()
+                ),
+                None => println!("would delete '{}'", path.display()),
+            }
+            continue;
+        }
+        log::info!("Delete '{}'", path.display());
+        let what = if *is_dir { path.join("") } else { path.clone() };
+        itemize("deleted", &what, None, options);
+        if options.progress {
+            eprintln!(" Delete {}", path.display());
+        }
+        if let Some(backup) = backup {
+            back_up(path, &backup, false)?;
+            continue;
+        }
+        let result = if *is_dir {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        };
+        match result {
+            Ok(()) => {}
+            // Entries inside it were protected by the filter.
+            Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                if options.progress {
+                    eprintln!("Keeping '{}', it holds excluded entries", path.display());
+                }
+            }
+            Err(e) => return Err(format!("Failed to delete '{}': {}", path.display(), e).into()),
+        }
+    }
+    Ok(())
+}
+
+/// Split `files` into the runs threads take at once: up to `batch` small
+/// files in a row, or one larger file.
+fn batches(files: Vec<FileCopy>, batch: usize) -> Vec<Vec<FileCopy>> {
+    let mut batches: Vec<Vec<FileCopy>> = Vec::new();
+    for file in files {
+        match batches.last_mut() {
+            Some(last)
+                if file.size < SMALL_FILE_SIZE
+                    && last[0].size < SMALL_FILE_SIZE
+                    && last.len() < batch =>
+            {
+                last.push(file)
+            }
+            _ => batches.push(vec![file]),
+        }
+    }
+    batches
+}
+
+/// Put `files` in the order they are to be started in.
+fn sort_files(files: &mut [FileCopy], order: FileOrder) {
+    match order {
+        FileOrder::Walk => {}
+        FileOrder::Name => files.sort_by(|a, b| a.src.cmp(&b.src)),
+        FileOrder::Size => files.sort_by_key(|file| file.size),
+        FileOrder::LargestFirst => files.sort_by_key(|file| std::cmp::Reverse(file.size)),
+        FileOrder::Inode => files.sort_by_cached_key(|file| inode_number(&file.src)),
+    }
+}
+
+/// The inode number of `path`, or 0 where it has none.
+#[cfg(unix)]
+fn inode_number(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).map_or(0, |metadata| metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn inode_number(_path: &Path) -> u64 {
+    0
+}
+
+/// Walk `src` following or not following symbolic links as set in
+/// `options`, leaving out entries excluded by [`CopyOptions::filter`] and
+/// links that point outside `src` when [`CopyOptions::safe_links`] is set. Broken links are left out when links
+/// are followed.
+pub(crate) fn walk(
+    src: &Path,
+    options: &CopyOptions,
+) -> impl Iterator<Item = walkdir::Result<DirEntry>> {
+    let root = std::path::absolute(src).unwrap_or_else(|_| src.to_path_buf());
+    let safe_links = options.safe_links;
+    let show_warnings = options.progress;
+    let filter = options.filter.clone();
+    let src = src.to_path_buf();
+    WalkDir::new(&src)
+        .follow_links(options.symlinks == Symlinks::Follow)
+        .max_depth(options.max_depth.unwrap_or(usize::MAX))
+        .same_file_system(options.one_file_system)
+        .into_iter()
+        .filter_entry(move |entry| {
+            if entry.depth() > 0 && !filter.is_empty() {
+                let relative_path = entry.path().strip_prefix(&src).unwrap_or(entry.path());
+                if filter.is_excluded(relative_path, entry.file_type().is_dir())
+                    || entry.metadata().is_ok_and(|m| filter.is_filtered_out(&m))
+                {
+                    return false;
+                }
+            }
+            if !safe_links || !entry.path_is_symlink() || link_is_safe(&root, entry.path()) {
+                return true;
+            }
+            if show_warnings {
+                warn_skipped(entry.path(), "it links outside the source tree");
+            }
+            false
+        })
+        .filter(move |entry| {
+            // Links that lead nowhere can only be followed to an error.
+            let Err(e) = entry else {
+                return true;
+            };
+            let broken_link = e.path().is_some_and(|path| {
+                path.symlink_metadata()
+                    .is_ok_and(|m| m.file_type().is_symlink())
+                    && !path.exists()
+            });
+            if broken_link && show_warnings {
+                warn_skipped(e.path().unwrap(), "it is a broken symlink");
+            }
+            !broken_link
+        })
+}
+
+/// Warn that the walk of a tree leaves out `path`, for the reason `why`.
+pub(crate) fn warn_skipped(path: &Path, why: &str) {
+    log::warn!("Skipped '{}', {}", path.display(), why);
+}
+
+/// Whether `dest` already holds a copy of the file `src`, which has the
+/// metadata `source`: a file of the same size and modification time or, with
+/// [`CopyOptions::checksum`], of the same size and digest.
+fn is_identical(
+    src: &Path,
+    source: &fs::Metadata,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<bool, Error> {
+    if !same_size(source, dest, options) {
+        return Ok(false);
+    }
+    if !options.checksum {
+        return Ok(matches!(
+            (source.modified(), dest.metadata().and_then(|existing| existing.modified())),
+            (Ok(source), Ok(existing)) if source == existing
+        ));
+    }
+    let algorithm = options.verify_hash.unwrap_or(HashAlgorithm::Blake3);
+    let digest_of = |path: &Path| {
+        digest(path, algorithm, options.buffer_size)
+            .map_err(|e| format!("Failed to checksum '{}': {}", path.display(), e))
+    };
+    Ok(digest_of(src)? == digest_of(dest)?)
+}
+
+/// Whether `dest` is a file of the size a copy of the file with `source`
+/// metadata has.
+fn same_size(source: &fs::Metadata, dest: &Path, options: &CopyOptions) -> bool {
+    // An encrypted copy is as much larger as its header and tags.
+    let len = match &options.encrypt {
+        Some(recipients) => recipients.encrypted_len(source.len()),
+        None => source.len(),
+    };
+    dest.metadata()
+        .is_ok_and(|existing| existing.is_file() && existing.len() == len)
+}
+
+/// Leave `dest`, which already holds a copy of the file `src` with the
+/// metadata `source`, as it is but for the metadata that changed.
+fn skip_identical(
+    src: &Path,
+    source: &fs::Metadata,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    log::info!("Skipped '{}', it is up to date", dest.display());
+    match metadata_change(source, dest, options) {
+        Some(change) if !options.dry_run => {
+            preserve_metadata(src, source, dest, options)?;
+            itemize("metadata", dest, Some(change), options);
+        }
+        _ => itemize("identical", dest, None, options),
+    }
+    if options.verbosity > 1 {
+        eprintln!("Skipping '{}', it is up to date", dest.display());
+    }
+    if let Some(stats) = &options.stats {
+        stats.skipped();
+    }
+    Ok(())
+}
+
+/// Why the file with `source` metadata is copied over `dest`, with
+/// [`CopyOptions::itemize`], if `dest` is there to copy over.
+fn update_reason(
+    source: &fs::Metadata,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Option<&'static str> {
+    let existing = dest.symlink_metadata().ok().filter(|_| options.itemize)?;
+    Some(if !existing.is_file() {
+        "replaces what is there"
+    } else if existing.len() != source.len() {
+        "size changed"
+    } else if !options.skip_identical {
+        "every file is copied"
+    } else if options.checksum {
+        "content changed"
+    } else {
+        "time changed"
+    })
+}
+
+/// What of the metadata [`CopyOptions::preserve`] carries over differs
+/// between the file with `source` metadata and its identical copy `dest`.
+fn metadata_change(
+    source: &fs::Metadata,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Option<&'static str> {
+    let existing = dest.metadata().ok().filter(|_| options.preserve)?;
+    if existing.permissions() != source.permissions() {
+        Some("permissions changed")
+    } else if existing.modified().ok() != source.modified().ok() {
+        Some("time changed")
+    } else {
+        None
+    }
+}
+
+/// Whether `previous`, the file at the same place in
+/// [`CopyOptions::link_dest`], is a copy of the file `src` with the metadata
+/// `source` that the copy can link to: identical to it and with the same
+/// permissions, which the link shares.
+fn is_unchanged(
+    src: &Path,
+    source: &fs::Metadata,
+    previous: &Path,
+    options: &CopyOptions,
+) -> Result<bool, Error> {
+    let same_permissions = previous
+        .symlink_metadata()
+        .is_ok_and(|existing| existing.is_file() && existing.permissions() == source.permissions());
+    Ok(same_permissions && is_identical(src, source, previous, options)?)
+}
+
+/// Whether the symbolic link at `link` points somewhere inside `root`.
+///
+/// The target is resolved lexically, so links that dangle are checked too.
+pub(crate) fn link_is_safe(root: &Path, link: &Path) -> bool {
+    let Ok(target) = fs::read_link(link) else {
+        return false;
+    };
+    let Ok(link) = std::path::absolute(link) else {
+        return false;
+    };
+    let mut resolved = PathBuf::new();
+    for component in link.parent().unwrap_or(&link).join(target).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    resolved.starts_with(root)
+}
+
+/// The device and inode of the file `metadata` is of, when it has other
+/// hard links to it.
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+/// Windows does not tell which files are linked to one another.
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Make `dest` a hard link to `target`, the copy of a file `src` is linked
+/// to, unless it already is one or is to be left alone under
+/// [`CopyOptions::overwrite`].
+fn link_file(src: &Path, dest: &Path, target: &Path, options: &CopyOptions) -> Result<(), Error> {
+    if let (Ok(existing), Ok(linked)) = (dest.symlink_metadata(), target.symlink_metadata()) {
+        if inode(&existing).is_some() && inode(&existing) == inode(&linked) {
+            log::info!("Skipped '{}', it is up to date", dest.display());
+            itemize("identical", dest, None, options);
+            if let Some(stats) = &options.stats {
+                stats.skipped();
+            }
+            return Ok(());
+        }
+    }
+    if let Some(reason) = keep_existing(&src.symlink_metadata()?, dest, options) {
+        // What is kept is not a link to `target`.
+        if let Some(manifest) = &options.manifest {
+            manifest.add(src, dest, options);
+        }
+        skip_existing(dest, reason, options);
+        return Ok(());
+    }
+    if options.verbosity > 1 {
+        eprintln!(" Hard link '{}' -> '{}'", dest.display(), target.display());
+    }
+    if dest.symlink_metadata().is_ok() {
+        match backup_path(dest, options) {
+            Some(backup) => back_up(dest, &backup, false)?,
+            None => fs::remove_file(dest)?,
+        }
+    }
+    fs::hard_link(target, dest).map_err(|e| {
+        format!(
+            "Failed to link '{}' to '{}': {}",
+            dest.display(),
+            target.display(),
+            e
+        )
+    })?;
+    let reason = format!("to {}", target.display());
+    itemize("linked", dest, Some(&reason), options);
+    if options.remove_source {
+        fs::remove_file(src)?;
+    }
+    Ok(())
+}
+
+/// Make `dest` a clone of `previous`, the unchanged copy of `src` in
+/// [`CopyOptions::reflink_dest`], sharing its extents, and give it the
+/// metadata of `src`. Returns the bytes copied, which is 0 unless the
+/// filesystem can't clone it and `src` is copied instead.
+fn clone_file(
+    src: &Path,
+    dest: &Path,
+    previous: &Path,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let metadata = fs::metadata(src)?;
+    if let Some(reason) = keep_existing(&metadata, dest, options) {
+        skip_existing(dest, reason, options);
+        return Ok(0);
+    }
+    if options.verbosity > 1 {
+        eprintln!(" Clone '{}' from '{}'", dest.display(), previous.display());
+    }
+    if dest.symlink_metadata().is_ok() {
+        match backup_path(dest, options) {
+            Some(backup) => back_up(dest, &backup, false)?,
+            None => fs::remove_file(dest)?,
+        }
+    }
+    let failed = |e: io::Error| {
+        format!(
+            "Failed to clone '{}' from '{}': {}",
+            dest.display(),
+            previous.display(),
+            e
+        )
+    };
+    let infile = fs::File::open(previous).map_err(failed)?;
+    let outfile = fs::File::create(dest).map_err(failed)?;
+    let whole = 0..metadata.len();
+    let ranges = std::slice::from_ref(&whole);
+    let cloned = offload_copy(&infile, &outfile, (previous, dest), ranges, true).map_err(failed)?;
+    drop(outfile);
+    if !cloned {
+        // Copied the usual way, from the source rather than the copy.
+        fs::remove_file(dest).map_err(failed)?;
+        return copy_file(src, dest, options);
+    }
+    preserve_metadata(src, &metadata, dest, options)?;
+    if options.fsync != Fsync::Off {
+        sync_file(dest, options.fsync)?;
+    }
+    if let Some(stats) = &options.stats {
+        stats.copied(0);
+    }
+    if options.remove_source {
+        fs::remove_file(src)?;
+    }
+    Ok(0)
+}
+
+/// Recreate the special file `src`, which has the metadata `metadata`, at
+/// `dest` when [`CopyOptions::specials`] or [`CopyOptions::devices`] asks
+/// for it, or skip it with a warning. Their contents can't be copied like
+/// those of files, reading a FIFO or a device would hang or never end.
+fn copy_special(
+    src: &Path,
+    dest: &Path,
+    special: Special,
+    metadata: &fs::Metadata,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    let wanted = if special.is_device() {
+        options.devices
+    } else {
+        options.specials
+    };
+    let reason = if !wanted {
+        format!("it is a {}", special)
+    } else if let Some(reason) = keep_existing(metadata, dest, options) {
+        reason.to_string()
+    } else {
+        if options.dry_run {
+            println!("would create {} '{}'", special, dest.display());
+            return Ok(());
+        }
+        if options.verbosity > 1 {
+            eprintln!(" Create {} '{}'", special, dest.display());
+        }
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(dest)?;
+        }
+        create_special(dest, metadata)?;
+        preserve_metadata(src, metadata, dest, options)?;
+        if options.remove_source {
+            fs::remove_file(src)?;
+        }
+        return Ok(());
+    };
+
+    if let Some(stats) = &options.stats {
+        stats.skipped();
+    }
+    if options.json {
+        Event::new("file_skipped")
+            .path("path", dest)
+            .string("reason", &reason)
+            .emit();
+    }
+    if options.dry_run {
+        println!("would skip '{}', {}", src.display(), reason);
+    } else if !wanted {
+        log::warn!("Skipped '{}', {}", src.display(), reason);
+    }
+    Ok(())
+}
+
+/// Recreate the symbolic link `src` at `dest`, replacing whatever is there.
+fn copy_symlink(src: &Path, dest: &Path) -> Result<(), Error> {
+    let target = fs::read_link(src)?;
+    if dest.symlink_metadata().is_ok() {
+        fs::remove_file(dest)?;
+    }
+    create_symlink(src, &target, dest).map_err(|e| {
+        format!(
+            "Failed to create symlink '{}' -> '{}': {}",
+            dest.display(),
+            target.display(),
+            e
+        )
+    })?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(_src: &Path, target: &Path, dest: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, dest)
+}
+
+/// Windows links are made either for a file or for a directory, the same
+/// kind as the link being copied.
+#[cfg(windows)]
+fn create_symlink(src: &Path, target: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file, FileTypeExt};
+    if fs::symlink_metadata(src)?.file_type().is_symlink_dir() {
+        symlink_dir(target, dest)
+    } else {
+        symlink_file(target, dest)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::stats::CopyStats;
+    use crate::testing::scratch;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn leaves_identical_links_alone() {
+        let dir = scratch("tree-links");
+        let src = dir.join("src");
+        fs::create_dir(&src).unwrap();
+        symlink("target", src.join("link")).unwrap();
+        let options = CopyOptions::new()
+            .symlinks(Symlinks::Recreate)
+            .skip_identical(true);
+        copy_tree(&src, dir.join("dest"), &options).unwrap();
+        let link = dir.join("dest/link");
+        let created = link.symlink_metadata().unwrap().modified().unwrap();
+        copy_tree(&src, dir.join("dest"), &options).unwrap();
+        assert_eq!(
+            link.symlink_metadata().unwrap().modified().unwrap(),
+            created
+        );
+
+        fs::remove_file(src.join("link")).unwrap();
+        symlink("elsewhere", src.join("link")).unwrap();
+        copy_tree(&src, dir.join("dest"), &options).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("elsewhere"));
+    }
+
+    #[test]
+    fn refuses_to_replace_directories_with_links() {
+        let dir = scratch("tree-link-dir");
+        let src = dir.join("src");
+        fs::create_dir_all(dir.join("dest/link")).unwrap();
+        fs::create_dir(&src).unwrap();
+        symlink("target", src.join("link")).unwrap();
+        let options = CopyOptions::new().symlinks(Symlinks::Recreate);
+        let e = copy_tree(&src, dir.join("dest"), &options).unwrap_err();
+        assert!(e.to_string().contains("Cannot replace directory"), "{}", e);
+        assert!(dir.join("dest/link").is_dir());
+    }
+
+    #[test]
+    fn counts_every_linked_file_on_a_rerun() {
+        let dir = scratch("tree-hard-links");
+        let src = dir.join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a"), b"linked").unwrap();
+        fs::hard_link(src.join("a"), src.join("b")).unwrap();
+        fs::hard_link(src.join("a"), src.join("c")).unwrap();
+        let options = CopyOptions::new()
+            .hard_links(true)
+            .preserve(true)
+            .skip_identical(true);
+        copy_tree(&src, dir.join("dest"), &options).unwrap();
+        let stats = Arc::new(CopyStats::new());
+        copy_tree(&src, dir.join("dest"), &options.stats(Arc::clone(&stats))).unwrap();
+        assert_eq!(stats.files_skipped(), 3);
+        assert_eq!(stats.files_copied(), 0);
+    }
+}
@@ -0,0 +1,169 @@
+use std::path::Path;
+
+/// Files are not split between more threads than leaves each thread this
+/// much to copy.
+const MIN_BYTES_PER_THREAD: u64 = 16 * 1024 * 1024;
+
+/// What a path is stored on, as far as it matters for how many threads to
+/// copy it with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Storage {
+    /// A network filesystem, where many requests in flight hide latency.
+    Network,
+    /// A spinning disk, which slows down when reads jump between chunks.
+    Rotational,
+    /// Local storage without seeks, or storage that could not be told.
+    Local,
+}
+
+/// Pick a number of threads to copy `sources` to `dest` with, from the
+/// number of CPUs, the size of the sources and what they are stored on.
+/// `remote` is set when either side is on another host, which is treated
+/// like a network filesystem.
+///
+/// Copies from or to spinning disks get two threads, ones over the network
+/// four per CPU between 16 and 64, and other local ones one per CPU between
+/// 4 and 16. Sources that are files also get no more than one thread for
+/// each 16 MiB of them.
+pub fn auto_threads<P: AsRef<Path>>(sources: &[P], dest: &Path, remote: bool) -> usize {
+    let cpus = std::thread::available_parallelism().map_or(4, |cpus| cpus.get());
+    let storage: Vec<Storage> = sources
+        .iter()
+        .map(|path| storage_of(path.as_ref()))
+        .chain([storage_of(dest)])
+        .collect();
+    let threads = if storage.contains(&Storage::Rotational) {
+        2
+    } else if remote || storage.contains(&Storage::Network) {
+        (cpus * 4).clamp(16, 64)
+    } else {
+        cpus.clamp(4, 16)
+    };
+    // Trees copy several files at once, which the threads are shared
+    // between, so only the size of plain files limits them.
+    let size = sources.iter().try_fold(0, |size, path| {
+        let metadata = std::fs::metadata(path).ok().filter(|m| m.is_file())?;
+        Some(size + metadata.len())
+    });
+    match size {
+        Some(size) => threads.min((size / MIN_BYTES_PER_THREAD).max(1) as usize),
+        None => threads,
+    }
+}
+
+/// The nearest of `path` and its parents that exists, as the destination
+/// usually does not yet.
+fn existing(path: &Path) -> Option<&Path> {
+    path.ancestors()
+        .map(|path| {
+            if path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                path
+            }
+        })
+        .find(|path| path.exists())
+}
+
+#[cfg(target_os = "linux")]
+fn storage_of(path: &Path) -> Storage {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    const NFS: u32 = 0x6969;
+    const SMB: u32 = 0x517b;
+    const CIFS: u32 = 0xff53_4d42;
+    const SMB2: u32 = 0xfe53_4d42;
+    const CEPH: u32 = 0x00c3_6400;
+    const AFS: u32 = 0x5346_414f;
+    const CODA: u32 = 0x7375_7245;
+    const NCP: u32 = 0x564c;
+    const V9FS: u32 = 0x0102_1997;
+    const LUSTRE: u32 = 0x0bd0_0bd0;
+    const GPFS: u32 = 0x4750_4653;
+
+    let Some(path) = existing(path) else {
+        return Storage::Local;
+    };
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return Storage::Local;
+    };
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut fs) } == 0 {
+        // The magic numbers are 32 bits wide whatever the field is.
+        #[allow(clippy::unnecessary_cast)]
+        let magic = fs.f_type as u32;
+        if matches!(
+            magic,
+            NFS | SMB | CIFS | SMB2 | CEPH | AFS | CODA | NCP | V9FS | LUSTRE | GPFS
+        ) {
+            return Storage::Network;
+        }
+    }
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return Storage::Local;
+    };
+    let dev = metadata.dev();
+    let device = format!(
+        "/sys/dev/block/{}:{}",
+        nix::sys::stat::major(dev),
+        nix::sys::stat::minor(dev)
+    );
+    // Partitions have no queue of their own, the disk they are on does.
+    let rotational = [
+        format!("{}/queue/rotational", device),
+        format!("{}/../queue/rotational", device),
+    ]
+    .iter()
+    .find_map(|flag| std::fs::read_to_string(flag).ok());
+    match rotational.as_deref().map(str::trim) {
+        Some("1") => Storage::Rotational,
+        _ => Storage::Local,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn storage_of(path: &Path) -> Storage {
+    use nix::libc;
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let Some(path) = existing(path) else {
+        return Storage::Local;
+    };
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return Storage::Local;
+    };
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut fs) } != 0 {
+        return Storage::Local;
+    }
+    let name = unsafe { CStr::from_ptr(fs.f_fstypename.as_ptr()) };
+    match name.to_bytes() {
+        b"nfs" | b"smbfs" | b"afpfs" | b"webdav" | b"cifs" => Storage::Network,
+        _ => Storage::Local,
+    }
+}
+
+/// UNC paths, `\\server\share`, are network shares.
+#[cfg(windows)]
+fn storage_of(path: &Path) -> Storage {
+    use std::path::{Component, Prefix};
+
+    let path = std::fs::canonicalize(existing(path).unwrap_or(path)).unwrap_or(path.into());
+    match path.components().next() {
+        Some(Component::Prefix(prefix))
+            if matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..)) =>
+        {
+            Storage::Network
+        }
+        _ => Storage::Local,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn storage_of(_path: &Path) -> Storage {
+    Storage::Local
+}
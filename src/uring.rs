@@ -0,0 +1,170 @@
+use crate::copy::{io_error, ChunkIo};
+use crate::pool::PooledBuffer;
+use crate::Error;
+use io_uring::{opcode, types, IoUring};
+use nix::libc;
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+
+/// Reads or writes each ring has in flight at once.
+pub(crate) const QUEUE_DEPTH: usize = 8;
+
+/// An io_uring owned by one copy thread, with buffers registered for fixed
+/// reads and writes.
+///
+/// A chunk is copied in batches of up to [`QUEUE_DEPTH`] buffers, or as many
+/// as the ring was given. All the
+/// reads of a batch are submitted together, then all of its writes, so the
+/// kernel sees many requests at once instead of one at a time.
+pub(crate) struct Ring {
+    ring: IoUring,
+    buffers: Vec<PooledBuffer>,
+}
+
+impl Ring {
+    pub(crate) fn new(mut buffers: Vec<PooledBuffer>) -> Result<Self, Error> {
+        let ring = IoUring::new(QUEUE_DEPTH as u32)
+            .map_err(|e| format!("Failed to set up io_uring: {}", e))?;
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect();
+        // The buffers live as long as the ring and are never resized, so
+        // they stay valid while registered.
+        unsafe { ring.submitter().register_buffers(&iovecs) }
+            .map_err(|e| format!("Failed to register io_uring buffers: {}", e))?;
+        Ok(Ring { ring, buffers })
+    }
+
+    pub(crate) fn copy_chunk(
+        &mut self,
+        io: &ChunkIo,
+        chunk: &Range<u64>,
+        on_data: &mut dyn FnMut(&[u8]),
+    ) -> Result<u64, Error> {
+        let buffer_size = self.buffers[0].len() as u64;
+        let (Some(infile), Some(outfile)) = (io.infile.as_file(), io.outfile.as_file()) else {
+            return Err("The io_uring engine only copies between local files".into());
+        };
+        let infd = types::Fd(infile.as_raw_fd());
+        let outfd = types::Fd(outfile.as_raw_fd());
+        let mut pos = chunk.start;
+
+        while pos < chunk.end {
+            let batch: Vec<Range<u64>> = (0..self.buffers.len() as u64)
+                .map(|i| pos + i * buffer_size)
+                .take_while(|&start| start < chunk.end)
+                .map(|start| start..chunk.end.min(start + buffer_size))
+                .collect();
+
+            for (i, piece) in batch.iter().enumerate() {
+                let read = opcode::ReadFixed::new(
+                    infd,
+                    self.buffers[i].as_mut_ptr(),
+                    (piece.end - piece.start) as u32,
+                    i as u16,
+                )
+                .offset(piece.start)
+                .build()
+                .user_data(i as u64);
+                // The buffer is not touched again until the read completes.
+                unsafe { self.ring.submission().push(&read) }
+                    .map_err(|_| "io_uring submission queue is full")?;
+            }
+            let mut lens = vec![0; batch.len()];
+            for (i, result) in self.complete(batch.len())? {
+                lens[i] = if result >= 0 {
+                    result as usize
+                } else if io.retries > 0 {
+                    // Retried one piece at a time without the ring.
+                    let buffer = &mut self.buffers[i][..(batch[i].end - batch[i].start) as usize];
+                    io.read_at(buffer, batch[i].start)?
+                } else {
+                    let error = io::Error::from_raw_os_error(-result);
+                    return Err(io_error("read", &io.infile_path, batch[i].start, error));
+                };
+            }
+
+            // Top up short reads, and stop at the first piece the source
+            // ends in.
+            let mut ended = false;
+            for (i, piece) in batch.iter().enumerate() {
+                let want = (piece.end - piece.start) as usize;
+                // Direct reads only come up short at the end of the file.
+                while !io.direct && lens[i] > 0 && lens[i] < want {
+                    let offset = piece.start + lens[i] as u64;
+                    let bytes_read = io.read_at(&mut self.buffers[i][lens[i]..want], offset)?;
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    lens[i] += bytes_read;
+                }
+                if lens[i] < want {
+                    lens.truncate(i + 1);
+                    ended = true;
+                    break;
+                }
+            }
+
+            let mut writes = 0;
+            for (i, &len) in lens.iter().enumerate() {
+                let data = &self.buffers[i][..len];
+                on_data(data);
+                if len == 0 || !io.admit(data) {
+                    continue;
+                }
+                let write_len = io.write_len(len) as u32;
+                let write = opcode::WriteFixed::new(outfd, data.as_ptr(), write_len, i as u16)
+                    .offset(batch[i].start)
+                    .build()
+                    .user_data(i as u64);
+                // As for reads, the buffer is left alone until completion.
+                unsafe { self.ring.submission().push(&write) }
+                    .map_err(|_| "io_uring submission queue is full")?;
+                writes += 1;
+            }
+            for (i, result) in self.complete(writes)? {
+                let start = batch[i].start;
+                if result < 0 && io.retries == 0 {
+                    let error = io::Error::from_raw_os_error(-result);
+                    return Err(io_error("write", &io.outfile_path, start, error));
+                }
+                // Failed writes are retried whole, like short ones are
+                // finished, without the ring.
+                let (written, len) = (result.max(0) as usize, io.write_len(lens[i]));
+                if written < len {
+                    io.write_all_at(&self.buffers[i][written..len], start + written as u64)?;
+                }
+            }
+
+            pos += lens.iter().sum::<usize>() as u64;
+            if ended {
+                break;
+            }
+        }
+        Ok(pos)
+    }
+
+    /// Submit what is queued and wait for `count` completions, returning
+    /// the result of each with the buffer index it was queued under.
+    fn complete(&mut self, count: usize) -> Result<Vec<(usize, i32)>, Error> {
+        let mut results = Vec::with_capacity(count);
+        while results.len() < count {
+            match self.ring.submit_and_wait(count - results.len()) {
+                Ok(_) => {}
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(e) => return Err(format!("io_uring submit failed: {}", e).into()),
+            }
+            results.extend(
+                self.ring
+                    .completion()
+                    .map(|cqe| (cqe.user_data() as usize, cqe.result())),
+            );
+        }
+        Ok(results)
+    }
+}
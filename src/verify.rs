@@ -0,0 +1,534 @@
+use crate::backend::StorageBackend;
+use crate::copy::{chunk_size, chunks};
+use crate::hash::HashAlgorithm;
+use crate::numa::Placement;
+use crate::options::CopyOptions;
+use crate::pool::take_buffers;
+use crate::progress::Event;
+use crate::sparse::is_zero;
+use crate::tree::walk;
+use crate::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Returned when a copied file does not match its source.
+#[derive(Debug)]
+pub struct VerifyError {
+    message: String,
+    pub(crate) removed: bool,
+}
+
+impl VerifyError {
+    /// Whether the invalid copy was a temporary file that has been removed,
+    /// leaving the destination as it was.
+    pub fn copy_removed(&self) -> bool {
+        self.removed
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+pub(crate) fn mismatch(message: String) -> Error {
+    Box::new(VerifyError {
+        message,
+        removed: false,
+    })
+}
+
+/// Digest of one chunk of a file, computed while it was copied.
+pub(crate) struct ChunkDigest {
+    pub(crate) range: Range<u64>,
+    pub(crate) digest: String,
+}
+
+/// Check that `dest` holds the same bytes as `src`.
+///
+/// The files are split into chunks that `options.threads` threads read and
+/// compare side by side, byte by byte or by the digests of the chunks when
+/// a hash algorithm is set with [`CopyOptions::verify_hash`]. Returns an
+/// error describing where the files differ, or that their sizes do.
+pub fn verify_copy<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    let (file1, file2) = (src.as_ref(), dest.as_ref());
+    let in1 = File::open(file1)?;
+    let in2 = File::open(file2)?;
+    let file_size = StorageBackend::len(&in1)?;
+
+    if options.progress {
+        eprintln!(
+            "\nVerifying '{}' and '{}' are the same. Size {}",
+            file1.display(),
+            file2.display(),
+            file_size
+        );
+    }
+    let dest_size = StorageBackend::len(&in2)?;
+    if dest_size != file_size {
+        return Err(mismatch(format!(
+            "Destination is {} bytes but the source is {} bytes",
+            dest_size, file_size
+        )));
+    }
+
+    compare_chunks(&in1, &in2, file_size, None, options)
+}
+
+/// Compare `dest` with `src` like [`verify_copy`] and copy the chunks that
+/// differ again, returning the number of bytes copied. A destination that
+/// doesn't exist is created, and one of another size is cut or extended to
+/// the size of the source first.
+///
+/// Every chunk copied is read back and compared again, and the repair
+/// fails if it still differs.
+pub fn repair_copy<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    let (file1, file2) = (src.as_ref(), dest.as_ref());
+    let in1 = File::open(file1)?;
+    let out = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(file2)
+        .map_err(|e| format!("Failed to open '{}': {}", file2.display(), e))?;
+    let file_size = StorageBackend::len(&in1)?;
+    if options.progress {
+        eprintln!(
+            "\nRepairing '{}' from '{}'. Size {}",
+            file2.display(),
+            file1.display(),
+            file_size
+        );
+    }
+    if StorageBackend::len(&out)? != file_size {
+        out.allocate(file_size)?;
+    }
+    let repaired = AtomicU64::new(0);
+    compare_chunks(&in1, &out, file_size, Some(&repaired), options)?;
+    Ok(repaired.into_inner())
+}
+
+/// Compare the first `size` bytes of two files chunk by chunk on
+/// `options.threads` threads. Stops at the first difference, unless
+/// `repaired` is given, in which case chunks that differ are copied from
+/// `in1` to `in2` and counted in it.
+fn compare_chunks(
+    in1: &File,
+    in2: &File,
+    size: u64,
+    repaired: Option<&AtomicU64>,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    let chunks = chunks(
+        std::slice::from_ref(&(0..size)),
+        chunk_size(size, options.threads as u64, options.buffer_size),
+    );
+    let next_chunk = AtomicUsize::new(0);
+    // Where the first difference found so far is, and why. Chunks after it
+    // need not be read.
+    let first_difference: Mutex<Option<(u64, Error)>> = Mutex::new(None);
+    let found_before = |offset: u64| {
+        first_difference
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(found, _)| *found < offset)
+    };
+
+    let placement = options.numa_node.map(Placement::new).transpose()?;
+    thread::scope(|scope| {
+        for index in 0..options.threads.min(chunks.len()) {
+            // Only the first thread waits for memory for its buffers, the
+            // others are left out when there is none.
+            let needed = if index == 0 { 2 } else { 0 };
+            let (chunks, next_chunk, found_before) = (&chunks, &next_chunk, &found_before);
+            let first_difference = &first_difference;
+            scope.spawn(move || {
+                let pool = options.buffer_pool.as_ref();
+                let mut buffers = take_buffers(pool, options.buffer_size, 2, needed);
+                if let Some(placement) = placement {
+                    placement.pin();
+                    buffers.iter().for_each(|buffer| placement.bind(buffer));
+                }
+                let (Some(buffer2), Some(buffer1)) = (buffers.pop(), buffers.pop()) else {
+                    return;
+                };
+                let mut buffers = (buffer1, buffer2);
+                while let Some(chunk) = chunks.get(next_chunk.fetch_add(1, Ordering::SeqCst)) {
+                    if found_before(chunk.start) {
+                        break;
+                    }
+                    let (buffer1, buffer2) = &mut buffers;
+                    let mut result = compare_chunk(in1, in2, chunk, (buffer1, buffer2), options);
+                    if let (Err((_, e)), Some(repaired)) = (&result, repaired) {
+                        if e.is::<VerifyError>() {
+                            if options.progress {
+                                eprintln!("Copying range {}..{} again", chunk.start, chunk.end);
+                            }
+                            result = copy_chunk(in1, in2, chunk, buffer1).and_then(|()| {
+                                compare_chunk(in1, in2, chunk, (buffer1, buffer2), options)
+                            });
+                            repaired.fetch_add(chunk.end - chunk.start, Ordering::SeqCst);
+                        }
+                    }
+                    if let Err(difference) = result {
+                        let mut first = first_difference.lock().unwrap();
+                        if first
+                            .as_ref()
+                            .is_none_or(|(found, _)| difference.0 < *found)
+                        {
+                            *first = Some(difference);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    match first_difference.into_inner().unwrap() {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Copy `chunk` of `in1` over the same range of `in2`.
+fn copy_chunk(
+    in1: &File,
+    in2: &File,
+    chunk: &Range<u64>,
+    buffer: &mut [u8],
+) -> Result<(), (u64, Error)> {
+    let mut pos = chunk.start;
+    while pos < chunk.end {
+        let want = buffer.len().min((chunk.end - pos) as usize);
+        let bytes_read = read_full(in1, &mut buffer[..want], pos).map_err(|e| (pos, e.into()))?;
+        in2.write_all_at(&buffer[..bytes_read], pos)
+            .map_err(|(offset, e)| (offset, e.into()))?;
+        if bytes_read < want {
+            break;
+        }
+        pos += want as u64;
+    }
+    Ok(())
+}
+
+/// Compare `chunk` of two files. On failure returns the offset the
+/// difference or read error was found at, along with the error.
+fn compare_chunk(
+    in1: &File,
+    in2: &File,
+    chunk: &Range<u64>,
+    (buffer1, buffer2): (&mut [u8], &mut [u8]),
+    options: &CopyOptions,
+) -> Result<(), (u64, Error)> {
+    let mut hashers = options
+        .verify_hash
+        .map(|algorithm| (algorithm.hasher(), algorithm.hasher()));
+    let mut pos = chunk.start;
+    while pos < chunk.end {
+        let want = buffer1.len().min((chunk.end - pos) as usize);
+        let bytes_read_from_file1 =
+            read_full(in1, &mut buffer1[..want], pos).map_err(|e| (pos, e.into()))?;
+        let bytes_read_from_file2 =
+            read_full(in2, &mut buffer2[..want], pos).map_err(|e| (pos, e.into()))?;
+        let (data1, data2) = (
+            &buffer1[..bytes_read_from_file1],
+            &buffer2[..bytes_read_from_file2],
+        );
+        match hashers.as_mut() {
+            Some((hasher1, hasher2)) => {
+                hasher1.update(data1);
+                hasher2.update(data2);
+            }
+            None if data1 != data2 => {
+                let at = data1
+                    .iter()
+                    .zip(data2)
+                    .position(|(byte1, byte2)| byte1 != byte2)
+                    .unwrap_or(data1.len().min(data2.len()));
+                let offset = pos + at as u64;
+                return Err((offset, difference(in1, in2, offset)));
+            }
+            None => {}
+        }
+        if bytes_read_from_file1 < want {
+            break;
+        }
+        pos += want as u64;
+    }
+    if let (Some((hasher1, hasher2)), Some(algorithm)) = (hashers, options.verify_hash) {
+        let (digest1, digest2) = (hasher1.finish(), hasher2.finish());
+        if digest1 != digest2 {
+            // Read the chunk again to find the first byte that differs.
+            let bytewise = options.clone().verify_hash(None);
+            compare_chunk(in1, in2, chunk, (buffer1, buffer2), &bytewise)?;
+            return Err((
+                chunk.start,
+                mismatch(format!(
+                    "{} digests of range {}..{} differ: source {}, destination {}",
+                    algorithm, chunk.start, chunk.end, digest1, digest2
+                )),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Describe the difference at `offset`, with a hex dump of both files
+/// around it.
+fn difference(in1: &File, in2: &File, offset: u64) -> Error {
+    const ROW: u64 = 16;
+    let start = (offset / ROW).saturating_sub(1) * ROW;
+    let mut message = format!("Files differ at byte {}", offset);
+    for row in (start..start + 3 * ROW).step_by(ROW as usize) {
+        for (name, file) in [("source", in1), ("destination", in2)] {
+            let mut bytes = [0; ROW as usize];
+            let len = read_full(file, &mut bytes, row).unwrap_or(0);
+            if len > 0 {
+                message.push_str(&format!("\n  {:<11} {}", name, hex_row(row, &bytes[..len])));
+            }
+        }
+    }
+    mismatch(message)
+}
+
+/// One line of a hex dump, like `hexdump -C` prints.
+fn hex_row(offset: u64, bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let text: String = bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    format!("{:010x}  {:<47}  |{}|", offset, hex.join(" "), text)
+}
+
+/// Read into all of `buffer` from `offset`, unless the end of `file` comes
+/// first. Returns how many bytes were read.
+pub(crate) fn read_full(
+    file: &dyn StorageBackend,
+    buffer: &mut [u8],
+    offset: u64,
+) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read_at(&mut buffer[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(bytes_read) => filled += bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Read `file`, the copy at `dest`, back and compare it against the chunk
+/// digests recorded while it was copied, returning the digest of the whole
+/// file. The bytes between chunks were holes in the source and must read
+/// back as zeros.
+pub(crate) fn verify_chunks(
+    dest: &Path,
+    file: &dyn StorageBackend,
+    size: u64,
+    chunks: &[ChunkDigest],
+    algorithm: HashAlgorithm,
+    options: &CopyOptions,
+) -> Result<String, Error> {
+    let file_size = file.len()?;
+
+    if options.progress {
+        eprintln!(
+            "\nVerifying '{}' against digests taken during the copy. Size {}",
+            dest.display(),
+            file_size
+        );
+    }
+    if file_size != size {
+        return Err(mismatch(format!(
+            "Destination is {} bytes but {} bytes were copied",
+            file_size, size
+        )));
+    }
+
+    let mut whole = algorithm.hasher();
+    let mut buffers = take_buffers(options.buffer_pool.as_ref(), options.buffer_size, 1, 1);
+    let buffer = &mut buffers[0];
+    let mut offset = 0;
+    let mut read_exact = |buffer: &mut [u8]| -> Result<(), Error> {
+        if read_full(file, buffer, offset)? < buffer.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        offset += buffer.len() as u64;
+        Ok(())
+    };
+    let mut pos = 0;
+    for chunk in chunks.iter().map(Some).chain([None]) {
+        let (start, end) = chunk.map_or((pos, size), |c| (c.range.start, c.range.end));
+        // The hole before this chunk.
+        let mut remaining = start - pos;
+        while remaining > 0 {
+            let want = buffer.len().min(remaining as usize);
+            read_exact(&mut buffer[..want])?;
+            if !is_zero(&buffer[..want]) {
+                return Err(mismatch(format!(
+                    "File differ in range {}..{} bytes, which is a hole in the source",
+                    pos, start
+                )));
+            }
+            whole.update(&buffer[..want]);
+            remaining -= want as u64;
+        }
+        let Some(chunk) = chunk else {
+            break;
+        };
+
+        let mut hasher = algorithm.hasher();
+        let mut remaining = end - start;
+        while remaining > 0 {
+            let want = buffer.len().min(remaining as usize);
+            read_exact(&mut buffer[..want])?;
+            hasher.update(&buffer[..want]);
+            whole.update(&buffer[..want]);
+            remaining -= want as u64;
+        }
+        if hasher.finish() != chunk.digest {
+            return Err(mismatch(format!(
+                "File differ in range {}..{} bytes",
+                start, end
+            )));
+        }
+        pos = end;
+    }
+
+    let digest = whole.finish();
+    // Past an offset it is only the digest of what was copied there.
+    if options.progress && options.seek == 0 {
+        eprintln!("{} {}  {}", algorithm, digest, dest.display());
+    }
+    Ok(digest)
+}
+
+/// Write the JSON event for the verification of `path`, which failed when
+/// there is a `reason`.
+pub(crate) fn verified_event(path: &Path, reason: Option<&str>) {
+    let event = Event::new("verified")
+        .path("path", path)
+        .value("identical", reason.is_none());
+    match reason {
+        Some(reason) => event.string("reason", reason).emit(),
+        None => event.emit(),
+    }
+}
+
+/// A file whose copy did not match the source during [`verify_tree`].
+#[derive(Debug)]
+pub struct VerifyFailure {
+    /// Path of the file relative to the root of the copied tree.
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Verify every file under `src` against its counterpart under `dest`.
+///
+/// Files are checked `options.threads` at a time. The returned list is empty
+/// when the whole tree matches.
+pub fn verify_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    options: &CopyOptions,
+) -> Result<Vec<VerifyFailure>, Error> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    let mut files = Vec::new();
+    let mut failures = Vec::new();
+    for entry in walk(src, options) {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(src)?.to_path_buf();
+        if entry.file_type().is_symlink() {
+            // Recreated links are checked by where they point.
+            let target = fs::read_link(entry.path())?;
+            match fs::read_link(dest.join(&path)) {
+                Ok(copy_target) if copy_target == target => {}
+                Ok(copy_target) => failures.push(VerifyFailure {
+                    path,
+                    reason: format!(
+                        "Link points to '{}' instead of '{}'",
+                        copy_target.display(),
+                        target.display()
+                    ),
+                }),
+                Err(e) => failures.push(VerifyFailure {
+                    path,
+                    reason: e.to_string(),
+                }),
+            }
+        } else if entry.file_type().is_file() {
+            files.push(path);
+        }
+    }
+
+    if options.progress {
+        eprintln!(
+            "Verifying {} files in '{}' against '{}'",
+            files.len(),
+            src.display(),
+            dest.display()
+        );
+    }
+
+    // Per file messages would interleave between threads. The threads are
+    // shared out between the files checked at once.
+    let threads_per_file = (options.threads / files.len().max(1)).max(1);
+    let file_options = options.clone().progress(false).threads(threads_per_file);
+    let next_file = AtomicUsize::new(0);
+    let failures = Mutex::new(failures);
+
+    thread::scope(|scope| {
+        for _ in 0..options.threads.min(files.len()) {
+            scope.spawn(|| {
+                while let Some(path) = files.get(next_file.fetch_add(1, Ordering::SeqCst)) {
+                    let result = verify_copy(src.join(path), dest.join(path), &file_options);
+                    if options.json {
+                        let reason = result.as_ref().err().map(|e| e.to_string());
+                        verified_event(&dest.join(path), reason.as_deref());
+                    }
+                    if let Err(e) = result {
+                        failures.lock().unwrap().push(VerifyFailure {
+                            path: path.clone(),
+                            reason: e.to_string(),
+                        });
+                    }
+                }
+            });
+        }
+    });
+
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+    if let Some(stats) = &options.stats {
+        stats.verify_failed(failures.len() as u64);
+    }
+    Ok(failures)
+}
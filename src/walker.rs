@@ -0,0 +1,310 @@
+use crate::options::CopyOptions;
+use crate::tree::walk;
+use crate::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An entry of a tree being copied.
+pub(crate) struct TreeEntry {
+    pub(crate) path: PathBuf,
+    /// Levels below the root of the tree, which is at 0.
+    pub(crate) depth: usize,
+    /// The metadata of the entry, or of what it links to when links are
+    /// followed.
+    pub(crate) metadata: fs::Metadata,
+}
+
+/// What went wrong walking a tree, with the path it went wrong at.
+pub(crate) type WalkError = (PathBuf, Error);
+
+/// The entries of the tree `src`, as [`walk`] finds them or, with
+/// [`CopyOptions::walk_threads`] above 1 on Unix, as that many threads
+/// reading directories at the same time find them. Each directory comes
+/// before the entries in it, but several threads find entries in no
+/// particular order otherwise.
+pub(crate) fn walk_tree(
+    src: &Path,
+    options: &CopyOptions,
+) -> Box<dyn Iterator<Item = Result<TreeEntry, WalkError>>> {
+    #[cfg(unix)]
+    if options.walk_threads > 1 {
+        return Box::new(parallel::walk(src, options));
+    }
+    let root = src.to_path_buf();
+    Box::new(walk(src, options).map(move |entry| {
+        let entry = entry.map_err(|e| (e.path().unwrap_or(&root).to_path_buf(), e.into()))?;
+        let metadata = entry
+            .metadata()
+            .map_err(|e| (entry.path().to_path_buf(), e.into()))?;
+        Ok(TreeEntry {
+            depth: entry.depth(),
+            path: entry.into_path(),
+            metadata,
+        })
+    }))
+}
+
+#[cfg(unix)]
+mod parallel {
+    use super::{TreeEntry, WalkError};
+    use crate::filter::Filter;
+    use crate::options::{CopyOptions, Symlinks};
+    use crate::tree::{link_is_safe, warn_skipped};
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{self, Receiver, SyncSender};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+
+    /// Entries found but not yet taken, beyond which the threads wait.
+    const BACKLOG: usize = 4096;
+
+    /// A directory to read.
+    struct Dir {
+        path: PathBuf,
+        depth: usize,
+        /// The directory itself and those it is in, when links are followed.
+        ancestors: Option<Arc<Ancestor>>,
+    }
+
+    struct Ancestor {
+        path: PathBuf,
+        id: (u64, u64),
+        parent: Option<Arc<Ancestor>>,
+    }
+
+    #[derive(Default)]
+    struct Queue {
+        dirs: Vec<Dir>,
+        /// Threads reading a directory, which may add more.
+        busy: usize,
+        /// Whether the entries are no longer wanted.
+        stopped: bool,
+    }
+
+    /// What the threads of a walk share.
+    struct Walk {
+        queue: Mutex<Queue>,
+        ready: Condvar,
+        src: PathBuf,
+        /// `src` made absolute, for checking where links point.
+        root: PathBuf,
+        follow_links: bool,
+        max_depth: usize,
+        /// The device of `src`, with [`CopyOptions::one_file_system`].
+        device: Option<u64>,
+        safe_links: bool,
+        show_warnings: bool,
+        filter: Filter,
+    }
+
+    pub(super) fn walk(
+        src: &Path,
+        options: &CopyOptions,
+    ) -> impl Iterator<Item = Result<TreeEntry, WalkError>> {
+        let (sender, receiver): (_, Receiver<Result<TreeEntry, WalkError>>) =
+            mpsc::sync_channel(BACKLOG);
+        // The root is always followed, as it is by walkdir.
+        let metadata = match fs::metadata(src) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                let _ = sender.send(Err((src.to_path_buf(), e.into())));
+                return receiver.into_iter();
+            }
+        };
+        let follow_links = options.symlinks == Symlinks::Follow;
+        let walk = Arc::new(Walk {
+            queue: Mutex::default(),
+            ready: Condvar::new(),
+            src: src.to_path_buf(),
+            root: std::path::absolute(src).unwrap_or_else(|_| src.to_path_buf()),
+            follow_links,
+            max_depth: options.max_depth.unwrap_or(usize::MAX),
+            device: options.one_file_system.then(|| metadata.dev()),
+            safe_links: options.safe_links,
+            show_warnings: options.progress,
+            filter: options.filter.clone(),
+        });
+        if metadata.is_dir() && walk.max_depth > 0 {
+            walk.push(Dir {
+                path: src.to_path_buf(),
+                depth: 0,
+                ancestors: follow_links.then(|| {
+                    Arc::new(Ancestor {
+                        path: src.to_path_buf(),
+                        id: (metadata.dev(), metadata.ino()),
+                        parent: None,
+                    })
+                }),
+            });
+        }
+        let _ = sender.send(Ok(TreeEntry {
+            path: src.to_path_buf(),
+            depth: 0,
+            metadata,
+        }));
+        for _ in 0..options.walk_threads {
+            let (walk, sender) = (walk.clone(), sender.clone());
+            thread::spawn(move || {
+                while let Some(dir) = walk.next() {
+                    let wanted = walk.read(&dir, &sender);
+                    walk.done(wanted);
+                }
+            });
+        }
+        receiver.into_iter()
+    }
+
+    impl Walk {
+        fn push(&self, dir: Dir) {
+            self.queue.lock().unwrap().dirs.push(dir);
+            self.ready.notify_one();
+        }
+
+        /// The next directory to read, or `None` once there are no more.
+        fn next(&self) -> Option<Dir> {
+            let mut queue = self.queue.lock().unwrap();
+            loop {
+                if queue.stopped {
+                    return None;
+                }
+                // The deepest first, which keeps the queue short.
+                if let Some(dir) = queue.dirs.pop() {
+                    queue.busy += 1;
+                    return Some(dir);
+                }
+                if queue.busy == 0 {
+                    return None;
+                }
+                queue = self.ready.wait(queue).unwrap();
+            }
+        }
+
+        /// Note that a directory has been read, and whether the entries are
+        /// still `wanted`.
+        fn done(&self, wanted: bool) {
+            let mut queue = self.queue.lock().unwrap();
+            queue.busy -= 1;
+            queue.stopped |= !wanted;
+            if queue.stopped || (queue.busy == 0 && queue.dirs.is_empty()) {
+                self.ready.notify_all();
+            }
+        }
+
+        /// Send the entries of `dir`, queueing the directories among them.
+        /// Returns whether the entries are still wanted.
+        fn read(&self, dir: &Dir, sender: &SyncSender<Result<TreeEntry, WalkError>>) -> bool {
+            let entries = match fs::read_dir(&dir.path) {
+                Ok(entries) => entries,
+                Err(e) => return sender.send(Err((dir.path.clone(), e.into()))).is_ok(),
+            };
+            for entry in entries {
+                let found = entry
+                    .map_err(|e| (dir.path.clone(), e.into()))
+                    .and_then(|entry| self.entry(&entry, dir));
+                let subdir = match found {
+                    Ok(None) => continue,
+                    Ok(Some((entry, subdir))) => {
+                        if sender.send(Ok(entry)).is_err() {
+                            return false;
+                        }
+                        subdir
+                    }
+                    Err(e) => {
+                        if sender.send(Err(e)).is_err() {
+                            return false;
+                        }
+                        None
+                    }
+                };
+                if let Some(subdir) = subdir {
+                    self.push(subdir);
+                }
+            }
+            true
+        }
+
+        /// The entry `entry` of `dir`, with the directory to read when it is
+        /// one to descend into, or `None` when it is left out.
+        fn entry(
+            &self,
+            entry: &fs::DirEntry,
+            dir: &Dir,
+        ) -> Result<Option<(TreeEntry, Option<Dir>)>, WalkError> {
+            let path = entry.path();
+            let failed = |e: io::Error| (path.clone(), e.into());
+            let is_symlink = entry.file_type().map_err(failed)?.is_symlink();
+            let relative_path = path.strip_prefix(&self.src).unwrap_or(&path);
+            let metadata = if is_symlink && self.follow_links {
+                match fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        if self.show_warnings && !self.filter.is_excluded(relative_path, false) {
+                            warn_skipped(&path, "it is a broken symlink");
+                        }
+                        return Ok(None);
+                    }
+                    Err(e) => return Err(failed(e)),
+                }
+            } else {
+                entry.metadata().map_err(failed)?
+            };
+            if !self.filter.is_empty()
+                && (self.filter.is_excluded(relative_path, metadata.is_dir())
+                    || self.filter.is_filtered_out(&metadata))
+            {
+                return Ok(None);
+            }
+            if self.safe_links && is_symlink && !link_is_safe(&self.root, &path) {
+                if self.show_warnings {
+                    warn_skipped(&path, "it links outside the source tree");
+                }
+                return Ok(None);
+            }
+            let depth = dir.depth + 1;
+            let mut subdir = None;
+            if metadata.is_dir()
+                && depth < self.max_depth
+                && self.device.is_none_or(|device| device == metadata.dev())
+            {
+                let id = (metadata.dev(), metadata.ino());
+                let mut ancestor = dir.ancestors.as_deref();
+                while let Some(this) = ancestor {
+                    if this.id == id {
+                        return Err((
+                            path.clone(),
+                            format!(
+                                "File system loop found: {} points to an ancestor {}",
+                                path.display(),
+                                this.path.display()
+                            )
+                            .into(),
+                        ));
+                    }
+                    ancestor = this.parent.as_deref();
+                }
+                subdir = Some(Dir {
+                    path: path.clone(),
+                    depth,
+                    ancestors: dir.ancestors.clone().map(|parent| {
+                        Arc::new(Ancestor {
+                            path: path.clone(),
+                            id,
+                            parent: Some(parent),
+                        })
+                    }),
+                });
+            }
+            Ok(Some((
+                TreeEntry {
+                    path,
+                    depth,
+                    metadata,
+                },
+                subdir,
+            )))
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::options::CopyOptions;
+use crate::Error;
+use std::path::Path;
+use std::time::Duration;
+
+/// Make `dest` a copy of the tree `src` with [`copy_tree`](crate::copy_tree),
+/// then keep copying what changes in `src` as it does, until
+/// [`CopyOptions::cancel`] is set. Returns the bytes copied in all.
+///
+/// Changes are seen with inotify, so this is Linux only. A file is copied
+/// once nothing has changed it for `debounce`, so one still being written
+/// is copied when it is done, and changes that come together are copied
+/// together with `options.threads` threads. Files created, written, moved
+/// in or whose metadata changed are copied with the same filters and
+/// options as the first copy, and with [`CopyOptions::delete`] entries
+/// removed or moved out of `src` are removed from `dest`. When changes come
+/// in faster than they can be followed the whole tree is copied again.
+///
+/// A change that fails to copy is reported and the watch goes on.
+pub fn watch_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dest: Q,
+    debounce: Duration,
+    options: &CopyOptions,
+) -> Result<u64, Error> {
+    imp::watch_tree(src.as_ref(), dest.as_ref(), debounce, options)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use crate::backup::{back_up, backup_path};
+    use crate::cancel::CancelledError;
+    use crate::copy::itemize;
+    use crate::options::CopyOptions;
+    use crate::tree::{copy_listed, copy_tree, walk};
+    use crate::Error;
+    use nix::errno::Errno;
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// How long to wait for changes before looking again.
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// The directories of the source that are watched, by their watch.
+    struct Watches {
+        inotify: Inotify,
+        dirs: HashMap<WatchDescriptor, PathBuf>,
+    }
+
+    impl Watches {
+        /// Watch every directory of `src` under `relative`, or all of it,
+        /// that a copy of it would walk.
+        fn add(&mut self, src: &Path, relative: &Path, options: &CopyOptions) {
+            let flags = AddWatchFlags::IN_CREATE
+                | AddWatchFlags::IN_MODIFY
+                | AddWatchFlags::IN_CLOSE_WRITE
+                | AddWatchFlags::IN_ATTRIB
+                | AddWatchFlags::IN_MOVED_FROM
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_DELETE
+                | AddWatchFlags::IN_ONLYDIR;
+            let filter = &options.filter;
+            if !relative.as_os_str().is_empty() && filter.is_excluded_with_parents(relative, true) {
+                return;
+            }
+            let top = src.join(relative);
+            for entry in walk(&top, options).filter_map(Result::ok) {
+                if !entry.file_type().is_dir() {
+                    continue;
+                }
+                // The walk filters what is under `top`, rules are of paths
+                // from `src`.
+                let path = entry.path().strip_prefix(src).unwrap_or(entry.path());
+                if !path.as_os_str().is_empty() && filter.is_excluded_with_parents(path, true) {
+                    continue;
+                }
+                match self.inotify.add_watch(entry.path(), flags) {
+                    Ok(wd) => {
+                        self.dirs.insert(wd, path.to_path_buf());
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to watch '{}': {}", entry.path().display(), e);
+                    }
+                }
+            }
+        }
+
+        /// Stop watching the directory `relative` and those in it.
+        fn remove(&mut self, relative: &Path) {
+            let gone: Vec<WatchDescriptor> = self
+                .dirs
+                .iter()
+                .filter(|(_, dir)| dir.starts_with(relative))
+                .map(|(wd, _)| *wd)
+                .collect();
+            for wd in gone {
+                // The kernel has already dropped watches of deleted ones.
+                let _ = self.inotify.rm_watch(wd);
+                self.dirs.remove(&wd);
+            }
+        }
+    }
+
+    pub(super) fn watch_tree(
+        src: &Path,
+        dest: &Path,
+        debounce: Duration,
+        options: &CopyOptions,
+    ) -> Result<u64, Error> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .map_err(|e| format!("Failed to watch '{}': {}", src.display(), e))?;
+        let mut watches = Watches {
+            inotify,
+            dirs: HashMap::new(),
+        };
+        // Watched first, so nothing changed during the first copy is missed.
+        watches.add(src, Path::new(""), options);
+        let mut total = copy_tree(src, dest, options)?;
+        if options.progress {
+            eprintln!(
+                "Watching {} directories of '{}' for changes",
+                watches.dirs.len(),
+                src.display()
+            );
+        }
+        log::info!("Watching '{}' for changes", src.display());
+
+        // Paths relative to the source that changed, and when they last did.
+        let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+        while !options.is_cancelled() {
+            let events = match watches.inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EAGAIN) => {
+                    thread::sleep(POLL_INTERVAL);
+                    Vec::new()
+                }
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(format!("Failed to watch '{}': {}", src.display(), e).into()),
+            };
+            for event in events {
+                if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                    // Too much changed to tell what, so all of it is copied.
+                    pending.insert(PathBuf::new(), Instant::now());
+                    continue;
+                }
+                if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                    watches.dirs.remove(&event.wd);
+                    continue;
+                }
+                let (Some(dir), Some(name)) = (watches.dirs.get(&event.wd), &event.name) else {
+                    continue;
+                };
+                let relative = dir.join(name);
+                if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+                    if event
+                        .mask
+                        .intersects(AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM)
+                    {
+                        watches.remove(&relative);
+                    } else if event
+                        .mask
+                        .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+                    {
+                        watches.add(src, &relative, options);
+                    }
+                }
+                pending.insert(relative, Instant::now());
+            }
+
+            let due: Vec<PathBuf> = pending
+                .iter()
+                .filter(|(_, changed)| changed.elapsed() >= debounce)
+                .map(|(path, _)| path.clone())
+                .collect();
+            if due.is_empty() {
+                continue;
+            }
+            for path in &due {
+                pending.remove(path);
+            }
+            match copy_changes(src, dest, &due, options) {
+                Ok(copied) => total += copied,
+                Err(e) if e.is::<CancelledError>() => break,
+                Err(e) => {
+                    log::error!("{}", e);
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    /// Copy the entries of `src` at `changed`, relative to it, to `dest`,
+    /// and with [`CopyOptions::delete`] remove those that are gone.
+    fn copy_changes(
+        src: &Path,
+        dest: &Path,
+        changed: &[PathBuf],
+        options: &CopyOptions,
+    ) -> Result<u64, Error> {
+        let filter = &options.filter;
+        let mut copied = Vec::new();
+        for relative in changed {
+            let metadata = src.join(relative).symlink_metadata();
+            let is_dir = metadata.as_ref().is_ok_and(|metadata| metadata.is_dir());
+            // Changes are seen in directories a walk would not go into.
+            if !relative.as_os_str().is_empty() && filter.is_excluded_with_parents(relative, is_dir)
+            {
+                continue;
+            }
+            match metadata {
+                Ok(metadata) if !is_dir && filter.is_filtered_out(&metadata) => {}
+                Ok(_) => copied.push(relative.clone()),
+                Err(_) if options.delete => remove(&dest.join(relative), options)?,
+                Err(_) => {}
+            }
+        }
+        if copied.is_empty() {
+            return Ok(0);
+        }
+        copied.sort();
+        copy_listed(src, &copied, dest, options)
+    }
+
+    /// Remove `path`, whose source is gone, or move it aside with
+    /// [`CopyOptions::backup`].
+    fn remove(path: &Path, options: &CopyOptions) -> Result<(), Error> {
+        let Ok(metadata) = path.symlink_metadata() else {
+            return Ok(());
+        };
+        log::info!("Delete '{}'", path.display());
+        if options.progress {
+            eprintln!(" Delete {}", path.display());
+        }
+        if metadata.is_dir() {
+            itemize("deleted", &path.join(""), None, options);
+            fs::remove_dir_all(path)
+        } else if let Some(backup) = backup_path(path, options) {
+            itemize("deleted", path, None, options);
+            return back_up(path, &backup, false);
+        } else {
+            itemize("deleted", path, None, options);
+            fs::remove_file(path)
+        }
+        .map_err(|e| format!("Failed to delete '{}': {}", path.display(), e).into())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::filter::Filter;
+        use crate::testing::scratch;
+
+        #[test]
+        fn leaves_out_excluded_directories() {
+            let dir = scratch("watch-excluded");
+            let (src, dest) = (dir.join("src"), dir.join("dest"));
+            fs::create_dir_all(src.join("node_modules/pkg")).unwrap();
+            fs::create_dir_all(src.join("lib")).unwrap();
+            fs::write(src.join("node_modules/pkg/index.js"), "x").unwrap();
+            fs::write(src.join("lib/main.js"), "x").unwrap();
+            fs::create_dir(&dest).unwrap();
+            let options = CopyOptions::new().filter(Filter::new().exclude("node_modules/"));
+
+            let inotify = Inotify::init(InitFlags::IN_CLOEXEC).unwrap();
+            let mut watches = Watches {
+                inotify,
+                dirs: HashMap::new(),
+            };
+            watches.add(&src, Path::new("node_modules"), &options);
+            watches.add(&src, Path::new("node_modules/pkg"), &options);
+            assert!(watches.dirs.is_empty());
+            watches.add(&src, Path::new(""), &options);
+            let mut dirs: Vec<_> = watches.dirs.into_values().collect();
+            dirs.sort();
+            assert_eq!(dirs, [PathBuf::new(), PathBuf::from("lib")]);
+
+            let changed = [
+                PathBuf::from("node_modules/pkg"),
+                PathBuf::from("node_modules/pkg/index.js"),
+                PathBuf::from("lib/main.js"),
+            ];
+            copy_changes(&src, &dest, &changed, &options).unwrap();
+            assert!(dest.join("lib/main.js").exists());
+            assert!(!dest.join("node_modules").exists());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use crate::options::CopyOptions;
+    use crate::Error;
+    use std::path::Path;
+    use std::time::Duration;
+
+    pub(super) fn watch_tree(
+        _src: &Path,
+        _dest: &Path,
+        _debounce: Duration,
+        _options: &CopyOptions,
+    ) -> Result<u64, Error> {
+        Err("Watching a tree for changes is only supported on Linux".into())
+    }
+}
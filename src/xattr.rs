@@ -0,0 +1,172 @@
+use crate::Error;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Namespaces of the extended attributes copied. `security` and `system`
+/// attributes belong to the kernel and security modules of the machine.
+#[cfg(target_os = "linux")]
+const NAMESPACES: [&[u8]; 2] = [b"user.", b"trusted."];
+
+/// The attributes Linux keeps POSIX ACLs in, the default ACL being the one
+/// directories hand down to what is created in them.
+#[cfg(target_os = "linux")]
+const ACLS: [&[u8]; 2] = [b"system.posix_acl_access", b"system.posix_acl_default"];
+
+/// Copy the `user` and `trusted` extended attributes of `src` onto `dest`.
+/// Reading `trusted` attributes needs root, without it they are not seen.
+#[cfg(target_os = "linux")]
+pub(crate) fn copy_xattrs(src: &Path, dest: &Path) -> Result<(), Error> {
+    copy_attributes(src, dest, |name| {
+        NAMESPACES.iter().any(|ns| name.starts_with(ns))
+    })
+    .map_err(|(message, e)| format!("{}: {}", message, e).into())
+}
+
+/// Copy the POSIX ACLs of `src` onto `dest`. When the filesystem of `dest`
+/// has no ACLs they are left out, with a warning the first time.
+#[cfg(target_os = "linux")]
+pub(crate) fn copy_acls(src: &Path, dest: &Path) -> Result<(), Error> {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    match copy_attributes(src, dest, |name| ACLS.contains(&name)) {
+        Ok(()) => Ok(()),
+        Err((_, e)) if e.raw_os_error() == Some(nix::libc::EOPNOTSUPP) => {
+            if !WARNED.swap(true, Ordering::SeqCst) {
+                log::warn!(
+                    "The filesystem of '{}' does not support ACLs, they are not copied",
+                    dest.display()
+                );
+            }
+            Ok(())
+        }
+        Err((message, e)) => Err(format!("{}: {}", message, e).into()),
+    }
+}
+
+/// Copy the extended attributes of `src` whose name is `wanted` onto `dest`.
+/// Errors come with what failed.
+#[cfg(target_os = "linux")]
+fn copy_attributes(
+    src: &Path,
+    dest: &Path,
+    wanted: impl Fn(&[u8]) -> bool,
+) -> Result<(), (String, std::io::Error)> {
+    let names = imp::list(src).map_err(|e| {
+        let message = format!(
+            "Failed to list the extended attributes of '{}'",
+            src.display()
+        );
+        (message, e)
+    })?;
+    for name in names.split(|&b| b == 0).filter(|name| wanted(name)) {
+        let name_text = String::from_utf8_lossy(name);
+        let value = match imp::get(src, name) {
+            Ok(Some(value)) => value,
+            // Removed since it was listed.
+            Ok(None) => continue,
+            Err(e) => {
+                let message = format!(
+                    "Failed to read extended attribute '{}' of '{}'",
+                    name_text,
+                    src.display()
+                );
+                return Err((message, e));
+            }
+        };
+        imp::set(dest, name, &value).map_err(|e| {
+            let message = format!(
+                "Failed to set extended attribute '{}' of '{}'",
+                name_text,
+                dest.display()
+            );
+            (message, e)
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn copy_xattrs(_src: &Path, _dest: &Path) -> Result<(), Error> {
+    Err("Extended attributes are only copied on Linux".into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn copy_acls(_src: &Path, _dest: &Path) -> Result<(), Error> {
+    Err("ACLs are only copied on Linux".into())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Call `f` with a buffer of the size it asks for when given none,
+    /// growing it while the value grows under it.
+    fn read_sized(f: impl Fn(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let size = f(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buffer = vec![0u8; size as usize];
+            let read = f(buffer.as_mut_ptr().cast(), buffer.len());
+            if read >= 0 {
+                buffer.truncate(read as usize);
+                return Ok(buffer);
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ERANGE) {
+                return Err(e);
+            }
+        }
+    }
+
+    /// The names of the attributes of `path`, each ending with a nul.
+    /// Filesystems without extended attributes have none.
+    pub(super) fn list(path: &Path) -> io::Result<Vec<u8>> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let names = read_sized(|buffer, size| unsafe {
+            libc::llistxattr(path.as_ptr(), buffer.cast(), size)
+        });
+        match names {
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => Ok(Vec::new()),
+            names => names,
+        }
+    }
+
+    pub(super) fn get(path: &Path, name: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let (path, name) = (c_string(path.as_os_str().as_bytes())?, c_string(name)?);
+        let value = read_sized(|buffer, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buffer, size)
+        });
+        match value {
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+            value => value.map(Some),
+        }
+    }
+
+    pub(super) fn set(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+        let (path, name) = (c_string(path.as_os_str().as_bytes())?, c_string(name)?);
+        let result = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}